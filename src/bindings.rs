@@ -0,0 +1,317 @@
+use simple_nes::{Buttons, ControllerPort};
+use std::collections::HashMap;
+use std::path::Path;
+use winit::keyboard::KeyCode;
+
+#[derive(Clone, Copy)]
+pub enum Input {
+    Button(Buttons),
+    /// Repeatedly presses and releases the button while held
+    Turbo(Buttons),
+}
+
+pub type KeyBindings = HashMap<KeyCode, (ControllerPort, Input)>;
+pub type GamepadBindings = HashMap<gilrs::Button, Input>;
+
+pub struct Bindings {
+    pub keys: KeyBindings,
+    /// Used by gamepads controlling the respective port
+    pub gamepad: [GamepadBindings; 2],
+}
+
+/// Key names are the names of winit's `KeyCode` variants
+macro_rules! key_codes {
+    ($($name:ident),* $(,)?) => {
+        fn parse_key_code(name: &str) -> Option<KeyCode> {
+            match name {
+                $(stringify!($name) => Some(KeyCode::$name),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+key_codes!(
+    KeyA,
+    KeyB,
+    KeyC,
+    KeyD,
+    KeyE,
+    KeyF,
+    KeyG,
+    KeyH,
+    KeyI,
+    KeyJ,
+    KeyK,
+    KeyL,
+    KeyM,
+    KeyN,
+    KeyO,
+    KeyP,
+    KeyQ,
+    KeyR,
+    KeyS,
+    KeyT,
+    KeyU,
+    KeyV,
+    KeyW,
+    KeyX,
+    KeyY,
+    KeyZ,
+    Digit0,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+    NumpadEnter,
+    NumpadAdd,
+    NumpadSubtract,
+    NumpadMultiply,
+    NumpadDivide,
+    NumpadDecimal,
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    Enter,
+    Backspace,
+    Tab,
+    Space,
+    Escape,
+    ShiftLeft,
+    ShiftRight,
+    ControlLeft,
+    ControlRight,
+    AltLeft,
+    AltRight,
+    Comma,
+    Period,
+    Slash,
+    Semicolon,
+    Quote,
+    BracketLeft,
+    BracketRight,
+    Backslash,
+    Backquote,
+    Minus,
+    Equal,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+);
+
+/// Gamepad button names are the names of gilrs' `Button` variants
+macro_rules! gamepad_buttons {
+    ($($name:ident),* $(,)?) => {
+        fn parse_gamepad_button(name: &str) -> Option<gilrs::Button> {
+            match name {
+                $(stringify!($name) => Some(gilrs::Button::$name),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+gamepad_buttons!(
+    South,
+    East,
+    North,
+    West,
+    C,
+    Z,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+);
+
+fn parse_input(name: &str) -> Option<Input> {
+    match name.to_ascii_lowercase().as_str() {
+        "a" => Some(Input::Button(Buttons::A)),
+        "b" => Some(Input::Button(Buttons::B)),
+        "select" => Some(Input::Button(Buttons::SELECT)),
+        "start" => Some(Input::Button(Buttons::START)),
+        "up" => Some(Input::Button(Buttons::UP)),
+        "down" => Some(Input::Button(Buttons::DOWN)),
+        "left" => Some(Input::Button(Buttons::LEFT)),
+        "right" => Some(Input::Button(Buttons::RIGHT)),
+        "turbo_a" => Some(Input::Turbo(Buttons::A)),
+        "turbo_b" => Some(Input::Turbo(Buttons::B)),
+        _ => None,
+    }
+}
+
+fn default_key_bindings() -> KeyBindings {
+    use ControllerPort::PortA;
+    use Input::{Button, Turbo};
+
+    HashMap::from([
+        (KeyCode::ArrowUp, (PortA, Button(Buttons::UP))),
+        (KeyCode::KeyW, (PortA, Button(Buttons::UP))),
+        (KeyCode::ArrowDown, (PortA, Button(Buttons::DOWN))),
+        (KeyCode::KeyS, (PortA, Button(Buttons::DOWN))),
+        (KeyCode::ArrowLeft, (PortA, Button(Buttons::LEFT))),
+        (KeyCode::KeyA, (PortA, Button(Buttons::LEFT))),
+        (KeyCode::ArrowRight, (PortA, Button(Buttons::RIGHT))),
+        (KeyCode::KeyD, (PortA, Button(Buttons::RIGHT))),
+        (KeyCode::Enter, (PortA, Button(Buttons::START))),
+        (KeyCode::Backspace, (PortA, Button(Buttons::SELECT))),
+        (KeyCode::KeyJ, (PortA, Button(Buttons::A))),
+        (KeyCode::KeyK, (PortA, Button(Buttons::B))),
+        (KeyCode::KeyU, (PortA, Turbo(Buttons::A))),
+        (KeyCode::KeyI, (PortA, Turbo(Buttons::B))),
+    ])
+}
+
+fn default_gamepad_bindings() -> GamepadBindings {
+    use gilrs::Button as Pad;
+    use Input::{Button, Turbo};
+
+    HashMap::from([
+        (Pad::DPadUp, Button(Buttons::UP)),
+        (Pad::DPadDown, Button(Buttons::DOWN)),
+        (Pad::DPadLeft, Button(Buttons::LEFT)),
+        (Pad::DPadRight, Button(Buttons::RIGHT)),
+        (Pad::Start, Button(Buttons::START)),
+        (Pad::Select, Button(Buttons::SELECT)),
+        (Pad::East, Button(Buttons::A)),
+        (Pad::South, Button(Buttons::A)),
+        (Pad::West, Button(Buttons::B)),
+        (Pad::North, Button(Buttons::B)),
+        (Pad::RightTrigger, Turbo(Buttons::A)),
+        (Pad::LeftTrigger, Turbo(Buttons::B)),
+    ])
+}
+
+pub fn default_bindings() -> Bindings {
+    Bindings {
+        keys: default_key_bindings(),
+        gamepad: [default_gamepad_bindings(), default_gamepad_bindings()],
+    }
+}
+
+/// Parses a TOML style file with a `[controller_a]` and a `[controller_b]` table,
+/// each mapping key or gamepad button names to buttons, e.g. `KeyJ = "a"` or `South = "a"`.
+/// Gamepads keep their default bindings unless the file binds at least one gamepad button.
+/// Invalid lines are reported and skipped.
+fn parse_bindings(text: &str) -> (Bindings, Vec<String>) {
+    let mut keys = KeyBindings::new();
+    let mut gamepad = [GamepadBindings::new(), GamepadBindings::new()];
+    let mut errors = Vec::new();
+    let mut port = None;
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(table) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            port = match table.trim() {
+                "controller_a" => Some(ControllerPort::PortA),
+                "controller_b" => Some(ControllerPort::PortB),
+                table => {
+                    errors.push(format!("line {line_number}: unknown table `{table}`"));
+                    None
+                }
+            };
+            continue;
+        }
+
+        let Some((key, button)) = line.split_once('=') else {
+            errors.push(format!("line {line_number}: expected `KEY = \"BUTTON\"`"));
+            continue;
+        };
+        let Some(port) = port else {
+            errors.push(format!(
+                "line {line_number}: binding outside of a controller table"
+            ));
+            continue;
+        };
+
+        let key = key.trim();
+        let button = button.trim().trim_matches('"');
+        let Some(input) = parse_input(button) else {
+            errors.push(format!("line {line_number}: unknown button `{button}`"));
+            continue;
+        };
+        if let Some(key) = parse_key_code(key) {
+            keys.insert(key, (port, input));
+        } else if let Some(pad_button) = parse_gamepad_button(key) {
+            gamepad[port as usize].insert(pad_button, input);
+        } else {
+            errors.push(format!(
+                "line {line_number}: unknown key or gamepad button `{key}`"
+            ));
+        }
+    }
+
+    if gamepad.iter().all(HashMap::is_empty) {
+        gamepad = [default_gamepad_bindings(), default_gamepad_bindings()];
+    }
+
+    (Bindings { keys, gamepad }, errors)
+}
+
+/// Falls back to the default bindings if the file can't be read
+pub fn load_bindings(file: &Path) -> Bindings {
+    match std::fs::read_to_string(file) {
+        Ok(text) => {
+            let (bindings, errors) = parse_bindings(&text);
+            for error in errors {
+                eprintln!("{}: {error}", file.display());
+            }
+            bindings
+        }
+        Err(err) => {
+            eprintln!(
+                "failed to read {}: {err}, using default key bindings",
+                file.display()
+            );
+            default_bindings()
+        }
+    }
+}
@@ -1,5 +1,13 @@
+mod archive;
+pub mod hash;
+pub mod patch;
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::Region;
+
 const PRG_BANK_SIZE: usize = 0x4000;
 const CHR_BANK_SIZE: usize = 0x2000;
+const TRAINER_SIZE: usize = 512;
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum MirrorMode {
@@ -7,6 +15,43 @@ pub enum MirrorMode {
     Vertical,
     OneScreenLow,
     OneScreenHigh,
+    FourScreen,
+}
+
+impl MirrorMode {
+    fn save_state(self, state: &mut StateWriter) {
+        state.write_u8(match self {
+            Self::Horizontal => 0,
+            Self::Vertical => 1,
+            Self::OneScreenLow => 2,
+            Self::OneScreenHigh => 3,
+            Self::FourScreen => 4,
+        });
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<Self, StateError> {
+        match state.read_u8()? {
+            0 => Ok(Self::Horizontal),
+            1 => Ok(Self::Vertical),
+            2 => Ok(Self::OneScreenLow),
+            3 => Ok(Self::OneScreenHigh),
+            4 => Ok(Self::FourScreen),
+            _ => Err(StateError::InvalidData),
+        }
+    }
+}
+
+/// Wraps a bank index the same way the address lines of a smaller ROM would
+#[inline]
+fn mask_bank(bank: usize, bank_count: usize) -> usize {
+    // ROM sizes that are not a power of two mirror the remaining banks
+    (bank & (bank_count.next_power_of_two() - 1)) % bank_count.max(1)
+}
+
+/// Wraps an address into ROM the same way `mask_bank` wraps banks, so any ROM size can be read safely
+#[inline]
+fn mask_rom_addr(addr: usize, rom_size: usize) -> usize {
+    (addr & (rom_size.next_power_of_two() - 1)) % rom_size.max(1)
 }
 
 enum MapperReadResult {
@@ -23,6 +68,39 @@ trait Mapper: Send {
 
     fn on_scanline(&mut self);
 
+    /// Called for every PPU memory access, for mappers that watch the PPU address bus
+    fn ppu_a12(&mut self, _addr: u16) {}
+
+    /// Called once per CPU cycle, for mappers with cycle based timers
+    fn on_cpu_cycle(&mut self) {}
+
+    /// Output of expansion audio chips on the cartridge, added to the output of the APU's mixer
+    /// which ranges from 0.0 to about 1.0
+    fn audio_sample(&self) -> f32 {
+        0.0
+    }
+
+    /// Whether writes to ROM are ANDed with the ROM byte at the written address
+    fn has_bus_conflicts(&self) -> bool {
+        false
+    }
+
+    /// Minimum size of the CHR RAM to allocate if the cartridge has no CHR ROM,
+    /// the header can ask for more
+    fn chr_ram_size(&self) -> usize {
+        CHR_BANK_SIZE
+    }
+
+    /// Maps a PPU address into CHR RAM, `None` if the address is not backed by CHR RAM.
+    /// By default CHR RAM is banked the same way CHR ROM would be.
+    /// Mappers that also place the nametables in CHR RAM map addresses above $2000 here.
+    fn map_chr_ram(&self, addr: u16) -> Option<usize> {
+        match self.ppu_read(addr) {
+            MapperReadResult::Address(mapped_addr) if addr <= 0x1FFF => mapped_addr,
+            _ => None,
+        }
+    }
+
     fn cpu_read(&self, addr: u16) -> MapperReadResult;
 
     fn ppu_read(&self, addr: u16) -> MapperReadResult;
@@ -30,20 +108,22 @@ trait Mapper: Send {
     fn cpu_write(&mut self, addr: u16, data: u8);
 
     fn reset(&mut self);
-}
 
-struct NRom {
-    mask: u16,
-}
+    /// Work RAM mapped at $6000-$7FFF, if the board has any
+    fn prg_ram(&self) -> Option<&[u8]>;
 
-impl NRom {
-    fn new(prg_banks: u8) -> Self {
-        Self {
-            mask: if prg_banks > 1 { 0x7FFF } else { 0x3FFF },
-        }
-    }
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]>;
+
+    /// Writes all registers, bank selections and RAM of the mapper
+    fn save_state(&self, state: &mut StateWriter);
+
+    /// Reads back everything written by `save_state`, in the same order
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
 }
 
+/// 16KB of PRG ROM are mirrored by `Cartridge` wrapping addresses around the actual ROM size
+struct NRom;
+
 impl Mapper for NRom {
     fn mirror(&self) -> Option<MirrorMode> {
         None
@@ -59,7 +139,7 @@ impl Mapper for NRom {
 
     fn cpu_read(&self, addr: u16) -> MapperReadResult {
         if addr >= 0x8000 {
-            MapperReadResult::Address(Some((addr & self.mask) as usize))
+            MapperReadResult::Address(Some((addr & 0x7FFF) as usize))
         } else {
             MapperReadResult::Address(None)
         }
@@ -76,10 +156,25 @@ impl Mapper for NRom {
     fn cpu_write(&mut self, _addr: u16, _data: u8) {}
 
     fn reset(&mut self) {}
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
 }
 
 struct Mmc1 {
     prg_banks: u8,
+    chr_banks: u8,
     load: u8,
     load_count: u8,
     control: u8,
@@ -94,9 +189,10 @@ struct Mmc1 {
 }
 
 impl Mmc1 {
-    fn new(prg_banks: u8) -> Self {
+    fn new(prg_banks: u8, chr_banks: u8) -> Self {
         Self {
             prg_banks,
+            chr_banks,
             load: 0,
             load_count: 0,
             control: 0x1C,
@@ -110,6 +206,12 @@ impl Mmc1 {
             prg_ram: vec![0; 0x2000].into_boxed_slice(),
         }
     }
+
+    #[inline]
+    fn chr_bank_offset(&self, bank: u8) -> usize {
+        // Banks are 4k in size
+        mask_bank(bank as usize, (self.chr_banks as usize) * 2) * 0x1000
+    }
 }
 
 impl Mapper for Mmc1 {
@@ -155,19 +257,19 @@ impl Mapper for Mmc1 {
         if addr <= 0x1FFF {
             if (self.control & 0x10) != 0 {
                 // 4k mode
-                if addr <= 0x0FFF {
-                    MapperReadResult::Address(Some(
-                        (self.chr_bank_4_lo as usize) * 0x1000 + ((addr & 0x0FFF) as usize),
-                    ))
+                let bank = if addr <= 0x0FFF {
+                    self.chr_bank_4_lo
                 } else {
-                    MapperReadResult::Address(Some(
-                        (self.chr_bank_4_hi as usize) * 0x1000 + ((addr & 0x0FFF) as usize),
-                    ))
-                }
+                    self.chr_bank_4_hi
+                };
+
+                MapperReadResult::Address(Some(
+                    self.chr_bank_offset(bank) + ((addr & 0x0FFF) as usize),
+                ))
             } else {
-                // 8k mode
+                // 8k mode, the bank number is still given in 4k units
                 MapperReadResult::Address(Some(
-                    (self.chr_bank_8 as usize) * CHR_BANK_SIZE + ((addr & 0x1FFF) as usize),
+                    self.chr_bank_offset(self.chr_bank_8) + ((addr & 0x1FFF) as usize),
                 ))
             }
         } else {
@@ -252,18 +354,60 @@ impl Mapper for Mmc1 {
         self.chr_bank_4_lo = 0;
         self.chr_bank_4_hi = 0;
     }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.load);
+        state.write_u8(self.load_count);
+        state.write_u8(self.control);
+        state.write_u8(self.prg_bank_32);
+        state.write_u8(self.chr_bank_8);
+        state.write_u8(self.prg_bank_16_lo);
+        state.write_u8(self.prg_bank_16_hi);
+        state.write_u8(self.chr_bank_4_lo);
+        state.write_u8(self.chr_bank_4_hi);
+        self.mirror.save_state(state);
+        state.write_bytes(&self.prg_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.load = state.read_u8()? & 0x1F;
+        self.load_count = state.read_u8()?;
+        if self.load_count >= 5 {
+            return Err(StateError::InvalidData);
+        }
+        self.control = state.read_u8()? & 0x1F;
+        self.prg_bank_32 = state.read_u8()?;
+        self.chr_bank_8 = state.read_u8()?;
+        self.prg_bank_16_lo = state.read_u8()?;
+        self.prg_bank_16_hi = state.read_u8()?;
+        self.chr_bank_4_lo = state.read_u8()?;
+        self.chr_bank_4_hi = state.read_u8()?;
+        self.mirror = MirrorMode::load_state(state)?;
+        state.read_bytes_into(&mut self.prg_ram)?;
+        Ok(())
+    }
 }
 
 struct UxRom {
     prg_bank_lo: u8,
     prg_bank_hi: u8,
+    bus_conflicts: bool,
 }
 
 impl UxRom {
-    fn new(prg_banks: u8) -> Self {
+    fn new(prg_banks: u8, bus_conflicts: bool) -> Self {
         Self {
             prg_bank_lo: 0,
             prg_bank_hi: prg_banks - 1,
+            bus_conflicts,
         }
     }
 }
@@ -312,18 +456,41 @@ impl Mapper for UxRom {
     fn reset(&mut self) {
         self.prg_bank_lo = 0;
     }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.prg_bank_lo);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank_lo = state.read_u8()?;
+        Ok(())
+    }
 }
 
 struct CNRom {
-    mask: u16,
+    chr_banks: u8,
     chr_bank: u8,
+    bus_conflicts: bool,
 }
 
 impl CNRom {
-    fn new(prg_banks: u8) -> Self {
+    fn new(chr_banks: u8, bus_conflicts: bool) -> Self {
         Self {
-            mask: if prg_banks > 1 { 0x7FFF } else { 0x3FFF },
+            chr_banks,
             chr_bank: 0,
+            bus_conflicts,
         }
     }
 }
@@ -343,7 +510,7 @@ impl Mapper for CNRom {
 
     fn cpu_read(&self, addr: u16) -> MapperReadResult {
         if addr >= 0x8000 {
-            MapperReadResult::Address(Some((addr & self.mask) as usize))
+            MapperReadResult::Address(Some((addr & 0x7FFF) as usize))
         } else {
             MapperReadResult::Address(None)
         }
@@ -351,9 +518,8 @@ impl Mapper for CNRom {
 
     fn ppu_read(&self, addr: u16) -> MapperReadResult {
         if addr <= 0x1FFF {
-            MapperReadResult::Address(Some(
-                (self.chr_bank as usize) * CHR_BANK_SIZE + (addr as usize),
-            ))
+            let bank = mask_bank(self.chr_bank as usize, self.chr_banks as usize);
+            MapperReadResult::Address(Some(bank * CHR_BANK_SIZE + (addr as usize)))
         } else {
             MapperReadResult::Address(None)
         }
@@ -368,6 +534,27 @@ impl Mapper for CNRom {
     fn reset(&mut self) {
         self.chr_bank = 0;
     }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.chr_bank = state.read_u8()?;
+        Ok(())
+    }
 }
 
 struct Mmc3 {
@@ -379,15 +566,19 @@ struct Mmc3 {
     interrupt_step: u16,
     interrupt_active: bool,
     interrupt_enabled: bool,
+    a12_low_count: u8,
     prg_bank_mode: bool,
     chr_inversion: bool,
     prg_banks: u8,
+    chr_banks: u8,
     mirror: MirrorMode,
     prg_ram: Box<[u8]>,
+    prg_ram_enabled: bool,
+    prg_ram_write_protect: bool,
 }
 
 impl Mmc3 {
-    fn new(prg_banks: u8) -> Self {
+    fn new(prg_banks: u8, chr_banks: u8) -> Self {
         Self {
             target_reg: 0,
             register: [0; 8],
@@ -402,11 +593,16 @@ impl Mmc3 {
             interrupt_step: 0,
             interrupt_active: false,
             interrupt_enabled: false,
+            a12_low_count: 0,
             prg_bank_mode: false,
             chr_inversion: false,
             prg_banks,
+            chr_banks,
             mirror: MirrorMode::Horizontal,
             prg_ram: vec![0; 0x2000].into_boxed_slice(),
+            // Some games never enable the RAM, so it starts out enabled like most emulators do
+            prg_ram_enabled: true,
+            prg_ram_write_protect: false,
         }
     }
 }
@@ -424,21 +620,41 @@ impl Mapper for Mmc3 {
         self.interrupt_active = false;
     }
 
-    fn on_scanline(&mut self) {
-        if self.interrupt_counter == 0 {
-            self.interrupt_counter = self.interrupt_step;
-        } else {
-            self.interrupt_counter -= 1;
+    fn on_scanline(&mut self) {}
+
+    // https://www.nesdev.org/wiki/MMC3#IRQ_Specifics
+    fn ppu_a12(&mut self, addr: u16) {
+        // A12 has to stay low for a while before a rising edge is counted, which filters out
+        // the toggling between nametable and pattern fetches within a single tile.
+        // The real filter counts CPU cycles, three consecutive low accesses are equivalent.
+        const A12_FILTER: u8 = 3;
+
+        if (addr & 0x1000) == 0 {
+            self.a12_low_count = self.a12_low_count.saturating_add(1);
+            return;
         }
 
-        if (self.interrupt_counter == 0) && self.interrupt_enabled {
-            self.interrupt_active = true;
+        if self.a12_low_count >= A12_FILTER {
+            if self.interrupt_counter == 0 {
+                self.interrupt_counter = self.interrupt_step;
+            } else {
+                self.interrupt_counter -= 1;
+            }
+
+            if (self.interrupt_counter == 0) && self.interrupt_enabled {
+                self.interrupt_active = true;
+            }
         }
+        self.a12_low_count = 0;
     }
 
     fn cpu_read(&self, addr: u16) -> MapperReadResult {
         if (0x6000..=0x7FFF).contains(&addr) {
-            MapperReadResult::Data(self.prg_ram[(addr & 0x1FFF) as usize])
+            if self.prg_ram_enabled {
+                MapperReadResult::Data(self.prg_ram[(addr & 0x1FFF) as usize])
+            } else {
+                MapperReadResult::Address(None)
+            }
         } else if addr >= 0x8000 {
             let bank = ((addr >> 13) & 0x03) as usize;
             let mapped_addr = self.prg_bank[bank] + ((addr & 0x1FFF) as usize);
@@ -450,8 +666,14 @@ impl Mapper for Mmc3 {
 
     fn ppu_read(&self, addr: u16) -> MapperReadResult {
         if addr <= 0x1FFF {
+            const CHR_BANK_SIZE_L: usize = 0x0400;
+
             let bank = ((addr >> 10u32) & 0x07) as usize;
-            let mapped_addr = self.chr_bank[bank] + ((addr & 0x03FF) as usize);
+            let chr_bank = mask_bank(
+                self.chr_bank[bank] / CHR_BANK_SIZE_L,
+                (self.chr_banks as usize) * (CHR_BANK_SIZE / CHR_BANK_SIZE_L),
+            );
+            let mapped_addr = chr_bank * CHR_BANK_SIZE_L + ((addr & 0x03FF) as usize);
             MapperReadResult::Address(Some(mapped_addr))
         } else {
             MapperReadResult::Address(None)
@@ -463,7 +685,9 @@ impl Mapper for Mmc3 {
         const CHR_BANK_SIZE_L: usize = 0x0400;
 
         if (0x6000..=0x7FFF).contains(&addr) {
-            self.prg_ram[(addr & 0x1FFF) as usize] = data;
+            if self.prg_ram_enabled && !self.prg_ram_write_protect {
+                self.prg_ram[(addr & 0x1FFF) as usize] = data;
+            }
         } else if addr >= 0x8000 {
             if addr <= 0x9FFF {
                 // Bank select
@@ -512,6 +736,10 @@ impl Mapper for Mmc3 {
                     } else {
                         self.mirror = MirrorMode::Vertical;
                     }
+                } else {
+                    // PRG RAM protect
+                    self.prg_ram_enabled = (data & 0x80) != 0;
+                    self.prg_ram_write_protect = (data & 0x40) != 0;
                 }
             } else if addr <= 0xDFFF {
                 // Interrupts
@@ -537,11 +765,14 @@ impl Mapper for Mmc3 {
         self.prg_bank_mode = false;
         self.chr_inversion = false;
         self.mirror = MirrorMode::Horizontal;
+        self.prg_ram_enabled = true;
+        self.prg_ram_write_protect = false;
 
         self.interrupt_active = false;
         self.interrupt_enabled = false;
         self.interrupt_counter = 0;
         self.interrupt_step = 0;
+        self.a12_low_count = 0;
 
         self.register = [0; 8];
         self.chr_bank = [0; 8];
@@ -552,18 +783,402 @@ impl Mapper for Mmc3 {
             ((self.prg_banks as usize) * 2 - 1) * 0x2000,
         ];
     }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_usize(self.target_reg);
+        for bank in &self.register {
+            state.write_usize(*bank);
+        }
+        for bank in &self.prg_bank {
+            state.write_usize(*bank);
+        }
+        for bank in &self.chr_bank {
+            state.write_usize(*bank);
+        }
+        state.write_u16(self.interrupt_counter);
+        state.write_u16(self.interrupt_step);
+        state.write_bool(self.interrupt_active);
+        state.write_bool(self.interrupt_enabled);
+        state.write_u8(self.a12_low_count);
+        state.write_bool(self.prg_bank_mode);
+        state.write_bool(self.chr_inversion);
+        self.mirror.save_state(state);
+        state.write_bytes(&self.prg_ram);
+        state.write_bool(self.prg_ram_enabled);
+        state.write_bool(self.prg_ram_write_protect);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.target_reg = state.read_usize()? & 0x07;
+        for bank in &mut self.register {
+            *bank = state.read_usize()? & 0xFF;
+        }
+        // The bank offsets are masked to what the registers and the largest PRG ROM can produce
+        for bank in &mut self.prg_bank {
+            *bank = state.read_usize()? & 0x3F_E000;
+        }
+        for bank in &mut self.chr_bank {
+            *bank = state.read_usize()? & 0x7_FC00;
+        }
+        self.interrupt_counter = state.read_u16()?;
+        self.interrupt_step = state.read_u16()?;
+        self.interrupt_active = state.read_bool()?;
+        self.interrupt_enabled = state.read_bool()?;
+        self.a12_low_count = state.read_u8()?;
+        self.prg_bank_mode = state.read_bool()?;
+        self.chr_inversion = state.read_bool()?;
+        self.mirror = MirrorMode::load_state(state)?;
+        state.read_bytes_into(&mut self.prg_ram)?;
+        self.prg_ram_enabled = state.read_bool()?;
+        self.prg_ram_write_protect = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/MMC5
+// ExRAM nametables, fill mode, split screen and the extra sound channels are not emulated
+struct Mmc5 {
+    prg_banks: u8,
+    chr_banks: u8,
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    /// $5113-$5117
+    prg_reg: [u8; 5],
+    /// $5120-$5127, used for sprites
+    chr_reg_a: [usize; 8],
+    /// $5128-$512B, used for the background in 8x16 sprite mode
+    chr_reg_b: [usize; 4],
+    chr_upper: u8,
+    /// 8x16 sprites can't be told apart from the background without snooping on the PPU,
+    /// so the register set that was written last is used for all fetches
+    chr_use_b: bool,
+    interrupt_target: u8,
+    interrupt_enabled: bool,
+    interrupt_active: bool,
+    in_frame: bool,
+    scanline_counter: u8,
+    multiplicand: u8,
+    multiplier: u8,
+    prg_ram: Box<[u8]>,
+    exram: Box<[u8]>,
+}
+
+enum Mmc5PrgTarget {
+    Rom(usize),
+    Ram(usize),
+}
+
+impl Mmc5 {
+    fn new(prg_banks: u8, chr_banks: u8) -> Self {
+        Self {
+            prg_banks,
+            chr_banks,
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            prg_reg: [0, 0, 0, 0, 0xFF],
+            chr_reg_a: [0; 8],
+            chr_reg_b: [0; 4],
+            chr_upper: 0,
+            chr_use_b: false,
+            interrupt_target: 0,
+            interrupt_enabled: false,
+            interrupt_active: false,
+            in_frame: false,
+            scanline_counter: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            // Enough for the largest boards, smaller ones just mirror it
+            prg_ram: vec![0; 0x10000].into_boxed_slice(),
+            exram: vec![0; 0x0400].into_boxed_slice(),
+        }
+    }
+
+    fn prg_target(&self, addr: u16) -> Mmc5PrgTarget {
+        const PRG_BANK_SIZE_L: usize = 0x2000;
+
+        if addr < 0x8000 {
+            let bank = (self.prg_reg[0] & 0x07) as usize;
+            return Mmc5PrgTarget::Ram(bank * PRG_BANK_SIZE_L + ((addr & 0x1FFF) as usize));
+        }
+
+        let (reg, size) = match (self.prg_mode, addr) {
+            (0, _) => (4, 0x8000),
+            (1, 0x8000..=0xBFFF) => (2, 0x4000),
+            (1, _) => (4, 0x4000),
+            (2, 0x8000..=0xBFFF) => (2, 0x4000),
+            (2, 0xC000..=0xDFFF) => (3, 0x2000),
+            (2, _) => (4, 0x2000),
+            _ => (1 + (((addr - 0x8000) >> 13) as usize), 0x2000),
+        };
+
+        // Bank numbers are always in 8K units, larger banks ignore the low bits
+        let value = self.prg_reg[reg];
+        let bank = ((value & 0x7F) as usize) & !((size / PRG_BANK_SIZE_L) - 1);
+        let offset = bank * PRG_BANK_SIZE_L + ((addr as usize) & (size - 1));
+
+        // $5117 always maps ROM
+        if (reg == 4) || ((value & 0x80) != 0) {
+            let bank = mask_bank(offset / PRG_BANK_SIZE_L, (self.prg_banks as usize) * 2);
+            Mmc5PrgTarget::Rom(bank * PRG_BANK_SIZE_L + (offset % PRG_BANK_SIZE_L))
+        } else {
+            Mmc5PrgTarget::Ram(offset & 0xFFFF)
+        }
+    }
+
+    #[inline]
+    fn prg_ram_writable(&self) -> bool {
+        (self.prg_ram_protect[0] == 0x02) && (self.prg_ram_protect[1] == 0x01)
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn mirror(&self) -> Option<MirrorMode> {
+        // Only the layouts that match regular mirroring are supported,
+        // ExRAM and fill mode nametables fall back to the CIRAM page in the low bit
+        let page = |slot: u8| (self.nametable_mapping >> (slot * 2)) & 0x01;
+        Some(match (page(0), page(1), page(2), page(3)) {
+            (0, 0, 0, 0) => MirrorMode::OneScreenLow,
+            (1, 1, 1, 1) => MirrorMode::OneScreenHigh,
+            (0, 1, 0, 1) => MirrorMode::Vertical,
+            _ => MirrorMode::Horizontal,
+        })
+    }
+
+    fn interrupt_state(&self) -> bool {
+        self.interrupt_active && self.interrupt_enabled
+    }
+
+    fn reset_interrupt(&mut self) {
+        self.interrupt_active = false;
+    }
+
+    fn on_scanline(&mut self) {
+        // Called for the pre-render line and every visible line while rendering is enabled
+        if self.in_frame {
+            self.scanline_counter = self.scanline_counter.wrapping_add(1);
+            if self.scanline_counter == self.interrupt_target {
+                self.interrupt_active = true;
+            }
+
+            if (self.scanline_counter as usize) >= crate::device::ppu::SCREEN_HEIGHT {
+                self.in_frame = false;
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline_counter = 0;
+        }
+    }
+
+    fn cpu_read(&self, addr: u16) -> MapperReadResult {
+        match addr {
+            0x5204 => {
+                let mut status = 0;
+                if self.interrupt_active {
+                    status |= 0x80;
+                }
+                if self.in_frame {
+                    status |= 0x40;
+                }
+                MapperReadResult::Data(status)
+            }
+            0x5205 => {
+                let product = (self.multiplicand as u16) * (self.multiplier as u16);
+                MapperReadResult::Data(product as u8)
+            }
+            0x5206 => {
+                let product = (self.multiplicand as u16) * (self.multiplier as u16);
+                MapperReadResult::Data((product >> 8) as u8)
+            }
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => {
+                MapperReadResult::Data(self.exram[(addr & 0x03FF) as usize])
+            }
+            0x6000..=0xFFFF => match self.prg_target(addr) {
+                Mmc5PrgTarget::Rom(mapped_addr) => MapperReadResult::Address(Some(mapped_addr)),
+                Mmc5PrgTarget::Ram(offset) => MapperReadResult::Data(self.prg_ram[offset]),
+            },
+            _ => MapperReadResult::Address(None),
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> MapperReadResult {
+        const CHR_BANK_SIZE_L: usize = 0x0400;
+
+        if addr <= 0x1FFF {
+            let size = 0x2000 >> self.chr_mode;
+            let (bank, addr) = if self.chr_use_b {
+                // The B set only covers 4K, which is mirrored into both pattern tables
+                let addr = if self.chr_mode == 0 {
+                    addr as usize
+                } else {
+                    (addr & 0x0FFF) as usize
+                };
+                let slot = addr / size;
+                let reg = (slot + 1) * (4 >> self.chr_mode.saturating_sub(1)) - 1;
+                (self.chr_reg_b[reg], addr)
+            } else {
+                let slot = (addr as usize) / size;
+                let reg = (slot + 1) * (8 >> self.chr_mode) - 1;
+                (self.chr_reg_a[reg], addr as usize)
+            };
+
+            let offset = bank * size + (addr % size);
+            let chr_bank = mask_bank(
+                offset / CHR_BANK_SIZE_L,
+                (self.chr_banks as usize) * (CHR_BANK_SIZE / CHR_BANK_SIZE_L),
+            );
+            let mapped_addr = chr_bank * CHR_BANK_SIZE_L + (offset % CHR_BANK_SIZE_L);
+            MapperReadResult::Address(Some(mapped_addr))
+        } else {
+            MapperReadResult::Address(None)
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.prg_ram_protect[0] = data & 0x03,
+            0x5103 => self.prg_ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable_mapping = data,
+            0x5113..=0x5117 => self.prg_reg[(addr - 0x5113) as usize] = data,
+            0x5120..=0x5127 => {
+                self.chr_reg_a[(addr - 0x5120) as usize] =
+                    ((self.chr_upper as usize) << 8) | (data as usize);
+                self.chr_use_b = false;
+            }
+            0x5128..=0x512B => {
+                self.chr_reg_b[(addr - 0x5128) as usize] =
+                    ((self.chr_upper as usize) << 8) | (data as usize);
+                self.chr_use_b = true;
+            }
+            0x5130 => self.chr_upper = data & 0x03,
+            0x5203 => self.interrupt_target = data,
+            0x5204 => self.interrupt_enabled = (data & 0x80) != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            // Writes in the nametable modes only work while rendering, which we can't see
+            0x5C00..=0x5FFF if self.exram_mode != 3 => {
+                self.exram[(addr & 0x03FF) as usize] = data;
+            }
+            0x6000..=0xFFFF => {
+                if let Mmc5PrgTarget::Ram(offset) = self.prg_target(addr) {
+                    if self.prg_ram_writable() {
+                        self.prg_ram[offset] = data;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.prg_mode = 3;
+        self.chr_mode = 0;
+        self.prg_ram_protect = [0; 2];
+        self.exram_mode = 0;
+        self.nametable_mapping = 0;
+        self.prg_reg = [0, 0, 0, 0, 0xFF];
+        self.chr_reg_a = [0; 8];
+        self.chr_reg_b = [0; 4];
+        self.chr_upper = 0;
+        self.chr_use_b = false;
+
+        self.interrupt_target = 0;
+        self.interrupt_enabled = false;
+        self.interrupt_active = false;
+        self.in_frame = false;
+        self.scanline_counter = 0;
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.prg_mode);
+        state.write_u8(self.chr_mode);
+        state.write_bytes(&self.prg_ram_protect);
+        state.write_u8(self.exram_mode);
+        state.write_u8(self.nametable_mapping);
+        state.write_bytes(&self.prg_reg);
+        for bank in &self.chr_reg_a {
+            state.write_usize(*bank);
+        }
+        for bank in &self.chr_reg_b {
+            state.write_usize(*bank);
+        }
+        state.write_u8(self.chr_upper);
+        state.write_bool(self.chr_use_b);
+        state.write_u8(self.interrupt_target);
+        state.write_bool(self.interrupt_enabled);
+        state.write_bool(self.interrupt_active);
+        state.write_bool(self.in_frame);
+        state.write_u8(self.scanline_counter);
+        state.write_u8(self.multiplicand);
+        state.write_u8(self.multiplier);
+        state.write_bytes(&self.prg_ram);
+        state.write_bytes(&self.exram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_mode = state.read_u8()? & 0x03;
+        self.chr_mode = state.read_u8()? & 0x03;
+        state.read_bytes_into(&mut self.prg_ram_protect)?;
+        self.exram_mode = state.read_u8()? & 0x03;
+        self.nametable_mapping = state.read_u8()?;
+        state.read_bytes_into(&mut self.prg_reg)?;
+        for bank in &mut self.chr_reg_a {
+            *bank = state.read_usize()? & 0x3FF;
+        }
+        for bank in &mut self.chr_reg_b {
+            *bank = state.read_usize()? & 0x3FF;
+        }
+        self.chr_upper = state.read_u8()? & 0x03;
+        self.chr_use_b = state.read_bool()?;
+        self.interrupt_target = state.read_u8()?;
+        self.interrupt_enabled = state.read_bool()?;
+        self.interrupt_active = state.read_bool()?;
+        self.in_frame = state.read_bool()?;
+        self.scanline_counter = state.read_u8()?;
+        self.multiplicand = state.read_u8()?;
+        self.multiplier = state.read_u8()?;
+        state.read_bytes_into(&mut self.prg_ram)?;
+        state.read_bytes_into(&mut self.exram)?;
+        Ok(())
+    }
 }
 
 struct AxRom {
     prg_bank: u8,
     mirror: MirrorMode,
+    bus_conflicts: bool,
 }
 
 impl AxRom {
-    fn new() -> Self {
+    fn new(bus_conflicts: bool) -> Self {
         Self {
             prg_bank: 0,
             mirror: MirrorMode::OneScreenLow,
+            bus_conflicts,
         }
     }
 }
@@ -614,16 +1229,44 @@ impl Mapper for AxRom {
         self.prg_bank = 0;
         self.mirror = MirrorMode::OneScreenLow;
     }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.prg_bank);
+        self.mirror.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank = state.read_u8()?;
+        self.mirror = MirrorMode::load_state(state)?;
+        Ok(())
+    }
 }
 
+// https://www.nesdev.org/wiki/GxROM
 struct GxRom {
+    prg_banks: u8,
+    chr_banks: u8,
     prg_bank: u8,
     chr_bank: u8,
 }
 
 impl GxRom {
-    fn new() -> Self {
+    fn new(prg_banks: u8, chr_banks: u8) -> Self {
         Self {
+            prg_banks,
+            chr_banks,
             prg_bank: 0,
             chr_bank: 0,
         }
@@ -645,9 +1288,11 @@ impl Mapper for GxRom {
 
     fn cpu_read(&self, addr: u16) -> MapperReadResult {
         if addr >= 0x8000 {
-            MapperReadResult::Address(Some(
-                (self.prg_bank as usize) * 2 * PRG_BANK_SIZE + ((addr & 0x7FFF) as usize),
-            ))
+            let bank = mask_bank(
+                self.prg_bank as usize,
+                (self.prg_banks as usize).div_ceil(2),
+            );
+            MapperReadResult::Address(Some(bank * 2 * PRG_BANK_SIZE + ((addr & 0x7FFF) as usize)))
         } else {
             MapperReadResult::Address(None)
         }
@@ -655,18 +1300,18 @@ impl Mapper for GxRom {
 
     fn ppu_read(&self, addr: u16) -> MapperReadResult {
         if addr <= 0x1FFF {
-            MapperReadResult::Address(Some(
-                (self.chr_bank as usize) * CHR_BANK_SIZE + (addr as usize),
-            ))
+            let bank = mask_bank(self.chr_bank as usize, self.chr_banks as usize);
+            MapperReadResult::Address(Some(bank * CHR_BANK_SIZE + (addr as usize)))
         } else {
             MapperReadResult::Address(None)
         }
     }
 
     fn cpu_write(&mut self, addr: u16, data: u8) {
+        // Official boards only decode two bits each, oversized images use the unused upper bits
         if addr >= 0x8000 {
-            self.chr_bank = data & 0x03;
-            self.prg_bank = (data >> 4) & 0x03;
+            self.chr_bank = data & 0x0F;
+            self.prg_bank = data >> 4;
         }
     }
 
@@ -674,30 +1319,991 @@ impl Mapper for GxRom {
         self.prg_bank = 0;
         self.chr_bank = 0;
     }
-}
 
-fn get_mapper_from_id(id: u8, prg_banks: u8) -> Option<Box<dyn Mapper>> {
-    // This is only a very small subset of all existing mappers,
-    // but these will enable most Nintendo first-party titles to be emulated
-    match id {
-        0 => Some(Box::new(NRom::new(prg_banks))),
-        1 => Some(Box::new(Mmc1::new(prg_banks))),
-        2 => Some(Box::new(UxRom::new(prg_banks))),
-        3 => Some(Box::new(CNRom::new(prg_banks))),
-        4 => Some(Box::new(Mmc3::new(prg_banks))),
-        7 => Some(Box::new(AxRom::new())),
-        66 => Some(Box::new(GxRom::new())),
-        _ => None,
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
     }
-}
 
-pub struct Cartridge {
-    mapper: Box<dyn Mapper>,
-    prg_rom: Box<[u8]>,
-    chr_rom: Box<[u8]>,
-    chr_is_ram: bool,
-    mirror: MirrorMode,
-}
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.prg_bank);
+        state.write_u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank = state.read_u8()?;
+        self.chr_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/Color_Dreams
+struct ColorDreams {
+    prg_banks: u8,
+    chr_banks: u8,
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl ColorDreams {
+    fn new(prg_banks: u8, chr_banks: u8) -> Self {
+        Self {
+            prg_banks,
+            chr_banks,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for ColorDreams {
+    fn mirror(&self) -> Option<MirrorMode> {
+        None
+    }
+
+    fn interrupt_state(&self) -> bool {
+        false
+    }
+
+    fn reset_interrupt(&mut self) {}
+
+    fn on_scanline(&mut self) {}
+
+    fn cpu_read(&self, addr: u16) -> MapperReadResult {
+        if addr >= 0x8000 {
+            let bank = mask_bank(
+                self.prg_bank as usize,
+                (self.prg_banks as usize).div_ceil(2),
+            );
+            MapperReadResult::Address(Some(bank * 2 * PRG_BANK_SIZE + ((addr & 0x7FFF) as usize)))
+        } else {
+            MapperReadResult::Address(None)
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> MapperReadResult {
+        if addr <= 0x1FFF {
+            let bank = mask_bank(self.chr_bank as usize, self.chr_banks as usize);
+            MapperReadResult::Address(Some(bank * CHR_BANK_SIZE + (addr as usize)))
+        } else {
+            MapperReadResult::Address(None)
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            self.prg_bank = data & 0x03;
+            self.chr_bank = data >> 4;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+        self.chr_bank = 0;
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.prg_bank);
+        state.write_u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank = state.read_u8()?;
+        self.chr_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/GTROM
+// The LEDs driven by the upper register bits are not emulated
+struct GtRom {
+    prg_banks: u8,
+    prg_bank: u8,
+    chr_bank: u8,
+    nametable_bank: u8,
+}
+
+impl GtRom {
+    fn new(prg_banks: u8) -> Self {
+        Self {
+            prg_banks,
+            prg_bank: 0,
+            chr_bank: 0,
+            nametable_bank: 0,
+        }
+    }
+}
+
+impl Mapper for GtRom {
+    fn mirror(&self) -> Option<MirrorMode> {
+        Some(MirrorMode::FourScreen)
+    }
+
+    fn interrupt_state(&self) -> bool {
+        false
+    }
+
+    fn reset_interrupt(&mut self) {}
+
+    fn on_scanline(&mut self) {}
+
+    fn cpu_read(&self, addr: u16) -> MapperReadResult {
+        if addr >= 0x8000 {
+            let bank = mask_bank(
+                self.prg_bank as usize,
+                (self.prg_banks as usize).div_ceil(2),
+            );
+            MapperReadResult::Address(Some(bank * 2 * PRG_BANK_SIZE + ((addr & 0x7FFF) as usize)))
+        } else {
+            MapperReadResult::Address(None)
+        }
+    }
+
+    fn ppu_read(&self, _addr: u16) -> MapperReadResult {
+        // All PPU memory is CHR RAM, see `map_chr_ram`
+        MapperReadResult::Address(None)
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if matches!(addr, 0x5000..=0x5FFF | 0x7000..=0x7FFF) {
+            self.prg_bank = data & 0x0F;
+            self.chr_bank = (data >> 4) & 0x01;
+            self.nametable_bank = (data >> 5) & 0x01;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+        self.chr_bank = 0;
+        self.nametable_bank = 0;
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn chr_ram_size(&self) -> usize {
+        0x8000
+    }
+
+    fn map_chr_ram(&self, addr: u16) -> Option<usize> {
+        // The first 16K hold two pattern table banks, the second 16K two sets of four nametables
+        match addr {
+            0x0000..=0x1FFF => Some((self.chr_bank as usize) * 0x2000 + (addr as usize)),
+            0x2000..=0x3EFF => {
+                Some(0x4000 + (self.nametable_bank as usize) * 0x2000 + ((addr & 0x0FFF) as usize))
+            }
+            _ => None,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.prg_bank);
+        state.write_u8(self.chr_bank);
+        state.write_u8(self.nametable_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank = state.read_u8()?;
+        self.chr_bank = state.read_u8()?;
+        self.nametable_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/Sunsoft_FME-7
+// The audio expansion of the Sunsoft 5B is not emulated
+struct Fme7 {
+    command: u8,
+    prg_banks: u8,
+    chr_banks: u8,
+    prg_bank: [usize; 4],
+    chr_bank: [usize; 8],
+    prg_bank_6000: u8,
+    prg_ram_selected: bool,
+    prg_ram_enabled: bool,
+    mirror: MirrorMode,
+    interrupt_counter: u16,
+    interrupt_enabled: bool,
+    counter_enabled: bool,
+    interrupt_active: bool,
+    prg_ram: Box<[u8]>,
+}
+
+impl Fme7 {
+    fn new(prg_banks: u8, chr_banks: u8) -> Self {
+        Self {
+            command: 0,
+            prg_banks,
+            chr_banks,
+            prg_bank: [0, 0, 0, (prg_banks as usize) * 2 - 1],
+            chr_bank: [0; 8],
+            prg_bank_6000: 0,
+            prg_ram_selected: false,
+            prg_ram_enabled: false,
+            mirror: MirrorMode::Vertical,
+            interrupt_counter: 0,
+            interrupt_enabled: false,
+            counter_enabled: false,
+            interrupt_active: false,
+            prg_ram: vec![0; 0x2000].into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    fn prg_addr(&self, bank: usize, addr: u16) -> usize {
+        const PRG_BANK_SIZE_L: usize = 0x2000;
+
+        let bank = mask_bank(bank, (self.prg_banks as usize) * 2);
+        bank * PRG_BANK_SIZE_L + ((addr & 0x1FFF) as usize)
+    }
+}
+
+impl Mapper for Fme7 {
+    fn mirror(&self) -> Option<MirrorMode> {
+        Some(self.mirror)
+    }
+
+    fn interrupt_state(&self) -> bool {
+        self.interrupt_active
+    }
+
+    fn reset_interrupt(&mut self) {
+        self.interrupt_active = false;
+    }
+
+    fn on_scanline(&mut self) {}
+
+    fn on_cpu_cycle(&mut self) {
+        if self.counter_enabled {
+            self.interrupt_counter = self.interrupt_counter.wrapping_sub(1);
+            if (self.interrupt_counter == 0xFFFF) && self.interrupt_enabled {
+                self.interrupt_active = true;
+            }
+        }
+    }
+
+    fn cpu_read(&self, addr: u16) -> MapperReadResult {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            if !self.prg_ram_selected {
+                MapperReadResult::Address(Some(self.prg_addr(self.prg_bank_6000 as usize, addr)))
+            } else if self.prg_ram_enabled {
+                MapperReadResult::Data(self.prg_ram[(addr & 0x1FFF) as usize])
+            } else {
+                MapperReadResult::Address(None)
+            }
+        } else if addr >= 0x8000 {
+            let bank = ((addr >> 13) & 0x03) as usize;
+            MapperReadResult::Address(Some(self.prg_addr(self.prg_bank[bank], addr)))
+        } else {
+            MapperReadResult::Address(None)
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> MapperReadResult {
+        if addr <= 0x1FFF {
+            const CHR_BANK_SIZE_L: usize = 0x0400;
+
+            let bank = ((addr >> 10) & 0x07) as usize;
+            let chr_bank = mask_bank(
+                self.chr_bank[bank],
+                (self.chr_banks as usize) * (CHR_BANK_SIZE / CHR_BANK_SIZE_L),
+            );
+            let mapped_addr = chr_bank * CHR_BANK_SIZE_L + ((addr & 0x03FF) as usize);
+            MapperReadResult::Address(Some(mapped_addr))
+        } else {
+            MapperReadResult::Address(None)
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            if self.prg_ram_selected && self.prg_ram_enabled {
+                self.prg_ram[(addr & 0x1FFF) as usize] = data;
+            }
+        } else if (0x8000..=0x9FFF).contains(&addr) {
+            self.command = data & 0x0F;
+        } else if (0xA000..=0xBFFF).contains(&addr) {
+            match self.command {
+                0x0..=0x7 => self.chr_bank[self.command as usize] = data as usize,
+                0x8 => {
+                    self.prg_bank_6000 = data & 0x3F;
+                    self.prg_ram_selected = (data & 0x40) != 0;
+                    self.prg_ram_enabled = (data & 0x80) != 0;
+                }
+                0x9..=0xB => {
+                    self.prg_bank[(self.command - 0x9) as usize] = (data & 0x3F) as usize;
+                }
+                0xC => {
+                    self.mirror = match data & 0x03 {
+                        0 => MirrorMode::Vertical,
+                        1 => MirrorMode::Horizontal,
+                        2 => MirrorMode::OneScreenLow,
+                        _ => MirrorMode::OneScreenHigh,
+                    };
+                }
+                0xD => {
+                    // Any write acknowledges a pending interrupt
+                    self.interrupt_enabled = (data & 0x01) != 0;
+                    self.counter_enabled = (data & 0x80) != 0;
+                    self.interrupt_active = false;
+                }
+                0xE => {
+                    self.interrupt_counter = (self.interrupt_counter & 0xFF00) | (data as u16);
+                }
+                _ => {
+                    self.interrupt_counter =
+                        (self.interrupt_counter & 0x00FF) | ((data as u16) << 8);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.command = 0;
+        self.prg_bank = [0, 0, 0, (self.prg_banks as usize) * 2 - 1];
+        self.chr_bank = [0; 8];
+        self.prg_bank_6000 = 0;
+        self.prg_ram_selected = false;
+        self.prg_ram_enabled = false;
+        self.mirror = MirrorMode::Vertical;
+
+        self.interrupt_counter = 0;
+        self.interrupt_enabled = false;
+        self.counter_enabled = false;
+        self.interrupt_active = false;
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.command);
+        for bank in &self.prg_bank {
+            state.write_usize(*bank);
+        }
+        for bank in &self.chr_bank {
+            state.write_usize(*bank);
+        }
+        state.write_u8(self.prg_bank_6000);
+        state.write_bool(self.prg_ram_selected);
+        state.write_bool(self.prg_ram_enabled);
+        self.mirror.save_state(state);
+        state.write_u16(self.interrupt_counter);
+        state.write_bool(self.interrupt_enabled);
+        state.write_bool(self.counter_enabled);
+        state.write_bool(self.interrupt_active);
+        state.write_bytes(&self.prg_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.command = state.read_u8()? & 0x0F;
+        // The last bank is fixed to the end of ROM, which can be further than the registers reach
+        for bank in &mut self.prg_bank {
+            *bank = state.read_usize()? & 0x1FF;
+        }
+        for bank in &mut self.chr_bank {
+            *bank = state.read_usize()? & 0xFF;
+        }
+        self.prg_bank_6000 = state.read_u8()? & 0x3F;
+        self.prg_ram_selected = state.read_bool()?;
+        self.prg_ram_enabled = state.read_bool()?;
+        self.mirror = MirrorMode::load_state(state)?;
+        self.interrupt_counter = state.read_u16()?;
+        self.interrupt_enabled = state.read_bool()?;
+        self.counter_enabled = state.read_bool()?;
+        self.interrupt_active = state.read_bool()?;
+        state.read_bytes_into(&mut self.prg_ram)?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/VRC6_audio
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    ignore_duty: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    enabled: bool,
+}
+
+impl Vrc6Pulse {
+    const fn new() -> Self {
+        Self {
+            volume: 0,
+            duty: 0,
+            ignore_duty: false,
+            period: 0,
+            timer: 0,
+            step: 0,
+            enabled: false,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.ignore_duty = (data & 0x80) != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | (data as u16),
+            _ => {
+                self.period = (self.period & 0x00FF) | (((data & 0x0F) as u16) << 8);
+                self.enabled = (data & 0x80) != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    #[inline]
+    const fn output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || (self.step <= self.duty)) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.volume);
+        state.write_u8(self.duty);
+        state.write_bool(self.ignore_duty);
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+        state.write_u8(self.step);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.volume = state.read_u8()? & 0x0F;
+        self.duty = state.read_u8()? & 0x07;
+        self.ignore_duty = state.read_bool()?;
+        self.period = state.read_u16()? & 0x0FFF;
+        self.timer = state.read_u16()? & 0x0FFF;
+        self.step = state.read_u8()? & 0x0F;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+    enabled: bool,
+}
+
+impl Vrc6Saw {
+    const fn new() -> Self {
+        Self {
+            rate: 0,
+            period: 0,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+            enabled: false,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => self.rate = data & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | (data as u16),
+            _ => {
+                self.period = (self.period & 0x00FF) | (((data & 0x0F) as u16) << 8);
+                self.enabled = (data & 0x80) != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            // The accumulator is only advanced on every other step and reset after 14 steps
+            self.step += 1;
+            if self.step >= 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if (self.step & 0x01) == 0 {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    #[inline]
+    const fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.rate);
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+        state.write_u8(self.step);
+        state.write_u8(self.accumulator);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rate = state.read_u8()? & 0x3F;
+        self.period = state.read_u16()? & 0x0FFF;
+        self.timer = state.read_u16()? & 0x0FFF;
+        self.step = state.read_u8()?;
+        if self.step >= 14 {
+            return Err(StateError::InvalidData);
+        }
+        self.accumulator = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/VRC6
+struct Vrc6 {
+    /// Mapper 26 has address lines A0 and A1 swapped
+    swap_lines: bool,
+    prg_banks: u8,
+    chr_banks: u8,
+    prg_bank_16: usize,
+    prg_bank_8: usize,
+    chr_bank: [usize; 8],
+    mirror: MirrorMode,
+    prg_ram_enabled: bool,
+    interrupt_latch: u8,
+    interrupt_counter: u8,
+    interrupt_prescaler: i16,
+    interrupt_cycle_mode: bool,
+    interrupt_enabled: bool,
+    interrupt_enabled_after_ack: bool,
+    interrupt_active: bool,
+    audio_halted: bool,
+    pulse_1: Vrc6Pulse,
+    pulse_2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    prg_ram: Box<[u8]>,
+}
+
+impl Vrc6 {
+    fn new(prg_banks: u8, chr_banks: u8, swap_lines: bool) -> Self {
+        Self {
+            swap_lines,
+            prg_banks,
+            chr_banks,
+            prg_bank_16: 0,
+            prg_bank_8: 0,
+            chr_bank: [0; 8],
+            mirror: MirrorMode::Vertical,
+            prg_ram_enabled: false,
+            interrupt_latch: 0,
+            interrupt_counter: 0,
+            interrupt_prescaler: 341,
+            interrupt_cycle_mode: false,
+            interrupt_enabled: false,
+            interrupt_enabled_after_ack: false,
+            interrupt_active: false,
+            audio_halted: false,
+            pulse_1: Vrc6Pulse::new(),
+            pulse_2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+            prg_ram: vec![0; 0x2000].into_boxed_slice(),
+        }
+    }
+
+    fn clock_interrupt_counter(&mut self) {
+        if self.interrupt_counter == 0xFF {
+            self.interrupt_counter = self.interrupt_latch;
+            self.interrupt_active = true;
+        } else {
+            self.interrupt_counter += 1;
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn mirror(&self) -> Option<MirrorMode> {
+        Some(self.mirror)
+    }
+
+    fn interrupt_state(&self) -> bool {
+        self.interrupt_active
+    }
+
+    fn reset_interrupt(&mut self) {
+        self.interrupt_active = false;
+    }
+
+    fn on_scanline(&mut self) {}
+
+    // https://www.nesdev.org/wiki/VRC_IRQ
+    fn on_cpu_cycle(&mut self) {
+        if self.interrupt_enabled {
+            if self.interrupt_cycle_mode {
+                self.clock_interrupt_counter();
+            } else {
+                // Scanline mode divides the CPU clock by 113.667, one scanline
+                self.interrupt_prescaler -= 3;
+                if self.interrupt_prescaler <= 0 {
+                    self.interrupt_prescaler += 341;
+                    self.clock_interrupt_counter();
+                }
+            }
+        }
+
+        if !self.audio_halted {
+            self.pulse_1.clock();
+            self.pulse_2.clock();
+            self.saw.clock();
+        }
+    }
+
+    fn audio_sample(&self) -> f32 {
+        // Scaled so a pulse channel is about as loud as one of the APU's
+        const PULSE_SCALE: f32 = 0.00752;
+
+        let output = self.pulse_1.output() + self.pulse_2.output() + self.saw.output();
+        (output as f32) * PULSE_SCALE
+    }
+
+    fn cpu_read(&self, addr: u16) -> MapperReadResult {
+        const PRG_BANK_SIZE_L: usize = 0x2000;
+
+        let prg_banks_l = (self.prg_banks as usize) * 2;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled => {
+                MapperReadResult::Data(self.prg_ram[(addr & 0x1FFF) as usize])
+            }
+            0x8000..=0xBFFF => {
+                let bank = mask_bank(
+                    self.prg_bank_16 * 2 + ((addr >> 13) & 0x01) as usize,
+                    prg_banks_l,
+                );
+                MapperReadResult::Address(Some(bank * PRG_BANK_SIZE_L + ((addr & 0x1FFF) as usize)))
+            }
+            0xC000..=0xDFFF => {
+                let bank = mask_bank(self.prg_bank_8, prg_banks_l);
+                MapperReadResult::Address(Some(bank * PRG_BANK_SIZE_L + ((addr & 0x1FFF) as usize)))
+            }
+            0xE000..=0xFFFF => {
+                let bank = prg_banks_l - 1;
+                MapperReadResult::Address(Some(bank * PRG_BANK_SIZE_L + ((addr & 0x1FFF) as usize)))
+            }
+            _ => MapperReadResult::Address(None),
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> MapperReadResult {
+        if addr <= 0x1FFF {
+            const CHR_BANK_SIZE_L: usize = 0x0400;
+
+            let bank = ((addr >> 10) & 0x07) as usize;
+            let chr_bank = mask_bank(
+                self.chr_bank[bank],
+                (self.chr_banks as usize) * (CHR_BANK_SIZE / CHR_BANK_SIZE_L),
+            );
+            let mapped_addr = chr_bank * CHR_BANK_SIZE_L + ((addr & 0x03FF) as usize);
+            MapperReadResult::Address(Some(mapped_addr))
+        } else {
+            MapperReadResult::Address(None)
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            if self.prg_ram_enabled {
+                self.prg_ram[(addr & 0x1FFF) as usize] = data;
+            }
+            return;
+        }
+
+        let addr = if self.swap_lines {
+            (addr & 0xF000) | ((addr & 0x0001) << 1) | ((addr & 0x0002) >> 1)
+        } else {
+            addr & 0xF003
+        };
+        let reg = addr & 0x0003;
+
+        match addr & 0xF000 {
+            0x8000 => self.prg_bank_16 = (data & 0x0F) as usize,
+            0x9000 if reg == 3 => self.audio_halted = (data & 0x01) != 0,
+            0x9000 => self.pulse_1.write(reg, data),
+            0xA000 if reg < 3 => self.pulse_2.write(reg, data),
+            0xB000 if reg < 3 => self.saw.write(reg, data),
+            0xB000 => {
+                // Only the common banking mode with 1K CHR banks is supported
+                self.mirror = match (data >> 2) & 0x03 {
+                    0 => MirrorMode::Vertical,
+                    1 => MirrorMode::Horizontal,
+                    2 => MirrorMode::OneScreenLow,
+                    _ => MirrorMode::OneScreenHigh,
+                };
+                self.prg_ram_enabled = (data & 0x80) != 0;
+            }
+            0xC000 => self.prg_bank_8 = (data & 0x1F) as usize,
+            0xD000 => self.chr_bank[reg as usize] = data as usize,
+            0xE000 => self.chr_bank[4 + (reg as usize)] = data as usize,
+            0xF000 => match reg {
+                0 => self.interrupt_latch = data,
+                1 => {
+                    self.interrupt_enabled_after_ack = (data & 0x01) != 0;
+                    self.interrupt_enabled = (data & 0x02) != 0;
+                    self.interrupt_cycle_mode = (data & 0x04) != 0;
+                    self.interrupt_active = false;
+                    if self.interrupt_enabled {
+                        self.interrupt_counter = self.interrupt_latch;
+                        self.interrupt_prescaler = 341;
+                    }
+                }
+                2 => {
+                    self.interrupt_active = false;
+                    self.interrupt_enabled = self.interrupt_enabled_after_ack;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank_16 = 0;
+        self.prg_bank_8 = 0;
+        self.chr_bank = [0; 8];
+        self.mirror = MirrorMode::Vertical;
+        self.prg_ram_enabled = false;
+
+        self.interrupt_latch = 0;
+        self.interrupt_counter = 0;
+        self.interrupt_prescaler = 341;
+        self.interrupt_cycle_mode = false;
+        self.interrupt_enabled = false;
+        self.interrupt_enabled_after_ack = false;
+        self.interrupt_active = false;
+
+        self.audio_halted = false;
+        self.pulse_1 = Vrc6Pulse::new();
+        self.pulse_2 = Vrc6Pulse::new();
+        self.saw = Vrc6Saw::new();
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_usize(self.prg_bank_16);
+        state.write_usize(self.prg_bank_8);
+        for bank in &self.chr_bank {
+            state.write_usize(*bank);
+        }
+        self.mirror.save_state(state);
+        state.write_bool(self.prg_ram_enabled);
+        state.write_u8(self.interrupt_latch);
+        state.write_u8(self.interrupt_counter);
+        state.write_i16(self.interrupt_prescaler);
+        state.write_bool(self.interrupt_cycle_mode);
+        state.write_bool(self.interrupt_enabled);
+        state.write_bool(self.interrupt_enabled_after_ack);
+        state.write_bool(self.interrupt_active);
+        state.write_bool(self.audio_halted);
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        self.saw.save_state(state);
+        state.write_bytes(&self.prg_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank_16 = state.read_usize()? & 0x0F;
+        self.prg_bank_8 = state.read_usize()? & 0x1F;
+        for bank in &mut self.chr_bank {
+            *bank = state.read_usize()? & 0xFF;
+        }
+        self.mirror = MirrorMode::load_state(state)?;
+        self.prg_ram_enabled = state.read_bool()?;
+        self.interrupt_latch = state.read_u8()?;
+        self.interrupt_counter = state.read_u8()?;
+        self.interrupt_prescaler = state.read_i16()?;
+        if !(1..=341).contains(&self.interrupt_prescaler) {
+            return Err(StateError::InvalidData);
+        }
+        self.interrupt_cycle_mode = state.read_bool()?;
+        self.interrupt_enabled = state.read_bool()?;
+        self.interrupt_enabled_after_ack = state.read_bool()?;
+        self.interrupt_active = state.read_bool()?;
+        self.audio_halted = state.read_bool()?;
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.saw.load_state(state)?;
+        state.read_bytes_into(&mut self.prg_ram)?;
+        Ok(())
+    }
+}
+
+fn get_mapper_from_id(
+    id: u8,
+    submapper: u8,
+    prg_banks: u8,
+    chr_banks: u8,
+) -> Option<Box<dyn Mapper>> {
+    // Discrete logic boards mark bus conflicts with submapper 2 in NES 2.0 headers,
+    // plain iNES headers default to no bus conflicts
+    let bus_conflicts = submapper == 2;
+
+    // This is only a very small subset of all existing mappers,
+    // but these will enable most Nintendo first-party titles to be emulated
+    match id {
+        0 => Some(Box::new(NRom)),
+        1 => Some(Box::new(Mmc1::new(prg_banks, chr_banks))),
+        2 => Some(Box::new(UxRom::new(prg_banks, bus_conflicts))),
+        3 => Some(Box::new(CNRom::new(chr_banks, bus_conflicts))),
+        4 => Some(Box::new(Mmc3::new(prg_banks, chr_banks))),
+        5 => Some(Box::new(Mmc5::new(prg_banks, chr_banks))),
+        7 => Some(Box::new(AxRom::new(bus_conflicts))),
+        11 => Some(Box::new(ColorDreams::new(prg_banks, chr_banks))),
+        24 => Some(Box::new(Vrc6::new(prg_banks, chr_banks, false))),
+        26 => Some(Box::new(Vrc6::new(prg_banks, chr_banks, true))),
+        66 => Some(Box::new(GxRom::new(prg_banks, chr_banks))),
+        69 => Some(Box::new(Fme7::new(prg_banks, chr_banks))),
+        111 => Some(Box::new(GtRom::new(prg_banks))),
+        _ => None,
+    }
+}
+
+/// What the header of a ROM says about the cartridge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CartridgeInfo {
+    pub mapper: u16,
+    pub submapper: u8,
+    /// Mirroring wired on the board, mappers with mirroring control can change it at runtime
+    pub mirror: MirrorMode,
+    /// Size in bytes
+    pub prg_rom_size: usize,
+    /// Size in bytes, 0 if the cartridge has CHR RAM instead
+    pub chr_rom_size: usize,
+    /// Size in bytes, 0 if the cartridge has CHR ROM instead
+    pub chr_ram_size: usize,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    /// Whether the header is in the NES 2.0 format rather than plain iNES
+    pub nes2: bool,
+    /// TV system the game was made for, NTSC unless the header says otherwise
+    pub region: Region,
+    /// CRC32 of PRG and CHR ROM without the header and trainer, as listed by No-Intro
+    pub crc32: u32,
+    /// SHA-1 of PRG and CHR ROM without the header and trainer, as listed by No-Intro
+    pub sha1: [u8; 20],
+}
+
+impl CartridgeInfo {
+    /// The SHA-1 as lowercase hex, the way ROM databases list it
+    pub fn sha1_hex(&self) -> String {
+        self.sha1
+            .iter()
+            .map(|byte| format!("{byte:0>2x}"))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for CartridgeInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+
+        writeln!(
+            f,
+            "mapper:    {} (submapper {})",
+            self.mapper, self.submapper
+        )?;
+        writeln!(f, "mirroring: {:?}", self.mirror)?;
+        writeln!(
+            f,
+            "PRG ROM:   {} KiB ({} x 16 KiB)",
+            self.prg_rom_size / 1024,
+            self.prg_rom_size.div_ceil(PRG_BANK_SIZE),
+        )?;
+        if self.chr_rom_size == 0 {
+            writeln!(f, "CHR RAM:   {} KiB", self.chr_ram_size / 1024)?;
+        } else {
+            writeln!(
+                f,
+                "CHR ROM:   {} KiB ({} x 8 KiB)",
+                self.chr_rom_size / 1024,
+                self.chr_rom_size.div_ceil(CHR_BANK_SIZE),
+            )?;
+        }
+        writeln!(f, "battery:   {}", yes_no(self.has_battery))?;
+        writeln!(f, "trainer:   {}", yes_no(self.has_trainer))?;
+        writeln!(f, "NES 2.0:   {}", yes_no(self.nes2))?;
+        writeln!(f, "region:    {:?}", self.region)?;
+        writeln!(f, "CRC32:     {:0>8X}", self.crc32)?;
+        write!(f, "SHA-1:     {}", self.sha1_hex())
+    }
+}
+
+pub struct Cartridge {
+    mapper: Box<dyn Mapper>,
+    prg_rom: Box<[u8]>,
+    chr_rom: Box<[u8]>,
+    info: CartridgeInfo,
+    /// CRC32 of the PRG ROM, identifies the game a save state belongs to
+    rom_crc: u32,
+}
 
 impl Cartridge {
     #[inline]
@@ -705,21 +2311,109 @@ impl Cartridge {
         mapper: Box<dyn Mapper>,
         prg_rom: Box<[u8]>,
         chr_rom: Box<[u8]>,
-        chr_is_ram: bool,
-        mirror: MirrorMode,
+        info: CartridgeInfo,
     ) -> Self {
+        let rom_crc = hash::crc32(&[&prg_rom]);
+
         Self {
             mapper,
             prg_rom,
             chr_rom,
-            chr_is_ram,
-            mirror,
+            info,
+            rom_crc,
+        }
+    }
+
+    #[inline]
+    pub fn info(&self) -> &CartridgeInfo {
+        &self.info
+    }
+
+    #[inline]
+    fn chr_is_ram(&self) -> bool {
+        self.info.chr_rom_size == 0
+    }
+
+    #[inline]
+    pub fn prg_ram(&self) -> Option<&[u8]> {
+        self.mapper.prg_ram()
+    }
+
+    /// Whether the cartridge has battery-backed RAM that should be persisted
+    #[inline]
+    pub fn has_battery(&self) -> bool {
+        self.info.has_battery && self.mapper.prg_ram().is_some()
+    }
+
+    /// Contents of the battery-backed RAM, `None` if the cartridge has no battery
+    #[inline]
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        if self.info.has_battery {
+            self.mapper.prg_ram()
+        } else {
+            None
+        }
+    }
+
+    /// Restores previously saved battery-backed RAM.
+    /// Does nothing if the cartridge has no battery.
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        if self.info.has_battery {
+            if let Some(prg_ram) = self.mapper.prg_ram_mut() {
+                let count = prg_ram.len().min(data.len());
+                prg_ram[..count].copy_from_slice(&data[..count]);
+            }
+        }
+    }
+
+    /// Copies trainer data to $7000-$71FF, does nothing if the cartridge has no PRG RAM
+    fn load_trainer(&mut self, trainer: &[u8]) {
+        if let Some(prg_ram) = self.mapper.prg_ram_mut() {
+            if let Some(dst) = prg_ram.get_mut(0x1000..(0x1000 + trainer.len())) {
+                dst.copy_from_slice(trainer);
+            }
+        }
+    }
+
+    /// Writes directly into PRG RAM, bypassing the mapper's enable and write protect bits.
+    /// Does nothing if the cartridge has no PRG RAM.
+    pub fn poke_prg_ram(&mut self, addr: u16, data: u8) {
+        if let Some(prg_ram) = self.mapper.prg_ram_mut() {
+            if let Some(byte) = prg_ram.get_mut((addr & 0x1FFF) as usize) {
+                *byte = data;
+            }
+        }
+    }
+
+    #[inline]
+    pub fn rom_crc(&self) -> u32 {
+        self.rom_crc
+    }
+
+    /// ROM contents are not part of the state, only CHR RAM is
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.mapper.save_state(state);
+        if self.chr_is_ram() {
+            state.write_bytes(&self.chr_rom);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mapper.load_state(state)?;
+        if self.chr_is_ram() {
+            state.read_bytes_into(&mut self.chr_rom)?;
         }
+        Ok(())
     }
 
     #[inline]
     pub fn mirror(&self) -> MirrorMode {
-        self.mapper.mirror().unwrap_or(self.mirror)
+        // Four-screen VRAM is hardwired and can't be overridden by the mapper
+        if self.info.mirror == MirrorMode::FourScreen {
+            MirrorMode::FourScreen
+        } else {
+            self.mapper.mirror().unwrap_or(self.info.mirror)
+        }
     }
 
     #[inline]
@@ -742,31 +2436,71 @@ impl Cartridge {
         self.mapper.on_scanline();
     }
 
+    #[inline]
+    pub fn ppu_a12(&mut self, addr: u16) {
+        self.mapper.ppu_a12(addr);
+    }
+
+    #[inline]
+    pub fn on_cpu_cycle(&mut self) {
+        self.mapper.on_cpu_cycle();
+    }
+
+    #[inline]
+    pub fn audio_sample(&self) -> f32 {
+        self.mapper.audio_sample()
+    }
+
     /// Address is absolute, **not** relative to cartridge space
     #[inline]
-    pub fn cpu_read(&mut self, addr: u16) -> u8 {
+    /// Returns `None` if nothing on the cartridge responds to the address, leaving the CPU's data bus open
+    pub fn cpu_read(&self, addr: u16) -> Option<u8> {
         match self.mapper.cpu_read(addr) {
-            MapperReadResult::Data(data) => data,
-            MapperReadResult::Address(Some(mapped_addr)) => self.prg_rom[mapped_addr],
-            _ => 0,
+            MapperReadResult::Data(data) => Some(data),
+            // ROM sizes that are not a power of two mirror the remaining part, only an empty ROM reads as 0
+            MapperReadResult::Address(Some(mapped_addr)) => {
+                let mapped_addr = mask_rom_addr(mapped_addr, self.prg_rom.len());
+                Some(self.prg_rom.get(mapped_addr).copied().unwrap_or(0))
+            }
+            _ => None,
         }
     }
 
     /// Address is absolute, **not** relative to cartridge space
     #[inline]
     pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        // On boards with bus conflicts the ROM drives the data bus at the same time as the CPU
+        let data = if (addr >= 0x8000) && self.mapper.has_bus_conflicts() {
+            match self.mapper.cpu_read(addr) {
+                MapperReadResult::Address(Some(mapped_addr)) => {
+                    let mapped_addr = mask_rom_addr(mapped_addr, self.prg_rom.len());
+                    data & self.prg_rom.get(mapped_addr).copied().unwrap_or(0xFF)
+                }
+                _ => data,
+            }
+        } else {
+            data
+        };
+
         self.mapper.cpu_write(addr, data);
     }
 
     /// Address is absolute, **not** relative to cartridge space
     #[inline]
-    pub fn ppu_read(&mut self, addr: u16) -> u8 {
-        if self.chr_is_ram {
-            self.chr_rom[(addr & 0x1FFF) as usize]
+    pub fn ppu_read(&self, addr: u16) -> u8 {
+        if self.chr_is_ram() {
+            self.mapper
+                .map_chr_ram(addr)
+                .and_then(|mapped_addr| self.chr_rom.get(mapped_addr).copied())
+                .unwrap_or(0)
         } else {
             match self.mapper.ppu_read(addr) {
                 MapperReadResult::Data(data) => data,
-                MapperReadResult::Address(Some(mapped_addr)) => self.chr_rom[mapped_addr],
+                // Bank selects beyond the end of the ROM mirror it, like they do for PRG ROM
+                MapperReadResult::Address(Some(mapped_addr)) => {
+                    let mapped_addr = mask_rom_addr(mapped_addr, self.chr_rom.len());
+                    self.chr_rom.get(mapped_addr).copied().unwrap_or(0)
+                }
                 _ => 0,
             }
         }
@@ -775,9 +2509,46 @@ impl Cartridge {
     /// Address is absolute, **not** relative to cartridge space
     #[inline]
     pub fn ppu_write(&mut self, addr: u16, data: u8) {
-        if self.chr_is_ram {
-            self.chr_rom[(addr & 0x1FFF) as usize] = data;
+        if self.chr_is_ram() {
+            if let Some(cell) = self
+                .mapper
+                .map_chr_ram(addr)
+                .and_then(|mapped_addr| self.chr_rom.get_mut(mapped_addr))
+            {
+                *cell = data;
+            }
+        }
+    }
+
+    /// Reads a nametable byte from the cartridge, `None` if the nametables live in the console VRAM.
+    /// Address is absolute.
+    #[inline]
+    pub fn nametable_read(&self, addr: u16) -> Option<u8> {
+        if self.chr_is_ram() {
+            self.mapper
+                .map_chr_ram(addr)
+                .and_then(|mapped_addr| self.chr_rom.get(mapped_addr).copied())
+        } else {
+            None
+        }
+    }
+
+    /// Writes a nametable byte to the cartridge, returns `false` if the nametables live in the console VRAM.
+    /// Address is absolute.
+    #[inline]
+    pub fn nametable_write(&mut self, addr: u16, data: u8) -> bool {
+        if self.chr_is_ram() {
+            if let Some(cell) = self
+                .mapper
+                .map_chr_ram(addr)
+                .and_then(|mapped_addr| self.chr_rom.get_mut(mapped_addr))
+            {
+                *cell = data;
+                return true;
+            }
         }
+
+        false
     }
 }
 
@@ -791,11 +2562,6 @@ impl BinReader {
         Self { data, pos: 0 }
     }
 
-    fn from_file<P: AsRef<std::path::Path>>(file: P) -> Result<Self, std::io::Error> {
-        let data = std::fs::read(file)?;
-        Ok(Self::new(data))
-    }
-
     fn read_byte(&mut self) -> Option<u8> {
         if self.pos < self.data.len() {
             let byte = self.data[self.pos];
@@ -807,35 +2573,61 @@ impl BinReader {
     }
 
     fn read_into(&mut self, target: &mut [u8]) -> usize {
-        let count = target.len().min(self.data.len() - self.pos);
+        let count = target.len().min(self.data.len().saturating_sub(self.pos));
         if count > 0 {
-            target.copy_from_slice(&self.data[self.pos..(self.pos + count)]);
+            target[..count].copy_from_slice(&self.data[self.pos..(self.pos + count)]);
             self.pos += count;
         }
         count
     }
 
-    fn skip(&mut self, count: usize) {
+    fn read_slice(&mut self, count: usize) -> Option<&[u8]> {
+        let slice = self.data.get(self.pos..self.pos.checked_add(count)?)?;
         self.pos += count;
+        Some(slice)
+    }
+}
+
+/// https://www.nesdev.org/wiki/NES_2.0#PRG-ROM_Area
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+    if msb == 0x0F {
+        // Exponent-multiplier notation, used for sizes that aren't a multiple of the unit
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = ((lsb & 0x03) as usize) * 2 + 1;
+        1usize.checked_shl(exponent)?.checked_mul(multiplier)
+    } else {
+        Some((((msb as usize) << 8) | (lsb as usize)) * unit)
     }
 }
 
+/// Bank count as seen by the mappers, rounding up incomplete banks
+#[inline]
+fn bank_count(size: usize, bank_size: usize) -> u8 {
+    u8::try_from(size.div_ceil(bank_size)).unwrap_or(u8::MAX)
+}
+
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
 struct INesHeader {
-    prg_banks: u8,
-    chr_banks: u8,
+    /// Size in bytes
+    prg_rom_size: Option<usize>,
+    /// Size in bytes
+    chr_rom_size: Option<usize>,
+    /// Size in bytes, only known for NES 2.0
+    chr_ram_size: Option<usize>,
+    mapper_id: u16,
+    submapper: u8,
     mapper_1: u8,
-    mapper_2: u8,
-    _prg_ram_size: u8,
-    _tv_system_1: u8,
-    _tv_system_2: u8,
+    nes2: bool,
+    region: Region,
 }
 
 impl INesHeader {
-    pub fn from_reader(reader: &mut BinReader) -> Option<Self> {
+    pub fn from_reader(reader: &mut BinReader) -> Result<Self, CartridgeError> {
         // The file ID is a fixed pattern of 4 bytes that has to match exactly
         let mut file_id: [u8; 4] = [0; 4];
         if reader.read_into(&mut file_id) != 4 {
-            return None;
+            return Err(CartridgeError::UnexpectedEof);
         }
 
         // This byte pattern resolves to "NES" followed by an MSDOS end-of-file character
@@ -844,72 +2636,557 @@ impl INesHeader {
             || (file_id[2] != 0x53)
             || (file_id[3] != 0x1A)
         {
-            return None;
+            return Err(CartridgeError::BadMagic);
         }
 
-        let prg_banks = reader.read_byte()?;
-        let chr_banks = reader.read_byte()?;
-        let mapper_1 = reader.read_byte()?;
-        let mapper_2 = reader.read_byte()?;
-        let prg_ram_size = reader.read_byte()?;
-        let tv_system_1 = reader.read_byte()?;
-        let tv_system_2 = reader.read_byte()?;
-        let mut unused: [u8; 5] = [0; 5];
-        if reader.read_into(&mut unused) != 5 {
-            return None;
+        let mut read_byte = || reader.read_byte().ok_or(CartridgeError::UnexpectedEof);
+        let prg_rom_lsb = read_byte()?;
+        let chr_rom_lsb = read_byte()?;
+        let mapper_1 = read_byte()?;
+        let mapper_2 = read_byte()?;
+        let mapper_3 = read_byte()?;
+        let rom_size_msb = read_byte()?;
+        let _prg_ram_size = read_byte()?;
+        let chr_ram_size = read_byte()?;
+        let timing = read_byte()?;
+        let mut unused: [u8; 3] = [0; 3];
+        if reader.read_into(&mut unused) != 3 {
+            return Err(CartridgeError::UnexpectedEof);
         }
 
-        Some(Self {
-            prg_banks,
-            chr_banks,
-            mapper_1,
-            mapper_2,
-            _prg_ram_size: prg_ram_size,
-            _tv_system_1: tv_system_1,
-            _tv_system_2: tv_system_2,
-        })
+        let mapper_id = ((mapper_2 & 0xF0) | (mapper_1 >> 4)) as u16;
+
+        if (mapper_2 & 0x0C) == 0x08 {
+            // NES 2.0
+            Ok(Self {
+                prg_rom_size: nes2_rom_size(prg_rom_lsb, rom_size_msb & 0x0F, PRG_BANK_SIZE),
+                chr_rom_size: nes2_rom_size(chr_rom_lsb, rom_size_msb >> 4, CHR_BANK_SIZE),
+                // Given as a shift count of 64 bytes, 0 means no CHR RAM
+                chr_ram_size: Some(match chr_ram_size & 0x0F {
+                    0 => 0,
+                    shift => 64 << shift,
+                }),
+                mapper_id: (((mapper_3 & 0x0F) as u16) << 8) | mapper_id,
+                submapper: mapper_3 >> 4,
+                mapper_1,
+                nes2: true,
+                // Multi-region games run on NTSC consoles as well
+                region: match timing & 0x03 {
+                    1 => Region::Pal,
+                    3 => Region::Dendy,
+                    _ => Region::Ntsc,
+                },
+            })
+        } else {
+            Ok(Self {
+                prg_rom_size: Some((prg_rom_lsb as usize) * PRG_BANK_SIZE),
+                chr_rom_size: Some((chr_rom_lsb as usize) * CHR_BANK_SIZE),
+                chr_ram_size: None,
+                mapper_id,
+                submapper: 0,
+                mapper_1,
+                nes2: false,
+                // Old dumps often have garbage like "DiskDude!" in the last bytes,
+                // the TV system bit is only trusted if they are clean
+                region: if ((rom_size_msb & 0x01) != 0) && (timing == 0) && (unused == [0; 3]) {
+                    Region::Pal
+                } else {
+                    Region::Ntsc
+                },
+            })
+        }
     }
 }
 
-pub fn load_cartridge<P: AsRef<std::path::Path>>(file: P) -> Option<Cartridge> {
-    let mut reader = BinReader::from_file(file).ok()?;
-    let header = INesHeader::from_reader(&mut reader)?;
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(std::io::Error),
+    InvalidArchive,
+    /// The zip archive contains no `.nes` file
+    NoRomInArchive,
+    InvalidPatch,
+    BadMagic,
+    UnexpectedEof,
+    UnsupportedMapper(u16),
+    NoPrgRom,
+    TruncatedPrg,
+    TruncatedChr,
+}
 
-    // Skip trainer data if it exists
-    if (header.mapper_1 & 0x04) != 0 {
-        reader.skip(512);
+impl std::fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::InvalidArchive => write!(
+                f,
+                "compressed file is corrupted or uses an unsupported format"
+            ),
+            Self::NoRomInArchive => write!(f, "zip archive contains no .nes file"),
+            Self::InvalidPatch => write!(f, "patch is invalid or does not match the ROM"),
+            Self::BadMagic => write!(f, "not an iNES file"),
+            Self::UnexpectedEof => write!(f, "file ends inside the header"),
+            Self::UnsupportedMapper(id) => write!(f, "mapper {id} is not supported"),
+            Self::NoPrgRom => write!(f, "header declares no PRG ROM"),
+            Self::TruncatedPrg => write!(f, "file ends inside the PRG ROM"),
+            Self::TruncatedChr => write!(f, "file ends inside the CHR ROM"),
+        }
     }
+}
 
-    let mapper_id = (header.mapper_2 & 0xF0) | (header.mapper_1 >> 4);
-    let mapper = get_mapper_from_id(mapper_id, header.prg_banks)?;
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
-    let mut prg_mem: Vec<u8> = vec![0; header.prg_banks as usize * PRG_BANK_SIZE];
-    if reader.read_into(&mut prg_mem) != prg_mem.len() {
-        return None;
+impl From<std::io::Error> for CartridgeError {
+    #[inline]
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
     }
+}
+
+pub fn load_cartridge<P: AsRef<std::path::Path>>(
+    file: P,
+    patch: Option<&std::path::Path>,
+) -> Result<Cartridge, CartridgeError> {
+    let data = std::fs::read(file)?;
+    let mut data = archive::extract_rom(data).map_err(|err| match err {
+        archive::ArchiveError::Corrupted => CartridgeError::InvalidArchive,
+        archive::ArchiveError::NoRom => CartridgeError::NoRomInArchive,
+    })?;
+    if let Some(patch) = patch {
+        let patch = std::fs::read(patch)?;
+        data = patch::apply_patch(&data, &patch).ok_or(CartridgeError::InvalidPatch)?;
+    }
+
+    parse_cartridge(data)
+}
+
+/// Builds a cartridge from an iNES or NES 2.0 image that has already been extracted and patched
+fn parse_cartridge(data: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+    let mut reader = BinReader::new(data);
+    let header = INesHeader::from_reader(&mut reader)?;
+
+    // Trainer data is copied to $7000 once the mapper is known
+    let trainer = if (header.mapper_1 & 0x04) != 0 {
+        Some(
+            reader
+                .read_slice(TRAINER_SIZE)
+                .ok_or(CartridgeError::UnexpectedEof)?
+                .to_vec(),
+        )
+    } else {
+        None
+    };
+
+    // Mappers rely on there being at least one PRG bank to compute their fixed banks
+    let prg_rom_size = match header.prg_rom_size {
+        Some(0) => return Err(CartridgeError::NoPrgRom),
+        Some(size) => size,
+        None => return Err(CartridgeError::TruncatedPrg),
+    };
+    let chr_rom_size = header.chr_rom_size.ok_or(CartridgeError::TruncatedChr)?;
+
+    // Only NES 2.0 headers specify the CHR RAM size, older ROMs get a single bank
+    let chr_ram_size = if chr_rom_size == 0 {
+        header
+            .chr_ram_size
+            .filter(|&size| size > 0)
+            .unwrap_or(CHR_BANK_SIZE)
+    } else {
+        0
+    };
 
-    let chr_mem: Vec<u8> = if header.chr_banks == 0 {
+    // Mappers bank CHR RAM like CHR ROM, so they see it as banks as well
+    let prg_banks = bank_count(prg_rom_size, PRG_BANK_SIZE);
+    let chr_banks = bank_count(chr_rom_size.max(chr_ram_size), CHR_BANK_SIZE);
+    let mapper = u8::try_from(header.mapper_id)
+        .ok()
+        .and_then(|id| get_mapper_from_id(id, header.submapper, prg_banks, chr_banks))
+        .ok_or(CartridgeError::UnsupportedMapper(header.mapper_id))?;
+
+    let prg_mem = reader
+        .read_slice(prg_rom_size)
+        .ok_or(CartridgeError::TruncatedPrg)?
+        .to_vec();
+
+    let has_trainer = trainer.is_some();
+    let chr_mem: Vec<u8> = if chr_rom_size == 0 {
         // We have RAM instead of ROM
-        vec![0; CHR_BANK_SIZE]
+        vec![0; chr_ram_size.max(mapper.chr_ram_size())]
     } else {
-        let mut tmp = vec![0; (header.chr_banks as usize) * CHR_BANK_SIZE];
-        if reader.read_into(&mut tmp) != tmp.len() {
-            return None;
-        }
-        tmp
+        reader
+            .read_slice(chr_rom_size)
+            .ok_or(CartridgeError::TruncatedChr)?
+            .to_vec()
     };
 
-    let mirror = if (header.mapper_1 & 0x01) != 0 {
+    let mirror = if (header.mapper_1 & 0x08) != 0 {
+        MirrorMode::FourScreen
+    } else if (header.mapper_1 & 0x01) != 0 {
         MirrorMode::Vertical
     } else {
         MirrorMode::Horizontal
     };
 
-    Some(Cartridge::new(
+    // CHR RAM is not part of the dump
+    let chr_rom: &[u8] = if chr_rom_size == 0 { &[] } else { &chr_mem };
+    let info = CartridgeInfo {
+        mapper: header.mapper_id,
+        submapper: header.submapper,
+        mirror,
+        prg_rom_size,
+        chr_rom_size,
+        chr_ram_size: if chr_rom_size == 0 { chr_mem.len() } else { 0 },
+        has_battery: (header.mapper_1 & 0x02) != 0,
+        has_trainer,
+        nes2: header.nes2,
+        region: header.region,
+        crc32: hash::crc32(&[&prg_mem, chr_rom]),
+        sha1: hash::sha1(&[&prg_mem, chr_rom]),
+    };
+
+    let mut cart = Cartridge::new(
         mapper,
         prg_mem.into_boxed_slice(),
         chr_mem.into_boxed_slice(),
-        header.chr_banks == 0,
-        mirror,
-    ))
+        info,
+    );
+
+    if let Some(trainer) = trainer {
+        cart.load_trainer(&trainer);
+    }
+
+    Ok(cart)
+}
+
+/// Builds a cartridge from a plain iNES image with the given ROM contents,
+/// no CHR ROM gives the cartridge 8KB of CHR RAM
+#[cfg(test)]
+pub(crate) fn test_cartridge(mapper: u8, prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
+    let mut data = vec![
+        0x4E,
+        0x45,
+        0x53,
+        0x1A,
+        (prg_rom.len() / PRG_BANK_SIZE) as u8,
+        (chr_rom.len() / CHR_BANK_SIZE) as u8,
+        mapper << 4,
+        mapper & 0xF0,
+    ];
+    data.resize(16, 0);
+    data.extend_from_slice(prg_rom);
+    data.extend_from_slice(chr_rom);
+    parse_cartridge(data).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a NES 2.0 image without CHR ROM, `rom_size` are the raw PRG size header fields
+    fn nes2_cartridge(
+        mapper: u8,
+        rom_size: (u8, u8),
+        chr_ram_shift: u8,
+        prg_rom: &[u8],
+    ) -> Cartridge {
+        let mut data = vec![
+            0x4E,
+            0x45,
+            0x53,
+            0x1A,
+            rom_size.0,
+            0,
+            mapper << 4,
+            (mapper & 0xF0) | 0x08,
+            0,
+            rom_size.1,
+            0,
+            chr_ram_shift,
+        ];
+        data.resize(16, 0);
+        data.extend_from_slice(prg_rom);
+        parse_cartridge(data).unwrap()
+    }
+
+    #[test]
+    fn prg_rom_of_any_size_mirrors() {
+        // 24KB, 2^13 * 3 in exponent-multiplier notation, every 8KB filled with its index
+        let prg_rom: Vec<u8> = (0..3).flat_map(|i| [i; 0x2000]).collect();
+        let cart = nes2_cartridge(0, ((13 << 2) | 1, 0x0F), 7, &prg_rom);
+        assert_eq!(cart.info().prg_rom_size, 0x6000);
+
+        assert_eq!(cart.cpu_read(0x8000), Some(0));
+        assert_eq!(cart.cpu_read(0xA000), Some(1));
+        assert_eq!(cart.cpu_read(0xDFFF), Some(2));
+        // The missing last 8KB mirror the start of the ROM
+        assert_eq!(cart.cpu_read(0xE000), Some(0));
+        assert_eq!(cart.cpu_read(0xFFFF), Some(0));
+
+        // 16KB CNROM mirrors the only bank into $C000-$FFFF
+        let prg_rom: Vec<u8> = (0..0x4000).map(|i| i as u8).collect();
+        let cart = test_cartridge(3, &prg_rom, &[0; 0x2000]);
+        assert_eq!(cart.cpu_read(0xC000), cart.cpu_read(0x8000));
+        assert_eq!(cart.cpu_read(0xFFFF), Some(0xFF));
+    }
+
+    #[test]
+    fn over_range_chr_bank_mirrors() {
+        // Two 8KB CHR banks, filled with their index
+        let chr_rom: Vec<u8> = (0..2).flat_map(|i| [i; 0x2000]).collect();
+        let mut cart = test_cartridge(3, &[0xFF; 0x8000], &chr_rom);
+
+        cart.cpu_write(0x8000, 0x01);
+        assert_eq!(cart.ppu_read(0x0000), 1);
+
+        // Bank 3 doesn't exist, the missing address line makes it bank 1
+        cart.cpu_write(0x8000, 0x03);
+        assert_eq!(cart.ppu_read(0x0000), 1);
+        assert_eq!(cart.ppu_read(0x1FFF), 1);
+
+        cart.cpu_write(0x8000, 0x02);
+        assert_eq!(cart.ppu_read(0x1FFF), 0);
+    }
+
+    #[test]
+    fn rom_without_prg_is_rejected() {
+        let mut data = vec![0x4E, 0x45, 0x53, 0x1A, 0, 1];
+        data.resize(16 + 0x2000, 0);
+        assert!(matches!(
+            parse_cartridge(data),
+            Err(CartridgeError::NoPrgRom)
+        ));
+    }
+
+    #[test]
+    fn chr_ram_is_banked() {
+        // 32KB of CHR RAM, 64 << 9 bytes
+        let mut cart = nes2_cartridge(3, (1, 0), 9, &[0xFF; 0x4000]);
+        assert_eq!(cart.info().chr_ram_size, 0x8000);
+
+        cart.ppu_write(0x0010, 0x11);
+        cart.cpu_write(0x8000, 0x01);
+        assert_eq!(cart.ppu_read(0x0010), 0x00);
+        cart.ppu_write(0x0010, 0x22);
+
+        cart.cpu_write(0x8000, 0x00);
+        assert_eq!(cart.ppu_read(0x0010), 0x11);
+        cart.cpu_write(0x8000, 0x01);
+        assert_eq!(cart.ppu_read(0x0010), 0x22);
+    }
+
+    #[test]
+    fn gxrom_banking() {
+        // 4 PRG banks of 32KB starting with their index, the rest is $FF so writes there don't conflict
+        let mut prg_rom = vec![0xFF; 0x20000];
+        for bank in 0..4 {
+            prg_rom[bank * 0x8000] = bank as u8;
+        }
+        let chr_rom: Vec<u8> = (0..4).flat_map(|i| [i; 0x2000]).collect();
+        let mut cart = test_cartridge(66, &prg_rom, &chr_rom);
+
+        for prg_bank in 0..4 {
+            for chr_bank in 0..4 {
+                cart.cpu_write(0x8001, (prg_bank << 4) | chr_bank);
+                assert_eq!(cart.cpu_read(0x8000), Some(prg_bank));
+                assert_eq!(cart.ppu_read(0x0000), chr_bank);
+            }
+        }
+
+        // Banks beyond the end of the ROMs mirror
+        cart.cpu_write(0x8001, 0x56);
+        assert_eq!(cart.cpu_read(0x8000), Some(1));
+        assert_eq!(cart.ppu_read(0x0000), 2);
+
+        // Bus conflicts AND the written value with the ROM byte, $01 here
+        cart.cpu_write(0x8000, 0x33);
+        assert_eq!(cart.cpu_read(0x8000), Some(0));
+        assert_eq!(cart.ppu_read(0x0000), 1);
+    }
+
+    #[test]
+    fn over_range_small_chr_banks_wrap() {
+        // 16KB of CHR ROM, every 1KB filled with its index
+        let chr_rom: Vec<u8> = (0..16).flat_map(|i| [i; 0x0400]).collect();
+
+        // MMC1 in 4KB CHR mode, bank $16 of 4 wraps to 2
+        let mut cart = test_cartridge(1, &[0xFF; 0x8000], &chr_rom);
+        let mut mmc1_write = |addr: u16, data: u8| {
+            for bit in 0..5 {
+                cart.cpu_write(addr, (data >> bit) & 0x01);
+            }
+        };
+        mmc1_write(0x8000, 0x1C);
+        mmc1_write(0xA000, 0x16);
+        assert_eq!(cart.ppu_read(0x0000), 8);
+        assert_eq!(cart.ppu_read(0x0FFF), 11);
+
+        // MMC3 1KB bank $13 of 16 wraps to 3
+        let mut cart = test_cartridge(4, &[0xFF; 0x8000], &chr_rom);
+        cart.cpu_write(0x8000, 0x02);
+        cart.cpu_write(0x8001, 0x13);
+        assert_eq!(cart.ppu_read(0x1000), 3);
+    }
+
+    #[test]
+    fn fme7_irq_counts_cpu_cycles() {
+        let mut cart = test_cartridge(69, &[0xFF; 0x8000], &[0; 0x2000]);
+        let mut write_register = |command: u8, data: u8| {
+            cart.cpu_write(0x8000, command);
+            cart.cpu_write(0xA000, data);
+        };
+        write_register(0x0E, 0x10);
+        write_register(0x0F, 0x00);
+        write_register(0x0D, 0x81);
+
+        // The IRQ fires when the counter underflows from 0 to $FFFF
+        for _ in 0..=0x10 {
+            assert!(!cart.interrupt_state());
+            cart.on_cpu_cycle();
+        }
+        assert!(cart.interrupt_state());
+
+        // Writing the IRQ control register acknowledges it, a halted counter doesn't fire again
+        cart.cpu_write(0x8000, 0x0D);
+        cart.cpu_write(0xA000, 0x01);
+        assert!(!cart.interrupt_state());
+        for _ in 0..0x20000 {
+            cart.on_cpu_cycle();
+        }
+        assert!(!cart.interrupt_state());
+    }
+
+    #[test]
+    fn corrupted_mmc5_modes_are_masked() {
+        let mut cart = test_cartridge(5, &[0; 0x8000], &[0; 0x2000]);
+        let mut state = StateWriter::new(cart.rom_crc());
+        cart.save_state(&mut state);
+        let mut data = state.finish();
+
+        // The mapper's fields start right after the header, with the PRG and CHR modes first
+        data[12] = 0xFF;
+        data[13] = 0xFF;
+        let mut state = StateReader::new(&data, cart.rom_crc()).unwrap();
+        cart.load_state(&mut state).unwrap();
+        state.finish().unwrap();
+
+        // Mode 3, so these would have divided by zero with the raw values
+        assert_eq!(cart.cpu_read(0x8000), Some(0));
+        assert_eq!(cart.ppu_read(0x0000), 0);
+    }
+
+    #[test]
+    fn mmc5_prg_modes() {
+        // 16 PRG banks of 8KB starting with their index plus $10, so they can't be mistaken for empty RAM
+        let prg_rom: Vec<u8> = (0..16).flat_map(|i| [0x10 + i; 0x2000]).collect();
+        let mut cart = test_cartridge(5, &prg_rom, &[0; 0x2000]);
+        let mut banks_in_mode = |mode: u8, regs: &[(u16, u8)]| {
+            cart.cpu_write(0x5100, mode);
+            for &(addr, data) in regs {
+                cart.cpu_write(addr, data);
+            }
+            [0x8000, 0xA000, 0xC000, 0xE000].map(|addr| cart.cpu_read(addr).unwrap() - 0x10)
+        };
+
+        // Larger banks ignore the low bits of the bank number
+        assert_eq!(banks_in_mode(0, &[(0x5117, 0x85)]), [4, 5, 6, 7]);
+        assert_eq!(
+            banks_in_mode(1, &[(0x5115, 0x83), (0x5117, 0x8A)]),
+            [2, 3, 10, 11]
+        );
+        assert_eq!(
+            banks_in_mode(2, &[(0x5115, 0x86), (0x5116, 0x89), (0x5117, 0x8D)]),
+            [6, 7, 9, 13]
+        );
+        assert_eq!(
+            banks_in_mode(
+                3,
+                &[
+                    (0x5114, 0x81),
+                    (0x5115, 0x83),
+                    (0x5116, 0x85),
+                    (0x5117, 0x8F)
+                ]
+            ),
+            [1, 3, 5, 15]
+        );
+
+        // Bit 7 selects ROM, except in $5117 which always maps ROM
+        cart.cpu_write(0x5116, 0x05);
+        cart.cpu_write(0x5117, 0x0F);
+        assert_eq!(cart.cpu_read(0xC000), Some(0));
+        assert_eq!(cart.cpu_read(0xE000), Some(0x1F));
+    }
+
+    #[test]
+    fn mmc5_multiplier() {
+        let mut cart = test_cartridge(5, &[0; 0x8000], &[0; 0x2000]);
+        cart.cpu_write(0x5205, 0xFF);
+        cart.cpu_write(0x5206, 0x12);
+        assert_eq!(cart.cpu_read(0x5205), Some(0xEE));
+        assert_eq!(cart.cpu_read(0x5206), Some(0x11));
+    }
+
+    #[test]
+    fn mmc5_scanline_irq() {
+        let mut cart = test_cartridge(5, &[0; 0x8000], &[0; 0x2000]);
+        cart.cpu_write(0x5203, 3);
+        cart.cpu_write(0x5204, 0x80);
+
+        // The first line of the frame only starts it, the counter then counts the following ones
+        cart.on_scanline();
+        assert_eq!(cart.cpu_read(0x5204), Some(0x40));
+        for _ in 0..2 {
+            cart.on_scanline();
+            assert!(!cart.interrupt_state());
+        }
+        cart.on_scanline();
+        assert!(cart.interrupt_state());
+        assert_eq!(cart.cpu_read(0x5204), Some(0xC0));
+
+        cart.reset_interrupt();
+        assert!(!cart.interrupt_state());
+    }
+
+    #[test]
+    fn vrc6_address_lines() {
+        // 16 CHR banks of 1KB filled with their index
+        let chr_rom: Vec<u8> = (0..16).flat_map(|i| [i; 0x0400]).collect();
+        for (mapper, swapped) in [(24, false), (26, true)] {
+            let mut cart = test_cartridge(mapper, &[0; 0x8000], &chr_rom);
+            cart.cpu_write(0xD001, 5);
+            cart.cpu_write(0xD002, 9);
+
+            let expected = if swapped { [9, 5] } else { [5, 9] };
+            assert_eq!([cart.ppu_read(0x0400), cart.ppu_read(0x0800)], expected);
+        }
+    }
+
+    #[test]
+    fn vrc6_cycle_irq() {
+        const LATCH: u8 = 0xF0;
+
+        let mut cart = test_cartridge(24, &[0; 0x8000], &[0; 0x2000]);
+        cart.cpu_write(0xF000, LATCH);
+        // Enabled in cycle mode, and enabled again after acknowledging
+        cart.cpu_write(0xF001, 0x07);
+
+        // The counter counts up from the latch and fires when it overflows, 256 - latch cycles later
+        for _ in 0..2 {
+            for _ in 0..(0xFF - LATCH) {
+                cart.on_cpu_cycle();
+            }
+            assert!(!cart.interrupt_state());
+            cart.on_cpu_cycle();
+            assert!(cart.interrupt_state());
+
+            cart.cpu_write(0xF002, 0);
+            assert!(!cart.interrupt_state());
+        }
+    }
 }
@@ -0,0 +1,542 @@
+// Compressed ROM files, recognized by their magic bytes so the file extension doesn't matter
+
+use super::hash::crc32;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+
+/// Far larger than any real ROM, but small enough that a zip bomb can't exhaust memory
+const MAX_ROM_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveError {
+    /// Also returned if the data would decompress to more than `MAX_ROM_SIZE` bytes
+    Corrupted,
+    /// The zip archive contains no file ending in `.nes`
+    NoRom,
+}
+
+/// Decompresses `.gz` files and the first `.nes` entry of `.zip` files, any other data is returned as is
+pub fn extract_rom(data: Vec<u8>) -> Result<Vec<u8>, ArchiveError> {
+    if data.starts_with(&GZIP_MAGIC) {
+        extract_gzip(&data).ok_or(ArchiveError::Corrupted)
+    } else if data.starts_with(&ZIP_MAGIC) {
+        extract_zip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+#[inline]
+fn read_u16(data: &[u8], pos: usize) -> Option<usize> {
+    let bytes = data.get(pos..(pos + 2))?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+#[inline]
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..(pos + 4))?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// https://www.rfc-editor.org/rfc/rfc1952
+fn extract_gzip(data: &[u8]) -> Option<Vec<u8>> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    const DEFLATE: u8 = 8;
+    if *data.get(2)? != DEFLATE {
+        return None;
+    }
+
+    let flags = *data.get(3)?;
+    let mut pos = 10;
+    if (flags & FEXTRA) != 0 {
+        pos += 2 + read_u16(data, pos)?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if (flags & flag) != 0 {
+            // Zero terminated strings
+            pos += data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if (flags & FHCRC) != 0 {
+        pos += 2;
+    }
+
+    let (output, len) = inflate(data.get(pos..)?, MAX_ROM_SIZE)?;
+    let crc = read_u32(data, pos + len)?;
+    let size = read_u32(data, pos + len + 4)?;
+    ((crc32(&[&output]) == crc) && (size == (output.len() as u32))).then_some(output)
+}
+
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+// Sizes are taken from the central directory, the local headers may leave them out
+fn extract_zip(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    const END_OF_DIRECTORY_SIGNATURE: u32 = 0x06054B50;
+    const END_OF_DIRECTORY_SIZE: usize = 22;
+    const DIRECTORY_ENTRY_SIGNATURE: u32 = 0x02014B50;
+    const DIRECTORY_ENTRY_SIZE: usize = 46;
+    const LOCAL_HEADER_SIZE: usize = 30;
+
+    const STORED: usize = 0;
+    const DEFLATED: usize = 8;
+
+    // The end of central directory record is followed by a comment of up to 64KiB
+    let end = (0..=data.len().saturating_sub(END_OF_DIRECTORY_SIZE))
+        .rev()
+        .take(u16::MAX as usize + 1)
+        .find(|&pos| read_u32(data, pos) == Some(END_OF_DIRECTORY_SIGNATURE))
+        .ok_or(ArchiveError::Corrupted)?;
+
+    let entry_count = read_u16(data, end + 10).ok_or(ArchiveError::Corrupted)?;
+    let mut pos = read_u32(data, end + 16).ok_or(ArchiveError::Corrupted)? as usize;
+
+    for _ in 0..entry_count {
+        let entry = (|| {
+            if read_u32(data, pos)? != DIRECTORY_ENTRY_SIGNATURE {
+                return None;
+            }
+
+            let name_len = read_u16(data, pos + 28)?;
+            let name =
+                data.get((pos + DIRECTORY_ENTRY_SIZE)..(pos + DIRECTORY_ENTRY_SIZE + name_len))?;
+            let entry = (
+                read_u16(data, pos + 10)?,
+                read_u32(data, pos + 16)?,
+                read_u32(data, pos + 20)? as usize,
+                read_u32(data, pos + 42)? as usize,
+                name.to_ascii_lowercase().ends_with(b".nes"),
+            );
+
+            pos += DIRECTORY_ENTRY_SIZE
+                + name_len
+                + read_u16(data, pos + 30)?
+                + read_u16(data, pos + 32)?;
+            Some(entry)
+        })();
+
+        let (method, crc, compressed_size, header_pos, is_rom) =
+            entry.ok_or(ArchiveError::Corrupted)?;
+        if !is_rom {
+            continue;
+        }
+
+        let output = (|| {
+            let start = header_pos
+                + LOCAL_HEADER_SIZE
+                + read_u16(data, header_pos + 26)?
+                + read_u16(data, header_pos + 28)?;
+            let compressed = data.get(start..(start + compressed_size))?;
+
+            let output = match method {
+                STORED => compressed.to_vec(),
+                DEFLATED => inflate(compressed, MAX_ROM_SIZE)?.0,
+                _ => return None,
+            };
+            (crc32(&[&output]) == crc).then_some(output)
+        })();
+        return output.ok_or(ArchiveError::Corrupted);
+    }
+
+    Err(ArchiveError::NoRom)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    #[inline]
+    const fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            bit_buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Deflate packs values starting at the least significant bit
+    fn read_bits(&mut self, count: u32) -> Option<u32> {
+        while self.bit_count < count {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            self.bit_buffer |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+
+        let value = self.bit_buffer & ((1 << count) - 1);
+        self.bit_buffer >>= count;
+        self.bit_count -= count;
+        Some(value)
+    }
+
+    /// Stored blocks start at the next byte boundary
+    #[inline]
+    fn align_to_byte(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
+}
+
+const MAX_CODE_LEN: usize = 15;
+
+/// Canonical Huffman code, decoded one bit at a time
+struct Huffman {
+    /// Number of codes of every length
+    counts: [u16; MAX_CODE_LEN + 1],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; MAX_CODE_LEN + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; MAX_CODE_LEN + 2];
+        for len in 1..=MAX_CODE_LEN {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Option<u16> {
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for len in 1..=MAX_CODE_LEN {
+            code |= reader.read_bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if (code - first) < count {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which the code lengths of the code length alphabet are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    lengths[0..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..288].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Option<(Huffman, Huffman)> {
+    let literal_count = (reader.read_bits(5)? as usize) + 257;
+    let distance_count = (reader.read_bits(5)? as usize) + 1;
+    let code_length_count = (reader.read_bits(4)? as usize) + 4;
+
+    let mut code_length_lengths = [0; 19];
+    for &symbol in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_length_lengths[symbol] = reader.read_bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_length_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < (literal_count + distance_count) {
+        let (len, repeat) = match code_length_code.decode(reader)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => (*lengths.last()?, 3 + reader.read_bits(2)?),
+            17 => (0, 3 + reader.read_bits(3)?),
+            18 => (0, 11 + reader.read_bits(7)?),
+            _ => return None,
+        };
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+    if lengths.len() != (literal_count + distance_count) {
+        return None;
+    }
+
+    Some((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+// https://www.rfc-editor.org/rfc/rfc1951
+/// Returns the decompressed data and how many bytes of `data` the compressed stream took up,
+/// `None` if the data is invalid or decompresses to more than `limit` bytes
+fn inflate(data: &[u8], limit: usize) -> Option<(Vec<u8>, usize)> {
+    const END_OF_BLOCK: u16 = 256;
+
+    let mut reader = BitReader::new(data);
+    let mut output = Vec::new();
+
+    loop {
+        let last_block = reader.read_bits(1)? != 0;
+        let (literals, distances) = match reader.read_bits(2)? {
+            0 => {
+                reader.align_to_byte();
+                let len = read_u16(data, reader.pos)?;
+                let inverted_len = read_u16(data, reader.pos + 2)?;
+                if len != (!inverted_len & 0xFFFF) {
+                    return None;
+                }
+
+                let start = reader.pos + 4;
+                if (output.len() + len) > limit {
+                    return None;
+                }
+                output.extend_from_slice(data.get(start..(start + len))?);
+                reader.pos = start + len;
+
+                if last_block {
+                    break;
+                }
+                continue;
+            }
+            1 => fixed_codes(),
+            2 => dynamic_codes(&mut reader)?,
+            _ => return None,
+        };
+
+        loop {
+            let symbol = literals.decode(&mut reader)?;
+            if symbol < END_OF_BLOCK {
+                if output.len() >= limit {
+                    return None;
+                }
+                output.push(symbol as u8);
+            } else if symbol == END_OF_BLOCK {
+                break;
+            } else {
+                let index = (symbol - END_OF_BLOCK - 1) as usize;
+                let len = (*LENGTH_BASE.get(index)? as usize)
+                    + (reader.read_bits(*LENGTH_EXTRA.get(index)? as u32)? as usize);
+
+                let index = distances.decode(&mut reader)? as usize;
+                let distance = (*DISTANCE_BASE.get(index)? as usize)
+                    + (reader.read_bits(*DISTANCE_EXTRA.get(index)? as u32)? as usize);
+
+                // The copy may overlap the bytes it produces
+                let start = output.len().checked_sub(distance)?;
+                if (output.len() + len) > limit {
+                    return None;
+                }
+                for i in 0..len {
+                    output.push(output[start + i]);
+                }
+            }
+        }
+
+        if last_block {
+            break;
+        }
+    }
+
+    // Any bits left over belong to the last byte of the stream
+    Some((output, reader.pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"Hello, Hello, Hello NES!";
+
+    // Generated with Python's zlib, gzip and zipfile modules
+    const STORED: &[u8] = &[
+        0x01, 0x06, 0x00, 0xF9, 0xFF, 0x73, 0x74, 0x6F, 0x72, 0x65, 0x64,
+    ];
+    const FIXED: &[u8] = &[
+        0xF3, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xF0, 0x40, 0xA2, 0x14, 0xFC, 0x5C, 0x83, 0x15,
+        0x01,
+    ];
+    const DYNAMIC: &[u8] = &[
+        0x25, 0x8A, 0xC1, 0x0D, 0x00, 0x40, 0x0C, 0x82, 0x66, 0x05, 0xEB, 0xFE, 0x2B, 0x5C, 0x9B,
+        0xF3, 0x61, 0x0C, 0xC8, 0xA0, 0x22, 0xA4, 0x09, 0x50, 0xE6, 0x6A, 0xB3, 0x3C, 0xAB, 0xCE,
+        0x61, 0xBE, 0xB9, 0xEB, 0x6E, 0x99, 0x6A, 0x5B, 0x1E,
+    ];
+    /// `TEXT` as `game.nes`
+    const GZIP: &[u8] = &[
+        0x1F, 0x8B, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xFF, 0x67, 0x61, 0x6D, 0x65, 0x2E,
+        0x6E, 0x65, 0x73, 0x00, 0xF3, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xF0, 0x40, 0xA2, 0x14,
+        0xFC, 0x5C, 0x83, 0x15, 0x01, 0xDB, 0x0F, 0xD5, 0xA3, 0x18, 0x00, 0x00, 0x00,
+    ];
+    /// A stored `readme.txt` followed by `TEXT` deflated as `Game.NES`
+    const ZIP: &[u8] = &[
+        0x50, 0x4B, 0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x00, 0xAC,
+        0x2A, 0x93, 0xD8, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00,
+        0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78, 0x74, 0x68, 0x69, 0x50, 0x4B, 0x03,
+        0x04, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x21, 0x00, 0xDB, 0x0F, 0xD5, 0xA3,
+        0x10, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x47, 0x61, 0x6D,
+        0x65, 0x2E, 0x4E, 0x45, 0x53, 0xF3, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xF0, 0x40, 0xA2,
+        0x14, 0xFC, 0x5C, 0x83, 0x15, 0x01, 0x50, 0x4B, 0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x00, 0xAC, 0x2A, 0x93, 0xD8, 0x02, 0x00, 0x00, 0x00,
+        0x02, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74,
+        0x78, 0x74, 0x50, 0x4B, 0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00,
+        0x00, 0x21, 0x00, 0xDB, 0x0F, 0xD5, 0xA3, 0x10, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00,
+        0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x01, 0x2A,
+        0x00, 0x00, 0x00, 0x47, 0x61, 0x6D, 0x65, 0x2E, 0x4E, 0x45, 0x53, 0x50, 0x4B, 0x05, 0x06,
+        0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x02, 0x00, 0x6E, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00,
+        0x00, 0x00, 0x00,
+    ];
+
+    /// Text with skewed letter frequencies and few repetitions, which zlib compresses
+    /// with dynamic codes
+    fn dynamic_text() -> Vec<u8> {
+        const LETTERS: &[u8] = b"aaaaaaaabbbbccde";
+
+        let mut x: u32 = 1;
+        (0..64)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345) & 0x7FFFFFFF;
+                LETTERS[((x >> 16) & 0x0F) as usize]
+            })
+            .collect()
+    }
+
+    /// A fixed code block of a single zero byte, repeated by `copies` back-references
+    /// of the maximum length
+    fn zero_run(copies: usize) -> Vec<u8> {
+        let mut bits = Vec::new();
+        // Huffman codes are packed starting at their most significant bit
+        let mut push_code = |code: u32, len: u32| {
+            bits.extend((0..len).rev().map(|bit| ((code >> bit) & 0x01) != 0));
+        };
+
+        // Last block, fixed codes
+        push_code(0b110, 3);
+        // Literal 0
+        push_code(0x30, 8);
+        for _ in 0..copies {
+            // Length 258, distance 1
+            push_code(0xC5, 8);
+            push_code(0x00, 5);
+        }
+        // End of block
+        push_code(0x00, 7);
+
+        bits.chunks(8)
+            .map(|byte| {
+                byte.iter()
+                    .enumerate()
+                    .fold(0, |acc, (i, &bit)| acc | ((bit as u8) << i))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn inflate_blocks() {
+        assert_eq!(
+            inflate(STORED, MAX_ROM_SIZE),
+            Some((b"stored".to_vec(), STORED.len()))
+        );
+        assert_eq!(
+            inflate(FIXED, MAX_ROM_SIZE),
+            Some((TEXT.to_vec(), FIXED.len()))
+        );
+        assert_eq!(
+            inflate(DYNAMIC, MAX_ROM_SIZE),
+            Some((dynamic_text(), DYNAMIC.len()))
+        );
+    }
+
+    #[test]
+    fn inflate_corrupted() {
+        // The length of a stored block has to match its complement
+        let mut stored = STORED.to_vec();
+        stored[3] ^= 0x01;
+        assert_eq!(inflate(&stored, MAX_ROM_SIZE), None);
+
+        // Block type 3 is reserved
+        assert_eq!(inflate(&[0x07], MAX_ROM_SIZE), None);
+
+        for len in 0..DYNAMIC.len() {
+            assert_eq!(inflate(&DYNAMIC[..len], MAX_ROM_SIZE), None);
+        }
+    }
+
+    #[test]
+    fn inflate_limit() {
+        let data = zero_run(100);
+        let (output, _) = inflate(&data, 1 + 100 * 258).unwrap();
+        assert_eq!(output, vec![0; 1 + 100 * 258]);
+
+        assert_eq!(inflate(&data, 100 * 258), None);
+    }
+
+    #[test]
+    fn extract_gzip_and_zip() {
+        assert_eq!(extract_rom(GZIP.to_vec()), Ok(TEXT.to_vec()));
+        assert_eq!(extract_rom(ZIP.to_vec()), Ok(TEXT.to_vec()));
+        // Anything else is passed through
+        assert_eq!(extract_rom(TEXT.to_vec()), Ok(TEXT.to_vec()));
+    }
+
+    #[test]
+    fn extract_corrupted() {
+        // Wrong CRC
+        let mut gzip = GZIP.to_vec();
+        let crc_pos = gzip.len() - 8;
+        gzip[crc_pos] ^= 0x01;
+        assert_eq!(extract_rom(gzip), Err(ArchiveError::Corrupted));
+
+        let gzip = GZIP[..(GZIP.len() - 1)].to_vec();
+        assert_eq!(extract_rom(gzip), Err(ArchiveError::Corrupted));
+
+        let mut zip = ZIP.to_vec();
+        let data_pos = zip.windows(8).position(|name| name == b"Game.NES").unwrap() + 8;
+        zip[data_pos] ^= 0x01;
+        assert_eq!(extract_rom(zip), Err(ArchiveError::Corrupted));
+    }
+
+    #[test]
+    fn zip_without_rom() {
+        // Only the names in the central directory count
+        let mut zip = ZIP.to_vec();
+        let name_pos = zip
+            .windows(8)
+            .rposition(|name| name == b"Game.NES")
+            .unwrap();
+        zip[name_pos..(name_pos + 8)].copy_from_slice(b"Game.TXT");
+        assert_eq!(extract_rom(zip), Err(ArchiveError::NoRom));
+    }
+}
@@ -0,0 +1,128 @@
+// https://www.rfc-editor.org/rfc/rfc3174
+
+/// CRC-32 of all parts concatenated, as used by ZIP, PNG and the BPS patch format
+pub fn crc32(parts: &[&[u8]]) -> u32 {
+    !parts.iter().copied().flatten().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u32), |crc, _| {
+            if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// SHA-1 of all parts concatenated, without copying them into one buffer first
+pub fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let total_len: usize = parts.iter().map(|part| part.len()).sum();
+
+    // The message is padded with a single 1 bit, zeros and its length in bits
+    let mut padding = vec![0x80];
+    padding.resize(((total_len + 8) | 63) + 1 - total_len, 0);
+    let padding_len = padding.len();
+    padding[(padding_len - 8)..].copy_from_slice(&((total_len as u64) * 8).to_be_bytes());
+
+    let mut block = [0; 64];
+    let mut block_len = 0;
+    for &byte in parts.iter().copied().flatten().chain(padding.iter()) {
+        block[block_len] = byte;
+        block_len += 1;
+        if block_len == block.len() {
+            process_block(&mut state, &block);
+            block_len = 0;
+        }
+    }
+
+    let mut hash = [0; 20];
+    for (chunk, word) in hash.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+fn process_block(state: &mut [u32; 5], block: &[u8; 64]) {
+    let mut w = [0u32; 80];
+    for (i, chunk) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+    for (i, &word) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5A827999),
+            20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+            _ => (b ^ c ^ d, 0xCA62C1D6),
+        };
+
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(word);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:0>2x}")).collect()
+    }
+
+    #[test]
+    fn sha1_known_answers() {
+        assert_eq!(hex(&sha1(&[])), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(&[b""])),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            hex(&sha1(&[b"abc"])),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+
+        // 56 bytes, so the padding spills into a second block
+        let message = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        let expected = "84983e441c3bd26ebaae4aa1f95129e5e54670f1";
+        assert_eq!(hex(&sha1(&[message])), expected);
+
+        // Parts split across a block boundary hash like the whole message
+        let long: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let whole = sha1(&[&long]);
+        for split in [1, 63, 64, 65, 128] {
+            assert_eq!(sha1(&[&long[..split], &[], &long[split..]]), whole);
+        }
+        assert_eq!(sha1(&[&message[..3], &message[3..]]), sha1(&[message]));
+    }
+
+    #[test]
+    fn crc32_known_answers() {
+        assert_eq!(crc32(&[]), 0);
+        assert_eq!(crc32(&[b""]), 0);
+        assert_eq!(crc32(&[b"abc"]), 0x352441C2);
+        assert_eq!(crc32(&[b"123456789"]), 0xCBF43926);
+
+        let long: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let whole = crc32(&[&long]);
+        for split in [1, 63, 64, 65, 128] {
+            assert_eq!(crc32(&[&long[..split], &[], &long[split..]]), whole);
+        }
+    }
+}
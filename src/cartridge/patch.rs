@@ -0,0 +1,311 @@
+// Soft patching of ROM images, the original file is never modified
+
+use super::hash::crc32;
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    #[inline]
+    const fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(count)?)?;
+        self.pos += count;
+        Some(bytes)
+    }
+
+    #[inline]
+    fn read_byte(&mut self) -> Option<u8> {
+        self.read_bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn read_be(&mut self, count: usize) -> Option<usize> {
+        let bytes = self.read_bytes(count)?;
+        Some(bytes.iter().fold(0, |acc, &b| (acc << 8) | (b as usize)))
+    }
+
+    /// https://www.romhacking.net/documents/746/
+    fn read_varint(&mut self) -> Option<usize> {
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.read_byte()?;
+            data = data.checked_add(((x & 0x7F) as usize).checked_mul(shift)?)?;
+            if (x & 0x80) != 0 {
+                break;
+            }
+            shift = shift.checked_shl(7)?;
+            data = data.checked_add(shift)?;
+        }
+        Some(data)
+    }
+}
+
+// https://zerosoft.zophar.net/ips.php
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Option<Vec<u8>> {
+    const EOF_MARKER: usize = 0x454F46; // "EOF"
+
+    let mut reader = PatchReader::new(&patch[5..]);
+    let mut target = rom.to_vec();
+
+    loop {
+        let offset = reader.read_be(3)?;
+        if offset == EOF_MARKER {
+            break;
+        }
+
+        let size = reader.read_be(2)?;
+        if size == 0 {
+            // RLE record
+            let count = reader.read_be(2)?;
+            let value = reader.read_byte()?;
+            if target.len() < offset + count {
+                target.resize(offset + count, 0);
+            }
+            target[offset..(offset + count)].fill(value);
+        } else {
+            let data = reader.read_bytes(size)?;
+            if target.len() < offset + size {
+                target.resize(offset + size, 0);
+            }
+            target[offset..(offset + size)].copy_from_slice(data);
+        }
+    }
+
+    // Optional truncation extension
+    if let Some(len) = reader.read_be(3) {
+        target.truncate(len);
+    }
+
+    Some(target)
+}
+
+// https://www.romhacking.net/documents/746/
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Option<Vec<u8>> {
+    const FOOTER_SIZE: usize = 12;
+
+    if patch.len() < 4 + FOOTER_SIZE {
+        return None;
+    }
+
+    let footer = &patch[(patch.len() - FOOTER_SIZE)..];
+    let source_crc = u32::from_le_bytes(footer[0..4].try_into().unwrap());
+    let target_crc = u32::from_le_bytes(footer[4..8].try_into().unwrap());
+    let patch_crc = u32::from_le_bytes(footer[8..12].try_into().unwrap());
+
+    if crc32(&[&patch[..(patch.len() - 4)]]) != patch_crc {
+        return None;
+    }
+    if crc32(&[rom]) != source_crc {
+        return None;
+    }
+
+    let actions = &patch[4..(patch.len() - FOOTER_SIZE)];
+    let mut reader = PatchReader::new(actions);
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    let metadata_size = reader.read_varint()?;
+    reader.read_bytes(metadata_size)?;
+
+    if source_size != rom.len() {
+        return None;
+    }
+
+    // The target size comes from the patch, only trust it as far as a plausible ROM size
+    let mut target: Vec<u8> = Vec::with_capacity(target_size.min(rom.len() * 16));
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+
+    fn apply_relative(offset: usize, data: usize) -> Option<usize> {
+        let delta = data >> 1;
+        if (data & 1) != 0 {
+            offset.checked_sub(delta)
+        } else {
+            offset.checked_add(delta)
+        }
+    }
+
+    while reader.pos < actions.len() {
+        let data = reader.read_varint()?;
+        let command = data & 0x03;
+        let length = (data >> 2) + 1;
+        if target.len().checked_add(length)? > target_size {
+            return None;
+        }
+
+        match command {
+            0 => {
+                // Source read
+                let start = target.len();
+                target.extend_from_slice(rom.get(start..start.checked_add(length)?)?);
+            }
+            1 => {
+                // Target read
+                target.extend_from_slice(reader.read_bytes(length)?);
+            }
+            2 => {
+                // Source copy
+                source_offset = apply_relative(source_offset, reader.read_varint()?)?;
+                target
+                    .extend_from_slice(rom.get(source_offset..source_offset.checked_add(length)?)?);
+                source_offset += length;
+            }
+            3 => {
+                // Target copy, may overlap the data being written
+                target_offset = apply_relative(target_offset, reader.read_varint()?)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_offset)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if (target.len() != target_size) || (crc32(&[&target]) != target_crc) {
+        return None;
+    }
+
+    Some(target)
+}
+
+/// Applies an IPS or BPS patch, the format is detected from the file header
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Option<Vec<u8>> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = b"ABCDEFGH";
+
+    fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | x);
+                break;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    fn write_action(out: &mut Vec<u8>, command: usize, length: usize) {
+        write_varint(out, ((length - 1) << 2) | command);
+    }
+
+    /// Relative offsets store the sign in the lowest bit
+    fn write_offset(out: &mut Vec<u8>, delta: isize) {
+        write_varint(out, (delta.unsigned_abs() << 1) | ((delta < 0) as usize));
+    }
+
+    fn bps(source: &[u8], target: &[u8], target_size: usize, actions: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, target_size);
+        write_varint(&mut patch, 0);
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32(&[source]).to_le_bytes());
+        patch.extend_from_slice(&crc32(&[target]).to_le_bytes());
+        patch.extend_from_slice(&crc32(&[&patch]).to_le_bytes());
+        patch
+    }
+
+    /// Uses every action to build `ABCDxyGHGHGH` from `ROM`
+    fn all_actions() -> (Vec<u8>, Vec<u8>) {
+        const TARGET: &[u8] = b"ABCDxyGHGHGH";
+
+        let mut actions = Vec::new();
+        // Source read of ABCD
+        write_action(&mut actions, 0, 4);
+        // Target read of xy
+        write_action(&mut actions, 1, 2);
+        actions.extend_from_slice(b"xy");
+        // Source copy of GH
+        write_action(&mut actions, 2, 2);
+        write_offset(&mut actions, 6);
+        // Target copy of GH, overlapping the bytes it writes
+        write_action(&mut actions, 3, 4);
+        write_offset(&mut actions, 6);
+
+        (TARGET.to_vec(), bps(ROM, TARGET, TARGET.len(), &actions))
+    }
+
+    #[test]
+    fn ips_records() {
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at offset 1
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, b'x', b'y']);
+        // RLE record of 3 bytes at offset 6, past the end of the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, b'z']);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply_patch(ROM, &patch), Some(b"AxyDEFzzz".to_vec()));
+
+        // Truncation extension
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply_patch(ROM, &patch), Some(b"AxyD".to_vec()));
+    }
+
+    #[test]
+    fn ips_truncated() {
+        let patch = b"PATCH\x00\x00\x01\x00\x02x";
+        assert_eq!(apply_patch(ROM, patch), None);
+
+        // No end marker
+        let patch = b"PATCH\x00\x00\x01\x00\x01x";
+        assert_eq!(apply_patch(ROM, patch), None);
+    }
+
+    #[test]
+    fn bps_actions() {
+        let (target, patch) = all_actions();
+        assert_eq!(apply_patch(ROM, &patch), Some(target));
+    }
+
+    #[test]
+    fn bps_crc_mismatch() {
+        let (_, patch) = all_actions();
+        assert_eq!(apply_patch(b"ABCDEFGX", &patch), None);
+
+        let (_, mut patch) = all_actions();
+        let target_crc = patch.len() - 8;
+        patch[target_crc] ^= 0x01;
+        let patch_crc = patch.len() - 4;
+        let crc = crc32(&[&patch[..patch_crc]]);
+        patch[patch_crc..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(apply_patch(ROM, &patch), None);
+    }
+
+    #[test]
+    fn bps_oversized_target() {
+        let (target, _) = all_actions();
+        let mut actions = Vec::new();
+        write_action(&mut actions, 0, 4);
+        let patch = bps(ROM, &target, usize::MAX >> 2, &actions);
+        assert_eq!(apply_patch(ROM, &patch), None);
+
+        // A target copy can't grow past the target size
+        let mut actions = Vec::new();
+        write_action(&mut actions, 0, 1);
+        write_action(&mut actions, 3, 1 << 20);
+        write_offset(&mut actions, 0);
+        let patch = bps(ROM, &target, 16, &actions);
+        assert_eq!(apply_patch(ROM, &patch), None);
+    }
+}
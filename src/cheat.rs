@@ -0,0 +1,137 @@
+// Game Genie codes patch single bytes of PRG ROM as the CPU reads them.
+// https://www.nesdev.org/wiki/Game_Genie
+
+/// Every letter stands for one nibble, in the order of its value
+const LETTERS: [u8; 16] = *b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatError {
+    /// Codes are either 6 or 8 letters long
+    InvalidLength(usize),
+    InvalidLetter(char),
+}
+
+impl std::fmt::Display for CheatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidLength(len) => {
+                write!(f, "Game Genie codes have 6 or 8 letters, not {len}")
+            }
+            Self::InvalidLetter(c) => write!(f, "`{c}` is not a Game Genie letter"),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+/// A decoded Game Genie code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cheat {
+    /// Always in PRG ROM space, $8000-$FFFF
+    pub addr: u16,
+    pub value: u8,
+    /// 8 letter codes only replace the value if the original value matches,
+    /// so they don't break other banks mapped to the same address
+    pub compare: Option<u8>,
+}
+
+impl Cheat {
+    pub fn decode(code: &str) -> Result<Self, CheatError> {
+        let code = code.trim();
+        if !matches!(code.len(), 6 | 8) {
+            return Err(CheatError::InvalidLength(code.chars().count()));
+        }
+
+        let mut n = [0u16; 8];
+        for (nibble, c) in n.iter_mut().zip(code.chars()) {
+            let upper = c.to_ascii_uppercase();
+            let value = LETTERS
+                .iter()
+                .position(|&letter| (letter as char) == upper)
+                .ok_or(CheatError::InvalidLetter(c))?;
+            *nibble = value as u16;
+        }
+
+        let addr = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8)
+            | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4)
+            | ((n[1] & 8) << 4)
+            | (n[4] & 7)
+            | (n[3] & 8);
+        let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+
+        let cheat = if code.len() == 6 {
+            Self {
+                addr,
+                value: (value | (n[5] & 8)) as u8,
+                compare: None,
+            }
+        } else {
+            Self {
+                addr,
+                value: (value | (n[7] & 8)) as u8,
+                compare: Some(
+                    (((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8)) as u8,
+                ),
+            }
+        };
+        Ok(cheat)
+    }
+
+    /// Value the CPU sees when reading `original` from `addr`
+    #[inline]
+    pub fn apply(&self, addr: u16, original: u8) -> u8 {
+        if (addr == self.addr) && self.compare.is_none_or(|compare| compare == original) {
+            self.value
+        } else {
+            original
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Examples from the nesdev wiki
+    #[test]
+    fn decode_six_letters() {
+        let cheat = Cheat::decode("GOSSIP").unwrap();
+        assert_eq!(
+            cheat,
+            Cheat {
+                addr: 0xD1DD,
+                value: 0x14,
+                compare: None,
+            }
+        );
+
+        assert_eq!(cheat.apply(0xD1DD, 0x00), 0x14);
+        assert_eq!(cheat.apply(0xD1DE, 0x00), 0x00);
+    }
+
+    #[test]
+    fn decode_eight_letters() {
+        let cheat = Cheat::decode("ZEXPYGLA").unwrap();
+        assert_eq!(
+            cheat,
+            Cheat {
+                addr: 0x94A7,
+                value: 0x02,
+                compare: Some(0x03),
+            }
+        );
+
+        assert_eq!(cheat.apply(0x94A7, 0x03), 0x02);
+        assert_eq!(cheat.apply(0x94A7, 0x04), 0x04);
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(Cheat::decode("GOSSI"), Err(CheatError::InvalidLength(5)));
+        assert_eq!(Cheat::decode("GOSSIB"), Err(CheatError::InvalidLetter('B')));
+        assert_eq!(Cheat::decode("gossip"), Cheat::decode("GOSSIP"));
+    }
+}
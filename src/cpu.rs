@@ -1,9 +1,14 @@
 mod addressing_mode;
+mod disassembler;
 mod instruction;
 
+use crate::debug::CpuRegisters;
+use crate::savestate::{StateError, StateReader, StateWriter};
 use crate::system::CpuBus;
 use bitflags::bitflags;
 
+pub use disassembler::disassemble;
+
 bitflags! {
     struct StatusFlags : u8 {
         /// Carry
@@ -30,6 +35,259 @@ const IRQ_VECTOR: u16 = 0xFFFE;
 const NMI_VECTOR: u16 = 0xFFFA;
 const RESET_VECTOR: u16 = 0xFFFC;
 
+// https://www.masswerk.at/6502/6502_instruction_set.html
+macro_rules! opcode_table {
+    ($callback:ident) => {
+        $callback!(
+            0x00 => Brk<Implicit>,
+            0x01 => Ora<OffsetXIndirect>,
+            0x02 => Jam<Implicit>,
+            0x03 => Slo<OffsetXIndirect>,
+            0x04 => Nop<ZeroPage>,
+            0x05 => Ora<ZeroPage>,
+            0x06 => Asl<ZeroPage>,
+            0x07 => Slo<ZeroPage>,
+            0x08 => Php<Implicit>,
+            0x09 => Ora<Immediate>,
+            0x0A => Asl<Accumulator>,
+            0x0C => Nop<Absolute>,
+            0x0D => Ora<Absolute>,
+            0x0E => Asl<Absolute>,
+            0x0F => Slo<Absolute>,
+            0x10 => Bpl<Relative>,
+            0x11 => Ora<IndirectOffsetY>,
+            0x12 => Jam<Implicit>,
+            0x13 => Slo<IndirectOffsetY>,
+            0x14 => Nop<ZeroPageOffsetX>,
+            0x15 => Ora<ZeroPageOffsetX>,
+            0x16 => Asl<ZeroPageOffsetX>,
+            0x17 => Slo<ZeroPageOffsetX>,
+            0x18 => Clc<Implicit>,
+            0x19 => Ora<AbsoluteOffsetY>,
+            0x1A => Nop<Implicit>,
+            0x1B => Slo<AbsoluteOffsetY>,
+            0x1C => Nop<AbsoluteOffsetX>,
+            0x1D => Ora<AbsoluteOffsetX>,
+            0x1E => Asl<AbsoluteOffsetX>,
+            0x1F => Slo<AbsoluteOffsetX>,
+            0x20 => Jsr<Absolute>,
+            0x21 => And<OffsetXIndirect>,
+            0x22 => Jam<Implicit>,
+            0x23 => Rla<OffsetXIndirect>,
+            0x24 => Bit<ZeroPage>,
+            0x25 => And<ZeroPage>,
+            0x26 => Rol<ZeroPage>,
+            0x27 => Rla<ZeroPage>,
+            0x28 => Plp<Implicit>,
+            0x29 => And<Immediate>,
+            0x2A => Rol<Accumulator>,
+            0x2C => Bit<Absolute>,
+            0x2D => And<Absolute>,
+            0x2E => Rol<Absolute>,
+            0x2F => Rla<Absolute>,
+            0x30 => Bmi<Relative>,
+            0x31 => And<IndirectOffsetY>,
+            0x32 => Jam<Implicit>,
+            0x33 => Rla<IndirectOffsetY>,
+            0x34 => Nop<ZeroPageOffsetX>,
+            0x35 => And<ZeroPageOffsetX>,
+            0x36 => Rol<ZeroPageOffsetX>,
+            0x37 => Rla<ZeroPageOffsetX>,
+            0x38 => Sec<Implicit>,
+            0x39 => And<AbsoluteOffsetY>,
+            0x3A => Nop<Implicit>,
+            0x3B => Rla<AbsoluteOffsetY>,
+            0x3C => Nop<AbsoluteOffsetX>,
+            0x3D => And<AbsoluteOffsetX>,
+            0x3E => Rol<AbsoluteOffsetX>,
+            0x3F => Rla<AbsoluteOffsetX>,
+            0x40 => Rti<Implicit>,
+            0x41 => Eor<OffsetXIndirect>,
+            0x42 => Jam<Implicit>,
+            0x43 => Sre<OffsetXIndirect>,
+            0x44 => Nop<ZeroPage>,
+            0x45 => Eor<ZeroPage>,
+            0x46 => Lsr<ZeroPage>,
+            0x47 => Sre<ZeroPage>,
+            0x48 => Pha<Implicit>,
+            0x49 => Eor<Immediate>,
+            0x4A => Lsr<Accumulator>,
+            0x4C => Jmp<Absolute>,
+            0x4D => Eor<Absolute>,
+            0x4E => Lsr<Absolute>,
+            0x4F => Sre<Absolute>,
+            0x50 => Bvc<Relative>,
+            0x51 => Eor<IndirectOffsetY>,
+            0x52 => Jam<Implicit>,
+            0x53 => Sre<IndirectOffsetY>,
+            0x54 => Nop<ZeroPageOffsetX>,
+            0x55 => Eor<ZeroPageOffsetX>,
+            0x56 => Lsr<ZeroPageOffsetX>,
+            0x57 => Sre<ZeroPageOffsetX>,
+            0x58 => Cli<Implicit>,
+            0x59 => Eor<AbsoluteOffsetY>,
+            0x5A => Nop<Implicit>,
+            0x5B => Sre<AbsoluteOffsetY>,
+            0x5C => Nop<AbsoluteOffsetX>,
+            0x5D => Eor<AbsoluteOffsetX>,
+            0x5E => Lsr<AbsoluteOffsetX>,
+            0x5F => Sre<AbsoluteOffsetX>,
+            0x60 => Rts<Implicit>,
+            0x61 => Adc<OffsetXIndirect>,
+            0x62 => Jam<Implicit>,
+            0x63 => Rra<OffsetXIndirect>,
+            0x64 => Nop<ZeroPage>,
+            0x65 => Adc<ZeroPage>,
+            0x66 => Ror<ZeroPage>,
+            0x67 => Rra<ZeroPage>,
+            0x68 => Pla<Implicit>,
+            0x69 => Adc<Immediate>,
+            0x6A => Ror<Accumulator>,
+            0x6C => Jmp<Indirect>,
+            0x6D => Adc<Absolute>,
+            0x6E => Ror<Absolute>,
+            0x6F => Rra<Absolute>,
+            0x70 => Bvs<Relative>,
+            0x71 => Adc<IndirectOffsetY>,
+            0x72 => Jam<Implicit>,
+            0x73 => Rra<IndirectOffsetY>,
+            0x74 => Nop<ZeroPageOffsetX>,
+            0x75 => Adc<ZeroPageOffsetX>,
+            0x76 => Ror<ZeroPageOffsetX>,
+            0x77 => Rra<ZeroPageOffsetX>,
+            0x78 => Sei<Implicit>,
+            0x79 => Adc<AbsoluteOffsetY>,
+            0x7A => Nop<Implicit>,
+            0x7B => Rra<AbsoluteOffsetY>,
+            0x7C => Nop<AbsoluteOffsetX>,
+            0x7D => Adc<AbsoluteOffsetX>,
+            0x7E => Ror<AbsoluteOffsetX>,
+            0x7F => Rra<AbsoluteOffsetX>,
+            0x80 => Nop<Immediate>,
+            0x81 => Sta<OffsetXIndirect>,
+            0x82 => Nop<Immediate>,
+            0x83 => Sax<OffsetXIndirect>,
+            0x84 => Sty<ZeroPage>,
+            0x85 => Sta<ZeroPage>,
+            0x86 => Stx<ZeroPage>,
+            0x87 => Sax<ZeroPage>,
+            0x88 => Dey<Implicit>,
+            0x89 => Nop<Immediate>,
+            0x8A => Txa<Implicit>,
+            0x8C => Sty<Absolute>,
+            0x8D => Sta<Absolute>,
+            0x8E => Stx<Absolute>,
+            0x8F => Sax<Absolute>,
+            0x90 => Bcc<Relative>,
+            0x91 => Sta<IndirectOffsetY>,
+            0x92 => Jam<Implicit>,
+            0x94 => Sty<ZeroPageOffsetX>,
+            0x95 => Sta<ZeroPageOffsetX>,
+            0x96 => Stx<ZeroPageOffsetY>,
+            0x97 => Sax<ZeroPageOffsetY>,
+            0x98 => Tya<Implicit>,
+            0x99 => Sta<AbsoluteOffsetY>,
+            0x9A => Txs<Implicit>,
+            0x9D => Sta<AbsoluteOffsetX>,
+            0xA0 => Ldy<Immediate>,
+            0xA1 => Lda<OffsetXIndirect>,
+            0xA2 => Ldx<Immediate>,
+            0xA3 => Lax<OffsetXIndirect>,
+            0xA4 => Ldy<ZeroPage>,
+            0xA5 => Lda<ZeroPage>,
+            0xA6 => Ldx<ZeroPage>,
+            0xA7 => Lax<ZeroPage>,
+            0xA8 => Tay<Implicit>,
+            0xA9 => Lda<Immediate>,
+            0xAA => Tax<Implicit>,
+            0xAC => Ldy<Absolute>,
+            0xAD => Lda<Absolute>,
+            0xAE => Ldx<Absolute>,
+            0xAF => Lax<Absolute>,
+            0xB0 => Bcs<Relative>,
+            0xB1 => Lda<IndirectOffsetY>,
+            0xB2 => Jam<Implicit>,
+            0xB3 => Lax<IndirectOffsetY>,
+            0xB4 => Ldy<ZeroPageOffsetX>,
+            0xB5 => Lda<ZeroPageOffsetX>,
+            0xB6 => Ldx<ZeroPageOffsetY>,
+            0xB7 => Lax<ZeroPageOffsetY>,
+            0xB8 => Clv<Implicit>,
+            0xB9 => Lda<AbsoluteOffsetY>,
+            0xBA => Tsx<Implicit>,
+            0xBC => Ldy<AbsoluteOffsetX>,
+            0xBD => Lda<AbsoluteOffsetX>,
+            0xBE => Ldx<AbsoluteOffsetY>,
+            0xBF => Lax<AbsoluteOffsetY>,
+            0xC0 => Cpy<Immediate>,
+            0xC1 => Cmp<OffsetXIndirect>,
+            0xC2 => Nop<Immediate>,
+            0xC3 => Dcp<OffsetXIndirect>,
+            0xC4 => Cpy<ZeroPage>,
+            0xC5 => Cmp<ZeroPage>,
+            0xC6 => Dec<ZeroPage>,
+            0xC7 => Dcp<ZeroPage>,
+            0xC8 => Iny<Implicit>,
+            0xC9 => Cmp<Immediate>,
+            0xCA => Dex<Implicit>,
+            0xCC => Cpy<Absolute>,
+            0xCD => Cmp<Absolute>,
+            0xCE => Dec<Absolute>,
+            0xCF => Dcp<Absolute>,
+            0xD0 => Bne<Relative>,
+            0xD1 => Cmp<IndirectOffsetY>,
+            0xD2 => Jam<Implicit>,
+            0xD3 => Dcp<IndirectOffsetY>,
+            0xD4 => Nop<ZeroPageOffsetX>,
+            0xD5 => Cmp<ZeroPageOffsetX>,
+            0xD6 => Dec<ZeroPageOffsetX>,
+            0xD7 => Dcp<ZeroPageOffsetX>,
+            0xD8 => Cld<Implicit>,
+            0xD9 => Cmp<AbsoluteOffsetY>,
+            0xDA => Nop<Implicit>,
+            0xDB => Dcp<AbsoluteOffsetY>,
+            0xDC => Nop<AbsoluteOffsetX>,
+            0xDD => Cmp<AbsoluteOffsetX>,
+            0xDE => Dec<AbsoluteOffsetX>,
+            0xDF => Dcp<AbsoluteOffsetX>,
+            0xE0 => Cpx<Immediate>,
+            0xE1 => Sbc<OffsetXIndirect>,
+            0xE2 => Nop<Immediate>,
+            0xE3 => Isb<OffsetXIndirect>,
+            0xE4 => Cpx<ZeroPage>,
+            0xE5 => Sbc<ZeroPage>,
+            0xE6 => Inc<ZeroPage>,
+            0xE7 => Isb<ZeroPage>,
+            0xE8 => Inx<Implicit>,
+            0xE9 => Sbc<Immediate>,
+            0xEA => Nop<Implicit>,
+            0xEB => Sbc<Immediate>,
+            0xEC => Cpx<Absolute>,
+            0xED => Sbc<Absolute>,
+            0xEE => Inc<Absolute>,
+            0xEF => Isb<Absolute>,
+            0xF0 => Beq<Relative>,
+            0xF1 => Sbc<IndirectOffsetY>,
+            0xF2 => Jam<Implicit>,
+            0xF3 => Isb<IndirectOffsetY>,
+            0xF4 => Nop<ZeroPageOffsetX>,
+            0xF5 => Sbc<ZeroPageOffsetX>,
+            0xF6 => Inc<ZeroPageOffsetX>,
+            0xF7 => Isb<ZeroPageOffsetX>,
+            0xF8 => Sed<Implicit>,
+            0xF9 => Sbc<AbsoluteOffsetY>,
+            0xFA => Nop<Implicit>,
+            0xFB => Isb<AbsoluteOffsetY>,
+            0xFC => Nop<AbsoluteOffsetX>,
+            0xFD => Sbc<AbsoluteOffsetX>,
+            0xFE => Inc<AbsoluteOffsetX>,
+            0xFF => Isb<AbsoluteOffsetX>,
+        )
+    };
+}
+
+pub(crate) use opcode_table;
+
 pub struct Cpu {
     /// Accumulator
     a: u8,
@@ -46,8 +304,25 @@ pub struct Cpu {
     pc: u16,
 
     cycle_counter: u8,
+    /// Interrupts are polled once `cycle_counter` reaches this value, 0 disables polling
+    poll_cycle: u8,
+    /// IRQ line asserted since the last poll
+    irq_line: bool,
+    /// NMI edge detected since the last poll
+    nmi_line: bool,
+    /// I flag seen by the next poll, for instructions that change it too late to take effect
+    delayed_i_flag: Option<bool>,
     irq_pending: bool,
     nmi_pending: bool,
+    /// Set while BRK or an IRQ sequence hasn't fetched its vector yet,
+    /// an NMI detected until then takes the sequence over
+    hijackable: bool,
+    /// Set by the JAM instructions, only a reset recovers the CPU
+    jammed: bool,
+    /// Total number of CPU cycles including the ones spent halted, only used for tracing
+    cycles: u64,
+    /// Number of instructions executed, lets the debugger find instruction boundaries
+    instructions: u64,
 }
 
 impl Cpu {
@@ -63,8 +338,17 @@ impl Cpu {
             pc: bus.read_16(RESET_VECTOR),
 
             cycle_counter: 0,
+            poll_cycle: 0,
+            irq_line: false,
+            nmi_line: false,
+            delayed_i_flag: None,
             irq_pending: false,
             nmi_pending: false,
+            hijackable: false,
+            jammed: false,
+            // The power-up sequence takes 7 cycles
+            cycles: 7,
+            instructions: 0,
         }
     }
 
@@ -72,18 +356,119 @@ impl Cpu {
         // https://www.nesdev.org/wiki/CPU_power_up_state#After_reset
         self.s = self.s.wrapping_sub(3);
         self.p.insert(StatusFlags::I);
+        self.jammed = false;
 
         self.pc = bus.read_16(RESET_VECTOR);
     }
 
-    pub fn signal_irq(&mut self) {
-        if !self.p.contains(StatusFlags::I) {
-            self.irq_pending = true;
+    #[inline]
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn registers(&self) -> CpuRegisters {
+        CpuRegisters {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            s: self.s,
+            p: self.p.bits() | U_FLAG,
+            pc: self.pc,
         }
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.a);
+        state.write_u8(self.x);
+        state.write_u8(self.y);
+        state.write_u8(self.s);
+        state.write_u8(self.p.bits());
+        state.write_u16(self.pc);
+        state.write_u8(self.cycle_counter);
+        state.write_u8(self.poll_cycle);
+        state.write_bool(self.irq_line);
+        state.write_bool(self.nmi_line);
+        state.write_bool(self.delayed_i_flag.is_some());
+        state.write_bool(self.delayed_i_flag.unwrap_or(false));
+        state.write_bool(self.irq_pending);
+        state.write_bool(self.nmi_pending);
+        state.write_bool(self.hijackable);
+        state.write_bool(self.jammed);
+        state.write_u64(self.cycles);
+        state.write_u64(self.instructions);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.a = state.read_u8()?;
+        self.x = state.read_u8()?;
+        self.y = state.read_u8()?;
+        self.s = state.read_u8()?;
+        self.p = StatusFlags::from_bits_retain(state.read_u8()?);
+        self.pc = state.read_u16()?;
+        self.cycle_counter = state.read_u8()?;
+        self.poll_cycle = state.read_u8()?;
+        self.irq_line = state.read_bool()?;
+        self.nmi_line = state.read_bool()?;
+        let has_delayed_i_flag = state.read_bool()?;
+        let delayed_i_flag = state.read_bool()?;
+        self.delayed_i_flag = has_delayed_i_flag.then_some(delayed_i_flag);
+        self.irq_pending = state.read_bool()?;
+        self.nmi_pending = state.read_bool()?;
+        self.hijackable = state.read_bool()?;
+        self.jammed = state.read_bool()?;
+        self.cycles = state.read_u64()?;
+        self.instructions = state.read_u64()?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    /// `true` if the next cycle starts a new instruction or interrupt sequence
+    #[inline]
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.cycle_counter == 0
+    }
+
+    #[inline]
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
+
+    #[inline]
+    pub fn count_cycle(&mut self) {
+        self.cycles += 1;
+    }
+
+    pub fn signal_irq(&mut self) {
+        self.irq_line = true;
+    }
+
     pub fn signal_nmi(&mut self) {
-        self.nmi_pending = true;
+        self.nmi_line = true;
+    }
+
+    /// Withdraws an NMI that was signaled but not yet polled
+    pub fn cancel_nmi(&mut self) {
+        self.nmi_line = false;
+    }
+
+    // https://www.nesdev.org/wiki/CPU_interrupts#Detailed_interrupt_behavior
+    fn poll_interrupts(&mut self) {
+        let i_flag = self
+            .delayed_i_flag
+            .take()
+            .unwrap_or(self.p.contains(StatusFlags::I));
+
+        self.irq_pending = self.irq_line && !i_flag;
+        self.irq_line = false;
+
+        if self.nmi_line {
+            self.nmi_pending = true;
+            self.nmi_line = false;
+        }
     }
 
     fn push(&mut self, bus: &mut CpuBus<'_>, data: u8) {
@@ -111,8 +496,18 @@ impl Cpu {
     }
 
     pub fn clock(&mut self, bus: &mut CpuBus<'_>) {
+        // BRK and IRQ fetch their vector on the last two of their 7 cycles
+        const HIJACK_CYCLES_LEFT: u8 = 3;
+
         if self.cycle_counter == 0 {
-            self.cycle_counter = if self.nmi_pending {
+            // The interrupt sequences and stalls don't poll, only instructions do
+            self.poll_cycle = 0;
+            self.hijackable = false;
+
+            self.cycle_counter = if self.jammed {
+                // Interrupts are ignored as well
+                1
+            } else if self.nmi_pending {
                 self.nmi_pending = false;
 
                 self.push_16(bus, self.pc);
@@ -132,17 +527,32 @@ impl Cpu {
 
                 self.p.insert(StatusFlags::I);
                 self.pc = bus.read_16(IRQ_VECTOR);
+                self.hijackable = true;
 
                 7
+            } else if bus.debugger.check_breakpoint(self.pc) {
+                // Stall until the debugger resumes execution
+                1
             } else {
+                // Polling happens on the second to last cycle of an instruction
+                self.poll_cycle = 1;
+
+                let opcode_pc = self.pc;
                 let opcode = bus.read(self.pc);
                 self.pc = self.pc.wrapping_add(1);
 
                 macro_rules! match_instr {
                     ($($opcode:literal => $instr:ty),+ $(,)?) => {
                         match opcode {
-                            $($opcode => instruction::execute::<$instr>(self, bus),)+
-                            _ => panic!("illegal opcode 0x{opcode:0>2X}"),
+                            $($opcode => {
+                                self.instructions += 1;
+                                instruction::execute::<$instr>(self, bus)
+                            })+
+                            _ => {
+                                bus.debugger.signal_illegal_opcode(opcode_pc, opcode);
+                                self.pc = opcode_pc;
+                                1
+                            }
                         }
                     };
                 }
@@ -150,283 +560,208 @@ impl Cpu {
                 use addressing_mode::*;
                 use instruction::*;
 
-                // https://www.masswerk.at/6502/6502_instruction_set.html
-                match_instr!(
-                    0x00 => Brk<Implicit>,
-                    0x01 => Ora<OffsetXIndirect>,
-                    // 0x02
-                    0x03 => Slo<OffsetXIndirect>,
-                    0x04 => Nop<ZeroPage>,
-                    0x05 => Ora<ZeroPage>,
-                    0x06 => Asl<ZeroPage>,
-                    0x07 => Slo<ZeroPage>,
-                    0x08 => Php<Implicit>,
-                    0x09 => Ora<Immediate>,
-                    0x0A => Asl<Accumulator>,
-                    // 0x0B
-                    0x0C => Nop<Absolute>,
-                    0x0D => Ora<Absolute>,
-                    0x0E => Asl<Absolute>,
-                    0x0F => Slo<Absolute>,
-                    // --------------------------------
-                    0x10 => Bpl<Relative>,
-                    0x11 => Ora<IndirectOffsetY>,
-                    // 0x12
-                    0x13 => Slo<IndirectOffsetY>,
-                    0x14 => Nop<ZeroPageOffsetX>,
-                    0x15 => Ora<ZeroPageOffsetX>,
-                    0x16 => Asl<ZeroPageOffsetX>,
-                    0x17 => Slo<ZeroPageOffsetX>,
-                    0x18 => Clc<Implicit>,
-                    0x19 => Ora<AbsoluteOffsetY>,
-                    0x1A => Nop<Implicit>,
-                    0x1B => Slo<AbsoluteOffsetY>,
-                    0x1C => Nop<AbsoluteOffsetX>,
-                    0x1D => Ora<AbsoluteOffsetX>,
-                    0x1E => Asl<AbsoluteOffsetX>,
-                    0x1F => Slo<AbsoluteOffsetX>,
-                    // --------------------------------
-                    0x20 => Jsr<Absolute>,
-                    0x21 => And<OffsetXIndirect>,
-                    // 0x22
-                    0x23 => Rla<OffsetXIndirect>,
-                    0x24 => Bit<ZeroPage>,
-                    0x25 => And<ZeroPage>,
-                    0x26 => Rol<ZeroPage>,
-                    0x27 => Rla<ZeroPage>,
-                    0x28 => Plp<Implicit>,
-                    0x29 => And<Immediate>,
-                    0x2A => Rol<Accumulator>,
-                    // 0x2B
-                    0x2C => Bit<Absolute>,
-                    0x2D => And<Absolute>,
-                    0x2E => Rol<Absolute>,
-                    0x2F => Rla<Absolute>,
-                    // --------------------------------
-                    0x30 => Bmi<Relative>,
-                    0x31 => And<IndirectOffsetY>,
-                    // 0x32
-                    0x33 => Rla<IndirectOffsetY>,
-                    0x34 => Nop<ZeroPageOffsetX>,
-                    0x35 => And<ZeroPageOffsetX>,
-                    0x36 => Rol<ZeroPageOffsetX>,
-                    0x37 => Rla<ZeroPageOffsetX>,
-                    0x38 => Sec<Implicit>,
-                    0x39 => And<AbsoluteOffsetY>,
-                    0x3A => Nop<Implicit>,
-                    0x3B => Rla<AbsoluteOffsetY>,
-                    0x3C => Nop<AbsoluteOffsetX>,
-                    0x3D => And<AbsoluteOffsetX>,
-                    0x3E => Rol<AbsoluteOffsetX>,
-                    0x3F => Rla<AbsoluteOffsetX>,
-                    // --------------------------------
-                    0x40 => Rti<Implicit>,
-                    0x41 => Eor<OffsetXIndirect>,
-                    // 0x42
-                    0x43 => Sre<OffsetXIndirect>,
-                    0x44 => Nop<ZeroPage>,
-                    0x45 => Eor<ZeroPage>,
-                    0x46 => Lsr<ZeroPage>,
-                    0x47 => Sre<ZeroPage>,
-                    0x48 => Pha<Implicit>,
-                    0x49 => Eor<Immediate>,
-                    0x4A => Lsr<Accumulator>,
-                    // 0x4B
-                    0x4C => Jmp<Absolute>,
-                    0x4D => Eor<Absolute>,
-                    0x4E => Lsr<Absolute>,
-                    0x4F => Sre<Absolute>,
-                    // --------------------------------
-                    0x50 => Bvc<Relative>,
-                    0x51 => Eor<IndirectOffsetY>,
-                    // 0x52
-                    0x53 => Sre<IndirectOffsetY>,
-                    0x54 => Nop<ZeroPageOffsetX>,
-                    0x55 => Eor<ZeroPageOffsetX>,
-                    0x56 => Lsr<ZeroPageOffsetX>,
-                    0x57 => Sre<ZeroPageOffsetX>,
-                    0x58 => Cli<Implicit>,
-                    0x59 => Eor<AbsoluteOffsetY>,
-                    0x5A => Nop<Implicit>,
-                    0x5B => Sre<AbsoluteOffsetY>,
-                    0x5C => Nop<AbsoluteOffsetX>,
-                    0x5D => Eor<AbsoluteOffsetX>,
-                    0x5E => Lsr<AbsoluteOffsetX>,
-                    0x5F => Sre<AbsoluteOffsetX>,
-                    // --------------------------------
-                    0x60 => Rts<Implicit>,
-                    0x61 => Adc<OffsetXIndirect>,
-                    // 0x62
-                    0x63 => Rra<OffsetXIndirect>,
-                    0x64 => Nop<ZeroPage>,
-                    0x65 => Adc<ZeroPage>,
-                    0x66 => Ror<ZeroPage>,
-                    0x67 => Rra<ZeroPage>,
-                    0x68 => Pla<Implicit>,
-                    0x69 => Adc<Immediate>,
-                    0x6A => Ror<Accumulator>,
-                    // 0x6B
-                    0x6C => Jmp<Indirect>,
-                    0x6D => Adc<Absolute>,
-                    0x6E => Ror<Absolute>,
-                    0x6F => Rra<Absolute>,
-                    // --------------------------------
-                    0x70 => Bvs<Relative>,
-                    0x71 => Adc<IndirectOffsetY>,
-                    // 0x72
-                    0x73 => Rra<IndirectOffsetY>,
-                    0x74 => Nop<ZeroPageOffsetX>,
-                    0x75 => Adc<ZeroPageOffsetX>,
-                    0x76 => Ror<ZeroPageOffsetX>,
-                    0x77 => Rra<ZeroPageOffsetX>,
-                    0x78 => Sei<Implicit>,
-                    0x79 => Adc<AbsoluteOffsetY>,
-                    0x7A => Nop<Implicit>,
-                    0x7B => Rra<AbsoluteOffsetY>,
-                    0x7C => Nop<AbsoluteOffsetX>,
-                    0x7D => Adc<AbsoluteOffsetX>,
-                    0x7E => Ror<AbsoluteOffsetX>,
-                    0x7F => Rra<AbsoluteOffsetX>,
-                    // --------------------------------
-                    0x80 => Nop<Immediate>,
-                    0x81 => Sta<OffsetXIndirect>,
-                    0x82 => Nop<Immediate>,
-                    0x83 => Sax<OffsetXIndirect>,
-                    0x84 => Sty<ZeroPage>,
-                    0x85 => Sta<ZeroPage>,
-                    0x86 => Stx<ZeroPage>,
-                    0x87 => Sax<ZeroPage>,
-                    0x88 => Dey<Implicit>,
-                    0x89 => Nop<Immediate>,
-                    0x8A => Txa<Implicit>,
-                    // 0x8B
-                    0x8C => Sty<Absolute>,
-                    0x8D => Sta<Absolute>,
-                    0x8E => Stx<Absolute>,
-                    0x8F => Sax<Absolute>,
-                    // --------------------------------
-                    0x90 => Bcc<Relative>,
-                    0x91 => Sta<IndirectOffsetY>,
-                    // 0x92
-                    // 0x93
-                    0x94 => Sty<ZeroPageOffsetX>,
-                    0x95 => Sta<ZeroPageOffsetX>,
-                    0x96 => Stx<ZeroPageOffsetY>,
-                    0x97 => Sax<ZeroPageOffsetY>,
-                    0x98 => Tya<Implicit>,
-                    0x99 => Sta<AbsoluteOffsetY>,
-                    0x9A => Txs<Implicit>,
-                    // 0x9B
-                    // 0x9C
-                    0x9D => Sta<AbsoluteOffsetX>,
-                    // 0x9E
-                    // 0x9F
-                    // --------------------------------
-                    0xA0 => Ldy<Immediate>,
-                    0xA1 => Lda<OffsetXIndirect>,
-                    0xA2 => Ldx<Immediate>,
-                    0xA3 => Lax<OffsetXIndirect>,
-                    0xA4 => Ldy<ZeroPage>,
-                    0xA5 => Lda<ZeroPage>,
-                    0xA6 => Ldx<ZeroPage>,
-                    0xA7 => Lax<ZeroPage>,
-                    0xA8 => Tay<Implicit>,
-                    0xA9 => Lda<Immediate>,
-                    0xAA => Tax<Implicit>,
-                    // 0xAB
-                    0xAC => Ldy<Absolute>,
-                    0xAD => Lda<Absolute>,
-                    0xAE => Ldx<Absolute>,
-                    0xAF => Lax<Absolute>,
-                    // --------------------------------
-                    0xB0 => Bcs<Relative>,
-                    0xB1 => Lda<IndirectOffsetY>,
-                    // 0xB2
-                    0xB3 => Lax<IndirectOffsetY>,
-                    0xB4 => Ldy<ZeroPageOffsetX>,
-                    0xB5 => Lda<ZeroPageOffsetX>,
-                    0xB6 => Ldx<ZeroPageOffsetY>,
-                    0xB7 => Lax<ZeroPageOffsetY>,
-                    0xB8 => Clv<Implicit>,
-                    0xB9 => Lda<AbsoluteOffsetY>,
-                    0xBA => Tsx<Implicit>,
-                    // 0xBB
-                    0xBC => Ldy<AbsoluteOffsetX>,
-                    0xBD => Lda<AbsoluteOffsetX>,
-                    0xBE => Ldx<AbsoluteOffsetY>,
-                    0xBF => Lax<AbsoluteOffsetY>,
-                    // --------------------------------
-                    0xC0 => Cpy<Immediate>,
-                    0xC1 => Cmp<OffsetXIndirect>,
-                    0xC2 => Nop<Immediate>,
-                    0xC3 => Dcp<OffsetXIndirect>,
-                    0xC4 => Cpy<ZeroPage>,
-                    0xC5 => Cmp<ZeroPage>,
-                    0xC6 => Dec<ZeroPage>,
-                    0xC7 => Dcp<ZeroPage>,
-                    0xC8 => Iny<Implicit>,
-                    0xC9 => Cmp<Immediate>,
-                    0xCA => Dex<Implicit>,
-                    // 0xCB
-                    0xCC => Cpy<Absolute>,
-                    0xCD => Cmp<Absolute>,
-                    0xCE => Dec<Absolute>,
-                    0xCF => Dcp<Absolute>,
-                    // --------------------------------
-                    0xD0 => Bne<Relative>,
-                    0xD1 => Cmp<IndirectOffsetY>,
-                    // 0xD2
-                    0xD3 => Dcp<IndirectOffsetY>,
-                    0xD4 => Nop<ZeroPageOffsetX>,
-                    0xD5 => Cmp<ZeroPageOffsetX>,
-                    0xD6 => Dec<ZeroPageOffsetX>,
-                    0xD7 => Dcp<ZeroPageOffsetX>,
-                    0xD8 => Cld<Implicit>,
-                    0xD9 => Cmp<AbsoluteOffsetY>,
-                    0xDA => Nop<Implicit>,
-                    0xDB => Dcp<AbsoluteOffsetY>,
-                    0xDC => Nop<AbsoluteOffsetX>,
-                    0xDD => Cmp<AbsoluteOffsetX>,
-                    0xDE => Dec<AbsoluteOffsetX>,
-                    0xDF => Dcp<AbsoluteOffsetX>,
-                    // --------------------------------
-                    0xE0 => Cpx<Immediate>,
-                    0xE1 => Sbc<OffsetXIndirect>,
-                    0xE2 => Nop<Immediate>,
-                    0xE3 => Isb<OffsetXIndirect>,
-                    0xE4 => Cpx<ZeroPage>,
-                    0xE5 => Sbc<ZeroPage>,
-                    0xE6 => Inc<ZeroPage>,
-                    0xE7 => Isb<ZeroPage>,
-                    0xE8 => Inx<Implicit>,
-                    0xE9 => Sbc<Immediate>,
-                    0xEA => Nop<Implicit>,
-                    0xEB => Sbc<Immediate>,
-                    0xEC => Cpx<Absolute>,
-                    0xED => Sbc<Absolute>,
-                    0xEE => Inc<Absolute>,
-                    0xEF => Isb<Absolute>,
-                    // --------------------------------
-                    0xF0 => Beq<Relative>,
-                    0xF1 => Sbc<IndirectOffsetY>,
-                    // 0xF2
-                    0xF3 => Isb<IndirectOffsetY>,
-                    0xF4 => Nop<ZeroPageOffsetX>,
-                    0xF5 => Sbc<ZeroPageOffsetX>,
-                    0xF6 => Inc<ZeroPageOffsetX>,
-                    0xF7 => Isb<ZeroPageOffsetX>,
-                    0xF8 => Sed<Implicit>,
-                    0xF9 => Sbc<AbsoluteOffsetY>,
-                    0xFA => Nop<Implicit>,
-                    0xFB => Isb<AbsoluteOffsetY>,
-                    0xFC => Nop<AbsoluteOffsetX>,
-                    0xFD => Sbc<AbsoluteOffsetX>,
-                    0xFE => Inc<AbsoluteOffsetX>,
-                    0xFF => Isb<AbsoluteOffsetX>,
-                )
+                opcode_table!(match_instr)
             };
+        } else if self.hijackable && (self.cycle_counter == HIJACK_CYCLES_LEFT) {
+            // https://www.nesdev.org/wiki/CPU_interrupts#Interrupt_hijacking
+            // The pushed status keeps the B flag of BRK, so handlers can still tell them apart
+            self.hijackable = false;
+            if self.nmi_line {
+                self.nmi_line = false;
+                self.pc = bus.read_16(NMI_VECTOR);
+            }
         }
 
         self.cycle_counter -= 1;
+        if (self.poll_cycle != 0) && (self.cycle_counter == self.poll_cycle) {
+            self.poll_interrupts();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_cartridge;
+    use crate::device::apu::AudioSink;
+    use crate::system::System;
+    use crate::Region;
+
+    const RESET_HANDLER: u16 = 0x8000;
+    const NMI_HANDLER: u16 = 0x9000;
+    const IRQ_HANDLER: u16 = 0xA000;
+
+    struct NoAudio;
+
+    impl AudioSink for NoAudio {
+        fn push_sample(&mut self, _sample: crate::Sample) {}
+    }
+
+    /// NROM cartridge running `program` from reset, everything else is filled with NOP
+    fn system_with_program(program: &[u8]) -> System {
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        for (vector, handler) in [
+            (NMI_VECTOR, NMI_HANDLER),
+            (RESET_VECTOR, RESET_HANDLER),
+            (IRQ_VECTOR, IRQ_HANDLER),
+        ] {
+            let offset = (vector - 0x8000) as usize;
+            prg_rom[offset..(offset + 2)].copy_from_slice(&handler.to_le_bytes());
+        }
+
+        System::new(test_cartridge(0, &prg_rom, &[0; 0x2000]), Region::Ntsc)
+    }
+
+    /// Runs a single CPU cycle, a device holding the IRQ line asserts it at the end of the cycle
+    fn cycle(system: &mut System, irq: bool) {
+        system.clock_exact(1, &mut NoAudio);
+        if irq {
+            system.cpu_mut().signal_irq();
+        }
+    }
+
+    /// Runs up to the next instruction boundary and returns the program counter there
+    fn step(system: &mut System, irq: bool) -> u16 {
+        loop {
+            cycle(system, irq);
+            if system.cpu_mut().at_instruction_boundary() {
+                return system.cpu_registers().pc;
+            }
+        }
+    }
+
+    #[test]
+    fn cli_takes_effect_after_the_next_instruction() {
+        // CLI, NOP
+        let mut system = system_with_program(&[0x58, 0xEA]);
+
+        assert_eq!(step(&mut system, true), 0x8001);
+        assert_eq!(step(&mut system, true), 0x8002);
+        assert_eq!(step(&mut system, true), IRQ_HANDLER);
+    }
+
+    #[test]
+    fn irq_is_taken_right_after_sei() {
+        // CLI, NOP, SEI, NOP
+        let mut system = system_with_program(&[0x58, 0xEA, 0x78, 0xEA]);
+
+        assert_eq!(step(&mut system, false), 0x8001);
+
+        // Asserted after the NOP polled, so the SEI is the first to see it
+        cycle(&mut system, false);
+        assert_eq!(step(&mut system, true), 0x8002);
+        assert_eq!(step(&mut system, true), 0x8003);
+        assert_eq!(step(&mut system, true), IRQ_HANDLER);
+
+        // The pushed status already has the I flag set by SEI
+        let status = system.read_ram(0x01FB);
+        assert_ne!(status & StatusFlags::I.bits(), 0);
+    }
+
+    #[test]
+    fn plp_takes_effect_after_the_next_instruction() {
+        // LDA #$00, PHA, PLP, NOP
+        let mut system = system_with_program(&[0xA9, 0x00, 0x48, 0x28, 0xEA]);
+
+        assert_eq!(step(&mut system, true), 0x8002);
+        assert_eq!(step(&mut system, true), 0x8003);
+        assert_eq!(step(&mut system, true), 0x8004);
+        assert_eq!(step(&mut system, true), 0x8005);
+        assert_eq!(step(&mut system, true), IRQ_HANDLER);
+    }
+
+    #[test]
+    fn taken_branch_without_page_cross_delays_irq() {
+        // CLI, NOP, BNE +0, NOP
+        let mut system = system_with_program(&[0x58, 0xEA, 0xD0, 0x00, 0xEA]);
+        step(&mut system, false);
+        step(&mut system, false);
+
+        // Asserted after the branch polled on its first cycle, a 3 cycle instruction
+        // would normally still see it on its second cycle
+        cycle(&mut system, true);
+        assert_eq!(step(&mut system, true), 0x8004);
+        assert_eq!(step(&mut system, true), 0x8005);
+        assert_eq!(step(&mut system, true), IRQ_HANDLER);
+    }
+
+    #[test]
+    fn branch_not_taken_polls_normally() {
+        // CLI, NOP, BEQ +0, NOP
+        let mut system = system_with_program(&[0x58, 0xEA, 0xF0, 0x00, 0xEA]);
+        step(&mut system, false);
+
+        // Asserted after the NOP polled, so the branch is the first to see it
+        cycle(&mut system, false);
+        assert_eq!(step(&mut system, true), 0x8002);
+        assert_eq!(step(&mut system, true), 0x8004);
+        assert_eq!(step(&mut system, true), IRQ_HANDLER);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        // BRK
+        let mut system = system_with_program(&[0x00]);
+
+        cycle(&mut system, false);
+        system.cpu_mut().signal_nmi();
+        assert_eq!(step(&mut system, false), NMI_HANDLER);
+
+        // The hijacked BRK still pushes the B flag and the NMI isn't taken a second time
+        let status = system.read_ram(0x01FB);
+        assert_ne!(status & B_FLAG, 0);
+        assert_eq!(step(&mut system, false), NMI_HANDLER + 1);
+    }
+
+    #[test]
+    fn late_nmi_runs_after_brk() {
+        // BRK
+        let mut system = system_with_program(&[0x00]);
+
+        for _ in 0..5 {
+            cycle(&mut system, false);
+        }
+        system.cpu_mut().signal_nmi();
+        assert_eq!(step(&mut system, false), IRQ_HANDLER);
+        assert_eq!(step(&mut system, false), NMI_HANDLER);
+    }
+
+    /// Runs `opcode` with an immediate operand in decimal mode and returns A and the flags
+    #[cfg(feature = "decimal-mode")]
+    fn decimal_op(opcode: u8, lhs: u8, rhs: u8, carry: bool) -> (u8, StatusFlags) {
+        let set_carry = if carry { 0x38 } else { 0x18 };
+        // SED, SEC or CLC, LDA #lhs, opcode #rhs
+        let mut system = system_with_program(&[0xF8, set_carry, 0xA9, lhs, opcode, rhs]);
+        for _ in 0..4 {
+            step(&mut system, false);
+        }
+
+        let registers = system.cpu_registers();
+        (registers.a, StatusFlags::from_bits_retain(registers.p))
+    }
+
+    #[cfg(feature = "decimal-mode")]
+    #[test]
+    fn decimal_adc() {
+        let (a, p) = decimal_op(0x69, 0x58, 0x46, true);
+        assert_eq!(a, 0x05);
+        assert!(p.contains(StatusFlags::C));
+
+        // Invalid BCD: N and V come from the sum before the high digit is adjusted, Z from the binary sum
+        let (a, p) = decimal_op(0x69, 0x0F, 0xF1, false);
+        assert_eq!(a, 0x66);
+        assert!(p.contains(StatusFlags::C));
+        assert!(p.contains(StatusFlags::Z));
+        assert!(!p.contains(StatusFlags::N));
+        assert!(!p.contains(StatusFlags::V));
+    }
+
+    #[cfg(feature = "decimal-mode")]
+    #[test]
+    fn decimal_sbc() {
+        let (a, p) = decimal_op(0xE9, 0x12, 0x21, true);
+        assert_eq!(a, 0x91);
+        assert!(!p.contains(StatusFlags::C));
     }
 }
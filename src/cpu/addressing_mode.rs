@@ -4,8 +4,21 @@ use super::Cpu;
 use crate::system::CpuBus;
 use std::fmt::Display;
 
+/// `Display` formats the operand like nestest.log, including the effective addresses
 pub trait AddressingMode: Sized + Display {
+    /// Number of operand bytes following the opcode
+    const OPERAND_SIZE: u16;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool);
+
+    /// Formats a raw operand like `Display` does, minus anything that depends on CPU state.
+    /// `next_pc` is the address of the following instruction.
+    fn format_operand(operand: u16, next_pc: u16) -> String;
+
+    /// Memory location the operand refers to, its value is appended to the trace
+    fn data_addr(&self) -> Option<u16> {
+        None
+    }
 }
 
 pub trait ProducesData: AddressingMode {
@@ -29,23 +42,35 @@ impl Display for Implicit {
 }
 
 impl AddressingMode for Implicit {
+    const OPERAND_SIZE: u16 = 0;
+
     fn decode(_cpu: &mut Cpu, _bus: &mut CpuBus<'_>) -> (Self, bool) {
         (Self, false)
     }
+
+    fn format_operand(_operand: u16, _next_pc: u16) -> String {
+        String::new()
+    }
 }
 
 pub struct Accumulator;
 
 impl Display for Accumulator {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(" a")
+        f.write_str(" A")
     }
 }
 
 impl AddressingMode for Accumulator {
+    const OPERAND_SIZE: u16 = 0;
+
     fn decode(_cpu: &mut Cpu, _bus: &mut CpuBus<'_>) -> (Self, bool) {
         (Self, false)
     }
+
+    fn format_operand(_operand: u16, _next_pc: u16) -> String {
+        " A".to_owned()
+    }
 }
 
 impl ProducesData for Accumulator {
@@ -66,17 +91,23 @@ pub struct Immediate {
 
 impl Display for Immediate {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " #{}", self.value)
+        write!(f, " #${:0>2X}", self.value)
     }
 }
 
 impl AddressingMode for Immediate {
+    const OPERAND_SIZE: u16 = 1;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let value = bus.read(cpu.pc);
         cpu.pc = cpu.pc.wrapping_add(1);
 
         (Self { value }, false)
     }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" #${operand:0>2X}")
+    }
 }
 
 impl ProducesData for Immediate {
@@ -91,17 +122,27 @@ pub struct ZeroPage {
 
 impl Display for ZeroPage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " 0x{:0>2X}", self.zp_addr)
+        write!(f, " ${:0>2X}", self.zp_addr)
     }
 }
 
 impl AddressingMode for ZeroPage {
+    const OPERAND_SIZE: u16 = 1;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let zp_addr = bus.read(cpu.pc);
         cpu.pc = cpu.pc.wrapping_add(1);
 
         (Self { zp_addr }, false)
     }
+
+    fn data_addr(&self) -> Option<u16> {
+        Some(self.zp_addr as u16)
+    }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" ${operand:0>2X}")
+    }
 }
 
 impl ProducesData for ZeroPage {
@@ -123,11 +164,13 @@ pub struct ZeroPageOffsetX {
 
 impl Display for ZeroPageOffsetX {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " 0x{:0>2X},x", self.base_addr)
+        write!(f, " ${:0>2X},X @ {:0>2X}", self.base_addr, self.zp_addr)
     }
 }
 
 impl AddressingMode for ZeroPageOffsetX {
+    const OPERAND_SIZE: u16 = 1;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let base_addr = bus.read(cpu.pc);
         let zp_addr = base_addr.wrapping_add(cpu.x);
@@ -135,6 +178,14 @@ impl AddressingMode for ZeroPageOffsetX {
 
         (Self { base_addr, zp_addr }, false)
     }
+
+    fn data_addr(&self) -> Option<u16> {
+        Some(self.zp_addr as u16)
+    }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" ${operand:0>2X},X")
+    }
 }
 
 impl ProducesData for ZeroPageOffsetX {
@@ -156,11 +207,13 @@ pub struct ZeroPageOffsetY {
 
 impl Display for ZeroPageOffsetY {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " 0x{:0>2X},y", self.base_addr)
+        write!(f, " ${:0>2X},Y @ {:0>2X}", self.base_addr, self.zp_addr)
     }
 }
 
 impl AddressingMode for ZeroPageOffsetY {
+    const OPERAND_SIZE: u16 = 1;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let base_addr = bus.read(cpu.pc);
         let zp_addr = base_addr.wrapping_add(cpu.y);
@@ -168,6 +221,14 @@ impl AddressingMode for ZeroPageOffsetY {
 
         (Self { base_addr, zp_addr }, false)
     }
+
+    fn data_addr(&self) -> Option<u16> {
+        Some(self.zp_addr as u16)
+    }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" ${operand:0>2X},Y")
+    }
 }
 
 impl ProducesData for ZeroPageOffsetY {
@@ -183,17 +244,18 @@ impl ConsumesData for ZeroPageOffsetY {
 }
 
 pub struct Relative {
-    offset: i8,
     abs_addr: u16,
 }
 
 impl Display for Relative {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " {:+}", self.offset)
+        write!(f, " ${:0>4X}", self.abs_addr)
     }
 }
 
 impl AddressingMode for Relative {
+    const OPERAND_SIZE: u16 = 1;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let offset = bus.read(cpu.pc) as i8;
         cpu.pc = cpu.pc.wrapping_add(1);
@@ -205,7 +267,14 @@ impl AddressingMode for Relative {
         let page_after = abs_addr >> 8;
         let page_crossed = page_after != page_before;
 
-        (Self { offset, abs_addr }, page_crossed)
+        (Self { abs_addr }, page_crossed)
+    }
+
+    fn format_operand(operand: u16, next_pc: u16) -> String {
+        format!(
+            " ${:0>4X}",
+            next_pc.wrapping_add_signed(operand as u8 as i8 as i16)
+        )
     }
 }
 
@@ -221,17 +290,27 @@ pub struct Absolute {
 
 impl Display for Absolute {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " 0x{:0>4X}", self.abs_addr)
+        write!(f, " ${:0>4X}", self.abs_addr)
     }
 }
 
 impl AddressingMode for Absolute {
+    const OPERAND_SIZE: u16 = 2;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let abs_addr = bus.read_16(cpu.pc);
         cpu.pc = cpu.pc.wrapping_add(2);
 
         (Self { abs_addr }, false)
     }
+
+    fn data_addr(&self) -> Option<u16> {
+        Some(self.abs_addr)
+    }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" ${operand:0>4X}")
+    }
 }
 
 impl ProducesData for Absolute {
@@ -259,11 +338,13 @@ pub struct AbsoluteOffsetX {
 
 impl Display for AbsoluteOffsetX {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " 0x{:0>4X},x", self.base_addr)
+        write!(f, " ${:0>4X},X @ {:0>4X}", self.base_addr, self.abs_addr)
     }
 }
 
 impl AddressingMode for AbsoluteOffsetX {
+    const OPERAND_SIZE: u16 = 2;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let base_addr = bus.read_16(cpu.pc);
         let abs_addr = base_addr.wrapping_add(cpu.x as u16);
@@ -281,6 +362,14 @@ impl AddressingMode for AbsoluteOffsetX {
             page_crossed,
         )
     }
+
+    fn data_addr(&self) -> Option<u16> {
+        Some(self.abs_addr)
+    }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" ${operand:0>4X},X")
+    }
 }
 
 impl ProducesData for AbsoluteOffsetX {
@@ -302,11 +391,13 @@ pub struct AbsoluteOffsetY {
 
 impl Display for AbsoluteOffsetY {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " 0x{:0>4X},y", self.base_addr)
+        write!(f, " ${:0>4X},Y @ {:0>4X}", self.base_addr, self.abs_addr)
     }
 }
 
 impl AddressingMode for AbsoluteOffsetY {
+    const OPERAND_SIZE: u16 = 2;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let base_addr = bus.read_16(cpu.pc);
         let abs_addr = base_addr.wrapping_add(cpu.y as u16);
@@ -324,6 +415,14 @@ impl AddressingMode for AbsoluteOffsetY {
             page_crossed,
         )
     }
+
+    fn data_addr(&self) -> Option<u16> {
+        Some(self.abs_addr)
+    }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" ${operand:0>4X},Y")
+    }
 }
 
 impl ProducesData for AbsoluteOffsetY {
@@ -352,11 +451,13 @@ pub struct Indirect {
 
 impl Display for Indirect {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " (0x{:0>4X})", self.ind_addr)
+        write!(f, " (${:0>4X}) = {:0>4X}", self.ind_addr, self.addr)
     }
 }
 
 impl AddressingMode for Indirect {
+    const OPERAND_SIZE: u16 = 2;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let ind_addr = bus.read_16(cpu.pc);
         cpu.pc = cpu.pc.wrapping_add(2);
@@ -367,6 +468,10 @@ impl AddressingMode for Indirect {
 
         (Self { ind_addr, addr }, false)
     }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" (${operand:0>4X})")
+    }
 }
 
 impl ProducesAddress for Indirect {
@@ -377,16 +482,23 @@ impl ProducesAddress for Indirect {
 
 pub struct OffsetXIndirect {
     zp_base_addr: u8,
+    zp_ind_addr: u8,
     abs_addr: u16,
 }
 
 impl Display for OffsetXIndirect {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " (0x{:0>2X},x)", self.zp_base_addr)
+        write!(
+            f,
+            " (${:0>2X},X) @ {:0>2X} = {:0>4X}",
+            self.zp_base_addr, self.zp_ind_addr, self.abs_addr
+        )
     }
 }
 
 impl AddressingMode for OffsetXIndirect {
+    const OPERAND_SIZE: u16 = 1;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let zp_base_addr = bus.read(cpu.pc);
         let zp_ind_addr = zp_base_addr.wrapping_add(cpu.x);
@@ -399,11 +511,20 @@ impl AddressingMode for OffsetXIndirect {
         (
             Self {
                 zp_base_addr,
+                zp_ind_addr,
                 abs_addr,
             },
             false,
         )
     }
+
+    fn data_addr(&self) -> Option<u16> {
+        Some(self.abs_addr)
+    }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" (${operand:0>2X},X)")
+    }
 }
 
 impl ProducesData for OffsetXIndirect {
@@ -420,16 +541,23 @@ impl ConsumesData for OffsetXIndirect {
 
 pub struct IndirectOffsetY {
     zp_base_addr: u8,
+    base_addr: u16,
     abs_addr: u16,
 }
 
 impl Display for IndirectOffsetY {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, " (0x{:0>2X}),y", self.zp_base_addr)
+        write!(
+            f,
+            " (${:0>2X}),Y = {:0>4X} @ {:0>4X}",
+            self.zp_base_addr, self.base_addr, self.abs_addr
+        )
     }
 }
 
 impl AddressingMode for IndirectOffsetY {
+    const OPERAND_SIZE: u16 = 1;
+
     fn decode(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> (Self, bool) {
         let zp_base_addr = bus.read(cpu.pc);
         cpu.pc = cpu.pc.wrapping_add(1);
@@ -446,11 +574,20 @@ impl AddressingMode for IndirectOffsetY {
         (
             Self {
                 zp_base_addr,
+                base_addr,
                 abs_addr,
             },
             page_crossed,
         )
     }
+
+    fn data_addr(&self) -> Option<u16> {
+        Some(self.abs_addr)
+    }
+
+    fn format_operand(operand: u16, _next_pc: u16) -> String {
+        format!(" (${operand:0>2X}),Y")
+    }
 }
 
 impl ProducesData for IndirectOffsetY {
@@ -0,0 +1,113 @@
+use super::addressing_mode::*;
+use super::instruction::*;
+use super::opcode_table;
+
+fn disassemble_instruction<I: Instruction>(
+    opcode: u8,
+    operand: &[u8],
+    addr: u16,
+) -> Option<(String, usize)> {
+    let size = I::Mode::OPERAND_SIZE as usize;
+    let operand = operand.get(..size)?;
+    let value = operand
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| (value << 8) | (byte as u16));
+    let next_pc = addr.wrapping_add(1 + I::Mode::OPERAND_SIZE);
+
+    let marker = if OFFICIAL_OPCODES.contains(&opcode) {
+        ""
+    } else {
+        "*"
+    };
+
+    let text = format!(
+        "{marker}{}{}",
+        I::NAME,
+        I::Mode::format_operand(value, next_pc)
+    );
+    Some((text, 1 + size))
+}
+
+/// Decodes `bytes` as instructions placed at `origin`, returning the address and text of each one.
+/// Unofficial opcodes are marked with `*`, bytes that don't decode to an instruction
+/// (including an instruction cut off at the end) are emitted as `.db`.
+pub fn disassemble(bytes: &[u8], origin: u16) -> Vec<(u16, String)> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    while let Some(&opcode) = bytes.get(offset) {
+        let addr = origin.wrapping_add(offset as u16);
+        let operand = &bytes[(offset + 1)..];
+
+        macro_rules! match_instr {
+            ($($opcode:literal => $instr:ty),+ $(,)?) => {
+                match opcode {
+                    $($opcode => disassemble_instruction::<$instr>(opcode, operand, addr),)+
+                    _ => None,
+                }
+            };
+        }
+
+        let (text, size) =
+            opcode_table!(match_instr).unwrap_or_else(|| (format!(".db ${opcode:0>2X}"), 1));
+        lines.push((addr, text));
+        offset += size;
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(expected: &[(u16, &str)]) -> Vec<(u16, String)> {
+        expected
+            .iter()
+            .map(|&(addr, text)| (addr, text.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn addressing_modes() {
+        let bytes = [
+            0xA9, 0x01, // LDA #$01
+            0x8D, 0x00, 0x02, // STA $0200
+            0xB1, 0x10, // LDA ($10),Y
+            0xBD, 0x34, 0x12, // LDA $1234,X
+            0xD0, 0xF4, // BNE to the start
+            0x6C, 0xFC, 0xFF, // JMP ($FFFC)
+            0x0A, // ASL A
+        ];
+
+        assert_eq!(
+            disassemble(&bytes, 0x8000),
+            lines(&[
+                (0x8000, "LDA #$01"),
+                (0x8002, "STA $0200"),
+                (0x8005, "LDA ($10),Y"),
+                (0x8007, "LDA $1234,X"),
+                (0x800A, "BNE $8000"),
+                (0x800C, "JMP ($FFFC)"),
+                (0x800F, "ASL A"),
+            ])
+        );
+    }
+
+    #[test]
+    fn unofficial_and_truncated_instructions() {
+        // LAX $20, JAM, then a JSR cut off after one operand byte
+        let bytes = [0xA7, 0x20, 0x02, 0x20, 0x00];
+
+        assert_eq!(
+            disassemble(&bytes, 0xC000),
+            lines(&[
+                (0xC000, "*LAX $20"),
+                (0xC002, "*JAM"),
+                (0xC003, ".db $20"),
+                (0xC004, "BRK"),
+            ])
+        );
+    }
+}
@@ -2,7 +2,9 @@
 
 use super::addressing_mode::*;
 use super::{Cpu, StatusFlags, B_FLAG, IRQ_VECTOR, U_FLAG};
+use crate::debug::CpuRegisters;
 use crate::system::CpuBus;
+use std::fmt::Write;
 use std::marker::PhantomData;
 
 pub trait Instruction {
@@ -15,10 +17,77 @@ pub trait Instruction {
 }
 
 pub fn execute<I: Instruction>(cpu: &mut Cpu, bus: &mut CpuBus<'_>) -> u8 {
+    let registers = cpu.registers();
     let (mode, page_crossed) = I::Mode::decode(cpu, bus);
+
+    if bus.debugger.trace().is_enabled() {
+        trace::<I>(cpu, bus, registers, &mode);
+    }
     let branch_taken = I::execute(cpu, bus, mode);
 
-    I::CYCLE_COUNT + ((page_crossed & I::AFFECTED_BY_PAGE_CROSS) as u8) + (branch_taken as u8)
+    // Branches only pay for a page cross if they are taken
+    let branch_cycles = if branch_taken {
+        if !page_crossed {
+            // https://www.nesdev.org/wiki/CPU_interrupts#Branch_instructions_and_interrupts
+            cpu.poll_cycle = 2;
+        }
+
+        1 + (page_crossed as u8)
+    } else {
+        0
+    };
+
+    I::CYCLE_COUNT + ((page_crossed & I::AFFECTED_BY_PAGE_CROSS) as u8) + branch_cycles
+}
+
+#[rustfmt::skip]
+pub(super) const OFFICIAL_OPCODES: [u8; 151] = [
+    0x00, 0x01, 0x05, 0x06, 0x08, 0x09, 0x0A, 0x0D, 0x0E, 0x10, 0x11, 0x15, 0x16, 0x18, 0x19, 0x1D,
+    0x1E, 0x20, 0x21, 0x24, 0x25, 0x26, 0x28, 0x29, 0x2A, 0x2C, 0x2D, 0x2E, 0x30, 0x31, 0x35, 0x36,
+    0x38, 0x39, 0x3D, 0x3E, 0x40, 0x41, 0x45, 0x46, 0x48, 0x49, 0x4A, 0x4C, 0x4D, 0x4E, 0x50, 0x51,
+    0x55, 0x56, 0x58, 0x59, 0x5D, 0x5E, 0x60, 0x61, 0x65, 0x66, 0x68, 0x69, 0x6A, 0x6C, 0x6D, 0x6E,
+    0x70, 0x71, 0x75, 0x76, 0x78, 0x79, 0x7D, 0x7E, 0x81, 0x84, 0x85, 0x86, 0x88, 0x8A, 0x8C, 0x8D,
+    0x8E, 0x90, 0x91, 0x94, 0x95, 0x96, 0x98, 0x99, 0x9A, 0x9D, 0xA0, 0xA1, 0xA2, 0xA4, 0xA5, 0xA6,
+    0xA8, 0xA9, 0xAA, 0xAC, 0xAD, 0xAE, 0xB0, 0xB1, 0xB4, 0xB5, 0xB6, 0xB8, 0xB9, 0xBA, 0xBC, 0xBD,
+    0xBE, 0xC0, 0xC1, 0xC4, 0xC5, 0xC6, 0xC8, 0xC9, 0xCA, 0xCC, 0xCD, 0xCE, 0xD0, 0xD1, 0xD5, 0xD6,
+    0xD8, 0xD9, 0xDD, 0xDE, 0xE0, 0xE1, 0xE4, 0xE5, 0xE6, 0xE8, 0xE9, 0xEA, 0xEC, 0xED, 0xEE, 0xF0,
+    0xF1, 0xF5, 0xF6, 0xF8, 0xF9, 0xFD, 0xFE,
+];
+
+/// Logs an instruction in the format of nestest.log, with unofficial opcodes marked by `*`
+fn trace<I: Instruction>(cpu: &Cpu, bus: &mut CpuBus<'_>, registers: CpuRegisters, mode: &I::Mode) {
+    let CpuRegisters { a, x, y, s, p, .. } = registers;
+    let pc = registers.pc.wrapping_sub(1);
+
+    let mut bytes = String::new();
+    let mut addr = pc;
+    while addr != cpu.pc {
+        if !bytes.is_empty() {
+            bytes.push(' ');
+        }
+        write!(bytes, "{:0>2X}", bus.peek(addr).unwrap_or(0)).unwrap();
+        addr = addr.wrapping_add(1);
+    }
+
+    let opcode = bus.peek(pc).unwrap_or(0);
+    let marker = if OFFICIAL_OPCODES.contains(&opcode) {
+        ' '
+    } else {
+        '*'
+    };
+
+    let mut asm = format!("{}{mode}", I::NAME);
+    // Jumps only use the address and never access the memory behind it
+    if !matches!(I::NAME, "JMP" | "JSR") {
+        if let Some(data) = mode.data_addr().and_then(|addr| bus.peek(addr)) {
+            write!(asm, " = {data:0>2X}").unwrap();
+        }
+    }
+
+    bus.debugger.trace_mut().push(format_args!(
+        "{pc:0>4X}  {bytes:<8} {marker}{asm:<31} A:{a:0>2X} X:{x:0>2X} Y:{y:0>2X} P:{p:0>2X} SP:{s:0>2X} CYC:{}",
+        cpu.cycles
+    ));
 }
 
 macro_rules! instruction {
@@ -32,7 +101,7 @@ macro_rules! instruction {
                 type Mode = $mode_ty;
                 const CYCLE_COUNT: u8 = instruction!(@CYCLE_COUNT $($cycles)+);
                 const AFFECTED_BY_PAGE_CROSS: bool = instruction!(@PAGE_CROSS $($cycles)+);
-                const NAME: &'static str = const_str::convert_ascii_case!(lower, stringify!($instr));
+                const NAME: &'static str = const_str::convert_ascii_case!(upper, stringify!($instr));
 
                 fn execute($cpu: &mut Cpu, $bus: &mut CpuBus<'_>, $mode: Self::Mode) -> bool {
                     $execute
@@ -51,7 +120,7 @@ fn carry_add(lhs: u8, rhs: u8, c_in: bool) -> (u8, bool) {
     (r2, c1 | c2)
 }
 
-fn execute_add(cpu: &mut Cpu, rhs: u8) {
+fn execute_binary_add(cpu: &mut Cpu, rhs: u8) {
     let lhs = cpu.a;
     let c_in = cpu.p.contains(StatusFlags::C);
     let (result, c_out) = carry_add(lhs, rhs, c_in);
@@ -70,6 +139,78 @@ fn execute_add(cpu: &mut Cpu, rhs: u8) {
     cpu.p.set(StatusFlags::N, result_sign != 0);
 }
 
+// http://www.6502.org/tutorials/decimal_mode.html#A
+// Follows the NMOS 6502: N and V come from the sum before the high digit is adjusted
+// and Z from the binary sum, which all differ from the decimal result for invalid BCD inputs.
+#[cfg(feature = "decimal-mode")]
+fn execute_decimal_add(cpu: &mut Cpu, rhs: u8) {
+    let lhs = cpu.a;
+    let c_in = cpu.p.contains(StatusFlags::C);
+
+    let mut low = ((lhs & 0x0F) as u16) + ((rhs & 0x0F) as u16) + (c_in as u16);
+    if low >= 0x0A {
+        low = ((low + 0x06) & 0x0F) + 0x10;
+    }
+    let mut result = ((lhs & 0xF0) as u16) + ((rhs & 0xF0) as u16) + low;
+
+    let (binary_result, _) = carry_add(lhs, rhs, c_in);
+    cpu.p.set(StatusFlags::Z, binary_result == 0);
+    cpu.p.set(StatusFlags::N, (result & 0x80) != 0);
+    cpu.p.set(
+        StatusFlags::V,
+        (!(lhs ^ rhs) & (lhs ^ (result as u8)) & 0x80) != 0,
+    );
+
+    if result >= 0xA0 {
+        result += 0x60;
+    }
+
+    cpu.a = result as u8;
+    cpu.p.set(StatusFlags::C, result >= 0x100);
+}
+
+// http://www.6502.org/tutorials/decimal_mode.html#A
+#[cfg(feature = "decimal-mode")]
+fn decimal_sub(lhs: u8, rhs: u8, c_in: bool) -> u8 {
+    let mut low = ((lhs & 0x0F) as i16) - ((rhs & 0x0F) as i16) + (c_in as i16) - 1;
+    if low < 0 {
+        low = ((low - 0x06) & 0x0F) - 0x10;
+    }
+    let mut result = ((lhs & 0xF0) as i16) - ((rhs & 0xF0) as i16) + low;
+    if result < 0 {
+        result -= 0x60;
+    }
+
+    result as u8
+}
+
+/// The 2A03 has no decimal mode, so the D flag is ignored unless the `decimal-mode` feature is enabled
+fn execute_add(cpu: &mut Cpu, rhs: u8) {
+    #[cfg(feature = "decimal-mode")]
+    if cpu.p.contains(StatusFlags::D) {
+        execute_decimal_add(cpu, rhs);
+        return;
+    }
+
+    execute_binary_add(cpu, rhs);
+}
+
+fn execute_sub(cpu: &mut Cpu, rhs: u8) {
+    #[cfg(feature = "decimal-mode")]
+    let decimal_result = cpu
+        .p
+        .contains(StatusFlags::D)
+        .then(|| decimal_sub(cpu.a, rhs, cpu.p.contains(StatusFlags::C)));
+
+    // In decimal mode all flags still come from the binary subtraction
+    execute_binary_add(cpu, !rhs);
+
+    #[cfg(feature = "decimal-mode")]
+    if let Some(result) = decimal_result {
+        cpu.a = result;
+    }
+}
+
 pub struct Adc<Mode: ProducesData>(PhantomData<fn(Mode)>);
 
 instruction!(
@@ -103,8 +244,8 @@ instruction!(
         OffsetXIndirect(6),
         IndirectOffsetY(5+),
     ] => |cpu, bus, mode| {
-        let rhs = !mode.produce_data(cpu, bus);
-        execute_add(cpu, rhs);
+        let rhs = mode.produce_data(cpu, bus);
+        execute_sub(cpu, rhs);
 
         false
     }
@@ -276,7 +417,7 @@ instruction!(
 pub struct Bcs<Mode: ProducesAddress>(PhantomData<fn(Mode)>);
 
 instruction!(
-    Bcs[Relative(2+)] => |cpu, bus, mode| {
+    Bcs[Relative(2)] => |cpu, bus, mode| {
         let condition = cpu.p.contains(StatusFlags::C);
         if condition {
             cpu.pc = mode.produce_address(cpu, bus);
@@ -288,7 +429,7 @@ instruction!(
 pub struct Bcc<Mode: ProducesAddress>(PhantomData<fn(Mode)>);
 
 instruction!(
-    Bcc[Relative(2+)] => |cpu, bus, mode| {
+    Bcc[Relative(2)] => |cpu, bus, mode| {
         let condition = !cpu.p.contains(StatusFlags::C);
         if condition {
             cpu.pc = mode.produce_address(cpu, bus);
@@ -300,7 +441,7 @@ instruction!(
 pub struct Beq<Mode: ProducesAddress>(PhantomData<fn(Mode)>);
 
 instruction!(
-    Beq[Relative(2+)] => |cpu, bus, mode| {
+    Beq[Relative(2)] => |cpu, bus, mode| {
         let condition = cpu.p.contains(StatusFlags::Z);
         if condition {
             cpu.pc = mode.produce_address(cpu, bus);
@@ -312,7 +453,7 @@ instruction!(
 pub struct Bne<Mode: ProducesAddress>(PhantomData<fn(Mode)>);
 
 instruction!(
-    Bne[Relative(2+)] => |cpu, bus, mode| {
+    Bne[Relative(2)] => |cpu, bus, mode| {
         let condition = !cpu.p.contains(StatusFlags::Z);
         if condition {
             cpu.pc = mode.produce_address(cpu, bus);
@@ -324,7 +465,7 @@ instruction!(
 pub struct Bmi<Mode: ProducesAddress>(PhantomData<fn(Mode)>);
 
 instruction!(
-    Bmi[Relative(2+)] => |cpu, bus, mode| {
+    Bmi[Relative(2)] => |cpu, bus, mode| {
         let condition = cpu.p.contains(StatusFlags::N);
         if condition {
             cpu.pc = mode.produce_address(cpu, bus);
@@ -336,7 +477,7 @@ instruction!(
 pub struct Bpl<Mode: ProducesAddress>(PhantomData<fn(Mode)>);
 
 instruction!(
-    Bpl[Relative(2+)] => |cpu, bus, mode| {
+    Bpl[Relative(2)] => |cpu, bus, mode| {
         let condition = !cpu.p.contains(StatusFlags::N);
         if condition {
             cpu.pc = mode.produce_address(cpu, bus);
@@ -348,7 +489,7 @@ instruction!(
 pub struct Bvs<Mode: ProducesAddress>(PhantomData<fn(Mode)>);
 
 instruction!(
-    Bvs[Relative(2+)] => |cpu, bus, mode| {
+    Bvs[Relative(2)] => |cpu, bus, mode| {
         let condition = cpu.p.contains(StatusFlags::V);
         if condition {
             cpu.pc = mode.produce_address(cpu, bus);
@@ -360,7 +501,7 @@ instruction!(
 pub struct Bvc<Mode: ProducesAddress>(PhantomData<fn(Mode)>);
 
 instruction!(
-    Bvc[Relative(2+)] => |cpu, bus, mode| {
+    Bvc[Relative(2)] => |cpu, bus, mode| {
         let condition = !cpu.p.contains(StatusFlags::V);
         if condition {
             cpu.pc = mode.produce_address(cpu, bus);
@@ -393,6 +534,7 @@ instruction!(
 
         cpu.p.insert(StatusFlags::I);
         cpu.pc = bus.read_16(IRQ_VECTOR);
+        cpu.hijackable = true;
 
         false
     }
@@ -420,6 +562,8 @@ pub struct Cli<Mode: AddressingMode>(PhantomData<fn(Mode)>);
 
 instruction!(
     Cli[Implicit(2)] => |cpu, _bus, _mode| {
+        // https://www.nesdev.org/wiki/CPU_interrupts#Delayed_IRQ_response_after_CLI,_SEI,_and_PLP
+        cpu.delayed_i_flag = Some(cpu.p.contains(StatusFlags::I));
         cpu.p.remove(StatusFlags::I);
         false
     }
@@ -456,6 +600,8 @@ pub struct Sei<Mode: AddressingMode>(PhantomData<fn(Mode)>);
 
 instruction!(
     Sei[Implicit(2)] => |cpu, _bus, _mode| {
+        // https://www.nesdev.org/wiki/CPU_interrupts#Delayed_IRQ_response_after_CLI,_SEI,_and_PLP
+        cpu.delayed_i_flag = Some(cpu.p.contains(StatusFlags::I));
         cpu.p.insert(StatusFlags::I);
         false
     }
@@ -785,6 +931,8 @@ pub struct Plp<Mode: AddressingMode>(PhantomData<fn(Mode)>);
 
 instruction!(
     Plp[Implicit(4)] => |cpu, bus, _mode| {
+        // https://www.nesdev.org/wiki/CPU_interrupts#Delayed_IRQ_response_after_CLI,_SEI,_and_PLP
+        cpu.delayed_i_flag = Some(cpu.p.contains(StatusFlags::I));
         cpu.p = StatusFlags::from_bits_truncate(cpu.pop(bus));
         false
     }
@@ -872,6 +1020,15 @@ instruction!(
     ] => |_cpu, _bus, _mode| false
 );
 
+pub struct Jam<Mode: AddressingMode>(PhantomData<fn(Mode)>);
+
+instruction!(
+    Jam[Implicit(2)] => |cpu, _bus, _mode| {
+        cpu.jammed = true;
+        false
+    }
+);
+
 pub struct Dcp<Mode: ProducesData + ConsumesData>(PhantomData<fn(Mode)>);
 
 instruction!(
@@ -910,7 +1067,7 @@ instruction!(
     ] => |cpu, bus, mode| {
         let value = mode.produce_data(cpu, bus).wrapping_add(1);
         mode.consume_data(cpu, bus, value);
-        execute_add(cpu, !value);
+        execute_sub(cpu, value);
 
         false
     }
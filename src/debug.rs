@@ -0,0 +1,397 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{self, Display, Write};
+use std::fs::File;
+use std::io::{LineWriter, Write as _};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakReason {
+    Breakpoint { pc: u16 },
+    Watchpoint { addr: u16, data: u8 },
+    IllegalOpcode { pc: u16, opcode: u8 },
+}
+
+impl Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Breakpoint { pc } => write!(f, "breakpoint hit at 0x{pc:0>4X}"),
+            Self::Watchpoint { addr, data } => {
+                write!(f, "watchpoint hit: 0x{data:0>2X} written to 0x{addr:0>4X}")
+            }
+            Self::IllegalOpcode { pc, opcode } => {
+                write!(f, "illegal opcode 0x{opcode:0>2X} at 0x{pc:0>4X}")
+            }
+        }
+    }
+}
+
+/// Ring buffer holding the most recently executed instructions,
+/// optionally also writing every instruction to a file
+pub struct TraceLog {
+    lines: VecDeque<String>,
+    capacity: usize,
+    file: Option<LineWriter<File>>,
+}
+
+impl TraceLog {
+    #[inline]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+            file: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        (self.capacity > 0) || self.file.is_some()
+    }
+
+    pub fn push(&mut self, args: fmt::Arguments<'_>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        // Reuse the allocation of the oldest line once the buffer is full
+        let mut line = if self.lines.len() >= self.capacity {
+            self.lines.pop_front().unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        line.clear();
+        line.write_fmt(args).unwrap();
+
+        if let Some(file) = &mut self.file {
+            if writeln!(file, "{line}").is_err() {
+                // Stop logging instead of failing on every instruction
+                self.file = None;
+            }
+        }
+
+        if self.capacity > 0 {
+            self.lines.push_back(line);
+        }
+    }
+
+    #[inline]
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+}
+
+pub struct Debugger {
+    breakpoints: HashSet<u16>,
+    watchpoints: HashSet<u16>,
+    trace: TraceLog,
+    break_reason: Option<BreakReason>,
+    resume_pc: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new(trace_size: usize) -> Self {
+        Self {
+            breakpoints: HashSet::new(),
+            watchpoints: HashSet::new(),
+            trace: TraceLog::new(trace_size),
+            break_reason: None,
+            resume_pc: None,
+        }
+    }
+
+    #[inline]
+    pub fn add_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.insert(pc);
+    }
+
+    #[inline]
+    pub fn remove_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.remove(&pc);
+    }
+
+    #[inline]
+    pub fn add_watchpoint(&mut self, addr: u16) {
+        self.watchpoints.insert(addr);
+    }
+
+    #[inline]
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        self.watchpoints.remove(&addr);
+    }
+
+    #[inline]
+    pub fn set_trace_size(&mut self, trace_size: usize) {
+        let file = self.trace.file.take();
+        self.trace = TraceLog::new(trace_size);
+        self.trace.file = file;
+    }
+
+    /// Writes every executed instruction to `file`
+    #[inline]
+    pub fn set_trace_file(&mut self, file: File) {
+        self.trace.file = Some(LineWriter::new(file));
+    }
+
+    #[inline]
+    pub fn trace(&self) -> &TraceLog {
+        &self.trace
+    }
+
+    #[inline]
+    pub fn trace_mut(&mut self) -> &mut TraceLog {
+        &mut self.trace
+    }
+
+    #[inline]
+    pub fn break_reason(&self) -> Option<BreakReason> {
+        self.break_reason
+    }
+
+    /// Returns `true` if execution should halt before the instruction at `pc`
+    pub fn check_breakpoint(&mut self, pc: u16) -> bool {
+        if self.break_reason.is_some() {
+            return true;
+        }
+
+        // Don't break again on the instruction we just resumed from
+        if self.resume_pc.take() == Some(pc) {
+            return false;
+        }
+
+        if self.breakpoints.contains(&pc) {
+            self.break_reason = Some(BreakReason::Breakpoint { pc });
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    pub fn check_watchpoint(&mut self, addr: u16, data: u8) {
+        if self.watchpoints.contains(&addr) && self.break_reason.is_none() {
+            self.break_reason = Some(BreakReason::Watchpoint { addr, data });
+        }
+    }
+
+    #[inline]
+    pub fn signal_illegal_opcode(&mut self, pc: u16, opcode: u8) {
+        self.break_reason = Some(BreakReason::IllegalOpcode { pc, opcode });
+    }
+
+    /// Clears any pending break, used when the system is reset
+    #[inline]
+    pub fn reset(&mut self) {
+        self.break_reason = None;
+        self.resume_pc = None;
+    }
+
+    pub fn resume(&mut self) {
+        if let Some(BreakReason::Breakpoint { pc }) = self.break_reason.take() {
+            self.resume_pc = Some(pc);
+        }
+    }
+
+    /// Clears any pending break and doesn't break on a breakpoint at `pc`, so single stepping
+    /// always makes progress
+    pub(crate) fn resume_from(&mut self, pc: u16) {
+        self.break_reason = None;
+        self.resume_pc = Some(pc);
+    }
+}
+
+/// An instruction executed by [`System::step_instruction`](crate::System::step_instruction)
+#[derive(Debug, Clone, Copy)]
+pub struct InstructionStep {
+    /// Address of the instruction
+    pub pc: u16,
+    pub opcode: u8,
+    /// Address of the next instruction
+    pub next_pc: u16,
+    /// CPU cycles since the previous step, including interrupt sequences and DMA halts
+    pub cycles: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuRegisters {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub pc: u16,
+}
+
+/// How a value has to relate to the value of the previous search step to stay a candidate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchFilter {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    /// Changed by exactly this amount, wrapping around like the 8 bit value itself would
+    ChangedBy(i8),
+}
+
+impl SearchFilter {
+    fn matches(self, previous: u8, current: u8) -> bool {
+        match self {
+            Self::Equal(value) => current == value,
+            Self::Changed => current != previous,
+            Self::Unchanged => current == previous,
+            Self::Increased => current > previous,
+            Self::Decreased => current < previous,
+            Self::ChangedBy(delta) => current == previous.wrapping_add_signed(delta),
+        }
+    }
+}
+
+/// Narrows down which RAM addresses hold a value, e.g. the number of lives,
+/// by repeatedly filtering snapshots of [`System::ram`](crate::System::ram)
+pub struct RamSearch {
+    previous: Box<[u8]>,
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    /// Starts with every address as a candidate
+    pub fn new(ram: &[u8]) -> Self {
+        Self {
+            previous: ram.into(),
+            candidates: (0..(ram.len() as u16)).collect(),
+        }
+    }
+
+    /// Keeps the candidates whose value in `ram` matches `filter`, compared to the previous snapshot
+    pub fn filter(&mut self, ram: &[u8], filter: SearchFilter) {
+        let previous = &self.previous;
+        self.candidates.retain(|&addr| {
+            let addr = addr as usize;
+            filter.matches(previous[addr], ram[addr])
+        });
+        self.previous.copy_from_slice(ram);
+    }
+
+    #[inline]
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+}
+
+pub struct MemoryDump {
+    pub name: &'static str,
+    pub base: u16,
+    pub data: Box<[u8]>,
+}
+
+impl MemoryDump {
+    #[inline]
+    pub fn new(name: &'static str, base: u16, data: &[u8]) -> Self {
+        Self {
+            name,
+            base,
+            data: data.into(),
+        }
+    }
+}
+
+impl Display for MemoryDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[{}]", self.name)?;
+
+        for (i, row) in self.data.chunks(16).enumerate() {
+            let addr = (self.base as usize) + i * 16;
+            write!(f, "{addr:0>4X}:")?;
+            for byte in row {
+                write!(f, " {byte:0>2X}")?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Snapshot of the system state, meant to be attached to bug reports
+pub struct CrashReport {
+    pub reason: Option<BreakReason>,
+    pub registers: CpuRegisters,
+    pub scanline: i16,
+    pub cycle: u16,
+    pub trace: Vec<String>,
+    pub memory: Vec<MemoryDump>,
+}
+
+impl CrashReport {
+    pub fn write_to_file<P: AsRef<std::path::Path>>(&self, file: P) -> std::io::Result<()> {
+        std::fs::write(file, self.to_string())
+    }
+}
+
+impl Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Some(reason) => writeln!(f, "Reason: {reason}")?,
+            None => writeln!(f, "Reason: none")?,
+        }
+        writeln!(f)?;
+
+        let CpuRegisters { a, x, y, s, p, pc } = self.registers;
+        writeln!(f, "[Registers]")?;
+        writeln!(
+            f,
+            "PC:{pc:0>4X} A:{a:0>2X} X:{x:0>2X} Y:{y:0>2X} P:{p:0>2X} SP:{s:0>2X}"
+        )?;
+        writeln!(f, "Scanline:{} Cycle:{}", self.scanline, self.cycle)?;
+        writeln!(f)?;
+
+        writeln!(f, "[Trace]")?;
+        for line in self.trace.iter() {
+            writeln!(f, "{line}")?;
+        }
+
+        for dump in self.memory.iter() {
+            writeln!(f)?;
+            write!(f, "{dump}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_search() {
+        let mut ram = [0u8; 8];
+        ram[2] = 3;
+        ram[5] = 3;
+        let mut search = RamSearch::new(&ram);
+
+        search.filter(&ram, SearchFilter::Equal(3));
+        assert_eq!(search.candidates(), [2, 5]);
+
+        // Lose a life
+        ram[2] = 2;
+        ram[5] = 4;
+        search.filter(&ram, SearchFilter::ChangedBy(-1));
+        assert_eq!(search.candidates(), [2]);
+
+        search.filter(&ram, SearchFilter::Unchanged);
+        assert_eq!(search.candidates(), [2]);
+
+        ram[2] = 0xFF;
+        search.filter(&ram, SearchFilter::Increased);
+        assert_eq!(search.candidates(), [2]);
+
+        // Wraps around like the value in RAM
+        ram[2] = 0x01;
+        search.filter(&ram, SearchFilter::ChangedBy(2));
+        assert_eq!(search.candidates(), [2]);
+
+        search.filter(&ram, SearchFilter::Changed);
+        assert!(search.candidates().is_empty());
+    }
+}
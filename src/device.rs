@@ -3,6 +3,18 @@ pub mod controller;
 pub mod ppu;
 pub mod vram;
 
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// Contents of RAM at power-up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zero,
+    Fill(u8),
+    /// Pseudo-random contents generated from the seed, so runs are reproducible
+    Random(u64),
+}
+
 pub struct Ram {
     addr_mask: usize,
     mem: Box<[u8]>,
@@ -10,13 +22,38 @@ pub struct Ram {
 
 impl Ram {
     pub fn new(p2_size: usize) -> Self {
+        Self::new_with_pattern(p2_size, RamInit::Zero)
+    }
+
+    pub fn new_with_pattern(p2_size: usize, init: RamInit) -> Self {
+        let mut mem = vec![0; 1 << p2_size].into_boxed_slice();
+        match init {
+            RamInit::Zero => {}
+            RamInit::Fill(value) => mem.fill(value),
+            RamInit::Random(seed) => {
+                // xorshift64*, the state must never be zero
+                let mut state = seed | 1;
+                for byte in mem.iter_mut() {
+                    state ^= state >> 12;
+                    state ^= state << 25;
+                    state ^= state >> 27;
+                    *byte = (state.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8;
+                }
+            }
+        }
+
         Self {
             addr_mask: (1 << p2_size) - 1,
-            mem: vec![0; 1 << p2_size].into_boxed_slice(),
+            mem,
         }
     }
 
-    pub fn read(&mut self, addr: u16) -> u8 {
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.mem
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
         let addr = (addr as usize) & self.addr_mask;
         self.mem[addr]
     }
@@ -25,4 +62,12 @@ impl Ram {
         let addr = (addr as usize) & self.addr_mask;
         self.mem[addr] = data;
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.mem);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.mem)
+    }
 }
@@ -1,6 +1,8 @@
 // https://www.nesdev.org/wiki/APU
 
 use crate::cartridge::Cartridge;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::Region;
 
 struct Sequencer {
     period: u16,
@@ -26,6 +28,14 @@ impl Sequencer {
         (self.period & 0x07FF) >= 2
     }
 
+    /// Periods below the threshold produce tones above ~12kHz, which are inaudible
+    /// on most speakers and mostly turn into aliasing buzz at our sample rate
+    #[inline]
+    const fn is_triangle_ultrasonic(&self) -> bool {
+        const ULTRASONIC_PERIOD: u16 = 4;
+        (self.period & 0x07FF) < ULTRASONIC_PERIOD
+    }
+
     #[inline]
     fn set_lo(&mut self, lo: u8) {
         self.period = (self.period & 0xFF00) | (lo as u16);
@@ -53,6 +63,17 @@ impl Sequencer {
             false
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        Ok(())
+    }
 }
 
 struct Sweep {
@@ -127,11 +148,42 @@ impl Sweep {
 
         self.sequencer.clock()
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.sequencer.save_state(state);
+        state.write_bool(self.enabled);
+        state.write_u8(self.period);
+        state.write_bool(self.negate);
+        state.write_u8(self.shift);
+        state.write_bool(self.reload);
+        state.write_u8(self.divider);
+        state.write_u16(self.target_period);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sequencer.load_state(state)?;
+        self.enabled = state.read_bool()?;
+        self.period = state.read_u8()?;
+        self.negate = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        self.reload = state.read_bool()?;
+        self.divider = state.read_u8()?;
+        self.target_period = state.read_u16()?;
+        Ok(())
+    }
 }
 
+// Register writes only take effect at the end of the cycle, after the frame counter has clocked.
+// A reload on the same cycle as a clock that decrements the counter is lost,
+// and that clock still sees the old halt flag.
+// https://www.nesdev.org/wiki/APU_Length_Counter
 struct LengthCounter {
     halt: bool,
     counter: u8,
+    /// Halt flag written this cycle
+    new_halt: bool,
+    /// Value written this cycle and the counter at the time of the write
+    reload: Option<(u8, u8)>,
 }
 
 impl LengthCounter {
@@ -140,9 +192,16 @@ impl LengthCounter {
         Self {
             halt: false,
             counter: 0,
+            new_halt: false,
+            reload: None,
         }
     }
 
+    #[inline]
+    fn set_halt(&mut self, halt: bool) {
+        self.new_halt = halt;
+    }
+
     #[inline]
     fn load(&mut self, value: u8) {
         const LOAD_TABLE: [u8; 0x20] = [
@@ -150,7 +209,25 @@ impl LengthCounter {
             96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
         ];
 
-        self.counter = LOAD_TABLE[((value & 0xF8) >> 3) as usize];
+        self.reload = Some((LOAD_TABLE[((value & 0xF8) >> 3) as usize], self.counter));
+    }
+
+    /// Applies the writes of this cycle, called once per CPU cycle after clocking
+    #[inline]
+    fn apply_writes(&mut self) {
+        if let Some((value, previous)) = self.reload.take() {
+            if self.counter == previous {
+                self.counter = value;
+            }
+        }
+        self.halt = self.new_halt;
+    }
+
+    /// Disabling the channel clears the counter immediately
+    #[inline]
+    fn clear(&mut self) {
+        self.counter = 0;
+        self.reload = None;
     }
 
     #[inline]
@@ -159,9 +236,53 @@ impl LengthCounter {
             self.counter -= 1;
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.halt);
+        state.write_u8(self.counter);
+        state.write_bool(self.new_halt);
+        state.write_bool(self.reload.is_some());
+        let (value, previous) = self.reload.unwrap_or_default();
+        state.write_u8(value);
+        state.write_u8(previous);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.halt = state.read_bool()?;
+        self.counter = state.read_u8()?;
+        self.new_halt = state.read_bool()?;
+        let has_reload = state.read_bool()?;
+        let value = state.read_u8()?;
+        let previous = state.read_u8()?;
+        self.reload = has_reload.then_some((value, previous));
+        Ok(())
+    }
 }
 
-const VOLUME_SCALE: f32 = 15.0;
+// Non-linear mixer of the APU's output pins, see https://www.nesdev.org/wiki/APU_Mixer
+const PULSE_TABLE: [f32; 31] = {
+    let mut table = [0.0; 31];
+    let mut n = 1;
+    while n < table.len() {
+        table[n] = 95.52 / ((8128.0 / (n as f32)) + 100.0);
+        n += 1;
+    }
+    table
+};
+
+/// Indexed by `3 * triangle + 2 * noise + dmc`
+const TND_TABLE: [f32; 203] = {
+    let mut table = [0.0; 203];
+    let mut n = 1;
+    while n < table.len() {
+        table[n] = 163.67 / ((24329.0 / (n as f32)) + 100.0);
+        n += 1;
+    }
+    table
+};
+
+/// The mixer outputs up to about 1.0, this keeps the full range within what the audio output expects
+const OUTPUT_SCALE: f32 = 0.1;
 
 struct Envelope {
     length_counter: LengthCounter,
@@ -186,15 +307,15 @@ impl Envelope {
         }
     }
 
-    fn get_volume(&self) -> f32 {
+    const fn volume(&self) -> u8 {
         if self.length_counter.counter > 0 {
             if self.use_constant_volume {
-                (self.volume_or_reload as f32) / VOLUME_SCALE
+                self.volume_or_reload
             } else {
-                (self.decay_counter as f32) / VOLUME_SCALE
+                self.decay_counter
             }
         } else {
-            0.0
+            0
         }
     }
 
@@ -224,9 +345,29 @@ impl Envelope {
             self.divider_counter -= 1;
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.length_counter.save_state(state);
+        state.write_bool(self.use_constant_volume);
+        state.write_u8(self.volume_or_reload);
+        state.write_bool(self.start);
+        state.write_u8(self.divider_counter);
+        state.write_u8(self.decay_counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.length_counter.load_state(state)?;
+        self.use_constant_volume = state.read_bool()?;
+        self.volume_or_reload = state.read_u8()?;
+        self.start = state.read_bool()?;
+        self.divider_counter = state.read_u8()?;
+        self.decay_counter = state.read_u8()?;
+        Ok(())
+    }
 }
 
 struct PulseChannel {
+    duty: u8,
     sequence: u8,
     sequence_pos: u8,
     enabled: bool,
@@ -239,6 +380,7 @@ impl PulseChannel {
 
     const fn new(is_channel_1: bool) -> Self {
         Self {
+            duty: 0,
             sequence: Self::SEQUENCES[0],
             sequence_pos: 0,
             enabled: true,
@@ -250,9 +392,9 @@ impl PulseChannel {
     fn write(&mut self, address: u8, data: u8) {
         match address {
             0 => {
-                let sequence_index = ((data & 0xC0) >> 6) as usize;
-                self.sequence = Self::SEQUENCES[sequence_index];
-                self.envelope.length_counter.halt = (data & 0x20) != 0;
+                self.duty = (data & 0xC0) >> 6;
+                self.sequence = Self::SEQUENCES[self.duty as usize];
+                self.envelope.length_counter.set_halt((data & 0x20) != 0);
                 self.envelope.set(data);
             }
             1 => {
@@ -263,7 +405,9 @@ impl PulseChannel {
             }
             3 => {
                 self.sweep.sequencer.set_hi(data);
-                self.envelope.length_counter.load(data);
+                if self.enabled {
+                    self.envelope.length_counter.load(data);
+                }
                 self.envelope.start = true;
             }
             _ => {
@@ -286,15 +430,47 @@ impl PulseChannel {
         }
     }
 
-    fn sample(&mut self) -> f32 {
+    fn sample(&mut self) -> u8 {
         if self.enabled && self.sweep.sequencer.is_pulse_enabled() {
             let mask: u8 = 0x01 << self.sequence_pos;
             let output = (self.sequence & mask) >> self.sequence_pos;
-            ((output as f32) * 2.0 - 1.0) * self.envelope.get_volume()
+            output * self.envelope.volume()
         } else {
-            0.0
+            0
         }
     }
+
+    fn state(&self, cpu_clock_speed: f64) -> PulseState {
+        let volume = if self.enabled && self.sweep.sequencer.is_pulse_enabled() {
+            self.envelope.volume()
+        } else {
+            0
+        };
+
+        PulseState {
+            period: self.sweep.sequencer.period & 0x07FF,
+            duty: self.duty,
+            volume,
+            cpu_clock_speed,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.duty);
+        state.write_u8(self.sequence_pos);
+        state.write_bool(self.enabled);
+        self.sweep.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.read_u8()? & 0x03;
+        self.sequence = Self::SEQUENCES[self.duty as usize];
+        self.sequence_pos = state.read_u8()? & 0x07;
+        self.enabled = state.read_bool()?;
+        self.sweep.load_state(state)?;
+        self.envelope.load_state(state)
+    }
 }
 
 struct TriangleChannel {
@@ -305,6 +481,7 @@ struct TriangleChannel {
     linear_counter: u8,
     linear_counter_reload: u8,
     reload: bool,
+    silence_ultrasonic: bool,
 }
 
 impl TriangleChannel {
@@ -317,13 +494,23 @@ impl TriangleChannel {
             linear_counter: 0,
             linear_counter_reload: 0,
             reload: false,
+            silence_ultrasonic: false,
         }
     }
 
+    #[inline]
+    const fn is_active(&self) -> bool {
+        self.enabled
+            && self.sequencer.is_triangle_enabled()
+            && !(self.silence_ultrasonic && self.sequencer.is_triangle_ultrasonic())
+            && (self.length_counter.counter > 0)
+            && (self.linear_counter > 0)
+    }
+
     fn write(&mut self, address: u8, data: u8) {
         match address {
             0 => {
-                self.length_counter.halt = (data & 0x80) != 0;
+                self.length_counter.set_halt((data & 0x80) != 0);
                 self.linear_counter_reload = data & 0x7F;
             }
             1 => {}
@@ -332,7 +519,9 @@ impl TriangleChannel {
             }
             3 => {
                 self.sequencer.set_hi(data);
-                self.length_counter.load(data);
+                if self.enabled {
+                    self.length_counter.load(data);
+                }
                 self.reload = true;
             }
             _ => {
@@ -363,52 +552,49 @@ impl TriangleChannel {
         }
     }
 
-    fn sample(&mut self) -> f32 {
-        const SEQUENCE: [f32; 32] = [
-            (15.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (14.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (13.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (12.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (11.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (10.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (9.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (8.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (7.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (6.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (5.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (4.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (3.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (2.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (1.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (0.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (0.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (1.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (2.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (3.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (4.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (5.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (6.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (7.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (8.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (9.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (10.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (11.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (12.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (13.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (14.0 / VOLUME_SCALE) * 2.0 - 1.0,
-            (15.0 / VOLUME_SCALE) * 2.0 - 1.0,
+    fn sample(&mut self) -> u8 {
+        #[rustfmt::skip]
+        const SEQUENCE: [u8; 32] = [
+            15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
         ];
 
-        if self.enabled
-            && self.sequencer.is_triangle_enabled()
-            && (self.length_counter.counter > 0)
-            && (self.linear_counter > 0)
-        {
+        if self.is_active() {
             SEQUENCE[self.sequence_pos as usize]
         } else {
-            0.0
+            0
         }
     }
+
+    fn state(&self, cpu_clock_speed: f64) -> TriangleState {
+        TriangleState {
+            period: self.sequencer.period & 0x07FF,
+            active: self.is_active(),
+            cpu_clock_speed,
+        }
+    }
+
+    /// `silence_ultrasonic` is a frontend setting and not part of the state
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.sequence_pos);
+        state.write_bool(self.enabled);
+        self.sequencer.save_state(state);
+        self.length_counter.save_state(state);
+        state.write_u8(self.linear_counter);
+        state.write_u8(self.linear_counter_reload);
+        state.write_bool(self.reload);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sequence_pos = state.read_u8()? & 0x1F;
+        self.enabled = state.read_bool()?;
+        self.sequencer.load_state(state)?;
+        self.length_counter.load_state(state)?;
+        self.linear_counter = state.read_u8()?;
+        self.linear_counter_reload = state.read_u8()?;
+        self.reload = state.read_bool()?;
+        Ok(())
+    }
 }
 
 struct NoiseChannel {
@@ -430,24 +616,33 @@ impl NoiseChannel {
         }
     }
 
-    fn write(&mut self, address: u8, data: u8) {
+    fn write(&mut self, address: u8, data: u8, region: Region) {
         const PERIOD_LOOKUP: [u16; 16] = [
             4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
         ];
+        const PERIOD_LOOKUP_PAL: [u16; 16] = [
+            4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+        ];
+        let period_lookup = match region {
+            Region::Ntsc | Region::Dendy => &PERIOD_LOOKUP,
+            Region::Pal => &PERIOD_LOOKUP_PAL,
+        };
 
         match address {
             0 => {
-                self.envelope.length_counter.halt = (data & 0x20) != 0;
+                self.envelope.length_counter.set_halt((data & 0x20) != 0);
                 self.envelope.set(data);
             }
             1 => {}
             2 => {
                 self.mode = (data & 0x80) != 0;
                 self.sequencer
-                    .set_period(PERIOD_LOOKUP[(data & 0x0F) as usize] - 1);
+                    .set_period(period_lookup[(data & 0x0F) as usize] - 1);
             }
             3 => {
-                self.envelope.length_counter.load(data);
+                if self.enabled {
+                    self.envelope.length_counter.load(data);
+                }
                 self.envelope.start = true;
             }
             _ => {
@@ -478,18 +673,41 @@ impl NoiseChannel {
         }
     }
 
-    fn sample(&mut self) -> f32 {
+    fn sample(&mut self) -> u8 {
         if self.enabled && ((self.shift & 0x0001) == 0) {
-            let volume = self.envelope.get_volume();
-            if volume == 0.0 {
-                0.0
-            } else {
-                volume * 2.0 - 1.0
-            }
+            self.envelope.volume()
         } else {
-            0.0
+            0
         }
     }
+
+    fn state(&self) -> NoiseState {
+        NoiseState {
+            period: self.sequencer.period,
+            mode: self.mode,
+            volume: if self.enabled {
+                self.envelope.volume()
+            } else {
+                0
+            },
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u16(self.shift);
+        state.write_bool(self.mode);
+        self.sequencer.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.shift = state.read_u16()?;
+        self.mode = state.read_bool()?;
+        self.sequencer.load_state(state)?;
+        self.envelope.load_state(state)
+    }
 }
 
 const DMC_BASE_ADDRESS: u16 = 0xC000;
@@ -503,10 +721,10 @@ struct SampleReader {
     loop_enabled: bool,
     current_pos: u16,
     bytes_remaining: u16,
-    current: u8,
+    sample_buffer: Option<u8>,
+    shift: u8,
     bits_remaining: u8,
-    output: bool,
-    has_ended: bool,
+    silence: bool,
 }
 
 impl SampleReader {
@@ -515,15 +733,15 @@ impl SampleReader {
         Self {
             address: DMC_BASE_ADDRESS,
             length: 0x0001,
-            irq_enabled: true,
+            irq_enabled: false,
             irq: false,
             loop_enabled: false,
             current_pos: DMC_BASE_ADDRESS,
             bytes_remaining: 0,
-            current: 0,
-            bits_remaining: 0,
-            output: false,
-            has_ended: true,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
         }
     }
 
@@ -551,19 +769,12 @@ impl SampleReader {
         if self.bytes_remaining == 0 {
             self.current_pos = self.address;
             self.bytes_remaining = self.length;
-            self.has_ended = false;
         }
     }
 
     #[inline]
     fn halt(&mut self) {
         self.bytes_remaining = 0;
-        self.has_ended = true;
-    }
-
-    #[inline]
-    const fn output(&self) -> bool {
-        self.output
     }
 
     #[inline]
@@ -578,36 +789,96 @@ impl SampleReader {
 
     #[inline]
     const fn has_ended(&self) -> bool {
-        self.has_ended
+        self.bytes_remaining == 0
     }
 
-    fn clock(&mut self, cart: &mut Cartridge) {
-        if self.bits_remaining == 0 {
-            self.bits_remaining = 8;
+    #[inline]
+    const fn is_silent(&self) -> bool {
+        self.silence
+    }
 
-            if !self.has_ended {
-                if self.bytes_remaining == 0 {
-                    self.has_ended = true;
+    /// Address of the next sample byte, if the sample buffer needs to be refilled
+    #[inline]
+    const fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && (self.bytes_remaining > 0) {
+            Some(self.current_pos)
+        } else {
+            None
+        }
+    }
 
-                    if self.loop_enabled {
-                        self.restart();
-                    } else if self.irq_enabled {
-                        self.irq = true;
-                    }
-                }
+    fn dma_complete(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
 
-                self.current = cart.cpu_read(self.current_pos);
-                self.current_pos = self.current_pos.wrapping_add(1);
-                if self.current_pos == 0 {
-                    self.current_pos = DMC_WRAP_ADDRESS;
-                }
-                self.bytes_remaining = self.bytes_remaining.wrapping_sub(1);
+        self.current_pos = self.current_pos.wrapping_add(1);
+        if self.current_pos == 0 {
+            self.current_pos = DMC_WRAP_ADDRESS;
+        }
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_enabled {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
             }
         }
+    }
 
-        self.output = (self.current & 0x01) != 0;
-        self.current >>= 1;
+    /// Returns the next bit of the sample, `None` while the output unit is silenced
+    // https://www.nesdev.org/wiki/APU_DMC#Output_unit
+    fn clock(&mut self) -> Option<bool> {
+        let output = (!self.silence).then_some((self.shift & 0x01) != 0);
+        self.shift >>= 1;
         self.bits_remaining -= 1;
+
+        // The next output cycle starts right away, emptying the sample buffer so the memory reader
+        // can fetch the following byte during this cycle
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.shift = data;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+
+        output
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.address);
+        state.write_u16(self.length);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq);
+        state.write_bool(self.loop_enabled);
+        state.write_u16(self.current_pos);
+        state.write_u16(self.bytes_remaining);
+        state.write_bool(self.sample_buffer.is_some());
+        state.write_u8(self.sample_buffer.unwrap_or(0));
+        state.write_u8(self.shift);
+        state.write_u8(self.bits_remaining);
+        state.write_bool(self.silence);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.address = state.read_u16()?;
+        self.length = state.read_u16()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq = state.read_bool()?;
+        self.loop_enabled = state.read_bool()?;
+        self.current_pos = state.read_u16()?;
+        self.bytes_remaining = state.read_u16()?;
+        let has_sample = state.read_bool()?;
+        let sample = state.read_u8()?;
+        self.sample_buffer = has_sample.then_some(sample);
+        self.shift = state.read_u8()?;
+        self.bits_remaining = state.read_u8()?.clamp(1, 8);
+        self.silence = state.read_bool()?;
+        Ok(())
     }
 }
 
@@ -630,15 +901,22 @@ impl DmcChannel {
         }
     }
 
-    fn write(&mut self, address: u8, data: u8) {
+    fn write(&mut self, address: u8, data: u8, region: Region) {
         const RATE_LOOKUP: [u8; 16] = [
             214, 190, 170, 160, 143, 127, 113, 107, 95, 80, 71, 64, 53, 42, 36, 27,
         ];
+        const RATE_LOOKUP_PAL: [u8; 16] = [
+            199, 177, 158, 149, 138, 118, 105, 99, 88, 74, 66, 59, 49, 39, 33, 25,
+        ];
+        let rate_lookup = match region {
+            Region::Ntsc | Region::Dendy => &RATE_LOOKUP,
+            Region::Pal => &RATE_LOOKUP_PAL,
+        };
 
         match address {
             0 => {
                 self.reader.set_flags(data);
-                self.rate = RATE_LOOKUP[(data & 0x0F) as usize] + 1;
+                self.rate = rate_lookup[(data & 0x0F) as usize];
             }
             1 => {
                 self.output = data & 0x7F;
@@ -655,37 +933,232 @@ impl DmcChannel {
         }
     }
 
-    fn clock(&mut self, cart: &mut Cartridge) {
+    fn clock(&mut self) {
         self.cycles = self.cycles.wrapping_add(1);
         if self.cycles == self.rate {
             self.cycles = 0;
 
-            self.reader.clock(cart);
-            if !self.reader.has_ended() {
-                if self.reader.output() {
-                    if self.output <= 125 {
-                        self.output += 2;
-                    }
-                } else if self.output >= 2 {
-                    self.output -= 2;
-                }
+            match self.reader.clock() {
+                Some(true) if self.output <= 125 => self.output += 2,
+                Some(false) if self.output >= 2 => self.output -= 2,
+                _ => {}
             }
         }
     }
 
-    fn sample(&mut self) -> f32 {
-        if self.enabled && !self.reader.has_ended {
-            (self.output as f32) / VOLUME_SCALE
-        } else {
-            0.5
+    /// The output level is held after a sample ends or the channel is disabled,
+    /// dropping it would cause a click
+    fn sample(&mut self) -> u8 {
+        self.output
+    }
+
+    fn state(&self) -> DmcState {
+        DmcState {
+            rate: self.rate,
+            output: self.output,
+            active: self.enabled && !self.reader.is_silent(),
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.rate);
+        state.write_u8(self.output);
+        self.reader.save_state(state);
+        state.write_u8(self.cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.rate = state.read_u8()?;
+        self.output = state.read_u8()?;
+        self.reader.load_state(state)?;
+        self.cycles = state.read_u8()?;
+        Ok(())
+    }
+}
+
+/// Receives the mixed audio output of the APU at `crate::SAMPLE_RATE`
+pub trait AudioSink {
+    fn push_sample(&mut self, sample: crate::Sample);
+}
+
+/// Samples that don't fit because the consumer isn't draining fast enough are dropped
+impl AudioSink for crate::SampleBuffer {
+    #[inline]
+    fn push_sample(&mut self, sample: crate::Sample) {
+        use ringbuf::traits::Producer;
+
+        let _ = self.try_push(sample);
+    }
 }
 
-const APU_CLOCK_SPEED: f64 = 1_789_773.0 / 2.0; // CPU clock / 2 because APU only emits samples on even cycles
-const SECONDS_PER_APU_CLOCK: f64 = 1.0 / APU_CLOCK_SPEED;
 const SECONDS_PER_SAMPLE: f64 = 1.0 / (crate::SAMPLE_RATE as f64);
 
+/// One-pole high-pass filter running at the output sample rate
+struct HighPass {
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl HighPass {
+    const fn new(cutoff: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = SECONDS_PER_SAMPLE as f32;
+        Self {
+            alpha: rc / (rc + dt),
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.alpha * (self.prev_output + input - self.prev_input);
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+/// One-pole low-pass filter running at the output sample rate
+struct LowPass {
+    alpha: f32,
+    prev_output: f32,
+}
+
+impl LowPass {
+    const fn new(cutoff: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = SECONDS_PER_SAMPLE as f32;
+        Self {
+            alpha: dt / (rc + dt),
+            prev_output: 0.0,
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, input: f32) -> f32 {
+        self.prev_output += self.alpha * (input - self.prev_output);
+        self.prev_output
+    }
+}
+
+/// The filters between the APU and the audio output of a NES,
+/// see https://www.nesdev.org/wiki/APU_Mixer
+struct OutputFilter {
+    enabled: bool,
+    high_pass_1: HighPass,
+    high_pass_2: HighPass,
+    low_pass: LowPass,
+}
+
+impl OutputFilter {
+    const fn new() -> Self {
+        Self {
+            enabled: true,
+            high_pass_1: HighPass::new(90.0),
+            high_pass_2: HighPass::new(440.0),
+            low_pass: LowPass::new(14_000.0),
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, sample: f32) -> f32 {
+        if self.enabled {
+            let sample = self.high_pass_1.process(sample);
+            let sample = self.high_pass_2.process(sample);
+            self.low_pass.process(sample)
+        } else {
+            sample
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PulseState {
+    /// Raw 11 bit timer period
+    pub period: u16,
+    /// Duty cycle index (0 = 12.5%, 1 = 25%, 2 = 50%, 3 = 25% negated)
+    pub duty: u8,
+    /// Current volume in the range 0-15
+    pub volume: u8,
+    /// Clock rate of the console's region in Hz, the period is counted in CPU cycles
+    pub cpu_clock_speed: f64,
+}
+
+impl PulseState {
+    /// Output frequency in Hz
+    #[inline]
+    pub fn frequency(&self) -> f64 {
+        self.cpu_clock_speed / (16.0 * ((self.period as f64) + 1.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TriangleState {
+    /// Raw 11 bit timer period
+    pub period: u16,
+    /// Whether the channel is currently producing output
+    pub active: bool,
+    /// Clock rate of the console's region in Hz, the period is counted in CPU cycles
+    pub cpu_clock_speed: f64,
+}
+
+impl TriangleState {
+    /// Output frequency in Hz
+    #[inline]
+    pub fn frequency(&self) -> f64 {
+        self.cpu_clock_speed / (32.0 * ((self.period as f64) + 1.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NoiseState {
+    /// Raw timer period, one less than the rate table entry selected by $400E
+    pub period: u16,
+    /// Short (93 step) mode
+    pub mode: bool,
+    /// Current volume in the range 0-15
+    pub volume: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DmcState {
+    /// Output rate in APU cycles
+    pub rate: u8,
+    /// Current output level in the range 0-127
+    pub output: u8,
+    /// Whether a sample is currently playing
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+/// Snapshot of the channel registers and IRQ sources, meant for visualization and debugging
+#[derive(Debug, Clone, Copy)]
+pub struct ApuState {
+    pub pulse_1: PulseState,
+    pub pulse_2: PulseState,
+    pub triangle: TriangleState,
+    pub noise: NoiseState,
+    pub dmc: DmcState,
+    /// Whether the frame counter is asserting its IRQ
+    pub frame_irq: bool,
+    /// Whether the DMC is asserting its IRQ
+    pub dmc_irq: bool,
+    /// Step of the frame counter sequence, 0-3 in 4 step mode and 0-4 in 5 step mode
+    pub frame_counter_step: u8,
+}
+
 pub struct Apu {
     pulse_channel_1: PulseChannel,
     pulse_channel_2: PulseChannel,
@@ -695,13 +1168,27 @@ pub struct Apu {
     counter_mode: bool,
     even_cycle: bool,
     cycles: u32,
+    /// CPU cycles until a write to $4017 resets the frame counter, 0 if none is pending
+    counter_reset_delay: u8,
     inhibit_irq: bool,
     irq: bool,
     t: f64,
+    /// Mixer output integrated over time since the last emitted sample
+    sample_sum: f64,
+    seconds_per_sample: f64,
+    region: Region,
+    /// The APU runs at half the CPU clock
+    seconds_per_apu_clock: f64,
+    filter: OutputFilter,
+    master_volume: f32,
+    /// Indexed by `Channel`
+    channels_enabled: [bool; 5],
+    /// Indexed by `Channel`, output levels at the time of the last emitted sample
+    channel_outputs: [f32; 5],
 }
 
 impl Apu {
-    pub const fn new() -> Self {
+    pub const fn new(region: Region) -> Self {
         let pulse_channel_1 = PulseChannel::new(true);
         let pulse_channel_2 = PulseChannel::new(false);
         let triangle_channel = TriangleChannel::new();
@@ -717,52 +1204,202 @@ impl Apu {
             counter_mode: false,
             even_cycle: false,
             cycles: 0,
+            counter_reset_delay: 0,
             inhibit_irq: true,
             irq: false,
             t: 0.0,
+            sample_sum: 0.0,
+            seconds_per_sample: SECONDS_PER_SAMPLE,
+            region,
+            seconds_per_apu_clock: 2.0 / region.cpu_clock_speed(),
+            filter: OutputFilter::new(),
+            master_volume: 1.0,
+            channels_enabled: [true; 5],
+            channel_outputs: [0.0; 5],
         }
     }
 
+    /// Returns to the power-up state, frontend settings like the speed, volume and muted channels are kept
+    pub fn power_cycle(&mut self) {
+        let silence_ultrasonic = self.triangle_channel.silence_ultrasonic;
+        let filter_enabled = self.filter.enabled;
+
+        *self = Self {
+            seconds_per_sample: self.seconds_per_sample,
+            master_volume: self.master_volume,
+            channels_enabled: self.channels_enabled,
+            ..Self::new(self.region)
+        };
+        self.triangle_channel.silence_ultrasonic = silence_ultrasonic;
+        self.filter.enabled = filter_enabled;
+    }
+
     pub fn reset(&mut self) {
         self.pulse_channel_1.enabled = false;
-        self.pulse_channel_1.envelope.length_counter.counter = 0;
+        self.pulse_channel_1.envelope.length_counter.clear();
 
         self.pulse_channel_2.enabled = false;
-        self.pulse_channel_2.envelope.length_counter.counter = 0;
+        self.pulse_channel_2.envelope.length_counter.clear();
 
         self.triangle_channel.enabled = false;
-        self.triangle_channel.length_counter.counter = 0;
+        self.triangle_channel.length_counter.clear();
 
         self.noise_channel.enabled = false;
-        self.noise_channel.envelope.length_counter.counter = 0;
+        self.noise_channel.envelope.length_counter.clear();
+    }
+
+    /// Frontend settings like the speed, volume and muted channels are not part of the state,
+    /// neither are the partially accumulated sample and the state of the output filter
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.pulse_channel_1.save_state(state);
+        self.pulse_channel_2.save_state(state);
+        self.triangle_channel.save_state(state);
+        self.noise_channel.save_state(state);
+        self.dmc_channel.save_state(state);
+        state.write_bool(self.counter_mode);
+        state.write_bool(self.even_cycle);
+        state.write_u32(self.cycles);
+        state.write_u8(self.counter_reset_delay);
+        state.write_bool(self.inhibit_irq);
+        state.write_bool(self.irq);
+        state.write_f64(self.t);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_channel_1.load_state(state)?;
+        self.pulse_channel_2.load_state(state)?;
+        self.triangle_channel.load_state(state)?;
+        self.noise_channel.load_state(state)?;
+        self.dmc_channel.load_state(state)?;
+        self.counter_mode = state.read_bool()?;
+        self.even_cycle = state.read_bool()?;
+        self.cycles = state.read_u32()?;
+        self.counter_reset_delay = state.read_u8()?;
+        self.inhibit_irq = state.read_bool()?;
+        self.irq = state.read_bool()?;
+        self.t = state.read_f64()?;
+        self.sample_sum = 0.0;
+        Ok(())
+    }
+
+    /// Mutes the triangle channel at very high frequencies instead of playing them like hardware does
+    #[inline]
+    pub fn set_silence_ultrasonic(&mut self, silence_ultrasonic: bool) {
+        self.triangle_channel.silence_ultrasonic = silence_ultrasonic;
+    }
+
+    /// Turns the output filter on or off, without it the raw mixer output is emitted
+    pub fn set_filter_enabled(&mut self, enabled: bool) {
+        self.filter.enabled = enabled;
+    }
+
+    #[inline]
+    pub fn master_volume(&self) -> f32 {
+        self.master_volume
     }
 
+    /// Scales the final output including expansion audio, 1.0 is the original volume
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = volume.max(0.0);
+    }
+
+    #[inline]
+    pub fn channel_enabled(&self, channel: Channel) -> bool {
+        self.channels_enabled[channel as usize]
+    }
+
+    /// A disabled channel keeps running but doesn't contribute to the mix
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.channels_enabled[channel as usize] = enabled;
+    }
+
+    /// Emulates `speed` times as much time per output sample, which pitches the audio up
+    /// and makes frontends that are paced by audio playback run `speed` times as fast
+    pub fn set_speed(&mut self, speed: f64) {
+        self.seconds_per_sample = SECONDS_PER_SAMPLE * speed;
+    }
+
+    pub fn state(&self) -> ApuState {
+        ApuState {
+            pulse_1: self.pulse_channel_1.state(self.region.cpu_clock_speed()),
+            pulse_2: self.pulse_channel_2.state(self.region.cpu_clock_speed()),
+            triangle: self.triangle_channel.state(self.region.cpu_clock_speed()),
+            noise: self.noise_channel.state(),
+            dmc: self.dmc_channel.state(),
+            frame_irq: self.frame_irq_pending(),
+            dmc_irq: self.dmc_irq_pending(),
+            frame_counter_step: self.frame_counter_step(),
+        }
+    }
+
+    /// Whether the DMC is asserting its IRQ because a sample ended
     #[inline]
-    pub const fn dmc_irq_requested(&self) -> bool {
+    pub const fn dmc_irq_pending(&self) -> bool {
         self.dmc_channel.reader.irq()
     }
 
+    /// Whether the frame counter is asserting its IRQ, reading $4015 acknowledges it
     #[inline]
-    pub const fn irq_requested(&self) -> bool {
+    pub const fn frame_irq_pending(&self) -> bool {
         self.irq
     }
 
-    pub fn clock(&mut self, cart: &mut Cartridge, sample_buffer: &mut crate::SampleBuffer) {
-        use ringbuf::traits::Producer;
+    /// Step of the frame counter sequence the APU is currently in, 0-3 in 4 step mode and 0-4 in 5 step mode
+    pub fn frame_counter_step(&self) -> u8 {
+        let step_count = if self.counter_mode { 5 } else { 4 };
+        let steps = self.frame_counter_steps();
+        steps[..(step_count - 1)]
+            .iter()
+            .filter(|&&step| self.cycles >= step)
+            .count() as u8
+    }
 
+    /// APU cycles of the quarter and half frame steps, then the end of the 4 and 5 step sequences
+    // https://www.nesdev.org/wiki/APU_Frame_Counter
+    const fn frame_counter_steps(&self) -> [u32; 5] {
+        match self.region {
+            Region::Ntsc | Region::Dendy => [3729, 7457, 11186, 14915, 18641],
+            Region::Pal => [4157, 8314, 12470, 16627, 20783],
+        }
+    }
+
+    /// Address the DMC wants to read its next sample byte from
+    #[inline]
+    pub const fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc_channel.reader.dma_request()
+    }
+
+    #[inline]
+    pub fn dmc_dma_complete(&mut self, data: u8) {
+        self.dmc_channel.reader.dma_complete(data);
+    }
+
+    pub fn clock<S: AudioSink + ?Sized>(&mut self, cart: &Cartridge, sink: &mut S) {
         self.even_cycle = !self.even_cycle;
 
+        // Switching to 5 step mode clocks the envelopes and length counters right away
+        let mut reset_clock = false;
+        if self.counter_reset_delay > 0 {
+            self.counter_reset_delay -= 1;
+            if self.counter_reset_delay == 0 {
+                self.cycles = 0;
+                reset_clock = self.counter_mode;
+            }
+        }
+
         if self.even_cycle {
             self.cycles += 1;
         }
 
+        let steps = self.frame_counter_steps();
+
         let full = if self.counter_mode {
-            self.cycles == 18641
+            self.cycles == steps[4]
         } else {
-            self.cycles == 14915
+            self.cycles == steps[3]
         };
-        let half = (self.cycles == 7457) || full;
-        let quarter = (self.cycles == 3729) || (self.cycles == 11186) || half;
+        let half = (self.cycles == steps[1]) || full || reset_clock;
+        let quarter = (self.cycles == steps[0]) || (self.cycles == steps[2]) || half;
         if full {
             self.cycles = 0;
             if !self.inhibit_irq && !self.counter_mode {
@@ -777,27 +1414,77 @@ impl Apu {
             self.pulse_channel_1.clock(quarter, half);
             self.pulse_channel_2.clock(quarter, half);
             self.noise_channel.clock(quarter, half);
-            self.dmc_channel.clock(cart);
+            self.dmc_channel.clock();
+
+            let raw_samples = [
+                self.pulse_channel_1.sample(),
+                self.pulse_channel_2.sample(),
+                self.triangle_channel.sample(),
+                self.noise_channel.sample(),
+                self.dmc_channel.sample(),
+            ];
+
+            let [pulse_1_enabled, pulse_2_enabled, triangle_enabled, noise_enabled, dmc_enabled] =
+                self.channels_enabled;
+            let pulse_1_sample = raw_samples[0] * (pulse_1_enabled as u8);
+            let pulse_2_sample = raw_samples[1] * (pulse_2_enabled as u8);
+            let triangle_sample = raw_samples[2] * (triangle_enabled as u8);
+            let noise_sample = raw_samples[3] * (noise_enabled as u8);
+            let dmc_sample = raw_samples[4] * (dmc_enabled as u8);
+
+            let pulse_index = (pulse_1_sample + pulse_2_sample) as usize;
+            let tnd_index = (3 * (triangle_sample as usize))
+                + (2 * (noise_sample as usize))
+                + (dmc_sample as usize);
+            let sample = (PULSE_TABLE[pulse_index] + TND_TABLE[tnd_index] + cart.audio_sample())
+                * OUTPUT_SCALE
+                * self.master_volume;
+
+            // Every emitted sample is the average of the mixer output over its period,
+            // which filters out most of what would otherwise alias into the audible range.
+            // `remaining` is the part of this APU cycle not yet added to a sample.
+            let mut remaining = self.seconds_per_apu_clock;
+            self.t += self.seconds_per_apu_clock;
+            while self.t >= 0.0 {
+                self.sample_sum += (sample as f64) * (remaining - self.t);
+                let average = (self.sample_sum / self.seconds_per_sample) as f32;
+                sink.push_sample(self.filter.process(average));
+                self.store_channel_outputs(raw_samples);
+
+                self.sample_sum = 0.0;
+                remaining = self.t;
+                self.t -= self.seconds_per_sample;
+            }
+            self.sample_sum += (sample as f64) * remaining;
+        }
 
-            let pulse_1_sample = self.pulse_channel_1.sample();
-            let pulse_2_sample = self.pulse_channel_2.sample();
-            let triangle_sample = self.triangle_channel.sample();
-            let noise_sample = self.noise_channel.sample();
-            let dmc_sample = self.dmc_channel.sample();
+        self.pulse_channel_1.envelope.length_counter.apply_writes();
+        self.pulse_channel_2.envelope.length_counter.apply_writes();
+        self.triangle_channel.length_counter.apply_writes();
+        self.noise_channel.envelope.length_counter.apply_writes();
+    }
 
-            let sample = (0.00752 * (pulse_1_sample + pulse_2_sample))
-                + (0.00851 * triangle_sample)
-                + (0.00494 * noise_sample)
-                + (0.00335 * dmc_sample) * VOLUME_SCALE;
+    fn store_channel_outputs(&mut self, raw_samples: [u8; 5]) {
+        // Pulse, triangle and noise have 4 bit DACs, the DMC a 7 bit DAC
+        const MAX_LEVELS: [f32; 5] = [15.0, 15.0, 15.0, 15.0, 127.0];
 
-            self.t += SECONDS_PER_APU_CLOCK;
-            while self.t >= 0.0 {
-                self.t -= SECONDS_PER_SAMPLE;
-                sample_buffer.try_push(sample).unwrap();
-            }
+        for ((output, sample), max_level) in self
+            .channel_outputs
+            .iter_mut()
+            .zip(raw_samples)
+            .zip(MAX_LEVELS)
+        {
+            *output = (sample as f32) / max_level;
         }
     }
 
+    /// Output level of every channel before mixing at the time of the last emitted sample,
+    /// indexed by `Channel` and scaled to 0.0-1.0. Muted channels still report their level.
+    #[inline]
+    pub fn channel_outputs(&self) -> [f32; 5] {
+        self.channel_outputs
+    }
+
     #[inline]
     pub fn write(&mut self, address: u16, data: u8) {
         let channel_index = address / 4;
@@ -806,13 +1493,21 @@ impl Apu {
             0 => self.pulse_channel_1.write(channel_address, data),
             1 => self.pulse_channel_2.write(channel_address, data),
             2 => self.triangle_channel.write(channel_address, data),
-            3 => self.noise_channel.write(channel_address, data),
-            4 => self.dmc_channel.write(channel_address, data),
+            3 => self.noise_channel.write(channel_address, data, self.region),
+            4 => self.dmc_channel.write(channel_address, data, self.region),
             _ => {}
         }
     }
 
+    /// Reads $4015 and acknowledges the frame IRQ
     pub fn read_status(&mut self) -> u8 {
+        let result = self.peek_status();
+        self.irq = false;
+        result
+    }
+
+    /// What reading $4015 would return, without acknowledging the frame IRQ
+    pub fn peek_status(&self) -> u8 {
         let mut result: u8 = 0x00;
 
         if self.pulse_channel_1.envelope.length_counter.counter > 0 {
@@ -837,8 +1532,6 @@ impl Apu {
             result |= 0x80;
         }
 
-        self.irq = false;
-
         result
     }
 
@@ -851,22 +1544,22 @@ impl Apu {
 
         self.pulse_channel_1.enabled = pulse_1_enabled;
         if !pulse_1_enabled {
-            self.pulse_channel_1.envelope.length_counter.counter = 0
+            self.pulse_channel_1.envelope.length_counter.clear();
         }
 
         self.pulse_channel_2.enabled = pulse_2_enabled;
         if !pulse_2_enabled {
-            self.pulse_channel_2.envelope.length_counter.counter = 0
+            self.pulse_channel_2.envelope.length_counter.clear();
         }
 
         self.triangle_channel.enabled = triangle_enabled;
         if !triangle_enabled {
-            self.triangle_channel.length_counter.counter = 0
+            self.triangle_channel.length_counter.clear();
         }
 
         self.noise_channel.enabled = noise_enabled;
         if !noise_enabled {
-            self.noise_channel.envelope.length_counter.counter = 0
+            self.noise_channel.envelope.length_counter.clear();
         }
 
         self.dmc_channel.enabled = dmc_enabled;
@@ -881,5 +1574,259 @@ impl Apu {
     pub fn write_frame_counter(&mut self, data: u8) {
         self.counter_mode = (data & 0x80) != 0;
         self.inhibit_irq = (data & 0x40) != 0;
+        if self.inhibit_irq {
+            self.irq = false;
+        }
+
+        // https://www.nesdev.org/wiki/APU_Frame_Counter
+        // The sequencer is reset 3 CPU cycles after a write during an APU cycle and 4 cycles after a write
+        // between APU cycles, so the reset always lands on an APU cycle. The clock of the write cycle
+        // itself counts as well.
+        self.counter_reset_delay = if self.even_cycle { 4 } else { 5 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_cartridge;
+
+    impl AudioSink for Vec<crate::Sample> {
+        fn push_sample(&mut self, sample: crate::Sample) {
+            self.push(sample);
+        }
+    }
+
+    fn cartridge() -> Cartridge {
+        test_cartridge(0, &[0; 0x4000], &[])
+    }
+
+    fn run(apu: &mut Apu, cart: &Cartridge, cycles: usize) {
+        for _ in 0..cycles {
+            apu.clock(cart, &mut Vec::new());
+        }
+    }
+
+    fn pulse_1_length(apu: &Apu) -> u8 {
+        apu.pulse_channel_1.envelope.length_counter.counter
+    }
+
+    /// Enables pulse 1 and loads its length counter with 10
+    fn load_pulse_1(apu: &mut Apu, cart: &Cartridge) {
+        apu.write_control(0x01);
+        apu.write(0x00, 0x10);
+        apu.write(0x03, 0x00);
+        run(apu, cart, 1);
+        assert_eq!(pulse_1_length(apu), 10);
+    }
+
+    #[test]
+    fn channel_outputs_are_reported_before_mixing() {
+        let cart = cartridge();
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.set_filter_enabled(false);
+
+        // Pulse 1 at constant volume 15 with a 50% duty cycle, muted in the mix
+        apu.write_control(0x01);
+        apu.write(0x00, 0xBF);
+        apu.write(0x02, 0xFF);
+        apu.write(0x03, 0x00);
+        apu.set_channel_enabled(Channel::Pulse1, false);
+
+        let mut samples = Vec::new();
+        let mut pulse_levels = Vec::new();
+        for _ in 0..20_000 {
+            apu.clock(&cart, &mut samples);
+
+            let outputs = apu.channel_outputs();
+            assert_eq!(outputs[1..], [0.0; 4]);
+            pulse_levels.push(outputs[Channel::Pulse1 as usize]);
+        }
+
+        assert!(samples.iter().all(|&sample| sample == 0.0));
+        assert!(pulse_levels.contains(&0.0));
+        assert!(pulse_levels.contains(&1.0));
+        assert!(pulse_levels
+            .iter()
+            .all(|&level| (level == 0.0) || (level == 1.0)));
+    }
+
+    #[test]
+    fn five_step_mode_clocks_immediately() {
+        let cart = cartridge();
+
+        for delay in [0, 1] {
+            let mut apu = Apu::new(Region::Ntsc);
+            load_pulse_1(&mut apu, &cart);
+            // Write both during and between APU cycles
+            run(&mut apu, &cart, delay);
+
+            apu.write_frame_counter(0x80);
+            run(&mut apu, &cart, 6);
+            assert_eq!(pulse_1_length(&apu), 9);
+        }
+
+        // 4 step mode waits for the first half frame step
+        let mut apu = Apu::new(Region::Ntsc);
+        load_pulse_1(&mut apu, &cart);
+        apu.write_frame_counter(0x00);
+        run(&mut apu, &cart, 6);
+        assert_eq!(pulse_1_length(&apu), 10);
+        run(&mut apu, &cart, 14_913);
+        assert_eq!(pulse_1_length(&apu), 9);
+    }
+
+    /// Writes to pulse 1 on the cycle a $4017 write clocks the length counters,
+    /// like blargg's len_halt_timing and len_reload_timing tests
+    fn write_on_length_clock(cart: &Cartridge, setup: fn(&mut Apu), write: fn(&mut Apu)) -> Apu {
+        // Find the cycle of the clock first, it only depends on the cycle the write happens on
+        let mut apu = Apu::new(Region::Ntsc);
+        load_pulse_1(&mut apu, cart);
+        apu.write_frame_counter(0x80);
+        let mut cycles = 0;
+        while pulse_1_length(&apu) == 10 {
+            run(&mut apu, cart, 1);
+            cycles += 1;
+        }
+
+        let mut apu = Apu::new(Region::Ntsc);
+        load_pulse_1(&mut apu, cart);
+        setup(&mut apu);
+        apu.write_frame_counter(0x80);
+        run(&mut apu, cart, cycles - 1);
+        write(&mut apu);
+        run(&mut apu, cart, 1);
+        apu
+    }
+
+    #[test]
+    fn length_counter_reload_during_clock() {
+        let cart = cartridge();
+
+        // The reload is lost if the clock decremented the counter
+        let apu = write_on_length_clock(&cart, |_| {}, |apu| apu.write(0x03, 0x08));
+        assert_eq!(pulse_1_length(&apu), 9);
+
+        // A counter at 0 is not decremented, so the reload goes through
+        let apu = write_on_length_clock(
+            &cart,
+            |apu| {
+                apu.write_control(0x00);
+                apu.write_control(0x01);
+            },
+            |apu| apu.write(0x03, 0x00),
+        );
+        assert_eq!(pulse_1_length(&apu), 10);
+    }
+
+    #[test]
+    fn length_counter_halt_during_clock() {
+        let cart = cartridge();
+
+        // The clock still sees the old halt flag
+        let mut apu = write_on_length_clock(&cart, |_| {}, |apu| apu.write(0x00, 0x30));
+        assert_eq!(pulse_1_length(&apu), 9);
+        apu.write_frame_counter(0x80);
+        run(&mut apu, &cart, 6);
+        assert_eq!(pulse_1_length(&apu), 9);
+
+        let mut apu = write_on_length_clock(
+            &cart,
+            |apu| apu.write(0x00, 0x30),
+            |apu| apu.write(0x00, 0x10),
+        );
+        assert_eq!(pulse_1_length(&apu), 10);
+        apu.write_frame_counter(0x80);
+        run(&mut apu, &cart, 6);
+        assert_eq!(pulse_1_length(&apu), 9);
+    }
+
+    /// Clocks the APU for `cycles` CPU cycles, answering every DMC read with `data`,
+    /// and returns the addresses that were read
+    fn run_dmc(apu: &mut Apu, cart: &Cartridge, cycles: usize, data: u8) -> Vec<u16> {
+        let mut reads = Vec::new();
+        for _ in 0..cycles {
+            apu.clock(cart, &mut Vec::new());
+            if let Some(addr) = apu.dmc_dma_request() {
+                reads.push(addr);
+                apu.dmc_dma_complete(data);
+            }
+        }
+        reads
+    }
+
+    #[test]
+    fn dmc_plays_the_last_byte_and_fires_irq() {
+        let cart = cartridge();
+        let mut apu = Apu::new(Region::Ntsc);
+
+        // Fastest rate with IRQ, output at 64, a single byte at $C000
+        apu.write(0x10, 0x8F);
+        apu.write(0x11, 0x40);
+        apu.write(0x12, 0x00);
+        apu.write(0x13, 0x00);
+        apu.write_control(0x10);
+        assert_eq!(apu.peek_status() & 0x10, 0x10);
+
+        let reads = run_dmc(&mut apu, &cart, 10, 0xFF);
+        assert_eq!(reads, [0xC000]);
+        // The IRQ fires once the last byte is fetched, not when it finished playing
+        assert!(apu.dmc_irq_pending());
+        assert_eq!(apu.peek_status() & 0x90, 0x80);
+
+        // All 8 bits of the byte are played, each of them raising the output by 2
+        let reads = run_dmc(&mut apu, &cart, 10_000, 0xFF);
+        assert!(reads.is_empty());
+        assert_eq!(apu.dmc_channel.output, 64 + 16);
+
+        // Restarting acknowledges the IRQ
+        apu.write_control(0x10);
+        assert!(!apu.dmc_irq_pending());
+    }
+
+    #[test]
+    fn dmc_wraps_and_loops() {
+        let cart = cartridge();
+        let mut apu = Apu::new(Region::Ntsc);
+
+        // Looping with IRQ enabled, 65 bytes starting at $FFC0
+        apu.write(0x10, 0xCF);
+        apu.write(0x12, 0xFF);
+        apu.write(0x13, 0x04);
+        apu.write_control(0x10);
+
+        // 65 bytes of 8 bits at 27 APU cycles each, plus a second round
+        let reads = run_dmc(&mut apu, &cart, 2 * 65 * 8 * 27 * 2, 0x00);
+        let sample: Vec<u16> = (0xFFC0..=0xFFFF).chain([0x8000]).collect();
+        assert_eq!(reads[..65], sample);
+        assert_eq!(reads[65..130], sample);
+
+        // Looping samples never end, so they don't fire the IRQ
+        assert!(!apu.dmc_irq_pending());
+        assert_eq!(apu.peek_status() & 0x10, 0x10);
+    }
+
+    #[test]
+    fn ultrasonic_triangle_silencing() {
+        let cart = cartridge();
+
+        let triangle_active = |period: u16, silence_ultrasonic: bool| {
+            let mut apu = Apu::new(Region::Ntsc);
+            apu.set_silence_ultrasonic(silence_ultrasonic);
+            apu.write_control(0x04);
+            apu.write(0x08, 0xFF);
+            apu.write(0x0A, period as u8);
+            apu.write(0x0B, (period >> 8) as u8);
+            // Reload the linear counter right away
+            apu.write_frame_counter(0x80);
+            run(&mut apu, &cart, 6);
+            apu.state().triangle.active
+        };
+
+        assert!(triangle_active(3, false));
+        assert!(!triangle_active(3, true));
+        assert!(triangle_active(4, true));
+        // Periods below 2 are always silent
+        assert!(!triangle_active(1, false));
     }
 }
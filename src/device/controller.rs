@@ -1,3 +1,4 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
 use bitflags::bitflags;
 
 bitflags! {
@@ -21,10 +22,44 @@ pub enum ControllerPort {
     PortB = 1,
 }
 
+/// Light gun plugged into port B
+#[derive(Clone, Copy, Default)]
+pub struct Zapper {
+    /// Screen pixel the gun points at, `None` while aiming off screen
+    aim: Option<(usize, usize)>,
+    trigger: bool,
+}
+
+impl Zapper {
+    /// Builds the value read from the port, `senses_light` reports whether the
+    /// photodiode currently sees a bright pixel at the given screen position
+    pub fn read(&self, senses_light: impl FnOnce(usize, usize) -> bool) -> u8 {
+        let light = self.aim.is_some_and(|(x, y)| senses_light(x, y));
+
+        // The light sense bit is active low, the trigger bit active high
+        let mut result = 0;
+        if !light {
+            result |= 0b00001000;
+        }
+        if self.trigger {
+            result |= 0b00010000;
+        }
+        result
+    }
+}
+
+/// Bits a Four Score reports on each port after the buttons of both of its controllers,
+/// in the order they are read
+const FOUR_SCORE_SIGNATURE: [u8; 2] = [0b00010000, 0b00100000];
+
 pub struct Controller {
-    controller: [u8; 2],
-    buffer: [Buttons; 2],
+    /// Shift registers, read starting at the most significant bit
+    controller: [u32; 2],
+    /// Players 1 and 2, followed by players 3 and 4 of a Four Score
+    buffer: [Buttons; 4],
     latch: bool,
+    four_score: bool,
+    zapper: Option<Zapper>,
 }
 
 impl Controller {
@@ -32,8 +67,27 @@ impl Controller {
     pub fn new() -> Self {
         Self {
             controller: [0; 2],
-            buffer: [Buttons::empty(); 2],
+            buffer: [Buttons::empty(); 4],
             latch: false,
+            four_score: false,
+            zapper: None,
+        }
+    }
+
+    #[inline]
+    pub fn zapper(&self) -> Option<&Zapper> {
+        self.zapper.as_ref()
+    }
+
+    pub fn set_zapper_connected(&mut self, connected: bool) {
+        self.zapper = connected.then(Zapper::default);
+    }
+
+    #[inline]
+    pub fn update_zapper_state(&mut self, aim: Option<(usize, usize)>, trigger: bool) {
+        if let Some(zapper) = &mut self.zapper {
+            zapper.aim = aim;
+            zapper.trigger = trigger;
         }
     }
 
@@ -42,18 +96,59 @@ impl Controller {
         self.buffer[0] = controller_a;
         self.buffer[1] = controller_b;
     }
+
+    #[inline]
+    pub fn update_four_score_state(&mut self, controllers: [Buttons; 4]) {
+        self.buffer = controllers;
+    }
+
+    pub fn set_four_score_connected(&mut self, connected: bool) {
+        self.four_score = connected;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        // The buffer and the zapper hold live input from the frontend and are not part of the state
+        state.write_u32(self.controller[0]);
+        state.write_u32(self.controller[1]);
+        state.write_bool(self.latch);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.controller[0] = state.read_u32()?;
+        self.controller[1] = state.read_u32()?;
+        self.latch = state.read_bool()?;
+        Ok(())
+    }
 }
 
 impl Controller {
+    /// Loads the shift register of a port with the buttons of its controller.
+    /// A Four Score appends the buttons of the port's second controller and its signature,
+    /// after all reports official controllers return 1.
+    fn reload(&mut self, port: usize) {
+        let mut value = ((self.buffer[port].bits() as u32) << 24) | 0x00FFFFFF;
+        if self.four_score {
+            value = (value & 0xFF0000FF)
+                | ((self.buffer[port + 2].bits() as u32) << 16)
+                | ((FOUR_SCORE_SIGNATURE[port] as u32) << 8);
+        }
+        self.controller[port] = value;
+    }
+
     pub fn read(&mut self, port: ControllerPort) -> u8 {
-        // When reading while the controller is latched, the bits are refreshed
+        let port = port as usize;
+
+        // While the strobe is held high the shift register is continuously reloaded,
+        // so every read returns the current state of the A button
         if self.latch {
-            self.controller[port as usize] = self.buffer[port as usize].bits();
+            self.reload(port);
+            return (self.controller[port] >> 31) as u8;
         }
 
-        // Reading is sequential
-        let result = self.controller[port as usize] >> 7;
-        self.controller[port as usize] <<= 1;
+        // Reading is sequential, each port shifts independently.
+        // Ones are shifted in, so official controllers keep reporting 1 after all bits are read.
+        let result = (self.controller[port] >> 31) as u8;
+        self.controller[port] = (self.controller[port] << 1) | 0x01;
         result
     }
 
@@ -62,9 +157,65 @@ impl Controller {
         if (data & 0x01) != 0 {
             self.latch = true;
         } else if self.latch {
-            self.controller[0] = self.buffer[0].bits();
-            self.controller[1] = self.buffer[1].bits();
+            self.reload(0);
+            self.reload(1);
             self.latch = false;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_bits(controller: &mut Controller, port: ControllerPort, count: usize) -> Vec<u8> {
+        (0..count).map(|_| controller.read(port)).collect()
+    }
+
+    #[test]
+    fn strobe_and_shift() {
+        let mut controller = Controller::new();
+        controller.update_state(Buttons::A | Buttons::START, Buttons::B | Buttons::RIGHT);
+
+        // While strobed every read returns the A button
+        controller.write(0x01);
+        assert_eq!(
+            read_bits(&mut controller, ControllerPort::PortA, 3),
+            [1, 1, 1]
+        );
+        assert_eq!(
+            read_bits(&mut controller, ControllerPort::PortB, 3),
+            [0, 0, 0]
+        );
+
+        controller.write(0x00);
+        assert_eq!(
+            read_bits(&mut controller, ControllerPort::PortA, 8),
+            [1, 0, 0, 1, 0, 0, 0, 0]
+        );
+
+        // Port B shifts independently of port A
+        assert_eq!(
+            read_bits(&mut controller, ControllerPort::PortB, 4),
+            [0, 1, 0, 0]
+        );
+        assert_eq!(controller.read(ControllerPort::PortA), 1);
+        assert_eq!(
+            read_bits(&mut controller, ControllerPort::PortB, 5),
+            [0, 0, 0, 1, 1]
+        );
+    }
+
+    #[test]
+    fn reads_past_the_report_return_one() {
+        let mut controller = Controller::new();
+        controller.update_state(Buttons::empty(), Buttons::empty());
+        controller.write(0x01);
+        controller.write(0x00);
+
+        assert_eq!(read_bits(&mut controller, ControllerPort::PortA, 8), [0; 8]);
+        assert!(read_bits(&mut controller, ControllerPort::PortA, 100)
+            .iter()
+            .all(|&bit| bit == 1));
+    }
+}
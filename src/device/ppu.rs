@@ -1,4 +1,6 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
 use crate::system::PpuBus;
+use crate::Region;
 use bitflags::bitflags;
 use bytemuck::{Pod, Zeroable};
 
@@ -13,9 +15,12 @@ const ADDR_PPU_DATA: u16 = 7;
 
 pub const SCREEN_WIDTH: usize = 256;
 pub const SCREEN_HEIGHT: usize = 240;
+/// Size of a `.pal` file, 64 colors with one byte each for red, green and blue
+pub const PALETTE_SIZE: usize = 64 * 3;
+/// Width and height of a rendered pattern table, 16 by 16 tiles
+pub const PATTERN_TABLE_SIZE: usize = 128;
 
 const MAX_CYCLE: u16 = 340;
-const MAX_SCANLINE: i16 = 260;
 const HBLANK_CYCLE: u16 = 256;
 const VBLANK_LINE: i16 = 240;
 
@@ -29,6 +34,26 @@ fn select<T>(eval: bool, if_true: T, if_false: T) -> T {
     }
 }
 
+/// Maps the palette mirrors onto the palette RAM, the backdrop color of every palette
+/// is shared with palette 0
+fn mirror_palette_addr(mut addr: u16) -> u16 {
+    addr &= 0x001F;
+    if (addr & 0x0003) == 0 {
+        addr = 0;
+    }
+    addr | 0x3F00
+}
+
+/// Decodes one row of a tile into 2 bit pixels, leftmost pixel first
+fn decode_tile_row(peek: &impl Fn(u16) -> u8, tile_addr: u16, row: u16) -> [u8; 8] {
+    let low = peek(tile_addr + row);
+    let high = peek(tile_addr + row + 8);
+    std::array::from_fn(|x| {
+        let bit = 7 - x;
+        (((high >> bit) & 0x01) << 1) | ((low >> bit) & 0x01)
+    })
+}
+
 fn flip_byte(mut b: u8) -> u8 {
     b = ((b & 0xF0) >> 4) | ((b & 0x0F) << 4);
     b = ((b & 0xCC) >> 2) | ((b & 0x33) << 2);
@@ -47,6 +72,12 @@ pub struct Color {
 }
 
 impl Color {
+    pub const TRANSPARENT: Self = Self {
+        r: u8::MIN,
+        g: u8::MIN,
+        b: u8::MIN,
+        a: u8::MIN,
+    };
     pub const BLACK: Self = Self::from_rgb(u8::MIN, u8::MIN, u8::MIN);
     pub const WHITE: Self = Self::from_rgb(u8::MAX, u8::MAX, u8::MAX);
 
@@ -128,6 +159,32 @@ const NES_PALETTE: [Color; 64] = [
     Color::BLACK,
 ];
 
+/// Applies PPUMASK's greyscale and color emphasis bits to a palette entry
+fn compose_color(palette: &[Color; 64], color_index: u8, mask: PpuMask) -> Color {
+    // Emphasizing a channel darkens the other two to roughly 81.6% on NTSC (209 / 256)
+    const ATTENUATION: u16 = 209;
+
+    #[inline]
+    fn attenuate(channel: u8) -> u8 {
+        (((channel as u16) * ATTENUATION) >> 8) as u8
+    }
+
+    let color_index = color_index & select(mask.contains(PpuMask::GREYSCALE), 0x30, 0x3F);
+    let mut color = palette[color_index as usize];
+
+    if mask.intersects(PpuMask::ENHANCE_GREEN | PpuMask::ENHANCE_BLUE) {
+        color.r = attenuate(color.r);
+    }
+    if mask.intersects(PpuMask::ENHANCE_RED | PpuMask::ENHANCE_BLUE) {
+        color.g = attenuate(color.g);
+    }
+    if mask.intersects(PpuMask::ENHANCE_RED | PpuMask::ENHANCE_GREEN) {
+        color.b = attenuate(color.b);
+    }
+
+    color
+}
+
 #[repr(transparent)]
 pub struct PixelBuffer {
     pixels: [Color; SCREEN_WIDTH * SCREEN_HEIGHT],
@@ -146,6 +203,11 @@ impl PixelBuffer {
         &self.pixels
     }
 
+    #[inline]
+    pub fn get_pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[(y * SCREEN_WIDTH) + x]
+    }
+
     #[inline]
     pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
         let index = (y * SCREEN_WIDTH) + x;
@@ -167,6 +229,7 @@ bitflags! {
 }
 
 bitflags! {
+    #[derive(Clone, Copy)]
     struct PpuMask : u8 {
         const GREYSCALE              = 0b00000001;
         const RENDER_BACKGROUND_LEFT = 0b00000010;
@@ -238,6 +301,20 @@ impl ObjectAttributes {
     }
 }
 
+/// A sprite as stored in OAM
+#[derive(Debug, Clone, Copy)]
+pub struct OamEntry {
+    /// Y coordinate of the top edge minus one
+    pub y: u8,
+    /// Tile index, in 8x16 mode bit 0 selects the pattern table
+    pub tile: u8,
+    /// Palette (bits 0-1), priority behind the background (bit 5) and horizontal and
+    /// vertical flip (bits 6 and 7)
+    pub attributes: u8,
+    /// X coordinate of the left edge
+    pub x: u8,
+}
+
 struct ObjectAttributeMemory {
     entries: [ObjectAttributes; 64],
 }
@@ -258,7 +335,14 @@ impl ObjectAttributeMemory {
     const fn read(&self, addr: u8) -> u8 {
         let index = (addr as usize) / 4;
         let offset = (addr as usize) % 4;
-        self.entries[index].attribs[offset]
+        let data = self.entries[index].attribs[offset];
+
+        // Bits 2-4 of the attribute byte do not exist in hardware and always read back as 0
+        if offset == 2 {
+            data & 0xE3
+        } else {
+            data
+        }
     }
 
     fn write(&mut self, addr: u8, data: u8) {
@@ -326,11 +410,13 @@ impl PpuShiftRegister {
 }
 
 pub struct Ppu {
+    region: Region,
     oam: ObjectAttributeMemory,
     scanline: i16,
     cycle: u16,
     back_buffer: Box<PixelBuffer>,
     front_buffer: Box<PixelBuffer>,
+    frame_count: u64,
     control: PpuControl,
     mask: PpuMask,
     status: PpuStatus,
@@ -354,18 +440,32 @@ pub struct Ppu {
     sprite_pattern_lo: [u8; 8],
     sprite_pattern_hi: [u8; 8],
     allow_zero_hit: bool,
+    /// Set by reading PPUSTATUS just before the vertical blank flag would be set
+    suppress_vblank: bool,
+    /// Set by reading PPUSTATUS just after the vertical blank flag was set,
+    /// the already signaled NMI has to be withdrawn
+    cancel_nmi: bool,
+    /// Value left on the data bus between the CPU and the PPU by the last register access,
+    /// returned for write-only registers and unused bits
+    io_latch: u8,
+    /// Frame each bit of the latch was last driven in
+    io_latch_frames: [u64; 8],
+    open_bus_decay: bool,
+    palette: [Color; 64],
 }
 
 impl Ppu {
-    pub fn new() -> Self {
+    pub fn new(region: Region) -> Self {
         let oam = ObjectAttributeMemory::new();
 
         Self {
+            region,
             oam,
             scanline: 0,
             cycle: 0,
             back_buffer: Box::new(PixelBuffer::new()),
             front_buffer: Box::new(PixelBuffer::new()),
+            frame_count: 0,
             control: PpuControl::empty(),
             mask: PpuMask::empty(),
             status: PpuStatus::empty(),
@@ -389,15 +489,207 @@ impl Ppu {
             sprite_pattern_lo: [0; 8],
             sprite_pattern_hi: [0; 8],
             allow_zero_hit: false,
+            suppress_vblank: false,
+            cancel_nmi: false,
+            io_latch: 0,
+            io_latch_frames: [0; 8],
+            open_bus_decay: false,
+            palette: NES_PALETTE,
+        }
+    }
+
+    /// Replaces the built-in colors with the 64 RGB triplets of a `.pal` file
+    pub fn set_palette(&mut self, palette: &[u8; PALETTE_SIZE]) {
+        for (color, rgb) in self.palette.iter_mut().zip(palette.chunks_exact(3)) {
+            *color = Color::from_rgb(rgb[0], rgb[1], rgb[2]);
         }
     }
 
+    /// Lets bits of the PPU's open bus decay to 0 when they haven't been driven for a while,
+    /// like they do on real hardware
+    pub fn set_open_bus_decay(&mut self, decay: bool) {
+        self.open_bus_decay = decay;
+    }
+
     #[inline]
     pub fn get_buffer(&self) -> &PixelBuffer {
         &self.front_buffer
     }
 
+    /// Number of frames completed since power-up
+    #[inline]
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    #[inline]
+    pub fn scanline(&self) -> i16 {
+        self.scanline
+    }
+
+    #[inline]
+    pub fn cycle(&self) -> u16 {
+        self.cycle
+    }
+
+    /// Current scanline and dot (0-340). The pre-render line is the last scanline,
+    /// 261 on NTSC and 311 on PAL and Dendy.
+    #[inline]
+    pub fn position(&self) -> (u16, u16) {
+        let scanline = if self.scanline < 0 {
+            (self.region.last_scanline() + 1) as u16
+        } else {
+            self.scanline as u16
+        };
+        (scanline, self.cycle)
+    }
+
+    /// Whether a light gun aimed at the given pixel currently detects light.
+    /// The photodiode only reacts to a bright pixel for a short while after the beam drew it.
+    pub fn senses_light(&self, x: usize, y: usize) -> bool {
+        const SENSE_SCANLINES: i16 = 20;
+
+        let beam_y = self.scanline;
+        let beam_x = (self.cycle as usize).saturating_sub(1);
+        if (x >= SCREEN_WIDTH)
+            || (y >= SCREEN_HEIGHT)
+            || !(0..SCREEN_HEIGHT as i16).contains(&beam_y)
+        {
+            return false;
+        }
+
+        let y = y as i16;
+        let drawn = (y < beam_y) || ((y == beam_y) && (x < beam_x));
+        if !drawn || ((beam_y - y) >= SENSE_SCANLINES) {
+            return false;
+        }
+
+        let color = self.back_buffer.get_pixel(x, y as usize);
+        let brightness = (color.r as u16) + (color.g as u16) + (color.b as u16);
+        brightness >= (3 * 0x80)
+    }
+
+    pub fn oam_bytes(&self) -> [u8; 256] {
+        let mut bytes = [0; 256];
+        for (i, entry) in self.oam.entries.iter().enumerate() {
+            bytes[(i * 4)..(i * 4 + 4)].copy_from_slice(&entry.attribs);
+        }
+        bytes
+    }
+
+    pub fn oam_entries(&self) -> [OamEntry; 64] {
+        self.oam.entries.map(|entry| OamEntry {
+            y: entry.y(),
+            tile: entry.id(),
+            attributes: entry.attribs[2],
+            x: entry.x(),
+        })
+    }
+
+    /// Height of all sprites in pixels, 8 or 16 depending on PPUCTRL
+    #[inline]
+    pub fn sprite_height(&self) -> usize {
+        select(self.control.contains(PpuControl::SPRITE_SIZE), 16, 8)
+    }
+
+    /// The pixel buffers and the frame counter are not part of the state,
+    /// the restored picture appears with the next completed frame.
+    /// The palette is a frontend setting and not part of the state either.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.oam_bytes());
+        state.write_i16(self.scanline);
+        state.write_u16(self.cycle);
+        state.write_u8(self.control.bits());
+        state.write_u8(self.mask.bits());
+        state.write_u8(self.status.bits());
+        state.write_bool(self.ppu_addr_latch);
+        state.write_u8(self.ppu_data_buffer);
+        state.write_bool(self.nmi);
+        state.write_bool(self.suppress_vblank);
+        state.write_bool(self.cancel_nmi);
+        state.write_u8(self.io_latch);
+        state.write_u16(self.vram_addr.value);
+        state.write_u16(self.tram_addr.value);
+        state.write_u8(self.fine_x);
+        state.write_u8(self.bg_next_id);
+        state.write_u8(self.bg_next_attr);
+        state.write_u8(self.bg_next_lsb);
+        state.write_u8(self.bg_next_msb);
+        state.write_u16(self.bg_pattern_lo.value);
+        state.write_u16(self.bg_pattern_hi.value);
+        state.write_u16(self.bg_attr_lo.value);
+        state.write_u16(self.bg_attr_hi.value);
+        state.write_u8(self.oam_addr);
+        for sprite in &self.sprites_line {
+            state.write_bytes(&sprite.attribs);
+        }
+        state.write_usize(self.sprite_count);
+        state.write_bytes(&self.sprite_pattern_lo);
+        state.write_bytes(&self.sprite_pattern_hi);
+        state.write_bool(self.allow_zero_hit);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut oam = [0; 256];
+        state.read_bytes_into(&mut oam)?;
+        for (addr, data) in oam.into_iter().enumerate() {
+            self.oam.write(addr as u8, data);
+        }
+
+        self.scanline = state.read_i16()?;
+        self.cycle = state.read_u16()?;
+        self.control = PpuControl::from_bits_retain(state.read_u8()?);
+        self.mask = PpuMask::from_bits_retain(state.read_u8()?);
+        self.status = PpuStatus::from_bits_retain(state.read_u8()?);
+        self.ppu_addr_latch = state.read_bool()?;
+        self.ppu_data_buffer = state.read_u8()?;
+        self.nmi = state.read_bool()?;
+        self.suppress_vblank = state.read_bool()?;
+        self.cancel_nmi = state.read_bool()?;
+        // The frame count isn't part of the state, so the loaded latch counts as freshly driven
+        self.io_latch = state.read_u8()?;
+        self.io_latch_frames = [self.frame_count; 8];
+        self.vram_addr.value = state.read_u16()?;
+        self.vram_addr.update_subfields();
+        self.tram_addr.value = state.read_u16()?;
+        self.tram_addr.update_subfields();
+        self.fine_x = state.read_u8()?;
+        self.bg_next_id = state.read_u8()?;
+        self.bg_next_attr = state.read_u8()?;
+        self.bg_next_lsb = state.read_u8()?;
+        self.bg_next_msb = state.read_u8()?;
+        self.bg_pattern_lo.value = state.read_u16()?;
+        self.bg_pattern_hi.value = state.read_u16()?;
+        self.bg_attr_lo.value = state.read_u16()?;
+        self.bg_attr_hi.value = state.read_u16()?;
+        self.oam_addr = state.read_u8()?;
+        for sprite in &mut self.sprites_line {
+            state.read_bytes_into(&mut sprite.attribs)?;
+        }
+        self.sprite_count = state.read_usize()?;
+        if self.sprite_count > self.sprites_line.len() {
+            return Err(StateError::InvalidData);
+        }
+        state.read_bytes_into(&mut self.sprite_pattern_lo)?;
+        state.read_bytes_into(&mut self.sprite_pattern_hi)?;
+        self.allow_zero_hit = state.read_bool()?;
+        Ok(())
+    }
+
+    /// Returns to the power-up state, clearing OAM. The palette is a frontend setting and is kept,
+    /// as is the frame count so frontends keep counting frames across a power cycle.
+    pub fn power_cycle(&mut self) {
+        *self = Self {
+            frame_count: self.frame_count,
+            open_bus_decay: self.open_bus_decay,
+            palette: self.palette,
+            ..Self::new(self.region)
+        };
+    }
+
     pub fn reset(&mut self) {
+        // https://www.nesdev.org/wiki/PPU_power_up_state
+        // PPUSTATUS, OAMADDR and the current VRAM address (v) are not affected by a reset
         self.fine_x = 0;
         self.ppu_addr_latch = false;
         self.ppu_data_buffer = 0;
@@ -411,11 +703,10 @@ impl Ppu {
         self.bg_pattern_hi.value = 0;
         self.bg_attr_lo.value = 0;
         self.bg_attr_hi.value = 0;
-        self.status = PpuStatus::empty();
         self.mask = PpuMask::empty();
         self.control = PpuControl::empty();
-        self.vram_addr = PpuRegister::new();
         self.tram_addr = PpuRegister::new();
+        self.nmi = false;
     }
 
     pub fn check_nmi(&mut self) -> bool {
@@ -424,13 +715,16 @@ impl Ppu {
         tmp
     }
 
+    /// Whether a PPUSTATUS read or disabling NMIs withdrew an NMI after it was already signaled
+    pub fn check_nmi_cancelled(&mut self) -> bool {
+        let tmp = self.cancel_nmi;
+        self.cancel_nmi = false;
+        tmp
+    }
+
     fn read_bus(&self, bus: &mut PpuBus<'_>, mut addr: u16) -> u8 {
         if addr >= 0x3F00 {
-            addr &= 0x001F;
-            if (addr & 0x000F) % 4 == 0 {
-                addr = 0;
-            }
-            addr |= 0x3F00;
+            addr = mirror_palette_addr(addr);
         }
         bus.read(addr)
     }
@@ -438,7 +732,7 @@ impl Ppu {
     fn write_bus(&self, bus: &mut PpuBus<'_>, mut addr: u16, data: u8) {
         if addr >= 0x3F00 {
             addr &= 0x001F;
-            if (addr & 0x000F) % 4 == 0 {
+            if (addr & 0x0003) == 0 {
                 addr &= 0x000F;
             }
             addr |= 0x3F00;
@@ -450,9 +744,116 @@ impl Ppu {
         // A pixel with value of 0 always mirrors to the first color in the palette (background)
         const BASE_ADDR: u16 = 0x3F00;
         let addr = BASE_ADDR + (palette * 4) + (pixel as u16);
-        let color_index =
-            self.read_bus(bus, addr) & select(self.mask.contains(PpuMask::GREYSCALE), 0x30, 0x3F);
-        NES_PALETTE[color_index as usize]
+        let color_index = self.read_bus(bus, addr);
+        compose_color(&self.palette, color_index, self.mask)
+    }
+
+    fn peek_palette_color(&self, peek: &impl Fn(u16) -> u8, palette: u8, pixel: u8) -> Color {
+        const BASE_ADDR: u16 = 0x3F00;
+        let addr = BASE_ADDR + ((palette as u16) * 4) + (pixel as u16);
+        let color_index = peek(mirror_palette_addr(addr));
+        compose_color(&self.palette, color_index, self.mask)
+    }
+
+    /// Renders a pattern table for debugging, reading memory through `peek` so mappers don't see the accesses
+    pub fn render_pattern_table(
+        &self,
+        peek: impl Fn(u16) -> u8,
+        table: u16,
+        palette: u8,
+    ) -> Vec<u8> {
+        let mut pixels = vec![Color::BLACK; PATTERN_TABLE_SIZE * PATTERN_TABLE_SIZE];
+
+        let table_addr = (table & 0x01) * 0x1000;
+        for tile in 0..256 {
+            let tile_x = (tile % 16) * 8;
+            let tile_y = (tile / 16) * 8;
+
+            for row in 0..8 {
+                let pixel_row = decode_tile_row(&peek, table_addr + (tile as u16) * 16, row as u16);
+                for (x, &pixel) in pixel_row.iter().enumerate() {
+                    let index = (tile_y + row) * PATTERN_TABLE_SIZE + tile_x + x;
+                    pixels[index] = self.peek_palette_color(&peek, palette & 0x07, pixel);
+                }
+            }
+        }
+
+        bytemuck::cast_slice(&pixels).to_vec()
+    }
+
+    /// Renders a nametable for debugging, reading memory through `peek` so mappers don't see the accesses
+    pub fn render_nametable(&self, peek: impl Fn(u16) -> u8, index: u16) -> Vec<u8> {
+        const TILES_X: u16 = 32;
+        const TILES_Y: u16 = 30;
+        const ATTRIBUTE_OFFSET: u16 = 0x03C0;
+
+        let mut pixels = vec![Color::BLACK; SCREEN_WIDTH * SCREEN_HEIGHT];
+
+        let nametable_addr = 0x2000 + (index & 0x03) * 0x0400;
+        let pattern_addr = select(
+            self.control.contains(PpuControl::PATTERN_BACKGROUND),
+            0x1000,
+            0x0000,
+        );
+
+        for tile_y in 0..TILES_Y {
+            for tile_x in 0..TILES_X {
+                let tile = peek(nametable_addr + tile_y * TILES_X + tile_x) as u16;
+
+                // Every attribute byte covers 4x4 tiles, with 2 bits for each 2x2 quadrant
+                let attribute =
+                    peek(nametable_addr + ATTRIBUTE_OFFSET + (tile_y / 4) * 8 + (tile_x / 4));
+                let shift = ((tile_y & 0x02) << 1) | (tile_x & 0x02);
+                let palette = (attribute >> shift) & 0x03;
+
+                for row in 0..8 {
+                    let pixel_row = decode_tile_row(&peek, pattern_addr + tile * 16, row);
+                    for (x, &pixel) in pixel_row.iter().enumerate() {
+                        let y = (tile_y * 8 + row) as usize;
+                        let index = y * SCREEN_WIDTH + (tile_x as usize) * 8 + x;
+                        pixels[index] = self.peek_palette_color(&peek, palette, pixel);
+                    }
+                }
+            }
+        }
+
+        bytemuck::cast_slice(&pixels).to_vec()
+    }
+
+    /// Renders a sprite for debugging, reading memory through `peek` so mappers don't see the accesses
+    pub fn render_sprite(&self, peek: impl Fn(u16) -> u8, index: usize) -> Vec<u8> {
+        let sprite = self.oam.get(index & 0x3F);
+        let height = self.sprite_height();
+        let flip_vert = sprite.attr().contains(SpriteAttributes::FLIP_VERT);
+        let flip_hor = sprite.attr().contains(SpriteAttributes::FLIP_HOR);
+
+        let mut pixels = vec![Color::TRANSPARENT; 8 * height];
+        for y in 0..height {
+            let row = select(flip_vert, height - 1 - y, y) as u16;
+
+            // Same tile selection as `get_sprite_addr`
+            let tile_addr = if self.control.contains(PpuControl::SPRITE_SIZE) {
+                let pattern = ((sprite.id() & 0x01) as u16) << 12;
+                pattern | ((((sprite.id() & 0xFE) as u16) + (row >> 3)) << 4)
+            } else {
+                let pattern = select(
+                    self.control.contains(PpuControl::PATTERN_SPRITE),
+                    1 << 12,
+                    0,
+                );
+                pattern | ((sprite.id() as u16) << 4)
+            };
+
+            let pixel_row = decode_tile_row(&peek, tile_addr, row & 0x07);
+            for x in 0..8 {
+                let pixel = pixel_row[select(flip_hor, 7 - x, x)];
+                if pixel != 0 {
+                    pixels[y * 8 + x] = self.peek_palette_color(&peek, sprite.palette(), pixel);
+                }
+            }
+        }
+
+        bytemuck::cast_slice(&pixels).to_vec()
     }
 
     fn inc_x(&mut self) {
@@ -587,29 +988,21 @@ impl Ppu {
         }
     }
 
+    // https://www.nesdev.org/wiki/PPU_OAM#Byte_1
     fn get_sprite_addr(&self, sprite: &ObjectAttributes) -> u16 {
+        let flip = sprite.attr().contains(SpriteAttributes::FLIP_VERT);
+        // Unused slots are fetched as well because the MMC3 watches their A12.
+        // They hold sprite $FF, whose row is meaningless and wraps around.
+        let row = (self.scanline as u16).wrapping_sub(sprite.y() as u16) & 0x0F;
+
         if self.control.contains(PpuControl::SPRITE_SIZE) {
-            // 8x16 mode
+            // 8x16 mode, bit 0 of the tile index selects the pattern table instead of $2000 bit 3
             let pattern = ((sprite.id() & 0x01) as u16) << 12;
-            let row = ((self.scanline as u16) - (sprite.y() as u16)) & 0x07;
-            #[allow(clippy::collapsible_else_if)]
-            if sprite.attr().contains(SpriteAttributes::FLIP_VERT) {
-                if (self.scanline - (sprite.y() as i16)) < 8 {
-                    // Top half
-                    pattern | ((((sprite.id() & 0xFE) as u16) + 1) << 4) | (7 - row)
-                } else {
-                    // Bottom half
-                    pattern | (((sprite.id() & 0xFE) as u16) << 4) | (7 - row)
-                }
-            } else {
-                if (self.scanline - (sprite.y() as i16)) < 8 {
-                    // Top half
-                    pattern | (((sprite.id() & 0xFE) as u16) << 4) | row
-                } else {
-                    // Bottom half
-                    pattern | ((((sprite.id() & 0xFE) as u16) + 1) << 4) | row
-                }
-            }
+
+            // Vertical flip spans both tiles, so the bottom tile is drawn on top
+            let row = select(flip, 15 - row, row);
+            let cell = (((sprite.id() & 0xFE) as u16) + (row >> 3)) << 4;
+            pattern | cell | (row & 0x07)
         } else {
             // 8x8 mode
             let pattern = select(
@@ -618,17 +1011,18 @@ impl Ppu {
                 0,
             );
             let cell = (sprite.id() as u16) << 4;
-            let row = ((self.scanline as u16) - (sprite.y() as u16)) & 0x07;
-            if sprite.attr().contains(SpriteAttributes::FLIP_VERT) {
-                pattern | cell | (7 - row)
-            } else {
-                pattern | cell | row
-            }
+            let row = row & 0x07;
+            pattern | cell | select(flip, 7 - row, row)
         }
     }
 
+    // https://www.nesdev.org/wiki/PPU_sprite_evaluation
     fn load_foreground_data(&mut self, bus: &mut PpuBus<'_>) {
-        if (self.cycle == MAX_CYCLE) && (self.scanline >= 0) {
+        // Sprites for the next line are fetched in cycles 257-320, all at once here.
+        // The MMC3 clocks its scanline counter on the A12 rise of these fetches, so they have to
+        // happen at the start of hblank and not at the end of the line like they used to.
+        // Cycle 257 still outputs a pixel using the sprites of the current line.
+        if self.cycle == (HBLANK_CYCLE + 2) {
             // Clear sprites
             self.sprites_line = [ObjectAttributes::new(); 8];
             for i in 0..8 {
@@ -639,14 +1033,16 @@ impl Ppu {
             let sprite_height = select(self.control.contains(PpuControl::SPRITE_SIZE), 16, 8);
 
             self.sprite_count = 0;
-            let mut oam_index: usize = 0;
             self.allow_zero_hit = false;
-            while (oam_index < 64) && (self.sprite_count < 9) {
-                let sprite = self.oam.get(oam_index);
 
-                let diff = self.scanline - (sprite.y() as i16);
-                if (diff >= 0) && (diff < sprite_height) {
-                    if self.sprite_count < 8 {
+            // The pre-render line never has sprites on the first visible line
+            if self.scanline >= 0 {
+                let mut oam_index: usize = 0;
+                while (oam_index < 64) && (self.sprite_count < 8) {
+                    let sprite = self.oam.get(oam_index);
+
+                    let diff = self.scanline - (sprite.y() as i16);
+                    if (diff >= 0) && (diff < sprite_height) {
                         if oam_index == 0 {
                             // Sprite zero hit detection
                             self.allow_zero_hit = true;
@@ -654,21 +1050,43 @@ impl Ppu {
 
                         self.sprites_line[self.sprite_count] = sprite;
                         self.sprite_count += 1;
-                    } else {
-                        self.status.insert(PpuStatus::SPRITE_OVERFLOW);
                     }
+
+                    oam_index += 1;
                 }
 
-                oam_index += 1;
+                // Once eight sprites are found the hardware keeps searching for a ninth one, but buggily:
+                // on every miss it advances both the sprite index and the byte within the sprite, so it
+                // compares tile indices, attributes and X coordinates as if they were Y coordinates.
+                // This emulates that bug, false positives and false negatives included.
+                let mut byte_index: usize = 0;
+                while (self.sprite_count == 8) && (oam_index < 64) {
+                    let y = self.oam.read((oam_index * 4 + byte_index) as u8);
+
+                    let diff = self.scanline - (y as i16);
+                    if (diff >= 0) && (diff < sprite_height) {
+                        self.status.insert(PpuStatus::SPRITE_OVERFLOW);
+                        break;
+                    }
+
+                    oam_index += 1;
+                    byte_index = (byte_index + 1) % 4;
+                }
             }
 
-            for i in 0..self.sprite_count {
+            // All eight slots are always fetched, unused ones read tile $FF.
+            // Mappers watching the address bus rely on this.
+            for i in 0..8 {
                 let sprite = &self.sprites_line[i];
                 let addr_lo = self.get_sprite_addr(sprite);
                 let addr_hi = addr_lo + 8;
 
                 let mut pattern_lo = self.read_bus(bus, addr_lo);
                 let mut pattern_hi = self.read_bus(bus, addr_hi);
+                if i >= self.sprite_count {
+                    continue;
+                }
+
                 if sprite.attr().contains(SpriteAttributes::FLIP_HOR) {
                     pattern_lo = flip_byte(pattern_lo);
                     pattern_hi = flip_byte(pattern_hi);
@@ -682,7 +1100,7 @@ impl Ppu {
 
     pub fn clock(&mut self, bus: &mut PpuBus<'_>) {
         if self.scanline < VBLANK_LINE {
-            if (self.scanline == 0) && (self.cycle == 0) {
+            if (self.scanline == 0) && (self.cycle == 0) && self.region.skips_odd_frame_dot() {
                 self.cycle = 1; // "Odd frame" skip
             }
 
@@ -699,11 +1117,18 @@ impl Ppu {
                 }
             }
 
+            let rendering = self
+                .mask
+                .intersects(PpuMask::RENDER_BACKGROUND | PpuMask::RENDER_SPRITES);
+
+            // No memory is fetched while rendering is disabled
             if ((self.cycle > 1) && (self.cycle < 258))
                 || ((self.cycle > 320) && (self.cycle < 338))
             {
                 self.update_shifters();
-                self.load_background_data(bus);
+                if rendering {
+                    self.load_background_data(bus);
+                }
             }
 
             if self.cycle == HBLANK_CYCLE {
@@ -717,19 +1142,33 @@ impl Ppu {
                 self.trans_y();
             }
 
-            self.load_foreground_data(bus);
+            if rendering {
+                self.load_foreground_data(bus);
+            }
         }
 
-        if (self.scanline == (VBLANK_LINE + 1)) && (self.cycle == 1) {
-            self.status.insert(PpuStatus::VERTICAL_BLANK);
-            if self.control.contains(PpuControl::ENABLE_NMI) {
-                self.nmi = true;
+        if (self.scanline == self.region.vblank_scanline()) && (self.cycle == 1) {
+            if !self.suppress_vblank {
+                self.status.insert(PpuStatus::VERTICAL_BLANK);
+                if self.control.contains(PpuControl::ENABLE_NMI) {
+                    self.nmi = true;
+                }
             }
+            self.suppress_vblank = false;
         }
 
+        let x = (self.cycle as isize) - 1;
+        let y = self.scanline as isize;
+        let visible =
+            (x >= 0) && (y >= 0) && (x < SCREEN_WIDTH as isize) && (y < SCREEN_HEIGHT as isize);
+        // The left 8 pixels of either layer can be hidden, which also prevents sprite zero hits there
+        let left_edge = x < 8;
+
         let mut bg_pixel: u8 = 0;
         let mut bg_palette: u8 = 0;
-        if self.mask.contains(PpuMask::RENDER_BACKGROUND) {
+        if self.mask.contains(PpuMask::RENDER_BACKGROUND)
+            && (!left_edge || self.mask.contains(PpuMask::RENDER_BACKGROUND_LEFT))
+        {
             let mux: u16 = 0x8000 >> self.fine_x;
 
             let p0: u8 = select((self.bg_pattern_lo.value & mux) != 0, 0x01, 0x00);
@@ -745,7 +1184,9 @@ impl Ppu {
         let mut fg_palette: u8 = 0;
         let mut fg_priority: bool = false;
         let mut zero_visible = false;
-        if self.mask.contains(PpuMask::RENDER_SPRITES) {
+        if self.mask.contains(PpuMask::RENDER_SPRITES)
+            && (!left_edge || self.mask.contains(PpuMask::RENDER_SPRITES_LEFT))
+        {
             for i in 0..self.sprite_count {
                 let sprite = &self.sprites_line[i];
                 if sprite.x() == 0 {
@@ -787,31 +1228,16 @@ impl Ppu {
                 palette = bg_palette;
             }
 
-            if self.allow_zero_hit
-                && zero_visible
-                && self
-                    .mask
-                    .contains(PpuMask::RENDER_BACKGROUND | PpuMask::RENDER_SPRITES)
-            {
-                let start_cycle = if self
-                    .mask
-                    .contains(PpuMask::RENDER_BACKGROUND_LEFT | PpuMask::RENDER_SPRITES_LEFT)
-                {
-                    0
-                } else {
-                    8
-                };
-
-                if (self.cycle > start_cycle) && (self.cycle < 258) {
-                    self.status.insert(PpuStatus::SPRITE_ZERO_HIT);
-                }
+            // The hit is flagged at the very dot both opaque pixels overlap,
+            // except on the last pixel of a line
+            // https://www.nesdev.org/wiki/PPU_OAM#Sprite_zero_hits
+            if self.allow_zero_hit && zero_visible && visible && (x < 255) {
+                self.status.insert(PpuStatus::SPRITE_ZERO_HIT);
             }
         }
 
-        let x = (self.cycle as isize) - 1;
-        let y = self.scanline as isize;
         let color = self.get_palette_color(bus, palette as u16, pixel);
-        if (x >= 0) && (y >= 0) && (x < SCREEN_WIDTH as isize) && (y < SCREEN_HEIGHT as isize) {
+        if visible {
             self.back_buffer.set_pixel(x as usize, y as usize, color);
         }
 
@@ -829,55 +1255,152 @@ impl Ppu {
         if self.cycle > MAX_CYCLE {
             self.cycle = 0;
             self.scanline += 1;
-            if self.scanline > MAX_SCANLINE {
+            if self.scanline > self.region.last_scanline() {
                 self.scanline = -1;
                 std::mem::swap(&mut self.back_buffer, &mut self.front_buffer);
+                self.frame_count += 1;
             }
         }
     }
 
+    fn read_oam_data(&self) -> u8 {
+        // https://www.nesdev.org/wiki/PPU_sprite_evaluation
+        let rendering = (self.scanline < VBLANK_LINE)
+            && self
+                .mask
+                .intersects(PpuMask::RENDER_BACKGROUND | PpuMask::RENDER_SPRITES);
+
+        if rendering {
+            match self.cycle {
+                // Secondary OAM is being cleared, the clear signal forces all reads to 0xFF
+                1..=64 => 0xFF,
+                // Sprite tiles are being fetched, reads expose the secondary OAM entry in use
+                257..=320 => {
+                    let fetch = (self.cycle - 257) as usize;
+                    let sprite = &self.sprites_line[fetch / 8];
+                    // After the 4 attribute bytes the X position is read repeatedly
+                    let offset = (fetch % 8).min(3);
+                    if offset == 2 {
+                        sprite.attribs[offset] & 0xE3
+                    } else {
+                        sprite.attribs[offset]
+                    }
+                }
+                _ => self.oam.read(self.oam_addr),
+            }
+        } else {
+            self.oam.read(self.oam_addr)
+        }
+    }
+
     #[inline]
     pub fn dma_write(&mut self, data: u8) {
         self.oam.write(self.oam_addr, data);
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
 
+    /// Sets the bits of the I/O latch selected by `mask` to `value`
+    fn drive_io_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+        for (bit, frame) in self.io_latch_frames.iter_mut().enumerate() {
+            if (mask & (1 << bit)) != 0 {
+                *frame = self.frame_count;
+            }
+        }
+    }
+
+    // https://www.nesdev.org/wiki/Open_bus_behavior#PPU_open_bus
+    fn read_io_latch(&mut self) -> u8 {
+        // Bits decay after roughly 600 ms without being driven
+        const DECAY_FRAMES: u64 = 36;
+
+        if self.open_bus_decay {
+            for (bit, &frame) in self.io_latch_frames.iter().enumerate() {
+                if self.frame_count.saturating_sub(frame) >= DECAY_FRAMES {
+                    self.io_latch &= !(1 << bit);
+                }
+            }
+        }
+
+        self.io_latch
+    }
+
     pub fn cpu_read(&mut self, bus: &mut PpuBus<'_>, addr: u16) -> u8 {
         match addr & 0x7 {
-            ADDR_CONTROL => 0, // Not readable
-            ADDR_MASK => 0,    // Not readable
             ADDR_STATUS => {
-                // The unused bytes contain the last buffer data on real hardware
-                let tmp = (self.status.bits() & 0xE0) | (self.ppu_data_buffer & 0x1F);
+                // https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
+                // Reading one dot before the flag is set reads it as clear and keeps it from being set,
+                // reading on the dot it is set or one dot later reads it as set but still suppresses the NMI.
+                // `cycle` is the next dot to be drawn.
+                if self.scanline == self.region.vblank_scanline() {
+                    match self.cycle {
+                        1 => self.suppress_vblank = true,
+                        2 | 3 => {
+                            self.nmi = false;
+                            self.cancel_nmi = true;
+                        }
+                        _ => {}
+                    }
+                }
+
+                // The unused bits are open bus
+                let tmp = (self.status.bits() & 0xE0) | (self.read_io_latch() & 0x1F);
+                self.drive_io_latch(tmp, 0xE0);
                 self.status.remove(PpuStatus::VERTICAL_BLANK);
                 self.ppu_addr_latch = false;
                 tmp
             }
-            ADDR_OAM_ADDRESS => 0, // Not readable
-            ADDR_OAM_DATA => self.oam.read(self.oam_addr),
-            ADDR_SCROLL => 0,      // Not readable
-            ADDR_PPU_ADDRESS => 0, // Not readable
+            ADDR_OAM_DATA => {
+                let tmp = self.read_oam_data();
+                self.drive_io_latch(tmp, 0xFF);
+                tmp
+            }
             ADDR_PPU_DATA => {
                 // Everything except palette data is buffered one cycle
                 let mut tmp = self.ppu_data_buffer;
                 self.ppu_data_buffer = self.read_bus(bus, self.vram_addr.value);
                 if self.vram_addr.value >= 0x3F00 {
-                    tmp = self.ppu_data_buffer;
+                    // Palette entries are only 6 bits wide, the upper 2 bits are open bus
+                    tmp = (self.ppu_data_buffer & 0x3F) | (self.read_io_latch() & 0xC0);
+                    self.drive_io_latch(tmp, 0x3F);
+                } else {
+                    self.drive_io_latch(tmp, 0xFF);
                 }
-                // Auto-increment
-                self.vram_addr.value +=
-                    select(self.control.contains(PpuControl::INCREMENT_MODE), 32, 1);
+                // Auto-increment, v is only 15 bits wide
+                self.vram_addr.value = self.vram_addr.value.wrapping_add(select(
+                    self.control.contains(PpuControl::INCREMENT_MODE),
+                    32,
+                    1,
+                )) & 0x7FFF;
                 self.vram_addr.update_subfields();
                 tmp
             }
-            _ => 0,
+            // Write-only registers return whatever is left on the bus
+            _ => self.read_io_latch(),
         }
     }
 
     pub fn cpu_write(&mut self, bus: &mut PpuBus<'_>, addr: u16, data: u8) {
+        self.drive_io_latch(data, 0xFF);
+
         match addr & 0x7 {
             ADDR_CONTROL => {
-                self.control = PpuControl::from_bits_truncate(data);
+                // https://www.nesdev.org/wiki/NMI
+                // The NMI line is the vblank flag ANDed with the enable bit, so enabling NMIs during vertical
+                // blank immediately triggers one and disabling them withdraws one the CPU hasn't polled yet
+                let control = PpuControl::from_bits_truncate(data);
+                if self.status.contains(PpuStatus::VERTICAL_BLANK) {
+                    let was_enabled = self.control.contains(PpuControl::ENABLE_NMI);
+                    let enabled = control.contains(PpuControl::ENABLE_NMI);
+                    if enabled && !was_enabled {
+                        self.nmi = true;
+                    } else if !enabled && was_enabled {
+                        self.nmi = false;
+                        self.cancel_nmi = true;
+                    }
+                }
+
+                self.control = control;
                 self.tram_addr.nametable_x =
                     select(self.control.contains(PpuControl::NAMETABLE_X), 1, 0);
                 self.tram_addr.nametable_y =
@@ -913,15 +1436,416 @@ impl Ppu {
             }
             ADDR_PPU_DATA => {
                 self.write_bus(bus, self.vram_addr.value, data);
-                // Auto-increment
+                // Auto-increment, v is only 15 bits wide
                 self.vram_addr.value = self.vram_addr.value.wrapping_add(select(
                     self.control.contains(PpuControl::INCREMENT_MODE),
                     32,
                     1,
-                ));
+                )) & 0x7FFF;
                 self.vram_addr.update_subfields();
             }
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{test_cartridge, Cartridge};
+    use crate::device::vram::Vram;
+    use crate::device::Ram;
+
+    /// Memory the PPU is wired to, an NROM cartridge with CHR RAM
+    struct TestBus {
+        cart: Cartridge,
+        vram: Vram,
+        palette: Ram,
+    }
+
+    impl TestBus {
+        fn new() -> Self {
+            Self {
+                cart: test_cartridge(0, &[0; 0x4000], &[]),
+                vram: Vram::new(),
+                palette: Ram::new(5),
+            }
+        }
+
+        fn bus(&mut self) -> PpuBus<'_> {
+            PpuBus {
+                cart: &mut self.cart,
+                vram: &mut self.vram,
+                palette: &mut self.palette,
+            }
+        }
+    }
+
+    /// Clocks the PPU until `cycle` is the next dot to be drawn on `scanline`
+    fn run_to(ppu: &mut Ppu, bus: &mut PpuBus<'_>, scanline: i16, cycle: u16) {
+        while (ppu.scanline != scanline) || (ppu.cycle != cycle) {
+            ppu.clock(bus);
+        }
+    }
+
+    // https://www.nesdev.org/wiki/PPU_pattern_tables
+    const HALF_TILE: [u8; 16] = [
+        0x41, 0xC2, 0x44, 0x48, 0x10, 0x20, 0x40, 0x80, // Bit plane 0
+        0x01, 0x02, 0x04, 0x08, 0x16, 0x21, 0x42, 0x87, // Bit plane 1
+    ];
+
+    const HALF_TILE_PIXELS: [[u8; 8]; 8] = [
+        [0, 1, 0, 0, 0, 0, 0, 3],
+        [1, 1, 0, 0, 0, 0, 3, 0],
+        [0, 1, 0, 0, 0, 3, 0, 0],
+        [0, 1, 0, 0, 3, 0, 0, 0],
+        [0, 0, 0, 3, 0, 2, 2, 0],
+        [0, 0, 3, 0, 0, 0, 0, 2],
+        [0, 3, 0, 0, 0, 0, 2, 0],
+        [3, 0, 0, 0, 0, 2, 2, 2],
+    ];
+
+    #[test]
+    fn decode_tile() {
+        let peek = |addr: u16| HALF_TILE[addr as usize];
+        for (row, pixels) in HALF_TILE_PIXELS.iter().enumerate() {
+            assert_eq!(&decode_tile_row(&peek, 0, row as u16), pixels);
+        }
+    }
+
+    #[test]
+    fn render_pattern_table_tile() {
+        const TILE: usize = 0x11;
+        const PALETTE: [u8; 4] = [0x0F, 0x30, 0x16, 0x12];
+
+        let ppu = Ppu::new(Region::Ntsc);
+        let peek = |addr: u16| match addr {
+            0x1000..=0x1FFF => {
+                let offset = (addr as usize) - 0x1000;
+                if (offset / 16) == TILE {
+                    HALF_TILE[offset % 16]
+                } else {
+                    0
+                }
+            }
+            // Background palette 2
+            0x3F00 => PALETTE[0],
+            0x3F09..=0x3F0B => PALETTE[(addr - 0x3F08) as usize],
+            _ => 0,
+        };
+
+        let pixels = ppu.render_pattern_table(peek, 1, 2);
+        for (row, row_pixels) in HALF_TILE_PIXELS.iter().enumerate() {
+            for (x, &pixel) in row_pixels.iter().enumerate() {
+                let y = (TILE / 16) * 8 + row;
+                let index = y * PATTERN_TABLE_SIZE + (TILE % 16) * 8 + x;
+                let expected = compose_color(&ppu.palette, PALETTE[pixel as usize], ppu.mask);
+                assert_eq!(
+                    &pixels[(index * 4)..((index + 1) * 4)],
+                    bytemuck::bytes_of(&expected)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn write_only_registers_read_open_bus() {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        ppu.cpu_write(&mut bus, ADDR_OAM_ADDRESS, 0xA5);
+        for addr in [
+            ADDR_CONTROL,
+            ADDR_MASK,
+            ADDR_OAM_ADDRESS,
+            ADDR_SCROLL,
+            ADDR_PPU_ADDRESS,
+        ] {
+            assert_eq!(ppu.cpu_read(&mut bus, addr), 0xA5);
+        }
+
+        // Only the top 3 bits of PPUSTATUS are driven, reading it refreshes just those
+        assert_eq!(ppu.cpu_read(&mut bus, ADDR_STATUS), 0x05);
+        assert_eq!(ppu.cpu_read(&mut bus, ADDR_CONTROL), 0x05);
+    }
+
+    #[test]
+    fn open_bus_decays() {
+        const DECAY_FRAMES: u64 = 36;
+
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        ppu.cpu_write(&mut bus, ADDR_OAM_ADDRESS, 0xFF);
+        ppu.frame_count += DECAY_FRAMES;
+        assert_eq!(ppu.cpu_read(&mut bus, ADDR_CONTROL), 0xFF);
+
+        ppu.set_open_bus_decay(true);
+        ppu.cpu_write(&mut bus, ADDR_OAM_ADDRESS, 0xFF);
+        ppu.frame_count += DECAY_FRAMES - 1;
+        assert_eq!(ppu.cpu_read(&mut bus, ADDR_CONTROL), 0xFF);
+
+        ppu.frame_count += 1;
+        assert_eq!(ppu.cpu_read(&mut bus, ADDR_CONTROL), 0x00);
+    }
+
+    #[test]
+    fn oam_data_reads_during_rendering() {
+        // Only sprites 0 and 1 are on screen, at Y 10
+        const SPRITES: [u8; 8] = [10, 0x42, 0xFF, 0x20, 10, 0x43, 0x1F, 0x30];
+
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        for i in 0..256 {
+            ppu.dma_write(SPRITES.get(i).copied().unwrap_or(0xFF));
+        }
+        ppu.cpu_write(&mut bus, ADDR_OAM_ADDRESS, 0x01);
+        assert_eq!(ppu.cpu_read(&mut bus, ADDR_OAM_DATA), 0x42);
+
+        ppu.cpu_write(&mut bus, ADDR_MASK, 0x18);
+        run_to(&mut ppu, &mut bus, 10, 30);
+        // Secondary OAM is being cleared
+        assert_eq!(ppu.cpu_read(&mut bus, ADDR_OAM_DATA), 0xFF);
+
+        // The fetches of the second sprite expose Y, tile, attributes with the unused bits clear,
+        // then X repeatedly
+        let mut reads = Vec::new();
+        for cycle in 265..=272 {
+            run_to(&mut ppu, &mut bus, 10, cycle);
+            reads.push(ppu.cpu_read(&mut bus, ADDR_OAM_DATA));
+        }
+        assert_eq!(reads, [10, 0x43, 0x03, 0x30, 0x30, 0x30, 0x30, 0x30]);
+
+        // Primary OAM is readable again in vertical blank
+        run_to(&mut ppu, &mut bus, VBLANK_LINE + 1, 30);
+        assert_eq!(ppu.cpu_read(&mut bus, ADDR_OAM_DATA), 0x42);
+    }
+
+    // https://www.nesdev.org/wiki/PPU_scrolling#Summary
+    #[test]
+    fn scroll_and_address_writes() {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        ppu.cpu_write(&mut bus, ADDR_SCROLL, 0x7D);
+        ppu.cpu_write(&mut bus, ADDR_SCROLL, 0x5E);
+        assert_eq!(ppu.fine_x, 0x05);
+        // Fine Y 6, coarse Y 11, coarse X 15
+        assert_eq!(ppu.tram_addr.value, 0x616F);
+
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0x3D);
+        assert_eq!(ppu.vram_addr.value, 0x0000);
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0xF0);
+        assert_eq!(ppu.vram_addr.value, 0x3DF0);
+        assert_eq!(ppu.fine_x, 0x05);
+    }
+
+    #[test]
+    fn status_read_resets_write_toggle() {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0x21);
+        ppu.cpu_read(&mut bus, ADDR_STATUS);
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0x23);
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0x45);
+        assert_eq!(ppu.vram_addr.value, 0x2345);
+
+        // The second $2005 write turns into a first one and sets fine X
+        ppu.cpu_write(&mut bus, ADDR_SCROLL, 0x7D);
+        ppu.cpu_read(&mut bus, ADDR_STATUS);
+        ppu.cpu_write(&mut bus, ADDR_SCROLL, 0x02);
+        assert_eq!(ppu.fine_x, 0x02);
+    }
+
+    #[test]
+    fn reset_keeps_vram_address() {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0x23);
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0x45);
+        ppu.cpu_write(&mut bus, ADDR_SCROLL, 0x7D);
+        ppu.reset();
+        assert_eq!(ppu.vram_addr.value, 0x2345);
+        assert_eq!(ppu.tram_addr.value, 0x0000);
+        assert_eq!(ppu.fine_x, 0x00);
+
+        // The write toggle is cleared
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0x21);
+        ppu.cpu_write(&mut bus, ADDR_PPU_ADDRESS, 0x00);
+        assert_eq!(ppu.vram_addr.value, 0x2100);
+    }
+
+    #[test]
+    fn tall_sprite_addresses() {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        // The sprite pattern table bit is ignored for 8x16 sprites
+        ppu.control = PpuControl::SPRITE_SIZE | PpuControl::PATTERN_SPRITE;
+
+        let mut sprite = ObjectAttributes {
+            attribs: [10, 0x43, 0x00, 0x00],
+        };
+        let mut addr_on_line = |sprite: &ObjectAttributes, scanline: i16| {
+            ppu.scanline = scanline;
+            ppu.get_sprite_addr(sprite)
+        };
+
+        // Bit 0 of the tile index selects the pattern table at $1000, rows 8-15 use the next tile
+        assert_eq!(addr_on_line(&sprite, 10), 0x1420);
+        assert_eq!(addr_on_line(&sprite, 17), 0x1427);
+        assert_eq!(addr_on_line(&sprite, 18), 0x1430);
+        assert_eq!(addr_on_line(&sprite, 25), 0x1437);
+
+        // Vertical flip swaps the tiles as well
+        sprite.attribs[2] = SpriteAttributes::FLIP_VERT.bits();
+        assert_eq!(addr_on_line(&sprite, 10), 0x1437);
+        assert_eq!(addr_on_line(&sprite, 25), 0x1420);
+
+        sprite.attribs[1] = 0x42;
+        assert_eq!(addr_on_line(&sprite, 18), 0x0427);
+    }
+
+    #[test]
+    fn scroll_setup_applies_next_frame() {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        run_to(&mut ppu, &mut bus, VBLANK_LINE + 1, 30);
+        ppu.cpu_write(&mut bus, ADDR_CONTROL, 0x02);
+        ppu.cpu_read(&mut bus, ADDR_STATUS);
+        ppu.cpu_write(&mut bus, ADDR_SCROLL, 0x7D);
+        ppu.cpu_write(&mut bus, ADDR_SCROLL, 0x5E);
+        ppu.cpu_write(&mut bus, ADDR_MASK, 0x08);
+        // Nametable $2800, fine Y 6, coarse Y 11, coarse X 15
+        assert_eq!(ppu.tram_addr.value, 0x696F);
+
+        // The pre-render line copies t into v, before the first tiles of the frame are fetched
+        run_to(&mut ppu, &mut bus, -1, 310);
+        assert_eq!(ppu.vram_addr.value, 0x696F);
+        assert_eq!(ppu.fine_x, 0x05);
+    }
+
+    #[test]
+    fn ppu_data_increment_wraps_at_15_bits() {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        // Only rendering can set bit 14 of v
+        ppu.vram_addr.value = 0x7FFF;
+        ppu.vram_addr.update_subfields();
+        ppu.cpu_read(&mut bus, ADDR_PPU_DATA);
+        assert_eq!(ppu.vram_addr.value, 0x0000);
+
+        ppu.vram_addr.value = 0x7FF0;
+        ppu.vram_addr.update_subfields();
+        ppu.cpu_write(&mut bus, ADDR_CONTROL, 0x04);
+        ppu.cpu_write(&mut bus, ADDR_PPU_DATA, 0x00);
+        assert_eq!(ppu.vram_addr.value, 0x0010);
+    }
+
+    // https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
+    #[test]
+    fn status_read_races_vblank() {
+        let vblank = Region::Ntsc.vblank_scanline();
+
+        // Reads PPUSTATUS when `cycle` is the next dot, returns the VBlank bit,
+        // whether it is set afterwards and whether the NMI went through
+        let read_at = |cycle: u16| {
+            let mut ppu = Ppu::new(Region::Ntsc);
+            let mut test_bus = TestBus::new();
+            let mut bus = test_bus.bus();
+
+            ppu.cpu_write(&mut bus, ADDR_CONTROL, 0x80);
+            run_to(&mut ppu, &mut bus, vblank, cycle);
+            let read = ppu.cpu_read(&mut bus, ADDR_STATUS) & 0x80;
+            let cancelled = ppu.check_nmi_cancelled();
+            run_to(&mut ppu, &mut bus, vblank, 10);
+            let nmi = ppu.check_nmi() && !cancelled;
+            (read, ppu.status.contains(PpuStatus::VERTICAL_BLANK), nmi)
+        };
+
+        // One dot early the flag reads as clear and is never set
+        assert_eq!(read_at(1), (0x00, false, false));
+        // On the dot and one dot later the flag is read, but the NMI is suppressed
+        assert_eq!(read_at(2), (0x80, false, false));
+        assert_eq!(read_at(3), (0x80, false, false));
+        assert_eq!(read_at(4), (0x80, false, true));
+        // Two dots early nothing is affected
+        assert_eq!(read_at(0), (0x00, true, true));
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank() {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut test_bus = TestBus::new();
+        let mut bus = test_bus.bus();
+
+        run_to(&mut ppu, &mut bus, Region::Ntsc.vblank_scanline(), 10);
+        assert!(!ppu.check_nmi());
+
+        // The NMI line follows the VBlank flag ANDed with the enable bit
+        ppu.cpu_write(&mut bus, ADDR_CONTROL, 0x80);
+        assert!(ppu.check_nmi());
+        ppu.cpu_write(&mut bus, ADDR_CONTROL, 0x00);
+        ppu.cpu_write(&mut bus, ADDR_CONTROL, 0x80);
+        assert!(ppu.check_nmi());
+
+        // Not after the flag was read
+        ppu.cpu_read(&mut bus, ADDR_STATUS);
+        ppu.cpu_write(&mut bus, ADDR_CONTROL, 0x00);
+        ppu.cpu_write(&mut bus, ADDR_CONTROL, 0x80);
+        assert!(!ppu.check_nmi());
+    }
+
+    #[test]
+    fn sprite_overflow_scan() {
+        const LINE: u8 = 20;
+
+        // Evaluates sprites for the line after `LINE`, every byte not set here is $FF and never in range
+        let overflow = |sprites: &[(usize, usize, u8)]| {
+            let mut ppu = Ppu::new(Region::Ntsc);
+            let mut test_bus = TestBus::new();
+            let mut bus = test_bus.bus();
+
+            let mut oam = [0xFF; 256];
+            for &(sprite, byte, value) in sprites {
+                oam[sprite * 4 + byte] = value;
+            }
+            for data in oam {
+                ppu.dma_write(data);
+            }
+
+            ppu.scanline = LINE as i16;
+            ppu.cycle = HBLANK_CYCLE + 2;
+            ppu.load_foreground_data(&mut bus);
+            ppu.status.contains(PpuStatus::SPRITE_OVERFLOW)
+        };
+        let eight_on_line: Vec<_> = (0..8).map(|sprite| (sprite, 0, LINE)).collect();
+
+        assert!(!overflow(&eight_on_line));
+
+        let mut nine_on_line = eight_on_line.clone();
+        nine_on_line.push((8, 0, LINE - 7));
+        assert!(overflow(&nine_on_line));
+
+        // After the miss on sprite 8 the tile index of sprite 9 is taken for its Y coordinate
+        let mut false_positive = eight_on_line.clone();
+        false_positive.push((9, 1, LINE));
+        assert!(overflow(&false_positive));
+
+        // Sprite 9 is on the line, but only its tile index is compared
+        let mut false_negative = eight_on_line;
+        false_negative.push((9, 0, LINE));
+        assert!(!overflow(&false_negative));
+    }
+}
@@ -1,20 +1,45 @@
 use super::Ram;
 use crate::cartridge::MirrorMode;
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 const TABLE_P2_SIZE: usize = 10; // 0x0400
 
 pub struct Vram {
-    tables: [Ram; 2],
+    /// The console only has two tables, the other two are provided by four-screen cartridges
+    tables: [Ram; 4],
 }
 
 impl Vram {
     pub fn new() -> Self {
         Self {
-            tables: [Ram::new(TABLE_P2_SIZE), Ram::new(TABLE_P2_SIZE)],
+            tables: [
+                Ram::new(TABLE_P2_SIZE),
+                Ram::new(TABLE_P2_SIZE),
+                Ram::new(TABLE_P2_SIZE),
+                Ram::new(TABLE_P2_SIZE),
+            ],
         }
     }
 
-    pub fn read(&mut self, mirror: MirrorMode, addr: u16) -> u8 {
+    #[inline]
+    pub fn table(&self, index: usize) -> &[u8] {
+        self.tables[index].as_slice()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for table in &self.tables {
+            table.save_state(state);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for table in &mut self.tables {
+            table.load_state(state)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, mirror: MirrorMode, addr: u16) -> u8 {
         match mirror {
             MirrorMode::Horizontal => {
                 let table_index = (addr >> 11) & 1;
@@ -26,6 +51,10 @@ impl Vram {
             }
             MirrorMode::OneScreenLow => self.tables[0].read(addr),
             MirrorMode::OneScreenHigh => self.tables[1].read(addr),
+            MirrorMode::FourScreen => {
+                let table_index = (addr >> 10) & 3;
+                self.tables[table_index as usize].read(addr)
+            }
         }
     }
 
@@ -41,6 +70,10 @@ impl Vram {
             }
             MirrorMode::OneScreenLow => self.tables[0].write(addr, data),
             MirrorMode::OneScreenHigh => self.tables[1].write(addr, data),
+            MirrorMode::FourScreen => {
+                let table_index = (addr >> 10) & 3;
+                self.tables[table_index as usize].write(addr, data);
+            }
         }
     }
 }
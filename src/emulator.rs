@@ -0,0 +1,72 @@
+use crate::{load_cartridge, Buttons, Cartridge, CartridgeError, Sample, StateError, System};
+
+/// Steps a system one frame at a time, the interface of typical reinforcement learning environments.
+/// Everything runs synchronously on the calling thread, no window or audio device is involved.
+pub struct Emulator {
+    system: System,
+    /// State right after power-on, `reset` returns to it
+    initial_state: Vec<u8>,
+    /// Samples emitted during the last step
+    samples: Vec<Sample>,
+}
+
+impl Emulator {
+    pub fn new<P: AsRef<std::path::Path>>(rom: P) -> Result<Self, CartridgeError> {
+        Ok(Self::from_cartridge(load_cartridge(rom, None)?))
+    }
+
+    /// Runs the cartridge on a console of the region its header asks for
+    pub fn from_cartridge(cart: Cartridge) -> Self {
+        let region = cart.info().region;
+        let system = System::new(cart, region);
+        let initial_state = system.save_state();
+
+        Self {
+            system,
+            initial_state,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Holds `action` on controller 1 for exactly one frame.
+    /// Returns the RGBA pixels of that frame and the samples emitted while running it.
+    pub fn step(&mut self, action: Buttons) -> (&[u8], &[Sample]) {
+        self.samples.clear();
+        self.system
+            .update_controller_state(action, Buttons::empty());
+
+        let samples = &mut self.samples;
+        self.system.run_frame(&mut |sample| samples.push(sample));
+
+        (self.system.framebuffer(), &self.samples)
+    }
+
+    /// Returns to the state right after power-on, including cartridge RAM, so every episode starts the same way
+    pub fn reset(&mut self) {
+        self.system
+            .load_state(&self.initial_state)
+            .expect("state was saved by the same system");
+        self.samples.clear();
+    }
+
+    #[inline]
+    pub fn save_state(&self) -> Vec<u8> {
+        self.system.save_state()
+    }
+
+    #[inline]
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        self.system.load_state(data)
+    }
+
+    /// The wrapped system, e.g. to read RAM for computing rewards
+    #[inline]
+    pub fn system(&self) -> &System {
+        &self.system
+    }
+
+    #[inline]
+    pub fn system_mut(&mut self) -> &mut System {
+        &mut self.system
+    }
+}
@@ -0,0 +1,120 @@
+use crate::png::write_png;
+use simple_nes::System;
+use std::path::Path;
+use std::process::ExitCode;
+
+/// 64 bit FNV-1a, stable across platforms and releases
+fn hash_frame(data: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xCBF29CE484222325;
+    const PRIME: u64 = 0x00000100000001B3;
+
+    data.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ (byte as u64)).wrapping_mul(PRIME)
+    })
+}
+
+/// Test ROMs following blargg's protocol report their progress at $6000
+/// once the signature $DE $B0 $61 has been written to $6001-$6003
+enum TestStatus {
+    Running,
+    /// The ROM asks to be reset after at least 100 ms
+    NeedsReset,
+    Finished(u8),
+}
+
+fn test_status(system: &System) -> Option<TestStatus> {
+    const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+    let signature = [
+        system.peek(0x6001)?,
+        system.peek(0x6002)?,
+        system.peek(0x6003)?,
+    ];
+    if signature != SIGNATURE {
+        return None;
+    }
+
+    match system.peek(0x6000)? {
+        0x80 => Some(TestStatus::Running),
+        0x81 => Some(TestStatus::NeedsReset),
+        code => Some(TestStatus::Finished(code)),
+    }
+}
+
+/// Zero terminated text the test ROM wrote starting at $6004
+fn test_message(system: &System) -> String {
+    (0x6004..=0x7FFF)
+        .map_while(|addr| system.peek(addr).filter(|&byte| byte != 0))
+        .map(char::from)
+        .collect()
+}
+
+/// Runs `frames` frames with no input, then prints the FNV-1a hash of the final frame's RGBA bytes.
+/// With `test_result` the run ends as soon as a test ROM reports its result, which is printed
+/// instead and determines the exit code.
+pub fn run(
+    mut system: System,
+    frames: u64,
+    pokes: &[(u16, u8)],
+    png: Option<&Path>,
+    crash_report: Option<&Path>,
+    test_result: bool,
+) -> ExitCode {
+    // Comfortably longer than the 100 ms a test ROM waits for at least
+    const RESET_DELAY_FRAMES: u64 = 10;
+
+    system.apply_ram_pokes(pokes);
+    let mut reset_frame = None;
+
+    while system.frame_count() < frames {
+        // Headless runs have no audio output
+        if system.run_frame(&mut |_| {}).frame_completed() {
+            system.apply_ram_pokes(pokes);
+        }
+
+        if system.break_reason().is_some() {
+            crate::report_break(&system, crash_report);
+            return ExitCode::FAILURE;
+        }
+
+        if test_result {
+            match test_status(&system) {
+                Some(TestStatus::NeedsReset) => {
+                    let frame =
+                        *reset_frame.get_or_insert(system.frame_count() + RESET_DELAY_FRAMES);
+                    if system.frame_count() >= frame {
+                        system.reset();
+                        reset_frame = None;
+                    }
+                }
+                Some(TestStatus::Finished(code)) => {
+                    print!("{}", test_message(&system));
+                    println!("result: {code}");
+                    return if code == 0 {
+                        ExitCode::SUCCESS
+                    } else {
+                        ExitCode::FAILURE
+                    };
+                }
+                Some(TestStatus::Running) | None => {}
+            }
+        }
+    }
+
+    if test_result {
+        eprintln!("test did not finish within {frames} frames");
+        return ExitCode::FAILURE;
+    }
+
+    let framebuffer = system.framebuffer();
+    println!("{:0>16x}", hash_frame(framebuffer));
+
+    if let Some(png) = png {
+        if let Err(err) = write_png(png, framebuffer) {
+            eprintln!("failed to write PNG: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,38 @@
+//! Core of the emulator, independent of any windowing or audio backend.
+//!
+//! A frontend loads a ROM with [`load_cartridge`], wraps it in a [`System`] and then drives it
+//! with [`System::clock_exact`], feeding controller input through
+//! [`System::update_controller_state`] and presenting [`System::framebuffer`] once a frame completes.
+//! Audio is delivered to any type implementing [`AudioSink`].
+
+pub mod cartridge;
+mod cheat;
+mod cpu;
+pub mod debug;
+mod device;
+mod emulator;
+mod region;
+mod rewind;
+mod savestate;
+mod system;
+
+pub use cartridge::{load_cartridge, Cartridge, CartridgeError, CartridgeInfo, MirrorMode};
+pub use cheat::{Cheat, CheatError};
+pub use cpu::disassemble;
+pub use device::apu::{
+    ApuState, AudioSink, Channel, DmcState, NoiseState, PulseState, TriangleState,
+};
+pub use device::controller::{Buttons, ControllerPort};
+pub use device::ppu::{OamEntry, PALETTE_SIZE, PATTERN_TABLE_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use device::RamInit;
+pub use emulator::Emulator;
+pub use region::Region;
+pub use rewind::Rewind;
+pub use savestate::StateError;
+pub use system::{FrameCallback, FrameStatus, System};
+
+/// Rate at which the APU emits samples, in Hz
+pub const SAMPLE_RATE: usize = 44100;
+
+pub type Sample = f32;
+pub type SampleBuffer = ringbuf::HeapProd<Sample>;
@@ -1,36 +1,50 @@
-mod cartridge;
-mod cpu;
-mod device;
-mod system;
-
+mod bindings;
+mod font;
+mod headless;
+mod movie;
+mod png;
+mod record;
+
+use bindings::{default_bindings, load_bindings, Bindings, GamepadBindings, Input};
 use bytemuck::{Pod, Zeroable};
 use gilrs::{GamepadId, Gilrs};
+use movie::Movie;
 use ouroboros::self_referencing;
+use png::write_png;
+use record::{Recorder, Tap};
 use rodio::{OutputStream, OutputStreamHandle};
+use simple_nes::{
+    load_cartridge, AudioSink, Buttons, CartridgeError, Channel, Cheat, ControllerPort,
+    FrameStatus, RamInit, Region, Rewind, Sample, SampleBuffer, System, PALETTE_SIZE, SAMPLE_RATE,
+    SCREEN_HEIGHT, SCREEN_WIDTH,
+};
 use std::mem;
-use std::sync::atomic::{self, AtomicBool};
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::ExitCode;
+use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 use wgpu::{
-    Adapter, BindGroup, Buffer, Device, Extent3d, ImageDataLayout, Queue, RenderPipeline, Sampler,
-    ShaderModule, Surface, SurfaceTexture, Texture,
+    Adapter, BindGroup, Buffer, Device, Extent3d, ImageDataLayout, PresentMode, Queue,
+    RenderPipeline, Sampler, ShaderModule, Surface, SurfaceTexture, Texture,
 };
 use winit::application::ApplicationHandler;
-use winit::dpi::PhysicalSize;
-use winit::event::{ElementState, KeyEvent, WindowEvent};
-use winit::event_loop::{ActiveEventLoop, ControlFlow};
-use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{ElementState, KeyEvent, MouseButton, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoopProxy};
+use winit::keyboard::{KeyCode, ModifiersState, PhysicalKey};
 use winit::window::{Window, WindowAttributes, WindowId};
 
-const SAMPLE_RATE: usize = 44100;
-
-type Sample = f32;
-type SampleBuffer = ringbuf::HeapProd<Sample>;
 type SampleSource = ringbuf::HeapCons<Sample>;
 
+/// Applied to the APU's samples before they are played back or recorded
+const OUTPUT_GAIN: f32 = 10.0;
+
 struct SampleBufferSource {
     source: SampleSource,
+    flags: Arc<EmuFlags>,
 }
 
 impl Iterator for SampleBufferSource {
@@ -39,8 +53,15 @@ impl Iterator for SampleBufferSource {
     fn next(&mut self) -> Option<Self::Item> {
         use ringbuf::traits::Consumer;
 
-        let sample = self.source.try_pop().unwrap_or(0.0);
-        Some(sample * 10.0)
+        let sample = self.source.try_pop().unwrap_or_else(|| {
+            if self.flags.audio_paced.load(atomic::Ordering::Acquire) {
+                self.flags
+                    .audio_underruns
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            0.0
+        });
+        Some(sample * OUTPUT_GAIN)
     }
 }
 
@@ -72,38 +93,54 @@ struct AudioResources {
     stream_handle: OutputStreamHandle,
 }
 
-impl AudioResources {
-    fn create() -> (Self, SampleBuffer) {
-        use ringbuf::traits::Split;
+/// Number of samples played back in `duration`
+fn samples_in(duration: Duration) -> usize {
+    (duration.as_secs_f64() * (SAMPLE_RATE as f64)) as usize
+}
+
+fn create_sample_buffer(latency: Duration) -> (SampleBuffer, SampleSource) {
+    use ringbuf::traits::Split;
+
+    // Emulation only tops the buffer up to `latency`, the rest is headroom. At least a whole frame has to fit,
+    // deterministic mode pushes the samples of an entire frame at once.
+    const MIN_CAPACITY: Duration = Duration::from_millis(50);
+
+    let capacity = (latency * 3).max(MIN_CAPACITY);
+    let sample_buffer = ringbuf::HeapRb::<Sample>::new(samples_in(capacity));
+    sample_buffer.split()
+}
 
-        let sample_buffer = ringbuf::HeapRb::<Sample>::new(SAMPLE_RATE / 20); // Buffer can store 50ms worth of samples
-        let (sample_buffer, sample_source) = sample_buffer.split();
-        let (stream, stream_handle) = rodio::OutputStream::try_default().unwrap();
+impl AudioResources {
+    fn create(latency: Duration, flags: Arc<EmuFlags>) -> Result<(Self, SampleBuffer), String> {
+        let (sample_buffer, sample_source) = create_sample_buffer(latency);
+        let (stream, stream_handle) =
+            rodio::OutputStream::try_default().map_err(|err| err.to_string())?;
         stream_handle
             .play_raw(SampleBufferSource {
                 source: sample_source,
+                flags,
             })
-            .unwrap();
+            .map_err(|err| err.to_string())?;
 
-        (
+        Ok((
             Self {
                 stream,
                 stream_handle,
             },
             sample_buffer,
-        )
+        ))
     }
 }
 
 const TEXTURE_SIZE: Extent3d = Extent3d {
-    width: device::ppu::SCREEN_WIDTH as u32,
-    height: device::ppu::SCREEN_HEIGHT as u32,
+    width: SCREEN_WIDTH as u32,
+    height: SCREEN_HEIGHT as u32,
     depth_or_array_layers: 1,
 };
 
 const TEXTURE_LAYOUT: ImageDataLayout = ImageDataLayout {
     offset: 0,
-    bytes_per_row: Some((device::ppu::SCREEN_WIDTH as u32) * 4),
+    bytes_per_row: Some((SCREEN_WIDTH as u32) * 4),
     rows_per_image: None,
 };
 
@@ -114,6 +151,14 @@ struct Vertex {
     tex_coords: [f32; 2],
 }
 
+#[derive(Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+struct ShaderParams {
+    mode: u32,
+    // Uniform buffers have to be a multiple of 16 bytes in size
+    _padding: [u32; 3],
+}
+
 #[allow(dead_code)]
 struct GpuResources<'w> {
     surface: Surface<'w>,
@@ -122,14 +167,20 @@ struct GpuResources<'w> {
     queue: Queue,
     shader: ShaderModule,
     vertex_buffer: Buffer,
+    params_buffer: Buffer,
     texture: Texture,
     sampler: Sampler,
     bind_group: BindGroup,
     pipeline: RenderPipeline,
+    present_mode: PresentMode,
 }
 
 impl<'w> GpuResources<'w> {
-    async fn create(window: &'w Window) -> Self {
+    async fn create(
+        window: &'w Window,
+        present_mode: PresentMode,
+        shader_mode: ShaderMode,
+    ) -> Self {
         use wgpu::*;
 
         let instance_desc = InstanceDescriptor {
@@ -176,6 +227,13 @@ impl<'w> GpuResources<'w> {
             mapped_at_creation: false,
         });
 
+        let params_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: mem::size_of::<ShaderParams>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
         let texture = device.create_texture(&TextureDescriptor {
             label: None,
             size: TEXTURE_SIZE,
@@ -217,6 +275,16 @@ impl<'w> GpuResources<'w> {
                     ty: BindingType::Sampler(SamplerBindingType::Filtering),
                     count: None,
                 },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         });
 
@@ -234,6 +302,10 @@ impl<'w> GpuResources<'w> {
                     binding: 1,
                     resource: BindingResource::Sampler(&sampler),
                 },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: params_buffer.as_entire_binding(),
+                },
             ],
         });
 
@@ -283,13 +355,16 @@ impl<'w> GpuResources<'w> {
             queue,
             shader,
             vertex_buffer,
+            params_buffer,
             texture,
             sampler,
             bind_group,
             pipeline,
+            present_mode,
         };
 
         this.configure_surface(window.inner_size());
+        this.set_shader_mode(shader_mode);
 
         this
     }
@@ -299,10 +374,19 @@ impl<'w> GpuResources<'w> {
             .surface
             .get_default_config(&self.adapter, size.width.max(1), size.height.max(1))
             .expect("failed to configure surface");
-        surface_config.present_mode = wgpu::PresentMode::AutoVsync;
+        surface_config.present_mode = self.present_mode;
 
         self.surface.configure(&self.device, &surface_config);
     }
+
+    fn set_shader_mode(&self, mode: ShaderMode) {
+        let params = ShaderParams {
+            mode: mode as u32,
+            _padding: [0; 3],
+        };
+        self.queue
+            .write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&params));
+    }
 }
 
 #[self_referencing]
@@ -314,84 +398,561 @@ struct AppResources {
     gpu_resources: Option<GpuResources<'this>>,
 }
 
-fn run_emu(running: &AtomicBool, system: &Mutex<system::System>, mut sample_buffer: SampleBuffer) {
-    use ringbuf::traits::Observer;
-    use std::time::Duration;
+fn report_break(system: &System, crash_report: Option<&std::path::Path>) {
+    if let Some(reason) = system.break_reason() {
+        eprintln!("emulation paused: {reason}");
+    }
+
+    if let Some(crash_report) = crash_report {
+        match system.crash_report().write_to_file(crash_report) {
+            Ok(()) => eprintln!("crash report written to {}", crash_report.display()),
+            Err(err) => eprintln!("failed to write crash report: {err}"),
+        }
+    }
+}
+
+/// Locks `mutex` even if the emulation thread panicked while holding it, the UI keeps showing the last state then
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Sent by the emulation thread when a new frame has been completed
+#[derive(Debug, Clone, Copy)]
+struct FrameReady;
+
+/// Flags shared between the event loop and the emulation thread
+struct EmuFlags {
+    running: AtomicBool,
+    paused: AtomicBool,
+    /// Runs a single frame while paused
+    advance: AtomicBool,
+    /// Goes back a single frame while paused
+    step_back: AtomicBool,
+    /// Plays back the rewind buffer for as long as it is set
+    rewinding: AtomicBool,
+    /// Runs at `fast_forward_speed` for as long as it is set
+    fast_forward: AtomicBool,
+    fast_forward_speed: AtomicU32,
+    /// Emulation speed in percent while not fast-forwarding
+    speed_percent: AtomicU32,
+    /// Set by the emulation thread while it keeps the sample buffer filled, an empty buffer is only
+    /// an underrun then and not the result of pausing, rewinding or unlimited fast-forward
+    audio_paced: AtomicBool,
+    /// Samples played back as silence because emulation didn't fill the buffer in time
+    audio_underruns: AtomicU64,
+    /// Set when the emulation thread panicked, emulation has stopped then
+    crashed: AtomicBool,
+}
+
+/// Fast-forward speed that runs emulation as fast as possible
+const UNLIMITED_SPEED: u32 = 0;
+/// Fast-forward speeds the speed hotkey cycles through
+const FAST_FORWARD_SPEEDS: [u32; 3] = [2, 4, UNLIMITED_SPEED];
+
+fn next_fast_forward_speed(speed: u32) -> u32 {
+    let next = FAST_FORWARD_SPEEDS
+        .iter()
+        .position(|&s| s == speed)
+        .map_or(0, |index| (index + 1) % FAST_FORWARD_SPEEDS.len());
+    FAST_FORWARD_SPEEDS[next]
+}
+
+/// Speeds in percent the speed hotkeys step through
+const SPEED_STEPS: [u32; 7] = [10, 25, 50, 75, 100, 150, 200];
+
+fn faster_speed(percent: u32) -> u32 {
+    SPEED_STEPS
+        .into_iter()
+        .find(|&step| step > percent)
+        .unwrap_or(percent)
+}
+
+/// How often FPS and emulation speed in the window title are refreshed
+const TITLE_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shows how many frames are presented per second and how fast emulation runs compared to the real console.
+/// Emulation running notably slower than it should is called out so users know to lower their settings.
+fn window_title(flags: &EmuFlags, fps: f64, realtime: f64, audio_stutter: bool) -> String {
+    const SLOW_TOLERANCE: f64 = 0.95;
+
+    if flags.crashed.load(atomic::Ordering::Acquire) {
+        return "SimpleNES - emulation crashed".to_owned();
+    }
+
+    if flags.paused.load(atomic::Ordering::Acquire) {
+        return "SimpleNES - paused".to_owned();
+    }
+
+    let slow = flags
+        .target_speed()
+        .is_some_and(|target_speed| realtime < (target_speed * SLOW_TOLERANCE));
+    format!(
+        "SimpleNES - {fps:.0} FPS - {:.0}% speed{}{}",
+        realtime * 100.0,
+        if slow { " (too slow)" } else { "" },
+        if audio_stutter {
+            " - audio stutter"
+        } else {
+            ""
+        },
+    )
+}
+
+fn slower_speed(percent: u32) -> u32 {
+    SPEED_STEPS
+        .into_iter()
+        .rev()
+        .find(|&step| step < percent)
+        .unwrap_or(percent)
+}
+
+impl EmuFlags {
+    /// Speed emulation should run at as a multiple of realtime, `None` while fast-forwarding at unlimited speed
+    fn target_speed(&self) -> Option<f64> {
+        if self.fast_forward.load(atomic::Ordering::Acquire) {
+            let speed = self.fast_forward_speed.load(atomic::Ordering::Acquire);
+            (speed != UNLIMITED_SPEED).then_some(speed as f64)
+        } else {
+            Some((self.speed_percent.load(atomic::Ordering::Acquire) as f64) / 100.0)
+        }
+    }
+
+    /// Message drawn over the picture, if any
+    fn status_message(&self) -> Option<String> {
+        if self.crashed.load(atomic::Ordering::Acquire) {
+            return Some("CRASHED".to_owned());
+        }
+
+        if self.paused.load(atomic::Ordering::Acquire) {
+            return Some("PAUSED".to_owned());
+        }
+
+        if self.fast_forward.load(atomic::Ordering::Acquire) {
+            let speed = self.fast_forward_speed.load(atomic::Ordering::Acquire);
+            if speed == UNLIMITED_SPEED {
+                return Some(">> MAX".to_owned());
+            }
+            return Some(format!(">> {speed}X"));
+        }
+
+        let percent = self.speed_percent.load(atomic::Ordering::Acquire);
+        (percent != 100).then(|| format!("{}X", (percent as f64) / 100.0))
+    }
+}
+
+/// Passes the input of all controllers on to the system, players 3 and 4 only exist with a Four Score
+fn apply_input(system: &mut System, input: [Buttons; 4], four_score: bool) {
+    if four_score {
+        system.update_four_score_state(input);
+    } else {
+        system.update_controller_state(input[0], input[1]);
+    }
+}
+
+/// Settings of the emulation thread that don't change while it is running
+struct EmuConfig {
+    crash_report: Option<std::path::PathBuf>,
+    frame_ready: Option<EventLoopProxy<FrameReady>>,
+    pokes: Vec<(u16, u8)>,
+    recorder: Option<Arc<Recorder>>,
+    movie: Option<Arc<Mutex<Movie>>>,
+    /// Only set in deterministic mode, input is then applied at frame boundaries instead of whenever the UI polls it
+    latched_input: Option<Arc<Mutex<[Buttons; 4]>>>,
+    four_score: bool,
+    /// Runs whole frames paced by a fixed frame time instead of by audio playback
+    deterministic: bool,
+    /// Amount of audio emulation stays ahead of playback
+    audio_latency: Duration,
+}
 
-    while running.load(atomic::Ordering::Acquire) {
-        // Run emulation until we have at least 15ms worth of samples in the buffer
-        {
-            let mut system = system.lock().unwrap();
-            while sample_buffer.occupied_len() < (SAMPLE_RATE / 67) {
-                system.clock(1000, &mut sample_buffer);
+impl EmuConfig {
+    /// Runs the system for `cpu_cycles` cycles, samples are also recorded if a recording is running
+    fn clock(
+        &self,
+        system: &mut System,
+        cpu_cycles: u32,
+        sample_buffer: &mut SampleBuffer,
+    ) -> FrameStatus {
+        match &self.recorder {
+            Some(recorder) => {
+                let mut samples = Vec::new();
+                let mut tap = Tap {
+                    inner: sample_buffer,
+                    samples: &mut samples,
+                };
+                let status = system.clock_exact(cpu_cycles, &mut tap);
+                recorder.push_samples(samples);
+                status
             }
+            None => system.clock_exact(cpu_cycles, sample_buffer),
         }
+    }
+
+    /// Runs the system up to the end of the current frame, samples are also recorded if a recording is running
+    fn run_frame(&self, system: &mut System, sample_buffer: &mut SampleBuffer) -> FrameStatus {
+        let mut samples = Vec::new();
+        let status = system.run_frame(&mut |sample| {
+            sample_buffer.push_sample(sample);
+            if self.recorder.is_some() {
+                samples.push(sample);
+            }
+        });
 
-        // Idle until we have less than 10ms worth of samples in the buffer
+        if let Some(recorder) = &self.recorder {
+            recorder.push_samples(samples);
+        }
+        status
+    }
+
+    /// Called by the emulation thread whenever a new frame has been completed
+    fn end_frame(&self, system: &mut System, rewind: &mut Rewind) {
+        if let Some(recorder) = &self.recorder {
+            recorder.push_frame(system.framebuffer());
+        }
+
+        if let Some(movie) = &self.movie {
+            let [a, b] = lock(movie).next_input();
+            system.update_controller_state(a, b);
+        } else if let Some(latched_input) = &self.latched_input {
+            apply_input(system, *lock(latched_input), self.four_score);
+        }
+
+        system.apply_ram_pokes(&self.pokes);
+        rewind.push(system);
+
+        if let Some(frame_ready) = &self.frame_ready {
+            // Only fails if the event loop has already exited
+            let _ = frame_ready.send_event(FrameReady);
+        }
+    }
+}
+
+/// Goes back by one captured state and runs a frame from the state before it,
+/// so the framebuffer shows the previous frame again
+fn step_back(system: &mut System, rewind: &mut Rewind, config: &EmuConfig) {
+    // The most recent state belongs to the frame that is currently shown
+    if rewind.len() < 2 {
+        return;
+    }
+    rewind.pop();
+
+    if let Some(state) = rewind.pop() {
+        if system.load_state(&state).is_ok() && system.run_frame(&mut |_| {}).frame_completed() {
+            config.end_frame(system, rewind);
+        }
+    }
+}
+
+fn run_emu(
+    flags: &EmuFlags,
+    system: &Mutex<System>,
+    config: &EmuConfig,
+    mut sample_buffer: SampleBuffer,
+    mut silent_source: Option<SampleSource>,
+) {
+    use ringbuf::traits::{Consumer, Observer};
+
+    // A state is captured every frame so stepping back while paused is frame exact
+    const REWIND_INTERVAL: u32 = 1;
+    const REWIND_SECONDS: u32 = 60;
+    const REWIND_FRAME_TIME: Duration = Duration::from_micros(16_639);
+    /// Samples produced per lock of the system while topping up the buffer, about 0.7 ms of audio
+    const MAX_CHUNK_SAMPLES: usize = 32;
+
+    let crash_report = config.crash_report.as_deref();
+    let mut rewind = Rewind::new(REWIND_INTERVAL, REWIND_SECONDS);
+    let mut speed = 1.0;
+    let (frame_rate, cpu_clock_speed) = {
+        let mut system = lock(system);
+        system.apply_ram_pokes(&config.pokes);
+        let region = system.region();
+        (region.frame_rate(), region.cpu_clock_speed())
+    };
+
+    // Growing the latency past two thirds of the buffer would leave no headroom
+    let mut audio_latency = config.audio_latency;
+    let max_audio_latency = Duration::from_secs_f64(
+        (sample_buffer.capacity().get() as f64) / (SAMPLE_RATE as f64) * 2.0 / 3.0,
+    )
+    .max(audio_latency);
+    let mut last_underruns = flags.audio_underruns.load(atomic::Ordering::Relaxed);
+
+    // Without an audio device nothing plays the samples, so they are discarded at playback speed
+    // instead, which keeps emulation paced the same way
+    let mut last_drain = Instant::now();
+    let mut next_frame = Instant::now();
+
+    while flags.running.load(atomic::Ordering::Acquire) {
+        if let Some(source) = &mut silent_source {
+            let due = (last_drain.elapsed().as_secs_f64() * (SAMPLE_RATE as f64)) as usize;
+            source.skip(due);
+            last_drain += Duration::from_secs_f64((due as f64) / (SAMPLE_RATE as f64));
+        }
+
+        if flags.rewinding.load(atomic::Ordering::Acquire) {
+            flags.audio_paced.store(false, atomic::Ordering::Release);
+            step_back(&mut lock(system), &mut rewind, config);
+            spin_sleep::sleep(REWIND_FRAME_TIME);
+            continue;
+        }
+
+        if flags.paused.load(atomic::Ordering::Acquire) {
+            flags.audio_paced.store(false, atomic::Ordering::Release);
+            if flags.step_back.swap(false, atomic::Ordering::AcqRel) {
+                step_back(&mut lock(system), &mut rewind, config);
+            } else if flags.advance.swap(false, atomic::Ordering::AcqRel) {
+                // Run exactly one frame, then stay paused
+                let mut system = lock(system);
+                loop {
+                    if config
+                        .clock(&mut system, 1, &mut sample_buffer)
+                        .frame_completed()
+                    {
+                        config.end_frame(&mut system, &mut rewind);
+                        break;
+                    }
+
+                    if system.break_reason().is_some() {
+                        report_break(&system, crash_report);
+                        break;
+                    }
+                }
+            } else {
+                spin_sleep::sleep(Duration::from_millis(10));
+            }
+
+            continue;
+        }
+
+        let target_speed = flags.target_speed();
+        let unlimited = target_speed.is_none();
+        let target_speed = target_speed.unwrap_or(speed);
+        if !unlimited && (target_speed != speed) {
+            // Emulation stays paced by audio playback, the audio is stretched or pitched up instead.
+            // This only changes how many samples are emitted, so deterministic runs are unaffected.
+            speed = target_speed;
+            lock(system).set_speed(speed);
+        }
+
+        if unlimited {
+            // Audio can't keep up at this speed so it is dropped, the lock is released
+            // after every frame so the UI thread can still present
+            flags.audio_paced.store(false, atomic::Ordering::Release);
+            let mut system = lock(system);
+            if system.run_frame(&mut |_| {}).frame_completed() {
+                config.end_frame(&mut system, &mut rewind);
+            }
+
+            if system.break_reason().is_some() {
+                flags.paused.store(true, atomic::Ordering::Release);
+                report_break(&system, crash_report);
+            }
+
+            continue;
+        }
+
+        if config.deterministic {
+            // Frames always end exactly at the frame boundary and audio gets whatever the frame produced.
+            // The speed also applies to the APU so a frame produces as many samples as playback drains
+            // in the wait between frames, whatever doesn't fit because the clocks drift apart is dropped.
+            {
+                let mut system = lock(system);
+                if config
+                    .run_frame(&mut system, &mut sample_buffer)
+                    .frame_completed()
+                {
+                    config.end_frame(&mut system, &mut rewind);
+                }
+
+                if system.break_reason().is_some() {
+                    flags.paused.store(true, atomic::Ordering::Release);
+                    report_break(&system, crash_report);
+                }
+            }
+            flags.audio_paced.store(true, atomic::Ordering::Release);
+
+            let now = Instant::now();
+            next_frame =
+                next_frame.max(now) + Duration::from_secs_f64(1.0 / (frame_rate * target_speed));
+            spin_sleep::sleep(next_frame.saturating_duration_since(now));
+            continue;
+        }
+
+        // Underruns mean this machine can't keep up with the configured latency, so more audio is buffered ahead
+        let underruns = flags.audio_underruns.load(atomic::Ordering::Relaxed);
+        if (underruns > last_underruns) && (audio_latency < max_audio_latency) {
+            audio_latency = (audio_latency * 5 / 4).min(max_audio_latency);
+            eprintln!(
+                "audio underruns, raising the audio latency to {} ms",
+                audio_latency.as_millis()
+            );
+        }
+        last_underruns = underruns;
+
+        // Run emulation until the buffer holds `audio_latency` worth of samples. Only as many cycles as the
+        // missing samples need are run, in small chunks with the lock released in between, so the UI can
+        // present and hand over input without waiting for the whole buffer to be topped up.
+        let cycles_per_sample = cpu_clock_speed * speed / (SAMPLE_RATE as f64);
+        loop {
+            let missing_samples =
+                samples_in(audio_latency).saturating_sub(sample_buffer.occupied_len());
+            if missing_samples == 0 {
+                break;
+            }
+
+            let chunk_samples = missing_samples.min(MAX_CHUNK_SAMPLES);
+            let cycles = ((chunk_samples as f64) * cycles_per_sample).ceil() as u32;
+            let mut system = lock(system);
+            if config
+                .clock(&mut system, cycles.max(1), &mut sample_buffer)
+                .frame_completed()
+            {
+                config.end_frame(&mut system, &mut rewind);
+            }
+
+            if system.break_reason().is_some() {
+                flags.paused.store(true, atomic::Ordering::Release);
+                report_break(&system, crash_report);
+                break;
+            }
+        }
+        flags.audio_paced.store(true, atomic::Ordering::Release);
+
+        // Idle until the buffer has drained to two thirds of the latency, which leaves a third of it
+        // to top the buffer up again
         let available_audio_duration =
             Duration::from_secs_f64((sample_buffer.occupied_len() as f64) / (SAMPLE_RATE as f64));
-        spin_sleep::sleep(available_audio_duration.saturating_sub(Duration::from_millis(10)));
+        spin_sleep::sleep(available_audio_duration.saturating_sub(audio_latency * 2 / 3));
     }
+
+    flags.audio_paced.store(false, atomic::Ordering::Release);
+}
+
+/// Returns the held buttons and the held turbo buttons of the active gamepad.
+/// The left stick acts as a second D-pad once it is pushed further than `stick_deadzone`.
+fn gamepad_buttons(
+    gamepad: gilrs::Gamepad,
+    bindings: &GamepadBindings,
+    stick_deadzone: f32,
+) -> (Buttons, Buttons) {
+    let mut controller_joy = Buttons::empty();
+    let mut turbo_joy = Buttons::empty();
+
+    let stick_x = gamepad.value(gilrs::Axis::LeftStickX);
+    let stick_y = gamepad.value(gilrs::Axis::LeftStickY);
+    controller_joy.set(Buttons::UP, stick_y > stick_deadzone);
+    controller_joy.set(Buttons::DOWN, stick_y < -stick_deadzone);
+    controller_joy.set(Buttons::LEFT, stick_x < -stick_deadzone);
+    controller_joy.set(Buttons::RIGHT, stick_x > stick_deadzone);
+
+    for (&pad_button, &input) in bindings.iter() {
+        if gamepad.is_pressed(pad_button) {
+            match input {
+                Input::Button(button) => controller_joy |= button,
+                Input::Turbo(button) => turbo_joy |= button,
+            }
+        }
+    }
+
+    (controller_joy, turbo_joy)
 }
 
 fn update_gamepad(
     gilrs: Option<&mut Gilrs>,
     active_gamepad: &mut Option<GamepadId>,
-) -> Option<device::controller::Buttons> {
+    bindings: &GamepadBindings,
+    stick_deadzone: f32,
+) -> Option<(Buttons, Buttons)> {
     gilrs.and_then(|gilrs| {
         while let Some(gilrs::Event { id, .. }) = gilrs.next_event() {
             *active_gamepad = Some(id);
         }
 
-        active_gamepad.map(|id| {
-            let gamepad = gilrs.gamepad(id);
-            let mut controller_a_joy = device::controller::Buttons::empty();
+        active_gamepad.map(|id| gamepad_buttons(gilrs.gamepad(id), bindings, stick_deadzone))
+    })
+}
 
-            controller_a_joy.set(
-                device::controller::Buttons::UP,
-                gamepad.is_pressed(gilrs::Button::DPadUp),
-            );
-            controller_a_joy.set(
-                device::controller::Buttons::DOWN,
-                gamepad.is_pressed(gilrs::Button::DPadDown),
-            );
-            controller_a_joy.set(
-                device::controller::Buttons::LEFT,
-                gamepad.is_pressed(gilrs::Button::DPadLeft),
-            );
-            controller_a_joy.set(
-                device::controller::Buttons::RIGHT,
-                gamepad.is_pressed(gilrs::Button::DPadRight),
-            );
-            controller_a_joy.set(
-                device::controller::Buttons::START,
-                gamepad.is_pressed(gilrs::Button::Start),
-            );
-            controller_a_joy.set(
-                device::controller::Buttons::SELECT,
-                gamepad.is_pressed(gilrs::Button::Select),
-            );
-            controller_a_joy.set(
-                device::controller::Buttons::A,
-                gamepad.is_pressed(gilrs::Button::East) | gamepad.is_pressed(gilrs::Button::South),
-            );
-            controller_a_joy.set(
-                device::controller::Buttons::B,
-                gamepad.is_pressed(gilrs::Button::West) | gamepad.is_pressed(gilrs::Button::North),
-            );
+/// Players 3 and 4 of a Four Score are controlled by the connected gamepads
+/// other than the one controlling player 1, in the order they were connected.
+/// They use the gamepad bindings of port A and B respectively, as they share those ports.
+fn extra_gamepads(
+    gilrs: Option<&Gilrs>,
+    active_gamepad: Option<GamepadId>,
+    bindings: &[GamepadBindings; 2],
+    stick_deadzone: f32,
+) -> [(Buttons, Buttons); 2] {
+    let mut extra = [(Buttons::empty(), Buttons::empty()); 2];
+    if let Some(gilrs) = gilrs {
+        let gamepads = gilrs
+            .gamepads()
+            .filter(|&(id, _)| Some(id) != active_gamepad);
+        for ((player, bindings), (_, gamepad)) in extra.iter_mut().zip(bindings).zip(gamepads) {
+            *player = gamepad_buttons(gamepad, bindings, stick_deadzone);
+        }
+    }
+    extra
+}
 
-            controller_a_joy
-        })
-    })
+/// Turbo buttons are pressed during the first half of every period and released during the second half.
+/// The period is measured in emulated frames so turbo keeps working when presentation runs at a different rate.
+fn apply_turbo(
+    held: Buttons,
+    turbo: Buttons,
+    frame: u64,
+    turbo_rate: f64,
+    frame_rate: f64,
+) -> Buttons {
+    let phase = ((frame as f64) * turbo_rate / frame_rate).fract();
+    if phase < 0.5 {
+        held | turbo
+    } else {
+        held
+    }
 }
 
-fn create_vertices(window_size: PhysicalSize<u32>) -> [Vertex; 6] {
-    let width_scale = (window_size.width as f32) / (device::ppu::SCREEN_WIDTH as f32);
-    let height_scale = (window_size.height as f32) / (device::ppu::SCREEN_HEIGHT as f32);
-    let scale = width_scale.min(height_scale);
+#[derive(Debug, Clone, Copy)]
+struct Scaling {
+    /// Only scale by whole multiples of the screen height
+    integer: bool,
+    aspect: Aspect,
+}
 
-    let width_coord = scale / width_scale;
-    let height_coord = scale / height_scale;
+/// Size of the picture within the window, in physical pixels
+fn picture_size(window_size: PhysicalSize<u32>, scaling: Scaling) -> (f32, f32) {
+    let display_width = (SCREEN_WIDTH as f32) * scaling.aspect.pixel_aspect_ratio();
+    let width_scale = (window_size.width as f32) / display_width;
+    let height_scale = (window_size.height as f32) / (SCREEN_HEIGHT as f32);
+    let mut scale = width_scale.min(height_scale);
+
+    // Windows smaller than the screen fall back to fractional scaling instead of showing nothing
+    if scaling.integer && (scale >= 1.0) {
+        scale = scale.floor();
+    }
+
+    (display_width * scale, (SCREEN_HEIGHT as f32) * scale)
+}
+
+/// Maps a position in the window to the screen pixel shown there, if any
+fn window_to_screen(
+    window_size: PhysicalSize<u32>,
+    scaling: Scaling,
+    position: PhysicalPosition<f64>,
+) -> Option<(usize, usize)> {
+    let (width, height) = picture_size(window_size, scaling);
+    let left = ((window_size.width as f32) - width) / 2.0;
+    let top = ((window_size.height as f32) - height) / 2.0;
+
+    let x = ((position.x as f32) - left) / width * (SCREEN_WIDTH as f32);
+    let y = ((position.y as f32) - top) / height * (SCREEN_HEIGHT as f32);
+    let on_screen =
+        (0.0..SCREEN_WIDTH as f32).contains(&x) && (0.0..SCREEN_HEIGHT as f32).contains(&y);
+    on_screen.then_some((x as usize, y as usize))
+}
+
+fn create_vertices(window_size: PhysicalSize<u32>, scaling: Scaling) -> [Vertex; 6] {
+    let (width, height) = picture_size(window_size, scaling);
+    let width_coord = width / (window_size.width as f32);
+    let height_coord = height / (window_size.height as f32);
 
     [
         Vertex {
@@ -459,124 +1020,557 @@ fn draw(gpu_resources: &GpuResources, frame: SurfaceTexture) {
     frame.present();
 }
 
+fn load_palette(file: &std::path::Path) -> Result<[u8; PALETTE_SIZE], String> {
+    let data = std::fs::read(file).map_err(|err| err.to_string())?;
+    data.as_slice().try_into().map_err(|_| {
+        format!(
+            "expected {PALETTE_SIZE} bytes (64 RGB colors) but the file is {} bytes long",
+            data.len()
+        )
+    })
+}
+
+fn create_system(args: &Args) -> Result<System, CartridgeError> {
+    let cart = load_cartridge(&args.rom, args.patch.as_deref())?;
+
+    let region = args.region.unwrap_or(cart.info().region);
+    let mut system = System::new(cart, region);
+    if let Entry::Addr(pc) = args.entry {
+        system.set_pc(pc);
+    }
+
+    system.set_ram_init(args.ram_init);
+    system.set_open_bus_decay(args.open_bus_decay);
+    system.set_four_score_connected(args.four_score);
+    system.set_zapper_connected(args.zapper);
+    for code in args.cheats.iter() {
+        system
+            .add_cheat(code)
+            .expect("cheat codes are validated while parsing arguments");
+    }
+    system.set_silence_ultrasonic(args.silence_ultrasonic);
+    system.set_audio_filter(!args.no_audio_filter);
+
+    let debugger = system.debugger_mut();
+    if args.crash_report.is_some() {
+        debugger.set_trace_size(args.trace_size);
+    }
+    for &pc in args.breakpoints.iter() {
+        debugger.add_breakpoint(pc);
+    }
+    for &addr in args.watchpoints.iter() {
+        debugger.add_watchpoint(addr);
+    }
+
+    Ok(system)
+}
+
+/// Draws a message centered at the top of the screen
+fn draw_message(framebuffer: &mut [u8], text: &str) {
+    const COLOR: [u8; 4] = [255, 255, 255, 255];
+
+    let x = SCREEN_WIDTH.saturating_sub(font::text_width(text)) / 2;
+    font::draw_text_shadowed(framebuffer, SCREEN_WIDTH, x, 8, text, COLOR);
+}
+
 struct App {
     resources: Option<AppResources>,
-    running: Arc<AtomicBool>,
-    system: Arc<Mutex<system::System>>,
+    flags: Arc<EmuFlags>,
+    system: Arc<Mutex<System>>,
     thread_handle: Option<JoinHandle<()>>,
     gilrs: Option<Gilrs>,
     active_gamepad: Option<GamepadId>,
-    controller_a_kb: device::controller::Buttons,
+    bindings: Bindings,
+    controller_kb: [Buttons; 2],
+    turbo_kb: [Buttons; 2],
+    turbo_rate: f64,
+    /// Frames per second of the emulated console
+    frame_rate: f64,
+    stick_deadzone: f32,
+    four_score: bool,
+    /// Screen pixel under the mouse cursor, used as the Zapper's aim
+    zapper_aim: Option<(usize, usize)>,
+    zapper_trigger: bool,
+    modifiers: ModifiersState,
+    save_path: Option<std::path::PathBuf>,
+    state_path: std::path::PathBuf,
+    /// Screenshots are written to this path with a timestamp appended
+    screenshot_base: std::path::PathBuf,
+    crash_report: Option<std::path::PathBuf>,
+    present_mode: PresentMode,
+    frame_interval: Option<Duration>,
+    next_frame: Instant,
+    pacing: Pacing,
+    /// Print frame pacing statistics when the window is closed
+    pacing_stats: bool,
+    scaling: Scaling,
+    shader_mode: ShaderMode,
+    frame_ready: EventLoopProxy<FrameReady>,
+    pokes: Vec<(u16, u8)>,
+    overlay: Vec<u8>,
+    last_presented_frame: u64,
+    presented_frames: u64,
+    /// Presented and emulated frame counts at the last window title update
+    title_update: (Instant, u64, u64, u64),
+    duplicated_frames: u64,
+    dropped_frames: u64,
+    audio_warning_shown: bool,
+    recorder: Option<Arc<Recorder>>,
+    /// While a movie is active it supplies the controller input instead of the player
+    movie: Option<Arc<Mutex<Movie>>>,
+    deterministic: bool,
+    audio_latency: Duration,
+    /// Input for the next frame in deterministic mode
+    latched_input: Option<Arc<Mutex<[Buttons; 4]>>>,
 }
 
 impl App {
-    fn new(rom: impl AsRef<std::path::Path>) -> Self {
-        let cart = cartridge::load_cartridge(rom).unwrap();
+    fn new(
+        args: Args,
+        mut system: System,
+        recorder: Option<Arc<Recorder>>,
+        movie: Option<Movie>,
+        frame_ready: EventLoopProxy<FrameReady>,
+    ) -> Self {
+        // Only battery-backed RAM is persisted. Movies start from a blank save,
+        // so they play back the same way regardless of the player's progress.
+        let save_path =
+            (system.has_battery() && movie.is_none()).then(|| args.rom.with_extension("sav"));
+        if let Some(save_path) = &save_path {
+            if let Ok(data) = std::fs::read(save_path) {
+                system.load_battery_ram(&data);
+            }
+        }
+
+        // The input of every following frame is applied by the emulation thread when the previous one ends
+        let movie = movie.map(|mut movie| {
+            let [a, b] = movie.next_input();
+            system.update_controller_state(a, b);
+            Arc::new(Mutex::new(movie))
+        });
 
+        let frame_rate = system.region().frame_rate();
         Self {
             resources: None,
-            running: Arc::new(AtomicBool::new(false)),
-            system: Arc::new(Mutex::new(system::System::new(cart))),
+            flags: Arc::new(EmuFlags {
+                running: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
+                advance: AtomicBool::new(false),
+                step_back: AtomicBool::new(false),
+                rewinding: AtomicBool::new(false),
+                fast_forward: AtomicBool::new(false),
+                fast_forward_speed: AtomicU32::new(args.fast_forward),
+                speed_percent: AtomicU32::new(args.speed),
+                audio_paced: AtomicBool::new(false),
+                audio_underruns: AtomicU64::new(0),
+                crashed: AtomicBool::new(false),
+            }),
+            system: Arc::new(Mutex::new(system)),
             thread_handle: None,
             gilrs: Gilrs::new().ok(),
             active_gamepad: None,
-            controller_a_kb: device::controller::Buttons::empty(),
+            bindings: args
+                .config
+                .as_deref()
+                .map_or_else(default_bindings, load_bindings),
+            controller_kb: [Buttons::empty(); 2],
+            turbo_kb: [Buttons::empty(); 2],
+            turbo_rate: args.turbo_rate,
+            frame_rate,
+            stick_deadzone: args.stick_deadzone,
+            four_score: args.four_score,
+            zapper_aim: None,
+            zapper_trigger: false,
+            modifiers: ModifiersState::empty(),
+            save_path,
+            state_path: args.rom.with_extension("state"),
+            screenshot_base: args.rom.with_extension(""),
+            crash_report: args.crash_report,
+            present_mode: if args.no_vsync {
+                PresentMode::AutoNoVsync
+            } else {
+                PresentMode::AutoVsync
+            },
+            frame_interval: args.frame_cap.map(|fps| Duration::from_secs_f64(1.0 / fps)),
+            next_frame: Instant::now(),
+            pacing: args.pacing,
+            pacing_stats: args.pacing_stats,
+            scaling: Scaling {
+                integer: args.integer_scale,
+                aspect: args.aspect,
+            },
+            shader_mode: args.shader,
+            frame_ready,
+            pokes: args.pokes,
+            overlay: Vec::new(),
+            last_presented_frame: 0,
+            presented_frames: 0,
+            title_update: (Instant::now(), 0, 0, 0),
+            duplicated_frames: 0,
+            dropped_frames: 0,
+            audio_warning_shown: false,
+            recorder,
+            movie,
+            deterministic: args.deterministic,
+            audio_latency: args.audio_latency,
+            latched_input: args
+                .deterministic
+                .then(|| Arc::new(Mutex::new([Buttons::empty(); 4]))),
+        }
+    }
+
+    fn save_battery_ram(&self) {
+        if let Some(save_path) = &self.save_path {
+            let system = lock(&self.system);
+            if let Some(data) = system.battery_ram() {
+                if let Err(err) = std::fs::write(save_path, data) {
+                    eprintln!("failed to write save file: {err}");
+                }
+            }
+        }
+    }
+
+    fn save_movie(&self) {
+        if let Some(movie) = &self.movie {
+            lock(movie).save();
+        }
+    }
+
+    fn save_state(&self) {
+        let state = lock(&self.system).save_state();
+        if let Err(err) = std::fs::write(&self.state_path, state) {
+            eprintln!("failed to write save state: {err}");
+        }
+    }
+
+    fn load_state(&self) {
+        // The movie has no way of following the system back to an earlier frame
+        if self.movie.is_some() {
+            eprintln!("save states can't be loaded while a movie is active");
+            return;
+        }
+
+        match std::fs::read(&self.state_path) {
+            Ok(state) => {
+                if let Err(err) = lock(&self.system).load_state(&state) {
+                    eprintln!("failed to load save state: {err}");
+                }
+            }
+            Err(err) => eprintln!("failed to read save state: {err}"),
         }
     }
 
+    fn save_screenshot(&self) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut file = self.screenshot_base.clone().into_os_string();
+        file.push(format!("-{timestamp}.png"));
+
+        // Encoding and writing happen on another thread so presentation doesn't stutter
+        let framebuffer = lock(&self.system).framebuffer().to_vec();
+        thread::spawn(move || {
+            if let Err(err) = write_png(std::path::Path::new(&file), &framebuffer) {
+                eprintln!("failed to write screenshot: {err}");
+            }
+        });
+    }
+
     fn update_keyboard(&mut self, event: KeyEvent) {
         match event.physical_key {
             PhysicalKey::Code(KeyCode::KeyR) if event.state == ElementState::Pressed => {
-                self.system.lock().unwrap().reset();
+                let mut system = lock(&self.system);
+                if self.modifiers.shift_key() {
+                    system.power_cycle();
+                } else {
+                    system.reset();
+                }
+                self.flags.paused.store(false, atomic::Ordering::Release);
             }
-            _ => (),
-        }
+            PhysicalKey::Code(KeyCode::Space)
+                if (event.state == ElementState::Pressed) && !event.repeat =>
+            {
+                // Also continues after a breakpoint or watchpoint stopped emulation
+                if self.flags.paused.load(atomic::Ordering::Acquire) {
+                    lock(&self.system).resume();
+                    self.flags.paused.store(false, atomic::Ordering::Release);
+                } else {
+                    self.flags.paused.store(true, atomic::Ordering::Release);
+                }
 
-        let button = match event.physical_key {
-            PhysicalKey::Code(KeyCode::ArrowUp) | PhysicalKey::Code(KeyCode::KeyW) => {
-                Some(device::controller::Buttons::UP)
+                // Make sure the pause indicator is updated even if no new frames are presented
+                if let Some(resources) = &self.resources {
+                    resources.borrow_window().request_redraw();
+                }
+            }
+            PhysicalKey::Code(KeyCode::F5)
+                if (event.state == ElementState::Pressed) && !event.repeat =>
+            {
+                self.save_state();
+            }
+            PhysicalKey::Code(KeyCode::F9)
+                if (event.state == ElementState::Pressed) && !event.repeat =>
+            {
+                self.load_state();
+            }
+            PhysicalKey::Code(KeyCode::F2)
+                if (event.state == ElementState::Pressed) && !event.repeat =>
+            {
+                self.shader_mode = self.shader_mode.next();
+                if let Some(resources) = &self.resources {
+                    resources.with_gpu_resources(|gpu_resources| {
+                        if let Some(gpu_resources) = gpu_resources {
+                            gpu_resources.set_shader_mode(self.shader_mode);
+                        }
+                    });
+                    resources.borrow_window().request_redraw();
+                }
+            }
+            PhysicalKey::Code(KeyCode::F12)
+                if (event.state == ElementState::Pressed) && !event.repeat =>
+            {
+                self.save_screenshot();
+            }
+            PhysicalKey::Code(KeyCode::Period)
+                if (event.state == ElementState::Pressed)
+                    && self.flags.paused.load(atomic::Ordering::Acquire) =>
+            {
+                lock(&self.system).resume();
+                self.flags.advance.store(true, atomic::Ordering::Release);
+            }
+            // Stepping back and rewinding would desync an active movie, the same as loading a state
+            PhysicalKey::Code(KeyCode::Comma)
+                if (event.state == ElementState::Pressed)
+                    && self.flags.paused.load(atomic::Ordering::Acquire)
+                    && self.movie.is_none() =>
+            {
+                self.flags.step_back.store(true, atomic::Ordering::Release);
+            }
+            PhysicalKey::Code(KeyCode::KeyQ) if !event.repeat && self.movie.is_none() => {
+                let rewinding = event.state == ElementState::Pressed;
+                self.flags
+                    .rewinding
+                    .store(rewinding, atomic::Ordering::Release);
             }
-            PhysicalKey::Code(KeyCode::ArrowDown) | PhysicalKey::Code(KeyCode::KeyS) => {
-                Some(device::controller::Buttons::DOWN)
+            PhysicalKey::Code(KeyCode::Tab) if !event.repeat => {
+                let fast_forward = event.state == ElementState::Pressed;
+                self.flags
+                    .fast_forward
+                    .store(fast_forward, atomic::Ordering::Release);
             }
-            PhysicalKey::Code(KeyCode::ArrowLeft) | PhysicalKey::Code(KeyCode::KeyA) => {
-                Some(device::controller::Buttons::LEFT)
+            PhysicalKey::Code(KeyCode::Equal | KeyCode::NumpadAdd)
+                if event.state == ElementState::Pressed =>
+            {
+                let percent = self.flags.speed_percent.load(atomic::Ordering::Acquire);
+                self.flags
+                    .speed_percent
+                    .store(faster_speed(percent), atomic::Ordering::Release);
             }
-            PhysicalKey::Code(KeyCode::ArrowRight) | PhysicalKey::Code(KeyCode::KeyD) => {
-                Some(device::controller::Buttons::RIGHT)
+            PhysicalKey::Code(KeyCode::Minus | KeyCode::NumpadSubtract)
+                if event.state == ElementState::Pressed =>
+            {
+                let percent = self.flags.speed_percent.load(atomic::Ordering::Acquire);
+                self.flags
+                    .speed_percent
+                    .store(slower_speed(percent), atomic::Ordering::Release);
             }
-            PhysicalKey::Code(KeyCode::Enter) => Some(device::controller::Buttons::START),
-            PhysicalKey::Code(KeyCode::Backspace) => Some(device::controller::Buttons::SELECT),
-            PhysicalKey::Code(KeyCode::KeyJ) => Some(device::controller::Buttons::A),
-            PhysicalKey::Code(KeyCode::KeyK) => Some(device::controller::Buttons::B),
-            _ => None,
+            PhysicalKey::Code(
+                key @ (KeyCode::Digit1
+                | KeyCode::Digit2
+                | KeyCode::Digit3
+                | KeyCode::Digit4
+                | KeyCode::Digit5),
+            ) if (event.state == ElementState::Pressed) && !event.repeat => {
+                let channel = match key {
+                    KeyCode::Digit1 => Channel::Pulse1,
+                    KeyCode::Digit2 => Channel::Pulse2,
+                    KeyCode::Digit3 => Channel::Triangle,
+                    KeyCode::Digit4 => Channel::Noise,
+                    _ => Channel::Dmc,
+                };
+
+                let mut system = lock(&self.system);
+                let enabled = system.channel_enabled(channel);
+                system.set_channel_enabled(channel, !enabled);
+            }
+            PhysicalKey::Code(key @ (KeyCode::BracketLeft | KeyCode::BracketRight))
+                if event.state == ElementState::Pressed =>
+            {
+                const VOLUME_STEP: f32 = 0.1;
+                const MAX_VOLUME: f32 = 2.0;
+
+                let step = if key == KeyCode::BracketRight {
+                    VOLUME_STEP
+                } else {
+                    -VOLUME_STEP
+                };
+
+                let mut system = lock(&self.system);
+                // Rounded to whole steps so repeated presses don't accumulate errors
+                let volume = ((system.master_volume() + step) / VOLUME_STEP).round() * VOLUME_STEP;
+                system.set_master_volume(volume.clamp(0.0, MAX_VOLUME));
+            }
+            PhysicalKey::Code(KeyCode::KeyF)
+                if (event.state == ElementState::Pressed) && !event.repeat =>
+            {
+                let speed = self
+                    .flags
+                    .fast_forward_speed
+                    .load(atomic::Ordering::Acquire);
+                self.flags
+                    .fast_forward_speed
+                    .store(next_fast_forward_speed(speed), atomic::Ordering::Release);
+            }
+            _ => (),
+        }
+
+        let binding = match event.physical_key {
+            PhysicalKey::Code(key) => self.bindings.keys.get(&key),
+            PhysicalKey::Unidentified(_) => None,
         };
 
-        if let Some(button) = button {
-            self.active_gamepad = None;
-            self.controller_a_kb
-                .set(button, event.state == ElementState::Pressed);
+        if let Some(&(port, input)) = binding {
+            if port == ControllerPort::PortA {
+                self.active_gamepad = None;
+            }
+
+            let pressed = event.state == ElementState::Pressed;
+            match input {
+                Input::Button(button) => self.controller_kb[port as usize].set(button, pressed),
+                Input::Turbo(button) => self.turbo_kb[port as usize].set(button, pressed),
+            }
         }
     }
 }
 
-impl ApplicationHandler for App {
+impl ApplicationHandler<FrameReady> for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let (audio_resource, sample_buffer) = AudioResources::create();
+        let (audio_resource, sample_buffer, silent_source) =
+            match AudioResources::create(self.audio_latency, Arc::clone(&self.flags)) {
+                Ok((audio_resource, sample_buffer)) => (Some(audio_resource), sample_buffer, None),
+                Err(err) => {
+                    if !self.audio_warning_shown {
+                        eprintln!("failed to open audio output: {err}, continuing without audio");
+                        self.audio_warning_shown = true;
+                    }
+
+                    let (sample_buffer, sample_source) = create_sample_buffer(self.audio_latency);
+                    (None, sample_buffer, Some(sample_source))
+                }
+            };
 
         if let Some(resources) = &mut self.resources {
             resources.with_mut(|fields| {
                 assert!(fields.audio_resources.is_none());
                 assert!(fields.gpu_resources.is_none());
 
-                *fields.audio_resources = Some(audio_resource);
-                *fields.gpu_resources =
-                    Some(pollster::block_on(GpuResources::create(fields.window)));
+                *fields.audio_resources = audio_resource;
+                *fields.gpu_resources = Some(pollster::block_on(GpuResources::create(
+                    fields.window,
+                    self.present_mode,
+                    self.shader_mode,
+                )));
             })
         } else {
-            const DEFAULT_WINDOW_WIDTH: u32 = (device::ppu::SCREEN_WIDTH as u32) * 3;
-            const DEFAULT_WINDOW_HEIGHT: u32 = (device::ppu::SCREEN_HEIGHT as u32) * 3;
+            const DEFAULT_WINDOW_WIDTH: u32 = (SCREEN_WIDTH as u32) * 3;
+            const DEFAULT_WINDOW_HEIGHT: u32 = (SCREEN_HEIGHT as u32) * 3;
 
+            let window_width = ((DEFAULT_WINDOW_WIDTH as f32)
+                * self.scaling.aspect.pixel_aspect_ratio())
+            .round() as u32;
             let window_attrs = WindowAttributes::default()
                 .with_title("SimpleNES")
-                .with_inner_size(PhysicalSize::new(
-                    DEFAULT_WINDOW_WIDTH,
-                    DEFAULT_WINDOW_HEIGHT,
-                ));
+                .with_inner_size(PhysicalSize::new(window_width, DEFAULT_WINDOW_HEIGHT));
             let window = event_loop
                 .create_window(window_attrs)
                 .expect("failed to create window");
 
+            let present_mode = self.present_mode;
+            let shader_mode = self.shader_mode;
             let builder = AppResourcesBuilder {
                 window,
-                audio_resources: Some(audio_resource),
+                audio_resources: audio_resource,
                 gpu_resources_builder: |window| {
-                    Some(pollster::block_on(GpuResources::create(window)))
+                    Some(pollster::block_on(GpuResources::create(
+                        window,
+                        present_mode,
+                        shader_mode,
+                    )))
                 },
             };
 
             self.resources = Some(builder.build())
         }
 
-        self.running.store(true, atomic::Ordering::Release);
-        let running = Arc::clone(&self.running);
+        self.flags.running.store(true, atomic::Ordering::Release);
+        let flags = Arc::clone(&self.flags);
         let system = Arc::clone(&self.system);
+        let config = EmuConfig {
+            crash_report: self.crash_report.clone(),
+            frame_ready: (self.pacing == Pacing::Vblank).then(|| self.frame_ready.clone()),
+            pokes: self.pokes.clone(),
+            recorder: self.recorder.clone(),
+            movie: self.movie.clone(),
+            latched_input: self.latched_input.clone(),
+            four_score: self.four_score,
+            deterministic: self.deterministic,
+            audio_latency: self.audio_latency,
+        };
+
+        let frame_ready = self.frame_ready.clone();
 
         assert!(self.thread_handle.is_none());
+        self.flags.crashed.store(false, atomic::Ordering::Release);
         self.thread_handle = Some(thread::spawn(move || {
-            let running = running;
+            let flags = flags;
             let system = system;
-            run_emu(&*running, &*system, sample_buffer);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                run_emu(&flags, &system, &config, sample_buffer, silent_source)
+            }));
+
+            // The panic message has already been printed, emulation stops but the window stays usable
+            if result.is_err() {
+                flags.audio_paced.store(false, atomic::Ordering::Release);
+                flags.crashed.store(true, atomic::Ordering::Release);
+                eprintln!("emulation crashed and has been stopped");
+                report_break(&lock(&system), config.crash_report.as_deref());
+                // Wakes the UI so the error is shown even if it only redraws on completed frames
+                let _ = frame_ready.send_event(FrameReady);
+            }
         }));
     }
 
+    fn user_event(&mut self, _: &ActiveEventLoop, _: FrameReady) {
+        if let Some(resources) = &self.resources {
+            let window = resources.borrow_window();
+            if self.flags.crashed.load(atomic::Ordering::Acquire) {
+                window.set_title(&window_title(&self.flags, 0.0, 0.0, false));
+            }
+            window.request_redraw();
+        }
+    }
+
+    fn about_to_wait(&mut self, _: &ActiveEventLoop) {
+        if self.frame_interval.is_some() && (Instant::now() >= self.next_frame) {
+            if let Some(resources) = &self.resources {
+                resources.borrow_window().request_redraw();
+            }
+        }
+    }
+
     fn suspended(&mut self, _: &ActiveEventLoop) {
-        self.running.store(false, atomic::Ordering::Release);
+        self.flags.running.store(false, atomic::Ordering::Release);
         self.thread_handle.take().unwrap().join().unwrap();
-        self.controller_a_kb = device::controller::Buttons::empty();
+        self.controller_kb = [Buttons::empty(); 2];
+        self.turbo_kb = [Buttons::empty(); 2];
+        self.zapper_trigger = false;
+
+        // The app may be killed while suspended without ever receiving a close request
+        self.save_battery_ram();
+        self.save_movie();
 
         self.resources.as_mut().unwrap().with_mut(|fields| {
-            assert!(fields.audio_resources.is_some());
             assert!(fields.gpu_resources.is_some());
 
             *fields.audio_resources = None;
@@ -595,10 +1589,28 @@ impl ApplicationHandler for App {
                 match event {
                     WindowEvent::CloseRequested => {
                         if let Some(thread_handle) = self.thread_handle.take() {
-                            self.running.store(false, atomic::Ordering::Release);
+                            self.flags.running.store(false, atomic::Ordering::Release);
                             thread_handle.join().unwrap();
                         }
 
+                        self.save_battery_ram();
+                        self.save_movie();
+                        // The emulation thread has let go of its reference, dropping the last one
+                        // finishes writing the recording
+                        self.recorder = None;
+                        if self.pacing_stats {
+                            eprintln!(
+                                "frame pacing ({}): {} frames presented, {} duplicated, {} dropped",
+                                self.pacing,
+                                self.presented_frames,
+                                self.duplicated_frames,
+                                self.dropped_frames,
+                            );
+                        }
+                        eprintln!(
+                            "audio: {} underrun samples",
+                            self.flags.audio_underruns.load(atomic::Ordering::Relaxed),
+                        );
                         event_loop.exit();
                     }
                     WindowEvent::Resized(new_size) => {
@@ -611,23 +1623,124 @@ impl ApplicationHandler for App {
                                     0,
                                     bytemuck::cast_slice(&create_vertices(
                                         resources.borrow_window().inner_size(),
+                                        self.scaling,
                                     )),
                                 );
                             }
                         });
                     }
+                    WindowEvent::ModifiersChanged(modifiers) => self.modifiers = modifiers.state(),
                     WindowEvent::KeyboardInput { event, .. } => self.update_keyboard(event),
+                    WindowEvent::CursorMoved { position, .. } => {
+                        self.zapper_aim = window_to_screen(
+                            resources.borrow_window().inner_size(),
+                            self.scaling,
+                            position,
+                        );
+                    }
+                    WindowEvent::CursorLeft { .. } => self.zapper_aim = None,
+                    WindowEvent::MouseInput {
+                        state,
+                        button: MouseButton::Left,
+                        ..
+                    } => self.zapper_trigger = state.is_pressed(),
                     WindowEvent::RedrawRequested => {
-                        let controller_a =
-                            update_gamepad(self.gilrs.as_mut(), &mut self.active_gamepad)
-                                .unwrap_or(self.controller_a_kb);
-
-                        let mut system = self.system.lock().unwrap();
+                        let (controller_a, turbo_a) = update_gamepad(
+                            self.gilrs.as_mut(),
+                            &mut self.active_gamepad,
+                            &self.bindings.gamepad[0],
+                            self.stick_deadzone,
+                        )
+                        .unwrap_or((self.controller_kb[0], self.turbo_kb[0]));
+
+                        let mut system = lock(&self.system);
+
+                        // Track how well presentation keeps up with emulation
+                        let frame = system.frame_count();
+                        self.presented_frames += 1;
+                        if frame == self.last_presented_frame {
+                            self.duplicated_frames += 1;
+                        } else {
+                            self.dropped_frames += frame - self.last_presented_frame - 1;
+                        }
+                        self.last_presented_frame = frame;
+
+                        let (last_update, last_presented, last_emulated, last_underruns) =
+                            self.title_update;
+                        let elapsed = last_update.elapsed();
+                        if elapsed >= TITLE_UPDATE_INTERVAL {
+                            let seconds = elapsed.as_secs_f64();
+                            let fps = ((self.presented_frames - last_presented) as f64) / seconds;
+                            let emulated_fps = ((frame - last_emulated) as f64) / seconds;
+                            let underruns =
+                                self.flags.audio_underruns.load(atomic::Ordering::Relaxed);
+                            let title = window_title(
+                                &self.flags,
+                                fps,
+                                emulated_fps / self.frame_rate,
+                                underruns > last_underruns,
+                            );
+                            resources.borrow_window().set_title(&title);
+                            self.title_update =
+                                (Instant::now(), self.presented_frames, frame, underruns);
+                        }
 
-                        system.update_controller_state(
+                        let controller_a = apply_turbo(
                             controller_a,
-                            device::controller::Buttons::empty(),
+                            turbo_a,
+                            frame,
+                            self.turbo_rate,
+                            self.frame_rate,
+                        );
+                        let controller_b = apply_turbo(
+                            self.controller_kb[1],
+                            self.turbo_kb[1],
+                            frame,
+                            self.turbo_rate,
+                            self.frame_rate,
                         );
+                        let input = if self.four_score {
+                            let [(controller_c, turbo_c), (controller_d, turbo_d)] = extra_gamepads(
+                                self.gilrs.as_ref(),
+                                self.active_gamepad,
+                                &self.bindings.gamepad,
+                                self.stick_deadzone,
+                            );
+                            [
+                                controller_a,
+                                controller_b,
+                                apply_turbo(
+                                    controller_c,
+                                    turbo_c,
+                                    frame,
+                                    self.turbo_rate,
+                                    self.frame_rate,
+                                ),
+                                apply_turbo(
+                                    controller_d,
+                                    turbo_d,
+                                    frame,
+                                    self.turbo_rate,
+                                    self.frame_rate,
+                                ),
+                            ]
+                        } else {
+                            [
+                                controller_a,
+                                controller_b,
+                                Buttons::empty(),
+                                Buttons::empty(),
+                            ]
+                        };
+
+                        if let Some(movie) = &self.movie {
+                            lock(movie).live_input = [controller_a, controller_b];
+                        } else if let Some(latched_input) = &self.latched_input {
+                            *lock(latched_input) = input;
+                        } else {
+                            apply_input(&mut system, input, self.four_score);
+                        }
+                        system.update_zapper_state(self.zapper_aim, self.zapper_trigger);
 
                         resources.with_gpu_resources(|gpu_resources| {
                             if let Some(gpu_resources) = gpu_resources {
@@ -637,9 +1750,19 @@ impl ApplicationHandler for App {
                                     Err(err) => panic!("failed to aquire framebuffer: {err:?}"),
                                 };
 
+                                let framebuffer = if let Some(message) = self.flags.status_message()
+                                {
+                                    self.overlay.clear();
+                                    self.overlay.extend_from_slice(system.framebuffer());
+                                    draw_message(&mut self.overlay, &message);
+                                    &self.overlay
+                                } else {
+                                    system.framebuffer()
+                                };
+
                                 gpu_resources.queue.write_texture(
                                     gpu_resources.texture.as_image_copy(),
-                                    system.framebuffer(),
+                                    framebuffer,
                                     TEXTURE_LAYOUT,
                                     TEXTURE_SIZE,
                                 );
@@ -649,7 +1772,16 @@ impl ApplicationHandler for App {
                             }
                         });
 
-                        resources.borrow_window().request_redraw();
+                        if self.pacing == Pacing::Vblank {
+                            // The next redraw is requested by the emulation thread
+                        } else if let Some(frame_interval) = self.frame_interval {
+                            // Throttle redraws with a timer instead of presenting as fast as the display allows
+                            let now = Instant::now();
+                            self.next_frame = (self.next_frame + frame_interval).max(now);
+                            event_loop.set_control_flow(ControlFlow::WaitUntil(self.next_frame));
+                        } else {
+                            resources.borrow_window().request_redraw();
+                        }
                     }
                     _ => (),
                 }
@@ -658,22 +1790,494 @@ impl ApplicationHandler for App {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Pacing {
+    /// Present the most recently completed frame at the display's pace,
+    /// duplicating or dropping frames when the two rates differ
+    Latest,
+    /// Present exactly once for every emulated frame, as soon as it is completed
+    Vblank,
+}
+
+impl std::fmt::Display for Pacing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Latest => f.write_str("latest"),
+            Self::Vblank => f.write_str("vblank"),
+        }
+    }
+}
+
+/// Post-processing applied to the picture, the values are passed to `shader.wgsl`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ShaderMode {
+    /// Sharp pixels with nearest neighbor scaling
+    None = 0,
+    /// Darkens every other row of the picture
+    Scanlines = 1,
+    /// Soft scanlines with a slight bloom
+    Crt = 2,
+}
+
+impl ShaderMode {
+    fn next(self) -> Self {
+        match self {
+            Self::None => Self::Scanlines,
+            Self::Scanlines => Self::Crt,
+            Self::Crt => Self::None,
+        }
+    }
+}
+
+impl std::fmt::Display for ShaderMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => f.write_str("none"),
+            Self::Scanlines => f.write_str("scanlines"),
+            Self::Crt => f.write_str("crt"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Aspect {
+    /// Square pixels, the picture is displayed at 256:240
+    Square,
+    /// 8:7 pixels like on an NTSC television, giving a roughly 4:3 picture
+    Ntsc,
+}
+
+impl Aspect {
+    /// Width of a pixel relative to its height
+    fn pixel_aspect_ratio(self) -> f32 {
+        match self {
+            Self::Square => 1.0,
+            Self::Ntsc => 8.0 / 7.0,
+        }
+    }
+}
+
+impl std::fmt::Display for Aspect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Square => f.write_str("square"),
+            Self::Ntsc => f.write_str("ntsc"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    /// Start at the address stored in the reset vector
+    Auto,
+    /// Start at a fixed address
+    Addr(u16),
+}
+
+fn parse_entry(s: &str) -> Result<Entry, String> {
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(Entry::Auto);
+    }
+
+    parse_addr(s).map(Entry::Addr)
+}
+
+fn parse_region(s: &str) -> Result<Region, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "ntsc" => Ok(Region::Ntsc),
+        "pal" => Ok(Region::Pal),
+        "dendy" => Ok(Region::Dendy),
+        _ => Err(format!("`{s}` is not a valid region (ntsc, pal or dendy)")),
+    }
+}
+
+fn parse_ram_init(s: &str) -> Result<RamInit, String> {
+    // A fixed seed keeps runs with random RAM reproducible
+    const RANDOM_SEED: u64 = 0x5EED_04E5;
+
+    match s.to_ascii_lowercase().as_str() {
+        "zero" => Ok(RamInit::Zero),
+        "fill" => Ok(RamInit::Fill(0xFF)),
+        "random" => Ok(RamInit::Random(RANDOM_SEED)),
+        _ => Err(format!(
+            "`{s}` is not a valid RAM pattern (zero, fill or random)"
+        )),
+    }
+}
+
+fn parse_frame_cap(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fps) if fps.is_finite() && (fps > 0.0) => Ok(fps),
+        _ => Err(format!("`{s}` is not a valid frame rate")),
+    }
+}
+
+fn parse_turbo_rate(s: &str) -> Result<f64, String> {
+    // A button can't be pressed and released more than once every two frames
+    const MAX_TURBO_RATE: f64 = 30.0;
+
+    match s.parse::<f64>() {
+        Ok(rate) if (rate > 0.0) && (rate <= MAX_TURBO_RATE) => Ok(rate),
+        _ => Err(format!("`{s}` is not a valid turbo rate (0-30 Hz)")),
+    }
+}
+
+fn parse_audio_latency(s: &str) -> Result<Duration, String> {
+    // Below a few milliseconds the emulation thread would wake up constantly to top up the buffer
+    const MIN_LATENCY_MS: u64 = 5;
+    const MAX_LATENCY_MS: u64 = 500;
+
+    match s.parse::<u64>() {
+        Ok(ms) if (MIN_LATENCY_MS..=MAX_LATENCY_MS).contains(&ms) => Ok(Duration::from_millis(ms)),
+        _ => Err(format!(
+            "`{s}` is not a valid audio latency ({MIN_LATENCY_MS}-{MAX_LATENCY_MS} ms)"
+        )),
+    }
+}
+
+fn parse_stick_deadzone(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(deadzone) if (0.0..1.0).contains(&deadzone) => Ok(deadzone),
+        _ => Err(format!("`{s}` is not a valid deadzone (0.0-1.0)")),
+    }
+}
+
+/// Parses a speed factor into percent
+fn parse_speed(s: &str) -> Result<u32, String> {
+    const MIN_SPEED: f64 = 0.1;
+    const MAX_SPEED: f64 = 2.0;
+
+    match s.parse::<f64>() {
+        Ok(speed) if (MIN_SPEED..=MAX_SPEED).contains(&speed) => Ok((speed * 100.0).round() as u32),
+        _ => Err(format!("`{s}` is not a valid speed (0.1-2.0)")),
+    }
+}
+
+fn parse_fast_forward(s: &str) -> Result<u32, String> {
+    const MAX_SPEED: u32 = 16;
+
+    if s.eq_ignore_ascii_case("unlimited") {
+        return Ok(UNLIMITED_SPEED);
+    }
+
+    match s.parse::<u32>() {
+        Ok(speed) if (2..=MAX_SPEED).contains(&speed) => Ok(speed),
+        _ => Err(format!(
+            "`{s}` is not a valid fast-forward speed (2-16 or `unlimited`)"
+        )),
+    }
+}
+
+fn parse_poke(s: &str) -> Result<(u16, u8), String> {
+    let (addr, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{s}` is not of the form ADDR=VAL"))?;
+
+    let addr = parse_addr(addr)?;
+    if !matches!(addr, 0x0000..=0x1FFF | 0x6000..=0x7FFF) {
+        return Err(format!("0x{addr:0>4X} is not in RAM or PRG RAM"));
+    }
+
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .or_else(|| value.strip_prefix('$'))
+        .unwrap_or(value);
+    let value = u8::from_str_radix(digits, 16)
+        .map_err(|_| format!("`{value}` is not a valid 8 bit hex value"))?;
+
+    Ok((addr, value))
+}
+
+fn parse_cheat(s: &str) -> Result<String, String> {
+    Cheat::decode(s).map_err(|err| err.to_string())?;
+    Ok(s.to_string())
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .or_else(|| s.strip_prefix('$'))
+        .unwrap_or(s);
+
+    u16::from_str_radix(digits, 16).map_err(|_| format!("`{s}` is not a valid 16 bit hex address"))
+}
+
 #[derive(Debug, clap::Parser)]
 struct Args {
     #[arg(short, long, required = true, value_name = "FILE")]
     rom: std::path::PathBuf,
+
+    /// IPS or BPS patch applied to the ROM in memory before it is loaded
+    #[arg(short, long, value_name = "FILE")]
+    patch: Option<std::path::PathBuf>,
+
+    /// Write VAL to ADDR in RAM or PRG RAM every frame, e.g. `0x075A=09` (hex, can be repeated)
+    #[arg(long = "poke", value_name = "ADDR=VAL", value_parser = parse_poke)]
+    pokes: Vec<(u16, u8)>,
+
+    /// Game Genie code patching the ROM, 6 or 8 letters, e.g. `SXIOPO` (can be repeated)
+    #[arg(long = "cheat", value_name = "CODE", value_parser = parse_cheat)]
+    cheats: Vec<String>,
+
+    /// Console to emulate: `ntsc`, `pal` or `dendy`, by default the one the ROM header asks for
+    #[arg(long, value_name = "REGION", value_parser = parse_region)]
+    region: Option<Region>,
+
+    /// Key bindings file, maps keys to controller buttons (see README)
+    #[arg(long, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+
+    /// How often turbo buttons are pressed per second
+    #[arg(long, value_name = "HZ", default_value_t = 15.0, value_parser = parse_turbo_rate)]
+    turbo_rate: f64,
+
+    /// How far the left stick of a gamepad has to be pushed to press a direction (0.0-1.0)
+    #[arg(long, value_name = "AMOUNT", default_value_t = 0.5, value_parser = parse_stick_deadzone)]
+    stick_deadzone: f32,
+
+    /// Emulation speed factor, e.g. 0.5 for slow motion (0.1-2.0)
+    #[arg(long, value_name = "FACTOR", default_value = "1.0", value_parser = parse_speed)]
+    speed: u32,
+
+    /// Speed multiplier while fast-forward is held (2-16, or `unlimited` to run as fast
+    /// as possible without audio)
+    #[arg(long, value_name = "SPEED", default_value = "4", value_parser = parse_fast_forward)]
+    fast_forward: u32,
+
+    /// Limit presentation to this many frames per second (e.g. 60 for NTSC or 50 for PAL),
+    /// independent of the display's refresh rate
+    #[arg(long, value_name = "FPS", value_parser = parse_frame_cap)]
+    frame_cap: Option<f64>,
+
+    /// Present frames immediately instead of waiting for vertical sync
+    #[arg(long)]
+    no_vsync: bool,
+
+    /// Mute the triangle channel when it plays inaudibly high notes, some games use this
+    /// to silence the channel which otherwise causes buzzing and pops
+    #[arg(long)]
+    silence_ultrasonic: bool,
+
+    /// Output the raw mixer signal instead of filtering it like the NES's audio circuit
+    #[arg(long)]
+    no_audio_filter: bool,
+
+    /// How far audio is buffered ahead of playback (5-500). Lower values reduce the delay between
+    /// the game and its sound, higher values avoid crackling on slow machines.
+    #[arg(
+        long = "audio-latency-ms",
+        value_name = "MS",
+        default_value = "15",
+        value_parser = parse_audio_latency
+    )]
+    audio_latency: Duration,
+
+    /// How presented frames are synchronized with emulated frames
+    #[arg(long, value_enum, default_value_t = Pacing::Latest)]
+    pacing: Pacing,
+
+    /// Print how many frames were presented, duplicated and dropped when the window is closed
+    #[arg(long)]
+    pacing_stats: bool,
+
+    /// 192 byte `.pal` file to use instead of the built-in palette
+    #[arg(long, value_name = "FILE")]
+    palette: Option<std::path::PathBuf>,
+
+    /// Scale the picture by the largest whole multiple that fits the window
+    #[arg(long)]
+    integer_scale: bool,
+
+    /// Pixel aspect ratio of the picture
+    #[arg(long, value_enum, default_value_t = Aspect::Square)]
+    aspect: Aspect,
+
+    /// Post-processing effect applied to the picture, F2 cycles through them
+    #[arg(long, value_enum, default_value_t = ShaderMode::None)]
+    shader: ShaderMode,
+
+    /// Record gameplay into this directory as a sequence of PPM frames and a WAV file,
+    /// which can be combined into a video with external tools
+    #[arg(long, value_name = "DIR")]
+    record: Option<std::path::PathBuf>,
+
+    /// Record the controller input of every frame into this movie file
+    #[arg(long, value_name = "FILE", conflicts_with = "play_movie")]
+    record_movie: Option<std::path::PathBuf>,
+
+    /// Play back a movie recorded with --record-movie, live input is ignored until it ends
+    #[arg(long, value_name = "FILE")]
+    play_movie: Option<std::path::PathBuf>,
+
+    /// Plug a Four Score adapter in for up to four players, players 3 and 4 use additional gamepads
+    #[arg(long)]
+    four_score: bool,
+
+    /// Plug a Zapper light gun into port B, aimed with the mouse and fired with the left button
+    #[arg(long)]
+    zapper: bool,
+
+    /// Address to start execution at instead of the reset vector (hex, or `auto`)
+    #[arg(
+        long,
+        value_name = "ADDR",
+        default_value = "auto",
+        value_parser = parse_entry,
+        help_heading = "Debug"
+    )]
+    entry: Entry,
+
+    /// Contents of RAM at power-up: `zero`, `fill` ($FF) or `random` (with a fixed seed)
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        default_value = "zero",
+        value_parser = parse_ram_init,
+        help_heading = "Debug"
+    )]
+    ram_init: RamInit,
+
+    /// Let unused bits read from PPU registers decay to 0 over time, like on real hardware
+    #[arg(long, help_heading = "Debug")]
+    open_bus_decay: bool,
+
+    /// Pause when execution reaches this address (hex, can be repeated)
+    #[arg(long = "break", value_name = "ADDR", value_parser = parse_addr, help_heading = "Debug")]
+    breakpoints: Vec<u16>,
+
+    /// Pause when the CPU writes to this address (hex, can be repeated)
+    #[arg(long = "watch", value_name = "ADDR", value_parser = parse_addr, help_heading = "Debug")]
+    watchpoints: Vec<u16>,
+
+    /// Write a crash report to this file whenever emulation pauses on a breakpoint,
+    /// watchpoint or illegal opcode
+    #[arg(long, value_name = "FILE", help_heading = "Debug")]
+    crash_report: Option<std::path::PathBuf>,
+
+    /// Number of executed instructions kept for the crash report
+    #[arg(long, value_name = "N", default_value_t = 256, help_heading = "Debug")]
+    trace_size: usize,
+
+    /// Write every executed instruction to this file, in the format of nestest.log
+    #[arg(long, value_name = "FILE", help_heading = "Debug")]
+    trace_log: Option<std::path::PathBuf>,
+
+    /// Print what the ROM header says about the cartridge and exit
+    #[arg(long, help_heading = "Testing")]
+    info: bool,
+
+    /// Run N frames without a window, print a hash of the final frame and exit
+    #[arg(long, value_name = "N", help_heading = "Testing")]
+    frames: Option<u64>,
+
+    /// Also write the final frame of a headless run to a PNG file
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "frames",
+        help_heading = "Testing"
+    )]
+    png: Option<std::path::PathBuf>,
+
+    /// End a headless run once a test ROM reports its result at $6000 (blargg's protocol),
+    /// print the result and exit with failure unless the test passed
+    #[arg(long, requires = "frames", help_heading = "Testing")]
+    test_result: bool,
+
+    /// Run whole frames at a fixed rate instead of pacing by audio playback and apply input only at frame
+    /// boundaries, so runs with the same input movie and --ram-init are bit-identical
+    #[arg(long, help_heading = "Testing")]
+    deterministic: bool,
 }
 
-fn main() {
+fn main() -> ExitCode {
     use clap::Parser;
     use winit::event_loop::EventLoop;
 
     let args = Args::parse();
-    let mut app = App::new(args.rom);
 
-    let event_loop = EventLoop::new().expect("unable to create event loop");
+    let mut system = match create_system(&args) {
+        Ok(system) => system,
+        Err(err) => {
+            eprintln!("failed to load {}: {err}", args.rom.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if args.info {
+        println!("{}", system.cartridge_info());
+        println!("PRG CRC32: {:0>8X}", system.rom_crc());
+        return ExitCode::SUCCESS;
+    }
+
+    if let Some(palette) = &args.palette {
+        match load_palette(palette) {
+            Ok(data) => system.set_palette(&data),
+            Err(err) => {
+                eprintln!("failed to load {}: {err}", palette.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if let Some(trace_log) = &args.trace_log {
+        match std::fs::File::create(trace_log) {
+            Ok(file) => system.debugger_mut().set_trace_file(file),
+            Err(err) => {
+                eprintln!("failed to create {}: {err}", trace_log.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if let Some(frames) = args.frames {
+        return headless::run(
+            system,
+            frames,
+            &args.pokes,
+            args.png.as_deref(),
+            args.crash_report.as_deref(),
+            args.test_result,
+        );
+    }
+
+    let movie = if let Some(path) = &args.play_movie {
+        match Movie::play(path, system.rom_crc()) {
+            Ok(movie) => Some(movie),
+            Err(err) => {
+                eprintln!("failed to load movie: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        args.record_movie
+            .as_deref()
+            .map(|path| Movie::record(path, system.rom_crc()))
+    };
+
+    // Lets users check their dump against a ROM database
+    let info = system.cartridge_info();
+    eprintln!("ROM CRC32 {:0>8X}, SHA-1 {}", info.crc32, info.sha1_hex());
+
+    let event_loop = EventLoop::<FrameReady>::with_user_event()
+        .build()
+        .expect("unable to create event loop");
+    let recorder = match args.record.as_deref().map(Recorder::start).transpose() {
+        Ok(recorder) => recorder.map(Arc::new),
+        Err(err) => {
+            eprintln!("failed to start recording: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut app = App::new(args, system, recorder, movie, event_loop.create_proxy());
+
     event_loop.set_control_flow(ControlFlow::Poll);
     event_loop
         .run_app(&mut app)
         .expect("unable to run event loop");
+
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,151 @@
+// Input movies in a text format modeled after FCEUX's .fm2: a small header followed by one line per frame,
+// e.g. `|0|R..U...A|........||` where every button is shown by its letter in the order RLDUTSBA while held
+
+use simple_nes::Buttons;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+const VERSION: u32 = 1;
+/// Same order as the bits of `Buttons`, starting at the least significant bit
+const BUTTON_CHARS: [u8; 8] = *b"RLDUTSBA";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieMode {
+    Record,
+    Play,
+}
+
+/// A movie being recorded or played back. Inputs are applied at frame boundaries by the emulation thread,
+/// so the game sees exactly the same input on every frame during playback.
+pub struct Movie {
+    path: PathBuf,
+    mode: MovieMode,
+    rom_crc: u32,
+    frames: Vec<[Buttons; 2]>,
+    /// Index of the frame the next input is for
+    frame: usize,
+    /// Input of both controllers as last reported by the frontend
+    pub live_input: [Buttons; 2],
+}
+
+fn format_buttons(buttons: Buttons, line: &mut String) {
+    for (bit, &c) in BUTTON_CHARS.iter().enumerate() {
+        let held = (buttons.bits() & (1 << bit)) != 0;
+        line.push(if held { c as char } else { '.' });
+    }
+}
+
+fn parse_buttons(text: &str) -> Option<Buttons> {
+    if text.len() != BUTTON_CHARS.len() {
+        return None;
+    }
+
+    let bits = text
+        .bytes()
+        .enumerate()
+        .fold(0, |bits, (bit, c)| bits | (((c != b'.') as u8) << bit));
+    Some(Buttons::from_bits_retain(bits))
+}
+
+impl Movie {
+    pub fn record(path: &Path, rom_crc: u32) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            mode: MovieMode::Record,
+            rom_crc,
+            frames: Vec::new(),
+            frame: 0,
+            live_input: [Buttons::empty(); 2],
+        }
+    }
+
+    /// Fails if the file can't be read, isn't a movie or was recorded with a different ROM
+    pub fn play(path: &Path, rom_crc: u32) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+
+        let mut version = None;
+        let mut movie_crc = None;
+        let mut frames = Vec::new();
+        for (line_index, line) in text.lines().enumerate() {
+            let line_number = line_index + 1;
+            if let Some(input) = line.strip_prefix('|') {
+                // Commands, controller 1, controller 2 and the unused expansion port
+                let fields: Vec<&str> = input.split('|').collect();
+                let (Some(a), Some(b)) = (
+                    fields.get(1).and_then(|text| parse_buttons(text)),
+                    fields.get(2).and_then(|text| parse_buttons(text)),
+                ) else {
+                    return Err(format!("line {line_number}: invalid input"));
+                };
+                frames.push([a, b]);
+            } else if let Some((key, value)) = line.split_once(' ') {
+                match key {
+                    "version" => version = value.trim().parse::<u32>().ok(),
+                    "romCrc" => movie_crc = u32::from_str_radix(value.trim(), 16).ok(),
+                    _ => {}
+                }
+            }
+        }
+
+        match version {
+            Some(VERSION) => {}
+            Some(version) => return Err(format!("movie version {version} is not supported")),
+            None => return Err("not a movie".to_string()),
+        }
+        if movie_crc != Some(rom_crc) {
+            return Err("movie was recorded with a different ROM".to_string());
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            mode: MovieMode::Play,
+            rom_crc,
+            frames,
+            frame: 0,
+            live_input: [Buttons::empty(); 2],
+        })
+    }
+
+    /// Input for the next frame. While recording this is the live input, which gets added to the movie.
+    /// Once playback reaches the end of the movie the live input takes over again.
+    pub fn next_input(&mut self) -> [Buttons; 2] {
+        let input = match self.mode {
+            MovieMode::Record => {
+                self.frames.push(self.live_input);
+                self.live_input
+            }
+            MovieMode::Play => match self.frames.get(self.frame) {
+                Some(&input) => input,
+                None => {
+                    if self.frame == self.frames.len() {
+                        eprintln!("movie playback finished after {} frames", self.frames.len());
+                    }
+                    self.live_input
+                }
+            },
+        };
+
+        self.frame += 1;
+        input
+    }
+
+    /// Only recorded movies are written
+    pub fn save(&self) {
+        if self.mode != MovieMode::Record {
+            return;
+        }
+
+        let mut text = format!("version {VERSION}\nromCrc {:0>8X}\n", self.rom_crc);
+        for &[a, b] in self.frames.iter() {
+            text.push_str("|0|");
+            format_buttons(a, &mut text);
+            text.push('|');
+            format_buttons(b, &mut text);
+            let _ = writeln!(text, "||");
+        }
+
+        if let Err(err) = std::fs::write(&self.path, text) {
+            eprintln!("failed to write movie: {err}");
+        }
+    }
+}
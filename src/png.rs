@@ -0,0 +1,65 @@
+// Minimal PNG encoder for screenshots, the output is valid but not compressed
+
+use simple_nes::cartridge::hash::crc32;
+use simple_nes::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::path::Path;
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + (byte as u32)) % MOD;
+        (a, (b + a) % MOD)
+    });
+    (b << 16) | a
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&[&out[start..]]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Encodes an RGBA image as PNG, using uncompressed deflate blocks
+fn encode_png(width: usize, height: usize, data: &[u8]) -> Vec<u8> {
+    // https://www.w3.org/TR/png/
+    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    const MAX_BLOCK_SIZE: usize = 0xFFFF;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]); // 8 bit RGBA, no interlacing
+
+    // Every scanline is prefixed with filter type 0 (none)
+    let mut raw = Vec::with_capacity(height * (width * 4 + 1));
+    for row in data.chunks(width * 4) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let block_count = raw.len().div_ceil(MAX_BLOCK_SIZE);
+    for (i, block) in raw.chunks(MAX_BLOCK_SIZE).enumerate() {
+        let len = block.len() as u16;
+        zlib.push((i + 1 == block_count) as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Writes a full `SCREEN_WIDTH`x`SCREEN_HEIGHT` frame
+pub fn write_png(file: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(file, encode_png(SCREEN_WIDTH, SCREEN_HEIGHT, data))
+}
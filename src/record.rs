@@ -0,0 +1,163 @@
+// Dumps gameplay as a sequence of PPM frames and a WAV file, which external tools can mux into a video,
+// e.g. `ffmpeg -framerate 60.0988 -i frame_%06d.ppm -i audio.wav out.mp4` (50.007 for PAL and Dendy)
+
+use simple_nes::{AudioSink, Sample, SAMPLE_RATE, SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+enum Message {
+    /// RGBA pixels of a completed frame
+    Frame(Vec<u8>),
+    Samples(Vec<Sample>),
+}
+
+/// Files are written on a separate thread, so a slow disk doesn't stall emulation
+pub struct Recorder {
+    sender: Option<Sender<Message>>,
+    thread_handle: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn start(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let wav = WavWriter::create(&dir.join("audio.wav"))?;
+
+        let (sender, receiver) = mpsc::channel();
+        let dir = dir.to_path_buf();
+        let thread_handle = thread::spawn(move || {
+            if let Err(err) = write_recording(&dir, wav, receiver) {
+                eprintln!("recording failed: {err}");
+            }
+        });
+
+        Ok(Self {
+            sender: Some(sender),
+            thread_handle: Some(thread_handle),
+        })
+    }
+
+    pub fn push_frame(&self, framebuffer: &[u8]) {
+        self.send(Message::Frame(framebuffer.to_vec()));
+    }
+
+    pub fn push_samples(&self, samples: Vec<Sample>) {
+        if !samples.is_empty() {
+            self.send(Message::Samples(samples));
+        }
+    }
+
+    fn send(&self, message: Message) {
+        if let Some(sender) = &self.sender {
+            // Only fails if writing has already failed, which has been reported
+            let _ = sender.send(message);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread finish writing everything still queued
+        self.sender = None;
+        if let Some(thread_handle) = self.thread_handle.take() {
+            let _ = thread_handle.join();
+        }
+    }
+}
+
+fn write_recording(
+    dir: &Path,
+    mut wav: WavWriter,
+    receiver: mpsc::Receiver<Message>,
+) -> io::Result<()> {
+    let mut frame_index = 0u64;
+    for message in receiver {
+        match message {
+            Message::Frame(framebuffer) => {
+                let path = dir.join(format!("frame_{frame_index:0>6}.ppm"));
+                write_ppm(&path, &framebuffer)?;
+                frame_index += 1;
+            }
+            Message::Samples(samples) => wav.write_samples(&samples)?,
+        }
+    }
+
+    wav.finish()
+}
+
+/// Binary PPM, the alpha channel is dropped
+fn write_ppm(path: &Path, framebuffer: &[u8]) -> io::Result<()> {
+    let mut out = Vec::with_capacity(16 + (SCREEN_WIDTH * SCREEN_HEIGHT * 3));
+    write!(out, "P6\n{SCREEN_WIDTH} {SCREEN_HEIGHT}\n255\n")?;
+    for pixel in framebuffer.chunks_exact(4) {
+        out.extend_from_slice(&pixel[..3]);
+    }
+    std::fs::write(path, out)
+}
+
+/// Mono 16 bit PCM, the sizes in the header are filled in once recording ends
+struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    const HEADER_SIZE: u32 = 44;
+
+    fn create(path: &Path) -> io::Result<Self> {
+        const CHANNELS: u16 = 1;
+        const BITS_PER_SAMPLE: u16 = 16;
+        const BLOCK_ALIGN: u16 = CHANNELS * (BITS_PER_SAMPLE / 8);
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&CHANNELS.to_le_bytes())?;
+        file.write_all(&(SAMPLE_RATE as u32).to_le_bytes())?;
+        file.write_all(&((SAMPLE_RATE as u32) * (BLOCK_ALIGN as u32)).to_le_bytes())?;
+        file.write_all(&BLOCK_ALIGN.to_le_bytes())?;
+        file.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { file, data_size: 0 })
+    }
+
+    fn write_samples(&mut self, samples: &[Sample]) -> io::Result<()> {
+        for &sample in samples {
+            let sample = (sample * crate::OUTPUT_GAIN).clamp(-1.0, 1.0);
+            let value = (sample * (i16::MAX as f32)) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += (samples.len() * 2) as u32;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(Self::HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// Passes samples on to `inner` and keeps a copy for the recording
+pub struct Tap<'a, S: AudioSink + ?Sized> {
+    pub inner: &'a mut S,
+    pub samples: &'a mut Vec<Sample>,
+}
+
+impl<S: AudioSink + ?Sized> AudioSink for Tap<'_, S> {
+    #[inline]
+    fn push_sample(&mut self, sample: Sample) {
+        self.inner.push_sample(sample);
+        self.samples.push(sample);
+    }
+}
@@ -0,0 +1,60 @@
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+
+/// TV system a console was built for, it determines clock rates and the length of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    /// Famiclone with PAL frame timing, but NTSC-like CPU speed and APU
+    Dendy,
+}
+
+impl Region {
+    /// CPU clock rate in Hz
+    pub const fn cpu_clock_speed(self) -> f64 {
+        match self {
+            Self::Ntsc => 1_789_773.0,
+            Self::Pal => 1_662_607.0,
+            Self::Dendy => 1_773_448.0,
+        }
+    }
+
+    /// Frames per second
+    pub const fn frame_rate(self) -> f64 {
+        match self {
+            Self::Ntsc => 60.0988,
+            Self::Pal | Self::Dendy => 50.0070,
+        }
+    }
+
+    /// PPU dots per 5 CPU cycles, the PAL PPU runs 3.2 dots per cycle instead of 3
+    pub(crate) const fn ppu_dots_per_5_cycles(self) -> u8 {
+        match self {
+            Self::Ntsc | Self::Dendy => 15,
+            Self::Pal => 16,
+        }
+    }
+
+    /// Last scanline before the pre-render line wraps around to -1
+    pub(crate) const fn last_scanline(self) -> i16 {
+        match self {
+            Self::Ntsc => 260,
+            Self::Pal | Self::Dendy => 310,
+        }
+    }
+
+    /// Scanline the vertical blank flag is set on, Dendy has 50 post-render lines before it
+    pub(crate) const fn vblank_scanline(self) -> i16 {
+        match self {
+            Self::Ntsc | Self::Pal => 241,
+            Self::Dendy => 291,
+        }
+    }
+
+    /// Only the NTSC PPU skips a dot on odd frames
+    #[inline]
+    pub(crate) const fn skips_odd_frame_dot(self) -> bool {
+        matches!(self, Self::Ntsc)
+    }
+}
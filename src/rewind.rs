@@ -0,0 +1,242 @@
+use crate::System;
+use std::collections::VecDeque;
+
+/// Runs of at least this many unchanged bytes end a literal block
+const MIN_ZERO_RUN: usize = 4;
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some(&byte) = data.get(*pos) {
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if (byte & 0x80) == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+/// Encodes the XOR of two equally sized states as alternating runs of unchanged bytes
+/// and literal changed bytes. Consecutive frames only differ in a few hundred bytes,
+/// so this shrinks a snapshot down to a small fraction of its size.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let xor: Vec<u8> = old.iter().zip(new).map(|(a, b)| a ^ b).collect();
+
+    let mut pos = 0;
+    while pos < xor.len() {
+        let zero_start = pos;
+        while (pos < xor.len()) && (xor[pos] == 0) {
+            pos += 1;
+        }
+
+        let literal_start = pos;
+        let mut zeros = 0;
+        while (pos < xor.len()) && (zeros < MIN_ZERO_RUN) {
+            zeros = if xor[pos] == 0 { zeros + 1 } else { 0 };
+            pos += 1;
+        }
+        if zeros == MIN_ZERO_RUN {
+            pos -= MIN_ZERO_RUN;
+        }
+
+        write_varint(&mut out, literal_start - zero_start);
+        write_varint(&mut out, pos - literal_start);
+        out.extend_from_slice(&xor[literal_start..pos]);
+    }
+
+    out
+}
+
+/// Applies a delta created by `encode_delta` in place, turning one of the two states into the other
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut delta_pos = 0;
+    let mut state_pos = 0;
+    while delta_pos < delta.len() {
+        state_pos += read_varint(delta, &mut delta_pos);
+        let literal_len = read_varint(delta, &mut delta_pos);
+
+        let literal = &delta[delta_pos..(delta_pos + literal_len)];
+        for (byte, change) in state[state_pos..].iter_mut().zip(literal) {
+            *byte ^= change;
+        }
+
+        delta_pos += literal_len;
+        state_pos += literal_len;
+    }
+}
+
+/// Ring buffer of recent save states.
+///
+/// Only the most recent state is kept in full, every older state is stored as the difference
+/// to the state that followed it, so a minute of history usually fits in a few MB.
+pub struct Rewind {
+    interval: u32,
+    frames_until_capture: u32,
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    /// Oldest first, each entry turns the state after it back into itself
+    history: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Captures a state every `interval` frames and keeps enough states to cover `capacity_seconds`
+    pub fn new(interval: u32, capacity_seconds: u32) -> Self {
+        const FRAMES_PER_SECOND: u32 = 60;
+
+        let interval = interval.max(1);
+        Self {
+            interval,
+            frames_until_capture: 0,
+            capacity: ((capacity_seconds * FRAMES_PER_SECOND / interval) as usize).max(1),
+            latest: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Number of states that can currently be popped
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.history.len() + (self.latest.is_some() as usize)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.history.clear();
+        self.frames_until_capture = 0;
+    }
+
+    /// Meant to be called once per completed frame, only every `interval`th call captures a state
+    pub fn push(&mut self, system: &System) {
+        if self.frames_until_capture > 0 {
+            self.frames_until_capture -= 1;
+            return;
+        }
+        self.frames_until_capture = self.interval - 1;
+
+        let state = system.save_state();
+        if let Some(previous) = self.latest.take() {
+            if previous.len() == state.len() {
+                self.history.push_back(encode_delta(&state, &previous));
+            } else {
+                // States of different sizes can't be diffed, this only happens if the ROM changed
+                self.history.clear();
+            }
+        }
+        self.latest = Some(state);
+
+        while self.len() > self.capacity {
+            self.history.pop_front();
+        }
+    }
+
+    /// Removes and returns the most recently captured state
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.latest.take()?;
+        if let Some(delta) = self.history.pop_back() {
+            let mut previous = state.clone();
+            apply_delta(&mut previous, &delta);
+            self.latest = Some(previous);
+        }
+
+        self.frames_until_capture = 0;
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_cartridge;
+    use crate::Region;
+
+    fn test_system() -> System {
+        System::new(
+            test_cartridge(0, &[0xEA; 0x8000], &[0; 0x2000]),
+            Region::Ntsc,
+        )
+    }
+
+    fn assert_delta_roundtrip(old: &[u8], new: &[u8]) {
+        let delta = encode_delta(old, new);
+
+        let mut state = old.to_vec();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, new);
+
+        // The XOR works both ways
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, old);
+    }
+
+    #[test]
+    fn delta_roundtrip() {
+        let old: Vec<u8> = (0..64).collect();
+
+        // Changed bytes separated by zero runs shorter than, equal to and longer than MIN_ZERO_RUN
+        for gap in [1, MIN_ZERO_RUN - 1, MIN_ZERO_RUN, MIN_ZERO_RUN + 5] {
+            let mut new = old.clone();
+            new[2] ^= 0xFF;
+            new[3 + gap] ^= 0x01;
+            new[4 + gap] ^= 0x80;
+            assert_delta_roundtrip(&old, &new);
+        }
+
+        // Changes at the very end, with and without a leading run of unchanged bytes
+        let mut new = old.clone();
+        new[63] = 0;
+        assert_delta_roundtrip(&old, &new);
+        new[0] = 0xAA;
+        assert_delta_roundtrip(&old, &new);
+
+        assert_delta_roundtrip(&old, &old);
+    }
+
+    #[test]
+    fn pop_returns_the_newest_state_first() {
+        let mut system = test_system();
+        let mut rewind = Rewind::new(1, 1);
+        let mut states = Vec::new();
+        for value in 0..5 {
+            system.write_ram(0x0010, value);
+            rewind.push(&system);
+            states.push(system.save_state());
+        }
+
+        assert_eq!(rewind.len(), states.len());
+        while let Some(state) = rewind.pop() {
+            assert_eq!(Some(state), states.pop());
+        }
+        assert!(states.is_empty());
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn len_never_exceeds_capacity() {
+        const FRAMES_PER_SECOND: usize = 60;
+
+        let mut system = test_system();
+        let mut rewind = Rewind::new(1, 1);
+        for value in 0..(FRAMES_PER_SECOND * 2) {
+            system.write_ram(0x0010, value as u8);
+            rewind.push(&system);
+            assert!(rewind.len() <= FRAMES_PER_SECOND);
+        }
+        assert_eq!(rewind.len(), FRAMES_PER_SECOND);
+    }
+}
@@ -0,0 +1,209 @@
+// Save states are a flat little endian stream of every component's fields, so components
+// have to read their fields back in exactly the order they wrote them.
+// Any change to that order or to the set of fields has to bump `VERSION`.
+
+const MAGIC: [u8; 4] = *b"SNSS";
+const VERSION: u32 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The state was saved with a different ROM
+    RomMismatch,
+    /// The state was saved by a console of a different region
+    RegionMismatch,
+    UnexpectedEof,
+    InvalidData,
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a save state"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "save state version {version} is not supported")
+            }
+            Self::RomMismatch => write!(f, "save state belongs to a different ROM"),
+            Self::RegionMismatch => write!(f, "save state belongs to a different region"),
+            Self::UnexpectedEof => write!(f, "save state is truncated"),
+            Self::InvalidData => write!(f, "save state is corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_crc: u32) -> Self {
+        let mut writer = Self { data: Vec::new() };
+        writer.data.extend_from_slice(&MAGIC);
+        writer.write_u32(VERSION);
+        writer.write_u32(rom_crc);
+        writer
+    }
+
+    #[inline]
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    #[inline]
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    #[inline]
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    #[inline]
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_i16(&mut self, value: i16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    #[inline]
+    pub fn write_f64(&mut self, value: f64) {
+        self.write_u64(value.to_bits());
+    }
+
+    /// Writes a length prefixed block of bytes
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], rom_crc: u32) -> Result<Self, StateError> {
+        let mut reader = Self { data, pos: 0 };
+
+        if reader.read_slice(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let version = reader.read_u32()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        if reader.read_u32()? != rom_crc {
+            return Err(StateError::RomMismatch);
+        }
+
+        Ok(reader)
+    }
+
+    /// Fails if the state contains more data than was read
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(StateError::InvalidData)
+        }
+    }
+
+    fn read_slice(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        let end = self
+            .pos
+            .checked_add(count)
+            .ok_or(StateError::UnexpectedEof)?;
+        let slice = self
+            .data
+            .get(self.pos..end)
+            .ok_or(StateError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_slice(N)?);
+        Ok(array)
+    }
+
+    #[inline]
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    #[inline]
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidData),
+        }
+    }
+
+    #[inline]
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    #[inline]
+    pub fn read_i16(&mut self) -> Result<i16, StateError> {
+        Ok(i16::from_le_bytes(self.read_array()?))
+    }
+
+    #[inline]
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    #[inline]
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    #[inline]
+    pub fn read_usize(&mut self) -> Result<usize, StateError> {
+        usize::try_from(self.read_u64()?).map_err(|_| StateError::InvalidData)
+    }
+
+    #[inline]
+    pub fn read_f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_bits(self.read_u64()?))
+    }
+
+    /// Reads a length prefixed block of bytes, the length has to match the size of `bytes`
+    pub fn read_bytes_into(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        let len = self.read_u32()? as usize;
+        if len != bytes.len() {
+            return Err(StateError::InvalidData);
+        }
+
+        bytes.copy_from_slice(self.read_slice(len)?);
+        Ok(())
+    }
+}
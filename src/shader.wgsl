@@ -1,29 +1,72 @@
-struct VertexInput {
-    @location(0) position: vec2<f32>,
-    @location(1) tex_coords: vec2<f32>,
-};
-
-struct VertexOutput {
-    @builtin(position) position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
-};
-
-@vertex
-fn vs_main(input: VertexInput) -> VertexOutput {
-    var output: VertexOutput;
-    output.position = vec4<f32>(input.position, 0.0, 1.0);
-    output.tex_coords = input.tex_coords;
-    return output;
-}
-
-@group(0)
-@binding(0)
-var texture: texture_2d<f32>;
-@group(0)
-@binding(1)
-var texture_sampler: sampler;
-
-@fragment
-fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(texture, texture_sampler, vertex.tex_coords);
-}
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(input: VertexInput) -> VertexOutput {
+    var output: VertexOutput;
+    output.position = vec4<f32>(input.position, 0.0, 1.0);
+    output.tex_coords = input.tex_coords;
+    return output;
+}
+
+@group(0)
+@binding(0)
+var texture: texture_2d<f32>;
+@group(0)
+@binding(1)
+var texture_sampler: sampler;
+
+// Has to match `ShaderMode` on the Rust side
+const MODE_NONE: u32 = 0u;
+const MODE_SCANLINES: u32 = 1u;
+const MODE_CRT: u32 = 2u;
+
+struct Params {
+    mode: u32,
+};
+
+@group(0)
+@binding(2)
+var<uniform> params: Params;
+
+// Brightness of a point `row_offset` (0 to 1) down an emulated scanline, brightest in the center
+fn scanline(row_offset: f32, depth: f32) -> f32 {
+    return 1.0 - depth * (0.5 + 0.5 * cos(6.2831853 * row_offset));
+}
+
+@fragment
+fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(texture, texture_sampler, vertex.tex_coords);
+    let size = vec2<f32>(textureDimensions(texture));
+    let row_offset = fract(vertex.tex_coords.y * size.y);
+
+    // Texture samples have to happen in uniform control flow, so neighbors are read for every mode
+    let texel = 1.0 / size;
+    let left = textureSample(texture, texture_sampler, vertex.tex_coords - vec2<f32>(texel.x, 0.0));
+    let right = textureSample(texture, texture_sampler, vertex.tex_coords + vec2<f32>(texel.x, 0.0));
+
+    switch params.mode {
+        case MODE_SCANLINES: {
+            // Every other output row darkened, with rows being half an emulated scanline
+            let dark = select(1.0, 0.55, row_offset >= 0.5);
+            return vec4<f32>(color.rgb * dark, color.a);
+        }
+        case MODE_CRT: {
+            // Bright pixels bleed into their neighbors, which also keeps the scanline gaps from
+            // making the picture too dark
+            let bloom = (left.rgb + right.rgb) * 0.5;
+            let glow = max(color.rgb, bloom * 0.6) + bloom * 0.15;
+            return vec4<f32>(min(glow * scanline(row_offset, 0.45) * 1.1, vec3<f32>(1.0)), color.a);
+        }
+        default: {
+            return color;
+        }
+    }
+}
@@ -1,10 +1,14 @@
-use crate::cartridge::Cartridge;
+use crate::cartridge::{Cartridge, CartridgeInfo, MirrorMode};
+use crate::cheat::{Cheat, CheatError};
 use crate::cpu::Cpu;
-use crate::device::apu::Apu;
+use crate::debug::{BreakReason, CpuRegisters, CrashReport, Debugger, InstructionStep, MemoryDump};
+use crate::device::apu::{Apu, ApuState, AudioSink, Channel};
 use crate::device::controller::{Buttons, Controller, ControllerPort};
-use crate::device::ppu::Ppu;
+use crate::device::ppu::{OamEntry, Ppu};
 use crate::device::vram::Vram;
-use crate::device::Ram;
+use crate::device::{Ram, RamInit};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::Region;
 
 const CHR_START: u16 = 0x0000;
 const CHR_END: u16 = 0x1FFF;
@@ -19,22 +23,43 @@ pub struct PpuBus<'a> {
     pub palette: &'a mut Ram,
 }
 
+/// Reads PPU memory without notifying the mapper, so scanline counters don't see the access
+fn ppu_peek(cart: &Cartridge, vram: &Vram, palette: &Ram, addr: u16) -> u8 {
+    let addr = addr & 0x3FFF;
+    match addr {
+        CHR_START..=CHR_END => cart.ppu_read(addr - CHR_START),
+        VRAM_START..=VRAM_END => match cart.nametable_read(addr) {
+            Some(data) => data,
+            None => vram.read(cart.mirror(), addr - VRAM_START),
+        },
+        PALETTE_START..=PALETTE_END => palette.read(addr - PALETTE_START),
+        _ => 0,
+    }
+}
+
 impl PpuBus<'_> {
     pub fn read(&mut self, addr: u16) -> u8 {
         let addr = addr & 0x3FFF;
-        match addr {
-            CHR_START..=CHR_END => self.cart.ppu_read(addr - CHR_START),
-            VRAM_START..=VRAM_END => self.vram.read(self.cart.mirror(), addr - VRAM_START),
-            PALETTE_START..=PALETTE_END => self.palette.read(addr - PALETTE_START),
-            _ => 0,
+        if addr < PALETTE_START {
+            // Palette RAM is internal to the PPU, so it never shows up on the address bus
+            self.cart.ppu_a12(addr);
         }
+        ppu_peek(self.cart, self.vram, self.palette, addr)
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
         let addr = addr & 0x3FFF;
+        if addr < PALETTE_START {
+            self.cart.ppu_a12(addr);
+        }
         match addr {
             CHR_START..=CHR_END => self.cart.ppu_write(addr - CHR_START, data),
-            VRAM_START..=VRAM_END => self.vram.write(self.cart.mirror(), addr - VRAM_START, data),
+            VRAM_START..=VRAM_END => {
+                let handled = self.cart.nametable_write(addr, data);
+                if !handled {
+                    self.vram.write(self.cart.mirror(), addr - VRAM_START, data);
+                }
+            }
             PALETTE_START..=PALETTE_END => self.palette.write(addr - PALETTE_START, data),
             _ => (),
         }
@@ -45,6 +70,12 @@ pub struct Dma {
     page: u8,
     addr: u8,
     active: bool,
+    /// Whether the CPU has been halted, which takes the first cycle of a transfer
+    halted: bool,
+    /// Byte read on the last get cycle, written to OAM on the following put cycle
+    data: Option<u8>,
+    /// Cycles the CPU has been halted for by a pending DMC fetch
+    dmc_halt_cycles: u8,
 }
 
 impl Dma {
@@ -54,6 +85,9 @@ impl Dma {
             page: 0,
             addr: 0,
             active: false,
+            halted: false,
+            data: None,
+            dmc_halt_cycles: 0,
         }
     }
 
@@ -62,11 +96,37 @@ impl Dma {
         self.page = data;
         self.addr = 0;
         self.active = true;
+        self.halted = false;
+        self.data = None;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.page);
+        state.write_u8(self.addr);
+        state.write_bool(self.active);
+        state.write_bool(self.halted);
+        state.write_bool(self.data.is_some());
+        state.write_u8(self.data.unwrap_or(0));
+        state.write_u8(self.dmc_halt_cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.page = state.read_u8()?;
+        self.addr = state.read_u8()?;
+        self.active = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        let has_data = state.read_bool()?;
+        let data = state.read_u8()?;
+        self.data = has_data.then_some(data);
+        self.dmc_halt_cycles = state.read_u8()?;
+        Ok(())
     }
 }
 
 const RAM_START: u16 = 0x0000;
 const RAM_END: u16 = 0x1FFF;
+const PRG_RAM_START: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
 const PPU_START: u16 = 0x2000;
 const PPU_END: u16 = 0x3FFF;
 const APU_START: u16 = 0x4000;
@@ -79,6 +139,15 @@ const APU_FRAME_COUNTER: u16 = 0x4017;
 const PRG_START: u16 = 0x4020;
 const PRG_END: u16 = 0xFFFF;
 
+/// Reads RAM and cartridge space without side effects, `None` for the memory mapped registers
+fn peek(ram: &Ram, cart: &Cartridge, addr: u16) -> Option<u8> {
+    match addr {
+        RAM_START..=RAM_END => Some(ram.read(addr - RAM_START)),
+        PRG_START..=PRG_END => cart.cpu_read(addr),
+        _ => None,
+    }
+}
+
 pub struct CpuBus<'a> {
     pub ram: &'a mut Ram,
     pub ppu: &'a mut Ppu,
@@ -89,11 +158,20 @@ pub struct CpuBus<'a> {
 
     pub vram: &'a mut Vram,
     pub palette: &'a mut Ram,
+
+    /// Last value on the data bus, returned by reads nothing responds to.
+    /// The CPU drives the bus every cycle so unlike the PPU's latch it doesn't decay in practice.
+    pub open_bus: &'a mut u8,
+    /// Game Genie codes patching PRG ROM reads
+    pub cheats: &'a [Cheat],
+
+    pub debugger: &'a mut Debugger,
 }
 
 impl CpuBus<'_> {
     pub fn read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let open_bus = *self.open_bus;
+        let data = match addr {
             RAM_START..=RAM_END => self.ram.read(addr - RAM_START),
             PPU_START..=PPU_END => {
                 let mut ppu_bus = PpuBus {
@@ -103,15 +181,34 @@ impl CpuBus<'_> {
                 };
                 self.ppu.cpu_read(&mut ppu_bus, addr - PPU_START)
             }
-            APU_STATUS_CONTROL => self.apu.read_status(),
-            CONTROLLER_A => self.controller.read(ControllerPort::PortA),
-            CONTROLLER_B => self.controller.read(ControllerPort::PortB),
-            PRG_START..=PRG_END => self.cart.cpu_read(addr),
-            _ => 0,
-        }
+            APU_STATUS_CONTROL => (self.apu.read_status() & !0x20) | (open_bus & 0x20),
+            // Only the low 5 bits are driven by the controller ports
+            CONTROLLER_A => self.controller.read(ControllerPort::PortA) | (open_bus & 0xE0),
+            CONTROLLER_B => {
+                let data = match self.controller.zapper() {
+                    Some(zapper) => zapper.read(|x, y| self.ppu.senses_light(x, y)),
+                    None => self.controller.read(ControllerPort::PortB),
+                };
+                data | (open_bus & 0xE0)
+            }
+            PRG_START..=PRG_END => match self.cart.cpu_read(addr) {
+                Some(data) => self
+                    .cheats
+                    .iter()
+                    .fold(data, |data, cheat| cheat.apply(addr, data)),
+                None => open_bus,
+            },
+            _ => open_bus,
+        };
+
+        *self.open_bus = data;
+        data
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
+        self.debugger.check_watchpoint(addr, data);
+        *self.open_bus = data;
+
         match addr {
             RAM_START..=RAM_END => self.ram.write(addr - RAM_START, data),
             PPU_START..=PPU_END => {
@@ -132,6 +229,11 @@ impl CpuBus<'_> {
         }
     }
 
+    #[inline]
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        peek(self.ram, self.cart, addr)
+    }
+
     pub fn read_16(&mut self, addr: u16) -> u16 {
         let low = self.read(addr);
         let high = self.read(addr.wrapping_add(1));
@@ -142,6 +244,35 @@ impl CpuBus<'_> {
 const PALETTE_P2_SIZE: usize = 5; // 0x0020
 const RAM_P2_SIZE: usize = 11; // 0x0800
 
+/// Result of `System::clock_exact`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStatus {
+    /// Number of CPU cycles that were run, only less than requested if the debugger halted execution
+    pub cycles: u32,
+    /// Number of frames that were completed
+    pub frames_completed: u32,
+}
+
+impl FrameStatus {
+    #[inline]
+    pub const fn frame_completed(&self) -> bool {
+        self.frames_completed > 0
+    }
+}
+
+/// Forwards samples to a closure
+struct FnSink<F>(F);
+
+impl<F: FnMut(crate::Sample)> AudioSink for FnSink<F> {
+    #[inline]
+    fn push_sample(&mut self, sample: crate::Sample) {
+        (self.0)(sample);
+    }
+}
+
+/// Called with the RGBA pixels and the number of a frame as soon as the PPU has completed it
+pub type FrameCallback = Box<dyn FnMut(&[u8], u64) + Send>;
+
 pub struct System {
     cpu: Cpu,
     ram: Ram,
@@ -155,18 +286,29 @@ pub struct System {
 
     cart: Cartridge,
     even_cycle: bool,
+    region: Region,
+    /// PPU dots owed to the PPU in fifths, PAL runs 16 dots every 5 CPU cycles
+    ppu_dot_fraction: u8,
+
+    debugger: Debugger,
+    ram_init: RamInit,
+    open_bus: u8,
+    cheats: Vec<Cheat>,
+    frame_callback: Option<FrameCallback>,
 }
 
 impl System {
-    pub fn new(mut cart: Cartridge) -> Self {
-        let mut ppu = Ppu::new();
+    pub fn new(mut cart: Cartridge, region: Region) -> Self {
+        let mut ppu = Ppu::new(region);
         let mut vram = Vram::new();
         let mut palette = Ram::new(PALETTE_P2_SIZE);
 
         let mut ram = Ram::new(RAM_P2_SIZE);
-        let mut apu = Apu::new();
+        let mut apu = Apu::new(region);
         let mut dma = Dma::new();
         let mut controller = Controller::new();
+        let mut debugger = Debugger::new(0);
+        let mut open_bus = 0;
 
         let mut cpu_bus = CpuBus {
             ram: &mut ram,
@@ -178,6 +320,10 @@ impl System {
 
             vram: &mut vram,
             palette: &mut palette,
+            open_bus: &mut open_bus,
+            cheats: &[],
+
+            debugger: &mut debugger,
         };
 
         let cpu = Cpu::new(&mut cpu_bus);
@@ -195,9 +341,24 @@ impl System {
 
             cart,
             even_cycle: false,
+            region,
+            ppu_dot_fraction: 0,
+
+            debugger,
+            ram_init: RamInit::Zero,
+            open_bus,
+            cheats: Vec::new(),
+            frame_callback: None,
         }
     }
 
+    /// Sets the contents of RAM at power-up and refills RAM with them, so this should be called
+    /// before running the system. Later power cycles fill RAM the same way.
+    pub fn set_ram_init(&mut self, init: RamInit) {
+        self.ram_init = init;
+        self.ram = Ram::new_with_pattern(RAM_P2_SIZE, init);
+    }
+
     pub fn reset(&mut self) {
         self.cart.reset_interrupt();
         self.cart.reset_mapper();
@@ -214,27 +375,537 @@ impl System {
 
             vram: &mut self.vram,
             palette: &mut self.palette,
+            open_bus: &mut self.open_bus,
+            cheats: &self.cheats,
+
+            debugger: &mut self.debugger,
         };
 
         self.cpu.reset(&mut cpu_bus);
 
         self.even_cycle = false;
+        self.ppu_dot_fraction = 0;
+        self.debugger.reset();
+    }
+
+    /// Turns the console off and on again. Unlike [`System::reset`] this also clears RAM, VRAM and OAM
+    /// and returns every device to its power-up state. Cartridge RAM and frontend settings are kept.
+    pub fn power_cycle(&mut self) {
+        self.cart.reset_interrupt();
+        self.cart.reset_mapper();
+
+        self.ram = Ram::new_with_pattern(RAM_P2_SIZE, self.ram_init);
+        self.vram = Vram::new();
+        self.palette = Ram::new(PALETTE_P2_SIZE);
+        self.dma = Dma::new();
+        self.ppu.power_cycle();
+        self.apu.power_cycle();
+
+        let mut cpu_bus = CpuBus {
+            ram: &mut self.ram,
+            ppu: &mut self.ppu,
+            apu: &mut self.apu,
+            dma: &mut self.dma,
+            controller: &mut self.controller,
+            cart: &mut self.cart,
+
+            vram: &mut self.vram,
+            palette: &mut self.palette,
+            open_bus: &mut self.open_bus,
+            cheats: &self.cheats,
+
+            debugger: &mut self.debugger,
+        };
+
+        self.cpu = Cpu::new(&mut cpu_bus);
+
+        self.even_cycle = false;
+        self.ppu_dot_fraction = 0;
+        self.debugger.reset();
     }
 
+    /// Serializes the entire machine state. ROM contents and the debugger are not included,
+    /// so a state can only be loaded back into a system running the same ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(self.cart.rom_crc());
+        state.write_u8(self.region as u8);
+        self.cpu.save_state(&mut state);
+        self.ram.save_state(&mut state);
+        self.apu.save_state(&mut state);
+        self.dma.save_state(&mut state);
+        self.controller.save_state(&mut state);
+        self.ppu.save_state(&mut state);
+        self.vram.save_state(&mut state);
+        self.palette.save_state(&mut state);
+        self.cart.save_state(&mut state);
+        state.write_bool(self.even_cycle);
+        state.write_u8(self.ppu_dot_fraction);
+        state.write_u8(self.open_bus);
+        state.finish()
+    }
+
+    fn load_state_unchecked(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if state.read_u8()? != (self.region as u8) {
+            return Err(StateError::RegionMismatch);
+        }
+        self.cpu.load_state(state)?;
+        self.ram.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.dma.load_state(state)?;
+        self.controller.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.vram.load_state(state)?;
+        self.palette.load_state(state)?;
+        self.cart.load_state(state)?;
+        self.even_cycle = state.read_bool()?;
+        self.ppu_dot_fraction = state.read_u8()?;
+        self.open_bus = state.read_u8()?;
+        Ok(())
+    }
+
+    /// Restores a state created by `save_state`. If the state is invalid the system is left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, self.cart.rom_crc())?;
+
+        // Components are restored one after another, so a state that turns out to be
+        // corrupted halfway through has to be rolled back
+        let backup = self.save_state();
+        let result = self
+            .load_state_unchecked(&mut state)
+            .and_then(|()| state.finish());
+
+        if result.is_err() {
+            let mut backup = StateReader::new(&backup, self.cart.rom_crc())
+                .expect("backup state is always valid");
+            self.load_state_unchecked(&mut backup)
+                .expect("backup state is always valid");
+        }
+
+        result
+    }
+
+    /// Overrides the program counter, bypassing the reset vector.
+    /// Meant for running bare CPU test programs.
+    #[inline]
+    pub fn set_pc(&mut self, pc: u16) {
+        self.cpu.set_pc(pc);
+    }
+
+    #[inline]
+    pub fn cartridge_info(&self) -> &CartridgeInfo {
+        self.cart.info()
+    }
+
+    /// CRC32 of the PRG ROM, identifies the game in save states and input movies
+    #[inline]
+    pub fn rom_crc(&self) -> u32 {
+        self.cart.rom_crc()
+    }
+
+    #[inline]
+    pub fn has_battery(&self) -> bool {
+        self.cart.has_battery()
+    }
+
+    #[inline]
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        self.cart.battery_ram()
+    }
+
+    #[inline]
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        self.cart.load_battery_ram(data);
+    }
+
+    #[inline]
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    #[inline]
+    pub fn break_reason(&self) -> Option<BreakReason> {
+        self.debugger.break_reason()
+    }
+
+    /// Continues execution after the debugger halted the system
+    #[inline]
+    pub fn resume(&mut self) {
+        self.debugger.resume();
+    }
+
+    #[cfg(test)]
+    #[inline]
+    pub(crate) fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    #[inline]
+    pub fn cpu_registers(&self) -> CpuRegisters {
+        self.cpu.registers()
+    }
+
+    /// Reads memory as the CPU sees it without side effects. Returns `None` for the PPU, APU and
+    /// controller registers, because reading those changes their state: the VBlank flag and
+    /// address latch of $2002, the address increment of $2007, the frame interrupt flag of $4015
+    /// and the shift registers of $4016/$4017. Addresses nothing responds to return `None` as well.
+    #[inline]
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        peek(&self.ram, &self.cart, addr)
+    }
+
+    /// Reads the 2KB of work RAM, addresses wrap around the same way the $0800-$1FFF mirrors do.
+    /// Unlike a CPU read this never touches the bus, so it doesn't affect emulation in any way.
+    #[inline]
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.ram.read(addr)
+    }
+
+    /// Writes the 2KB of work RAM without going through the bus, see `read_ram`
+    #[inline]
+    pub fn write_ram(&mut self, addr: u16, data: u8) {
+        self.ram.write(addr, data);
+    }
+
+    /// The entire 2KB of work RAM, e.g. for a `RamSearch`
+    #[inline]
+    pub fn ram(&self) -> &[u8] {
+        self.ram.as_slice()
+    }
+
+    /// Renders pattern table `table` (0 at $0000, 1 at $1000) as `PATTERN_TABLE_SIZE` squared
+    /// RGBA pixels, colored with `palette` (0-3 are the background palettes, 4-7 the sprite palettes)
+    pub fn render_pattern_table(&self, table: u16, palette: u8) -> Vec<u8> {
+        self.ppu.render_pattern_table(
+            |addr| ppu_peek(&self.cart, &self.vram, &self.palette, addr),
+            table,
+            palette,
+        )
+    }
+
+    /// Renders nametable `index` (0-3 at $2000, $2400, $2800 and $2C00, before mirroring) as
+    /// `SCREEN_WIDTH * SCREEN_HEIGHT` RGBA pixels, using the background pattern table selected in PPUCTRL
+    pub fn render_nametable(&self, index: u16) -> Vec<u8> {
+        self.ppu.render_nametable(
+            |addr| ppu_peek(&self.cart, &self.vram, &self.palette, addr),
+            index,
+        )
+    }
+
+    #[inline]
+    pub fn oam_entries(&self) -> [OamEntry; 64] {
+        self.ppu.oam_entries()
+    }
+
+    /// Height of all sprites in pixels, 8 or 16 depending on PPUCTRL
+    #[inline]
+    pub fn sprite_height(&self) -> usize {
+        self.ppu.sprite_height()
+    }
+
+    /// Renders OAM entry `index` as RGBA pixels, 8 wide and `sprite_height` tall.
+    /// The sprite is flipped like on screen and transparent pixels have an alpha of 0.
+    pub fn render_sprite(&self, index: usize) -> Vec<u8> {
+        self.ppu.render_sprite(
+            |addr| ppu_peek(&self.cart, &self.vram, &self.palette, addr),
+            index,
+        )
+    }
+
+    pub fn crash_report(&self) -> CrashReport {
+        let mut memory = vec![
+            MemoryDump::new("RAM", RAM_START, self.ram.as_slice()),
+            MemoryDump::new("Nametable 0", 0, self.vram.table(0)),
+            MemoryDump::new("Nametable 1", 0, self.vram.table(1)),
+            MemoryDump::new("Palette", PALETTE_START, self.palette.as_slice()),
+            MemoryDump::new("OAM", 0, &self.ppu.oam_bytes()),
+        ];
+        if self.cart.mirror() == MirrorMode::FourScreen {
+            memory.insert(3, MemoryDump::new("Nametable 2", 0, self.vram.table(2)));
+            memory.insert(4, MemoryDump::new("Nametable 3", 0, self.vram.table(3)));
+        }
+        if let Some(prg_ram) = self.cart.prg_ram() {
+            memory.insert(1, MemoryDump::new("PRG RAM", PRG_RAM_START, prg_ram));
+        }
+
+        CrashReport {
+            reason: self.debugger.break_reason(),
+            registers: self.cpu.registers(),
+            scanline: self.ppu.scanline(),
+            cycle: self.ppu.cycle(),
+            trace: self.debugger.trace().lines().map(str::to_owned).collect(),
+            memory,
+        }
+    }
+
+    /// Writes values straight into RAM or PRG RAM, meant to be called once per frame
+    /// to implement simple always-on cheats. Addresses outside of RAM are ignored so
+    /// registers can't be clobbered.
+    pub fn apply_ram_pokes(&mut self, pokes: &[(u16, u8)]) {
+        for &(addr, data) in pokes {
+            match addr {
+                RAM_START..=RAM_END => self.ram.write(addr - RAM_START, data),
+                PRG_RAM_START..=PRG_RAM_END => self.cart.poke_prg_ram(addr - PRG_RAM_START, data),
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds a Game Genie code, which stays active across resets until the cheats are cleared
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), CheatError> {
+        self.cheats.push(Cheat::decode(code)?);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+    /// Sets a callback that is run from within `clock_exact` whenever a frame completes, replacing
+    /// any previous one. It runs on the thread driving the system, which still holds whatever lock
+    /// it needed to do so, so it should hand the frame off quickly instead of doing heavy work.
+    pub fn set_frame_callback(&mut self, callback: FrameCallback) {
+        self.frame_callback = Some(callback);
+    }
+
+    pub fn clear_frame_callback(&mut self) {
+        self.frame_callback = None;
+    }
+
+    #[inline]
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Number of frames the PPU has completed since power-on
+    #[inline]
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.frame_count()
+    }
+
+    /// Scanline and dot the PPU is at, the pre-render line is the last scanline of the region
+    #[inline]
+    pub fn ppu_position(&self) -> (u16, u16) {
+        self.ppu.position()
+    }
+
+    /// The last rendered frame as `SCREEN_WIDTH * SCREEN_HEIGHT` RGBA pixels
     pub fn framebuffer(&self) -> &[u8] {
         bytemuck::cast_slice(self.ppu.get_buffer().get_pixels())
     }
 
+    #[inline]
+    pub fn set_silence_ultrasonic(&mut self, silence_ultrasonic: bool) {
+        self.apu.set_silence_ultrasonic(silence_ultrasonic);
+    }
+
+    #[inline]
+    pub fn master_volume(&self) -> f32 {
+        self.apu.master_volume()
+    }
+
+    /// Scales the audio output, 1.0 is the original volume
+    #[inline]
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.apu.set_master_volume(volume);
+    }
+
+    #[inline]
+    pub fn channel_enabled(&self, channel: Channel) -> bool {
+        self.apu.channel_enabled(channel)
+    }
+
+    /// Mutes or unmutes an APU channel without affecting its emulation
+    #[inline]
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.apu.set_channel_enabled(channel, enabled);
+    }
+
+    /// The APU output is filtered like on hardware by default, disabling the filter
+    /// passes the raw mixer output through
+    #[inline]
+    pub fn set_audio_filter(&mut self, enabled: bool) {
+        self.apu.set_filter_enabled(enabled);
+    }
+
+    /// Replaces the built-in palette, see `PALETTE_SIZE` for the format
+    #[inline]
+    pub fn set_palette(&mut self, palette: &[u8; crate::PALETTE_SIZE]) {
+        self.ppu.set_palette(palette);
+    }
+
+    /// Scales how much emulated time passes per audio sample, see `run_frame`.
+    /// A speed of 2.0 produces half as many samples per frame at twice the pitch.
+    #[inline]
+    pub fn set_speed(&mut self, speed: f64) {
+        self.apu.set_speed(speed);
+    }
+
+    /// Snapshot of the APU channel registers and IRQ sources, meant for visualization and debugging
+    #[inline]
+    pub fn apu_state(&self) -> ApuState {
+        self.apu.state()
+    }
+
+    /// Value of $4015 without the side effect of acknowledging the frame IRQ
+    #[inline]
+    pub fn peek_apu_status(&self) -> u8 {
+        self.apu.peek_status()
+    }
+
+    /// Output level of every APU channel before mixing, indexed by `Channel` and scaled to 0.0-1.0.
+    /// Updated whenever a sample is emitted, so a frontend can draw a waveform per channel.
+    #[inline]
+    pub fn channel_outputs(&self) -> [f32; 5] {
+        self.apu.channel_outputs()
+    }
+
+    /// Sets the buttons currently held on both controllers, games see them on their next poll
     #[inline]
     pub fn update_controller_state(&mut self, controller_a: Buttons, controller_b: Buttons) {
         self.controller.update_state(controller_a, controller_b);
     }
 
-    pub fn clock(&mut self, cycles: usize, sample_buffer: &mut crate::SampleBuffer) {
-        for _ in 0..cycles {
-            if self.dma.active {
-                if self.even_cycle {
-                    let addr = u16::from_le_bytes([self.dma.addr, self.dma.page]);
+    /// Lets bits of the PPU's open bus decay to 0 when they haven't been driven for about 600 ms,
+    /// like they do on real hardware. Off by default so the open bus only depends on register accesses.
+    pub fn set_open_bus_decay(&mut self, decay: bool) {
+        self.ppu.set_open_bus_decay(decay);
+    }
+
+    /// Plugs a Four Score adapter into both ports, which adds controllers for players 3 and 4
+    pub fn set_four_score_connected(&mut self, connected: bool) {
+        self.controller.set_four_score_connected(connected);
+    }
+
+    /// Updates all four controllers at once, players 3 and 4 are only visible to the game
+    /// while a Four Score is connected
+    pub fn update_four_score_state(&mut self, controllers: [Buttons; 4]) {
+        self.controller.update_four_score_state(controllers);
+    }
+
+    /// Plugs a Zapper light gun into port B in place of the standard controller
+    pub fn set_zapper_connected(&mut self, connected: bool) {
+        self.controller.set_zapper_connected(connected);
+    }
+
+    /// Updates the screen pixel the Zapper points at and whether its trigger is pulled,
+    /// has no effect unless a Zapper is connected
+    pub fn update_zapper_state(&mut self, aim: Option<(usize, usize)>, trigger: bool) {
+        self.controller.update_zapper_state(aim, trigger);
+    }
+
+    /// Runs until the PPU completes the current frame, unless the debugger halts execution first.
+    /// Every sample the APU emits in the meantime is passed to `sample_sink`.
+    pub fn run_frame(&mut self, sample_sink: &mut impl FnMut(crate::Sample)) -> FrameStatus {
+        let mut sink = FnSink(sample_sink);
+        let mut status = FrameStatus {
+            cycles: 0,
+            frames_completed: 0,
+        };
+
+        loop {
+            let step = self.clock_exact(1, &mut sink);
+            status.cycles += step.cycles;
+            status.frames_completed += step.frames_completed;
+
+            if step.frame_completed() || (step.cycles == 0) {
+                break status;
+            }
+        }
+    }
+
+    /// Runs until the CPU has executed exactly one instruction and reached the next instruction
+    /// boundary. Clears any pending break first and doesn't break on a breakpoint at the current
+    /// instruction. An interrupt taken before the instruction runs as part of the step.
+    /// Returns `None` if no instruction was executed, because the CPU is jammed or the debugger
+    /// halted first, for example on a breakpoint at the start of an interrupt handler.
+    pub fn step_instruction<S: AudioSink + ?Sized>(
+        &mut self,
+        sink: &mut S,
+    ) -> Option<InstructionStep> {
+        let mut step = InstructionStep {
+            pc: self.cpu.registers().pc,
+            opcode: 0,
+            next_pc: 0,
+            cycles: 0,
+        };
+
+        self.debugger.resume_from(step.pc);
+        let start_count = self.cpu.instruction_count();
+
+        loop {
+            if self.cpu.at_instruction_boundary() {
+                if (self.cpu.instruction_count() != start_count) || self.cpu.is_jammed() {
+                    break;
+                }
+
+                step.pc = self.cpu.registers().pc;
+            }
+
+            // A watchpoint can also halt in the middle of the instruction,
+            // its remaining cycles then count towards the next step
+            if self.clock_exact(1, sink).cycles == 0 {
+                break;
+            }
+            step.cycles += 1;
+        }
+
+        if self.cpu.instruction_count() == start_count {
+            return None;
+        }
+
+        step.opcode = self.peek(step.pc).unwrap_or(0);
+        step.next_pc = self.cpu.registers().pc;
+        Some(step)
+    }
+
+    /// Runs exactly `cpu_cycles` CPU cycles, unless the debugger halts execution first.
+    ///
+    /// Every CPU cycle also clocks the APU once and the PPU three times, so the budget maps
+    /// directly onto wall time at 1.789773 MHz. The budget may end in the middle of an
+    /// instruction or a DMA transfer: the CPU carries out an instruction on its first cycle
+    /// and then idles for its remaining cycles, so the effects of an instruction are already
+    /// visible when the call returns, and the next call just continues counting down the
+    /// remaining cycles. Splitting a run into several calls therefore produces the exact same
+    /// result as running all cycles in a single call.
+    ///
+    /// A frame counts as completed once the PPU wraps around to the pre-render scanline
+    /// at the end of vertical blank, at that point `framebuffer` holds the new frame.
+    pub fn clock_exact<S: AudioSink + ?Sized>(
+        &mut self,
+        cpu_cycles: u32,
+        sink: &mut S,
+    ) -> FrameStatus {
+        let start_frame = self.frame_count();
+        let mut cycles = 0;
+
+        while cycles < cpu_cycles {
+            if self.debugger.break_reason().is_some() {
+                break;
+            }
+
+            // https://www.nesdev.org/wiki/DMA
+            // DMC fetches take priority over OAM DMA, but can only steal one of its read cycles.
+            // Outside of OAM DMA a fetch halts the CPU for a halt cycle and a dummy cycle,
+            // then waits for the next read cycle to fetch, so 3 or 4 cycles in total.
+            const DMC_HALT_CYCLES: u8 = 2;
+
+            let mut dmc_fetched = false;
+            let mut dmc_halted = false;
+            if let Some(addr) = self.apu.dmc_dma_request() {
+                let can_fetch = if self.dma.active {
+                    self.even_cycle
+                } else {
+                    dmc_halted = true;
+                    self.dma.dmc_halt_cycles += 1;
+                    (self.dma.dmc_halt_cycles > DMC_HALT_CYCLES) && self.even_cycle
+                };
+
+                if can_fetch {
                     let data = CpuBus {
                         ram: &mut self.ram,
                         ppu: &mut self.ppu,
@@ -245,9 +916,47 @@ impl System {
 
                         vram: &mut self.vram,
                         palette: &mut self.palette,
+                        open_bus: &mut self.open_bus,
+                        cheats: &self.cheats,
+
+                        debugger: &mut self.debugger,
                     }
                     .read(addr);
 
+                    self.apu.dmc_dma_complete(data);
+                    self.dma.dmc_halt_cycles = 0;
+                    dmc_fetched = true;
+                }
+            }
+
+            // OAM DMA takes 513 or 514 cycles: a halt cycle, an alignment cycle if the next
+            // cycle is a put cycle, then 256 pairs of reading on a get cycle and writing on a put cycle
+            if self.dma.active {
+                if !self.dma.halted {
+                    self.dma.halted = true;
+                } else if self.even_cycle {
+                    if !dmc_fetched {
+                        let addr = u16::from_le_bytes([self.dma.addr, self.dma.page]);
+                        let data = CpuBus {
+                            ram: &mut self.ram,
+                            ppu: &mut self.ppu,
+                            apu: &mut self.apu,
+                            dma: &mut self.dma,
+                            controller: &mut self.controller,
+                            cart: &mut self.cart,
+
+                            vram: &mut self.vram,
+                            palette: &mut self.palette,
+                            open_bus: &mut self.open_bus,
+                            cheats: &self.cheats,
+
+                            debugger: &mut self.debugger,
+                        }
+                        .read(addr);
+
+                        self.dma.data = Some(data);
+                    }
+                } else if let Some(data) = self.dma.data.take() {
                     self.ppu.dma_write(data);
 
                     self.dma.addr = self.dma.addr.wrapping_add(1);
@@ -255,7 +964,7 @@ impl System {
                         self.dma.active = false;
                     }
                 }
-            } else {
+            } else if !dmc_halted {
                 let mut cpu_bus = CpuBus {
                     ram: &mut self.ram,
                     ppu: &mut self.ppu,
@@ -266,12 +975,19 @@ impl System {
 
                     vram: &mut self.vram,
                     palette: &mut self.palette,
+                    open_bus: &mut self.open_bus,
+                    cheats: &self.cheats,
+
+                    debugger: &mut self.debugger,
                 };
 
                 self.cpu.clock(&mut cpu_bus);
+                if self.ppu.check_nmi_cancelled() {
+                    self.cpu.cancel_nmi();
+                }
             }
 
-            self.apu.clock(&mut self.cart, sample_buffer);
+            self.apu.clock(&self.cart, sink);
 
             let mut ppu_bus = PpuBus {
                 cart: &mut self.cart,
@@ -279,25 +995,182 @@ impl System {
                 palette: &mut self.palette,
             };
 
-            // PPU is clocked exactly 3x faster than CPU
-            self.ppu.clock(&mut ppu_bus);
-            self.ppu.clock(&mut ppu_bus);
-            self.ppu.clock(&mut ppu_bus);
+            // NTSC and Dendy PPUs are clocked exactly 3x faster than the CPU, PAL PPUs 3.2x
+            self.ppu_dot_fraction += self.region.ppu_dots_per_5_cycles();
+            let dots = self.ppu_dot_fraction / 5;
+            self.ppu_dot_fraction %= 5;
+
+            let frame_count = self.ppu.frame_count();
+            for _ in 0..dots {
+                self.ppu.clock(&mut ppu_bus);
+            }
+
+            if self.ppu.frame_count() != frame_count {
+                if let Some(frame_callback) = &mut self.frame_callback {
+                    let framebuffer = bytemuck::cast_slice(self.ppu.get_buffer().get_pixels());
+                    frame_callback(framebuffer, self.ppu.frame_count());
+                }
+            }
 
             if self.ppu.check_nmi() {
                 self.cpu.signal_nmi();
             }
 
-            if self.apu.irq_requested() || self.apu.dmc_irq_requested() {
+            if self.apu.frame_irq_pending() || self.apu.dmc_irq_pending() {
                 self.cpu.signal_irq();
             }
 
+            self.cart.on_cpu_cycle();
             if self.cart.interrupt_state() {
                 self.cart.reset_interrupt();
                 self.cpu.signal_irq();
             }
 
+            self.cpu.count_cycle();
             self.even_cycle = !self.even_cycle;
+            cycles += 1;
+        }
+
+        FrameStatus {
+            cycles,
+            frames_completed: (self.frame_count() - start_frame) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_cartridge;
+    use crate::device::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+    fn test_system() -> System {
+        system_with_program(&[])
+    }
+
+    /// NROM filled with NOPs except for `program` at $8000, where the reset vector points
+    fn system_with_program(program: &[u8]) -> System {
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x7FFC..0x7FFE].copy_from_slice(&0x8000u16.to_le_bytes());
+        System::new(test_cartridge(0, &prg_rom, &[0; 0x2000]), Region::Ntsc)
+    }
+
+    #[test]
+    fn ram_access_wraps_like_the_mirrors() {
+        let mut system = test_system();
+
+        system.write_ram(0x0801, 0x42);
+        assert_eq!(system.read_ram(0x0001), 0x42);
+        assert_eq!(system.read_ram(0x1801), 0x42);
+        assert_eq!(system.ram()[0x0001], 0x42);
+        assert_eq!(system.peek(0x1001), Some(0x42));
+    }
+
+    #[test]
+    fn frame_callback_runs_once_per_frame() {
+        use std::sync::{Arc, Mutex};
+
+        let mut system = test_system();
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let callback_frames = Arc::clone(&frames);
+        system.set_frame_callback(Box::new(move |framebuffer, frame| {
+            assert_eq!(framebuffer.len(), SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+            callback_frames.lock().unwrap().push(frame);
+        }));
+
+        for _ in 0..3 {
+            system.run_frame(&mut |_| {});
         }
+        let expected: Vec<u64> = (1..=system.frame_count()).collect();
+        assert_eq!(*frames.lock().unwrap(), expected);
+
+        system.clear_frame_callback();
+        system.run_frame(&mut |_| {});
+        assert_eq!(*frames.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn oam_dma_takes_513_or_514_cycles() {
+        // STA $4014 takes 4 cycles and the following NOP 2, the DMA runs in between
+        const STA_AND_NOP_CYCLES: u32 = 6;
+
+        let mut dma_cycles = [
+            // LDA #$02, STA $4014
+            vec![0xA9, 0x02, 0x8D, 0x14, 0x40],
+            // LDA $02 takes one cycle more, the DMA starts on the other kind of cycle
+            vec![0xA5, 0x02, 0x8D, 0x14, 0x40],
+        ]
+        .map(|program| {
+            let mut system = system_with_program(&program);
+            system.step_instruction(&mut Vec::new()).unwrap();
+            let sta = system.step_instruction(&mut Vec::new()).unwrap();
+            let nop = system.step_instruction(&mut Vec::new()).unwrap();
+            assert_eq!(nop.opcode, 0xEA);
+            sta.cycles + nop.cycles - STA_AND_NOP_CYCLES
+        });
+
+        dma_cycles.sort();
+        assert_eq!(dma_cycles, [513, 514]);
+    }
+
+    /// Counts up at $10 and writes the count into the backdrop color, so both RAM and the picture change
+    fn counting_system() -> System {
+        system_with_program(&[
+            0xE6, 0x10, // INC $10
+            0xA9, 0x3F, // LDA #$3F
+            0x8D, 0x06, 0x20, // STA $2006
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x06, 0x20, // STA $2006
+            0xA5, 0x10, // LDA $10
+            0x8D, 0x07, 0x20, // STA $2007
+            0x4C, 0x00, 0x80, // JMP $8000
+        ])
+    }
+
+    #[test]
+    fn loaded_state_replays_the_same_frames() {
+        let mut system = counting_system();
+        system.run_frame(&mut |_| {});
+        let state = system.save_state();
+        let saved_ram = system.ram().to_vec();
+
+        for _ in 0..3 {
+            system.run_frame(&mut |_| {});
+        }
+        let ram = system.ram().to_vec();
+        let framebuffer = system.framebuffer().to_vec();
+        assert_ne!(ram, saved_ram);
+
+        system.load_state(&state).unwrap();
+        assert_eq!(system.ram(), saved_ram);
+        for _ in 0..3 {
+            system.run_frame(&mut |_| {});
+        }
+        assert_eq!(system.ram(), ram);
+        assert_eq!(system.framebuffer(), framebuffer);
+    }
+
+    #[test]
+    fn invalid_state_leaves_the_system_untouched() {
+        let mut system = counting_system();
+        system.run_frame(&mut |_| {});
+        let state = system.save_state();
+        system.run_frame(&mut |_| {});
+        let before = system.save_state();
+
+        let truncated = &state[..state.len() - 1];
+        assert_eq!(system.load_state(truncated), Err(StateError::UnexpectedEof));
+        assert_eq!(system.save_state(), before);
+
+        let mut trailing = state.clone();
+        trailing.push(0);
+        assert_eq!(system.load_state(&trailing), Err(StateError::InvalidData));
+        assert_eq!(system.save_state(), before);
+
+        let mut wrong_rom = state;
+        wrong_rom[8] ^= 0xFF;
+        assert_eq!(system.load_state(&wrong_rom), Err(StateError::RomMismatch));
+        assert_eq!(system.save_state(), before);
     }
 }